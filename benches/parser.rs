@@ -0,0 +1,70 @@
+//! How fast the trace parser gets through typical and oversized events, e.g. to check
+//! it keeps up with a server running 20k statements a second. Run with `cargo bench`.
+
+use rsfbtrace_model::parser;
+use std::hint::black_box;
+use std::time::Instant;
+
+/// A statement finish as servers print them, with its parameters and table counters.
+fn statement(n: usize) -> String {
+    format!(
+        "2024-01-15T10:23:45.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH
+\t/data/erp.fdb (ATT_{att}, ERP_APP:NONE, UTF8, TCPv4:10.0.0.5/51234)
+\t/opt/erp/bin/server:4567
+\t\t(TRA_{n}, READ_COMMITTED | REC_VERSION | WAIT | READ_WRITE)
+
+Statement {n}:
+-------------------------------------------------------------------------------
+select o.id, o.total, c.name
+from orders o join customers c on c.id = o.customer_id
+where o.customer_id = ? and o.status = ?
+^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
+PLAN JOIN (O INDEX (FK_ORDERS_CUSTOMER), C INDEX (PK_CUSTOMERS))
+param0 = integer, \"{n}\"
+param1 = varchar(10), \"OPEN\"
+
+12 records fetched
+      3 ms, 2 read(s), 140 fetch(es)
+
+Table                             Natural     Index    Update    Insert    Delete   Backout     Purge   Expunge
+***************************************************************************************************************
+ORDERS                                                12
+CUSTOMERS                                             12
+",
+        att = n % 200
+    )
+}
+
+/// Parses `text` line by line, as the trace is read, printing the rate.
+fn bench(name: &str, text: &str) {
+    let started = Instant::now();
+    let mut parser = parser::Parser::default();
+    let mut events = 0;
+    for line in text.lines() {
+        if black_box(parser.push(line)).is_some() {
+            events += 1;
+        }
+    }
+    if parser.finish().is_some() {
+        events += 1;
+    }
+    let secs = started.elapsed().as_secs_f64();
+    println!(
+        "{name}: {events} events in {:.0} ms, {:.0} events/s, {:.0} MB/s",
+        secs * 1000.0,
+        events as f64 / secs,
+        text.len() as f64 / secs / 1e6
+    );
+}
+
+fn main() {
+    let typical: String = (0..200_000).map(statement).collect();
+    bench("statement finishes", &typical);
+
+    // Statements generated by ORMs can run to megabytes, on one line.
+    let sql = format!("select {} from dual", vec!["x"; 1 << 20].join(", "));
+    let huge: String = (0..20)
+        .map(|n| statement(n).replacen("select o.id", &sql, 1))
+        .collect();
+    bench("4 MB statements", &huge);
+}
@@ -0,0 +1,416 @@
+//! Trace events as rsfbtrace parses them from the server's trace text, with everything it
+//! adds along the way, such as IDs, fingerprints and tags.
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The kind of a trace event, as named in the header line emitted by the server.
+/// Serialized as that name, e.g. `EXECUTE_STATEMENT_FINISH`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum EventKind {
+    TraceInit,
+    TraceFini,
+    AttachDatabase,
+    DetachDatabase,
+    StartTransaction,
+    CommitTransaction,
+    CommitRetaining,
+    RollbackTransaction,
+    RollbackRetaining,
+    PrepareStatement,
+    FreeStatement,
+    CloseCursor,
+    ExecuteStatementStart,
+    ExecuteStatementFinish,
+    ExecuteProcedureStart,
+    ExecuteProcedureFinish,
+    ExecuteFunctionStart,
+    ExecuteFunctionFinish,
+    ExecuteTriggerStart,
+    ExecuteTriggerFinish,
+    SetContext,
+    Error,
+    Warning,
+    SweepStart,
+    SweepProgress,
+    SweepFinish,
+    SweepFailed,
+    AttachService,
+    StartService,
+    QueryService,
+    DetachService,
+    /// An entry from the server's firebird.log, interleaved into the trace.
+    ServerLog,
+    /// MON$ state captured by rsfbtrace right after a lock conflict.
+    LockSnapshot,
+    /// Totals of a transaction, emitted by rsfbtrace when it ends.
+    TransactionSummary,
+    /// A lock conflict error, with the statements involved.
+    LockConflict,
+    /// Transactions in several databases linked by `--distributed-key`, once all ended.
+    DistributedTransaction,
+    /// A replication entry of firebird.log or replication.log, see `Replication`.
+    Replication,
+    /// Raised by rsfbtrace when the server looks overwhelmed, see `--on-distress`.
+    ServerDistress,
+    /// The duration and transaction counters of a sweep, emitted by rsfbtrace when it
+    /// ends.
+    SweepReport,
+    /// Throughput and backlog of the trace, emitted by rsfbtrace every `--heartbeat`.
+    Heartbeat,
+    Other(String),
+}
+
+impl EventKind {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "TRACE_INIT" => Self::TraceInit,
+            "TRACE_FINI" => Self::TraceFini,
+            "ATTACH_DATABASE" => Self::AttachDatabase,
+            "DETACH_DATABASE" => Self::DetachDatabase,
+            "START_TRANSACTION" => Self::StartTransaction,
+            "COMMIT_TRANSACTION" => Self::CommitTransaction,
+            "COMMIT_RETAINING" => Self::CommitRetaining,
+            "ROLLBACK_TRANSACTION" => Self::RollbackTransaction,
+            "ROLLBACK_RETAINING" => Self::RollbackRetaining,
+            "PREPARE_STATEMENT" => Self::PrepareStatement,
+            "FREE_STATEMENT" => Self::FreeStatement,
+            "CLOSE_CURSOR" => Self::CloseCursor,
+            "EXECUTE_STATEMENT_START" => Self::ExecuteStatementStart,
+            "EXECUTE_STATEMENT_FINISH" => Self::ExecuteStatementFinish,
+            "EXECUTE_PROCEDURE_START" => Self::ExecuteProcedureStart,
+            "EXECUTE_PROCEDURE_FINISH" => Self::ExecuteProcedureFinish,
+            "EXECUTE_FUNCTION_START" => Self::ExecuteFunctionStart,
+            "EXECUTE_FUNCTION_FINISH" => Self::ExecuteFunctionFinish,
+            "EXECUTE_TRIGGER_START" => Self::ExecuteTriggerStart,
+            "EXECUTE_TRIGGER_FINISH" => Self::ExecuteTriggerFinish,
+            "SET_CONTEXT" => Self::SetContext,
+            "ERROR" => Self::Error,
+            "WARNING" => Self::Warning,
+            "SWEEP_START" => Self::SweepStart,
+            "SWEEP_PROGRESS" => Self::SweepProgress,
+            "SWEEP_FINISH" => Self::SweepFinish,
+            "SWEEP_FAILED" => Self::SweepFailed,
+            "ATTACH_SERVICE" => Self::AttachService,
+            "START_SERVICE" => Self::StartService,
+            "QUERY_SERVICE" => Self::QueryService,
+            "DETACH_SERVICE" => Self::DetachService,
+            "SERVER_LOG" => Self::ServerLog,
+            "LOCK_SNAPSHOT" => Self::LockSnapshot,
+            "TRANSACTION_SUMMARY" => Self::TransactionSummary,
+            "LOCK_CONFLICT" => Self::LockConflict,
+            "DISTRIBUTED_TRANSACTION" => Self::DistributedTransaction,
+            "REPLICATION" => Self::Replication,
+            "SERVER_DISTRESS" => Self::ServerDistress,
+            "SWEEP_REPORT" => Self::SweepReport,
+            "HEARTBEAT" => Self::Heartbeat,
+            other => Self::Other(other.into()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::TraceInit => "TRACE_INIT",
+            Self::TraceFini => "TRACE_FINI",
+            Self::AttachDatabase => "ATTACH_DATABASE",
+            Self::DetachDatabase => "DETACH_DATABASE",
+            Self::StartTransaction => "START_TRANSACTION",
+            Self::CommitTransaction => "COMMIT_TRANSACTION",
+            Self::CommitRetaining => "COMMIT_RETAINING",
+            Self::RollbackTransaction => "ROLLBACK_TRANSACTION",
+            Self::RollbackRetaining => "ROLLBACK_RETAINING",
+            Self::PrepareStatement => "PREPARE_STATEMENT",
+            Self::FreeStatement => "FREE_STATEMENT",
+            Self::CloseCursor => "CLOSE_CURSOR",
+            Self::ExecuteStatementStart => "EXECUTE_STATEMENT_START",
+            Self::ExecuteStatementFinish => "EXECUTE_STATEMENT_FINISH",
+            Self::ExecuteProcedureStart => "EXECUTE_PROCEDURE_START",
+            Self::ExecuteProcedureFinish => "EXECUTE_PROCEDURE_FINISH",
+            Self::ExecuteFunctionStart => "EXECUTE_FUNCTION_START",
+            Self::ExecuteFunctionFinish => "EXECUTE_FUNCTION_FINISH",
+            Self::ExecuteTriggerStart => "EXECUTE_TRIGGER_START",
+            Self::ExecuteTriggerFinish => "EXECUTE_TRIGGER_FINISH",
+            Self::SetContext => "SET_CONTEXT",
+            Self::Error => "ERROR",
+            Self::Warning => "WARNING",
+            Self::SweepStart => "SWEEP_START",
+            Self::SweepProgress => "SWEEP_PROGRESS",
+            Self::SweepFinish => "SWEEP_FINISH",
+            Self::SweepFailed => "SWEEP_FAILED",
+            Self::AttachService => "ATTACH_SERVICE",
+            Self::StartService => "START_SERVICE",
+            Self::QueryService => "QUERY_SERVICE",
+            Self::DetachService => "DETACH_SERVICE",
+            Self::ServerLog => "SERVER_LOG",
+            Self::LockSnapshot => "LOCK_SNAPSHOT",
+            Self::TransactionSummary => "TRANSACTION_SUMMARY",
+            Self::LockConflict => "LOCK_CONFLICT",
+            Self::DistributedTransaction => "DISTRIBUTED_TRANSACTION",
+            Self::Replication => "REPLICATION",
+            Self::ServerDistress => "SERVER_DISTRESS",
+            Self::SweepReport => "SWEEP_REPORT",
+            Self::Heartbeat => "HEARTBEAT",
+            Self::Other(o) => o,
+        }
+    }
+}
+
+impl From<String> for EventKind {
+    fn from(name: String) -> Self {
+        Self::from_name(&name)
+    }
+}
+
+impl From<EventKind> for String {
+    fn from(kind: EventKind) -> Self {
+        kind.name().to_string()
+    }
+}
+
+/// The attachment an event belongs to, e.g.
+/// `/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: i64,
+    pub database: String,
+    pub user: String,
+    pub role: String,
+    pub charset: String,
+    pub remote: String,
+    pub process: Option<String>,
+    pub pid: Option<i64>,
+}
+
+/// The transaction an event belongs to, e.g. `(TRA_45, CONCURRENCY | WAIT | READ_WRITE)`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transaction {
+    pub id: i64,
+    pub options: String,
+}
+
+/// Where a replication log entry comes from: the database replicated from (the primary,
+/// or source) or to (a replica, or target).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Replication {
+    /// `primary` or `replica`, when the entry says.
+    pub role: Option<String>,
+    pub database: Option<String>,
+    /// `ERROR`, `WARNING`, `INFO` or `VERBOSE`.
+    pub severity: String,
+    pub message: String,
+}
+
+/// The first status code of an error, e.g. `335544345 : lock conflict on no wait
+/// transaction`, decoded into Firebird's name for it and its SQLSTATE.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorCode {
+    pub gdscode: i64,
+    /// E.g. `lock_conflict`, if rsfbtrace knows the code.
+    pub name: Option<String>,
+    /// E.g. `40001`, if rsfbtrace knows the code.
+    pub sqlstate: Option<String>,
+}
+
+/// A context variable, e.g. `[USER_SESSION] APP_USER = "jdoe"`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContextVar {
+    /// `USER_SESSION` or `USER_TRANSACTION`.
+    pub namespace: String,
+    pub name: String,
+    /// `None` when the variable is cleared by setting it to NULL.
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Statement {
+    pub id: i64,
+    pub sql: String,
+    pub plan: Option<String>,
+    /// Set when `sql` is incomplete, cut short by the server's `max_sql_length` or by
+    /// `truncate_sql`.
+    pub truncated: bool,
+    /// Set from the SQL by the `--fingerprint` strategy.
+    pub fingerprint: Option<String>,
+}
+
+/// A statement or procedure parameter, e.g. `param1 = varchar(10), "ACME"`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Param {
+    /// The declared type, e.g. `varchar(10)`.
+    pub ty: String,
+    pub value: ParamValue,
+    /// The line as emitted by the server.
+    pub line: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParamValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    /// Exact numerics and floating point values, as printed by the server.
+    Number(String),
+    /// Strings, dates, times and anything else.
+    Text(String),
+}
+
+impl std::fmt::Display for ParamValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Int(n) => write!(f, "{n}"),
+            Self::Number(n) => f.write_str(n),
+            Self::Text(t) => write!(f, "'{}'", t.replace('\'', "''")),
+        }
+    }
+}
+
+/// A row of the per-table counters printed with `print_perf`, e.g. the natural (sequential)
+/// and indexed reads of a table.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableStats {
+    pub table: String,
+    pub natural: i64,
+    pub index: i64,
+    pub update: i64,
+    pub insert: i64,
+    pub delete: i64,
+    pub backout: i64,
+    pub purge: i64,
+    pub expunge: i64,
+}
+
+/// Performance counters from the `N ms, N read(s), ...` line.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Perf {
+    pub duration_ms: i64,
+    pub reads: i64,
+    pub writes: i64,
+    pub fetches: i64,
+    pub marks: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    /// Assigned by `assign_id` when the event enters the stream; empty until then.
+    pub id: String,
+    pub timestamp: String,
+    /// The `(pid:address)` of the server worker that produced the event.
+    pub process: String,
+    pub kind: EventKind,
+    /// Set for `FAILED` and `UNAUTHORIZED` events.
+    pub failed: bool,
+    /// The text after `ERROR AT` / `WARNING AT`, e.g. `JStatement::prepare`, the
+    /// server name for firebird.log entries, the monitored database for snapshots or
+    /// the key of a distributed transaction.
+    pub location: Option<String>,
+    pub attachment: Option<Attachment>,
+    pub transaction: Option<Transaction>,
+    pub statement: Option<Statement>,
+    pub records_fetched: Option<i64>,
+    pub perf: Option<Perf>,
+    /// Only present if the session logs them, see `--max-arg-count`.
+    pub params: Vec<Param>,
+    /// Only present with `--print-perf`. The table is also kept in `lines`, where
+    /// structured output has always had it.
+    pub tables: Vec<TableStats>,
+    /// The `--tag`s of the trace session.
+    pub tags: BTreeMap<String, String>,
+    /// Only present for `REPLICATION` events.
+    pub replication: Option<Replication>,
+    /// Only present for events with a status code, usually errors and warnings.
+    pub error_code: Option<ErrorCode>,
+    /// For `SET_CONTEXT` events, the variables set. For statements, the variables their
+    /// attachment and transaction had set at the time, as far as the trace has seen.
+    pub context: Vec<ContextVar>,
+    /// Body lines not captured by any of the fields above.
+    pub lines: Vec<String>,
+    pub raw: String,
+}
+
+/// How the server formats event timestamps, e.g. `2024-01-15T10:23:45.1230`.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+/// Marks where `Event::truncate_sql` cut the middle out of a statement.
+pub const SQL_ELLIPSIS: &str = " \u{2026} ";
+
+impl Event {
+    pub fn parse_timestamp(timestamp: &str) -> Option<NaiveDateTime> {
+        NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT)
+            .ok()
+            .or_else(|| {
+                chrono::DateTime::parse_from_rfc3339(timestamp)
+                    .ok()
+                    .map(|t| t.naive_local())
+            })
+    }
+
+    /// The event's timestamp, which is in the server's local time.
+    pub fn time(&self) -> Option<NaiveDateTime> {
+        Self::parse_timestamp(&self.timestamp)
+    }
+
+    /// Shortens the statement's SQL to `max` characters plus a marker, keeping its start
+    /// and end: the end is usually where the interesting WHERE clause is.
+    pub fn truncate_sql(&mut self, max: usize) {
+        let Some(stmt) = &mut self.statement else {
+            return;
+        };
+        let len = stmt.sql.chars().count();
+        if len <= max {
+            return;
+        }
+
+        let head: String = stmt.sql.chars().take(max - max / 2).collect();
+        let tail: String = stmt.sql.chars().skip(len - max / 2).collect();
+        let short = format!("{head}{SQL_ELLIPSIS}{tail}");
+
+        self.raw = self.raw.replacen(&stmt.sql, &short, 1);
+        stmt.sql = short;
+        stmt.truncated = true;
+    }
+
+    /// The parameters as a literal list, e.g. `params: [1, 'ACME', null]`.
+    pub fn params_line(&self) -> String {
+        let values: Vec<String> = self.params.iter().map(|p| p.value.to_string()).collect();
+        format!("params: [{}]", values.join(", "))
+    }
+
+    /// The trace text with the parameter lines replaced by `params_line`.
+    pub fn inline_params(&self) -> String {
+        let mut params = self.params.iter();
+        let Some(first) = params.next() else {
+            return self.raw.clone();
+        };
+
+        let mut text = self.raw.replacen(&first.line, &self.params_line(), 1);
+        for p in params {
+            text = text.replacen(&format!("\n{}", p.line), "", 1);
+        }
+        text
+    }
+
+    /// Gives the event its ID, derived from the trace session, the event's position in
+    /// the stream and its timestamp.
+    ///
+    /// The ID only depends on those values, never on the rsfbtrace version or which sink
+    /// writes the event, so it can be used to find the same event in different stores.
+    pub fn assign_id(&mut self, session_id: i64, seq: u64) {
+        let hash = fnv1a(&format!("{session_id}:{seq}:{}", self.timestamp));
+        self.id = format!("{hash:016x}");
+    }
+}
+
+/// FNV-1a, for hashes that must be stable: std's hashers aren't guaranteed to be across
+/// releases.
+pub fn fnv1a(input: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in input.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
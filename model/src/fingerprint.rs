@@ -0,0 +1,290 @@
+//! Fingerprints group statements that only differ in their literal values, so e.g.
+//! `where id = 1` and `where id = 2` are counted as the same statement.
+//!
+//! Whether casing or the length of an IN list should tell statements apart depends on
+//! who's asking, so the normalization is a `Fingerprinter`, picked in rsfbtrace with
+//! `--fingerprint`. Consumers of its output can fingerprint SQL of their own the same way.
+
+use crate::event::fnv1a;
+use std::ops::Range;
+
+/// Derives the fingerprint of a statement from its SQL.
+pub trait Fingerprinter {
+    fn fingerprint(&self, sql: &str) -> String;
+}
+
+pub struct LiteralStrip;
+
+impl Fingerprinter for LiteralStrip {
+    fn fingerprint(&self, sql: &str) -> String {
+        let tokens: Vec<Token> = tokenize(sql)
+            .into_iter()
+            .filter(|t| !t.is_comment())
+            .map(Token::strip_literal)
+            .collect();
+        join(&tokens)
+    }
+}
+
+pub struct Structural;
+
+impl Fingerprinter for Structural {
+    fn fingerprint(&self, sql: &str) -> String {
+        let tokens: Vec<Token> = tokenize(sql)
+            .into_iter()
+            .filter(|t| !t.is_comment())
+            .map(|t| match t.strip_literal() {
+                Token::Word(w) => Token::Word(w.to_lowercase()),
+                t => t,
+            })
+            .collect();
+
+        // `in (?, ?, ?)` becomes `in (?, ...)`, whatever the number of values.
+        let mut out: Vec<Token> = vec![];
+        let mut i = 0;
+        while i < tokens.len() {
+            out.push(tokens[i].clone());
+            i += 1;
+            if out.len() < 2
+                || out[out.len() - 2] != Token::Word("in".into())
+                || !out[out.len() - 1].is_punct("(")
+            {
+                continue;
+            }
+
+            let mut end = i;
+            while tokens.get(end) == Some(&Token::Placeholder) {
+                end += 1;
+                if !tokens.get(end).is_some_and(|t| t.is_punct(",")) {
+                    break;
+                }
+                end += 1;
+            }
+            if end > i && tokens.get(end).is_some_and(|t| t.is_punct(")")) {
+                out.push(Token::List);
+                i = end;
+            }
+        }
+        join(&out)
+    }
+}
+
+pub struct TokenHash;
+
+impl Fingerprinter for TokenHash {
+    fn fingerprint(&self, sql: &str) -> String {
+        format!("{:016x}", fnv1a(&Structural.fingerprint(sql)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Word(String),
+    /// A `"quoted"` identifier, which is case-sensitive.
+    Quoted(String),
+    Str(String),
+    Number(String),
+    /// A `?` or `:name` parameter, or a stripped literal.
+    Placeholder,
+    /// The values of an IN list, collapsed by `Structural`.
+    List,
+    Punct(String),
+    Comment(String),
+}
+
+impl Token {
+    fn is_comment(&self) -> bool {
+        matches!(self, Self::Comment(_))
+    }
+
+    pub fn is_punct(&self, p: &str) -> bool {
+        matches!(self, Self::Punct(s) if s == p)
+    }
+
+    fn strip_literal(self) -> Self {
+        match self {
+            Self::Str(_) | Self::Number(_) => Self::Placeholder,
+            t => t,
+        }
+    }
+
+    fn text(&self) -> &str {
+        match self {
+            Self::Word(s) | Self::Quoted(s) | Self::Str(s) | Self::Number(s) => s,
+            Self::Punct(s) | Self::Comment(s) => s,
+            Self::Placeholder => "?",
+            Self::List => "?, ...",
+        }
+    }
+}
+
+/// Joins tokens with single spaces, except around `.` and inside parentheses, so the
+/// result still reads like SQL.
+fn join(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    let mut prev: Option<&Token> = None;
+    for token in tokens {
+        let text = token.text();
+        let tight = matches!(prev, Some(Token::Punct(p)) if p == "(" || p == ".")
+            || matches!(token, Token::Punct(p) if p == ")" || p == "," || p == ".");
+        if prev.is_some() && !tight {
+            out.push(' ');
+        }
+        out.push_str(text);
+        prev = Some(token);
+    }
+    out
+}
+
+/// Splits SQL (or a plan) into tokens, keeping comments.
+pub fn tokenize(sql: &str) -> Vec<Token> {
+    spans(sql).into_iter().map(|(_, t)| t).collect()
+}
+
+/// Like `tokenize`, with the byte range of each token in `sql`.
+pub fn spans(sql: &str) -> Vec<(Range<usize>, Token)> {
+    let chars: Vec<char> = sql.chars().collect();
+    let offsets: Vec<usize> = sql
+        .char_indices()
+        .map(|(o, _)| o)
+        .chain([sql.len()])
+        .collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    // Reads up to and including the closing `quote`; doubled quotes are escapes.
+    let quoted = |i: &mut usize, quote: char| {
+        let start = *i;
+        *i += 1;
+        while *i < chars.len() {
+            if chars[*i] == quote {
+                if chars.get(*i + 1) == Some(&quote) {
+                    *i += 2;
+                    continue;
+                }
+                *i += 1;
+                break;
+            }
+            *i += 1;
+        }
+        chars[start..*i].iter().collect::<String>()
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let token = match c {
+            _ if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '\'' => Token::Str(quoted(&mut i, '\'')),
+            '"' => Token::Quoted(quoted(&mut i, '"')),
+            '-' if chars.get(i + 1) == Some(&'-') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                Token::Comment(chars[start..i].iter().collect())
+            }
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    i += 1;
+                }
+                i = (i + 2).min(chars.len());
+                Token::Comment(chars[start..i].iter().collect())
+            }
+            '?' => {
+                i += 1;
+                Token::Placeholder
+            }
+            ':' if chars.get(i + 1).is_some_and(|c| c.is_alphabetic()) => {
+                i += 1;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                Token::Placeholder
+            }
+            _ if c.is_ascii_digit()
+                || (c == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit)) =>
+            {
+                while i < chars.len()
+                    && (chars[i].is_ascii_alphanumeric()
+                        || chars[i] == '.'
+                        || (matches!(chars[i], '+' | '-') && matches!(chars[i - 1], 'e' | 'E')))
+                {
+                    i += 1;
+                }
+                Token::Number(chars[start..i].iter().collect())
+            }
+            _ if c.is_alphabetic() || c == '_' || c == '$' => {
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$')
+                {
+                    i += 1;
+                }
+                Token::Word(chars[start..i].iter().collect())
+            }
+            _ => {
+                i += 1;
+                // Keep two-character operators together.
+                if let Some(&next) = chars.get(i) {
+                    if matches!(
+                        (c, next),
+                        ('<', '>' | '=') | ('>' | '!' | '^' | '~', '=') | ('|', '|')
+                    ) {
+                        i += 1;
+                    }
+                }
+                Token::Punct(chars[start..i].iter().collect())
+            }
+        };
+        tokens.push((offsets[start]..offsets[i], token));
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQL: &str =
+        "SELECT * FROM customers\n  WHERE id IN (1, 2, 3) -- ids\n  AND name = 'O''Brien'";
+
+    #[test]
+    fn literal_strip_keeps_casing_and_in_lists() {
+        assert_eq!(
+            LiteralStrip.fingerprint(SQL),
+            "SELECT * FROM customers WHERE id IN (?, ?, ?) AND name = ?"
+        );
+        assert_ne!(
+            LiteralStrip.fingerprint("select 1 from rdb$database"),
+            LiteralStrip.fingerprint("SELECT 1 FROM RDB$DATABASE")
+        );
+    }
+
+    #[test]
+    fn structural_ignores_casing_and_in_list_length() {
+        assert_eq!(
+            Structural.fingerprint(SQL),
+            "select * from customers where id in (?, ...) and name = ?"
+        );
+        assert_eq!(
+            Structural.fingerprint("select * from t where id in (:a)"),
+            Structural.fingerprint("SELECT * FROM T WHERE ID IN (?, ?)")
+        );
+        assert_ne!(
+            Structural.fingerprint("select \"Name\" from t"),
+            Structural.fingerprint("select \"NAME\" from t")
+        );
+    }
+
+    #[test]
+    fn token_hash_is_stable() {
+        assert_eq!(
+            TokenHash.fingerprint(SQL),
+            TokenHash.fingerprint("select * from CUSTOMERS where id in (4) and name = 'x'")
+        );
+        assert_eq!(TokenHash.fingerprint(SQL).len(), 16);
+    }
+}
@@ -0,0 +1,136 @@
+//! Firebird's status codes, which the trace prints as bare numbers, e.g. `335544345 :
+//! lock conflict on no wait transaction`, with their symbolic names, SQLSTATEs and what
+//! they usually mean.
+
+use crate::event::{ErrorCode, Event};
+
+pub struct Code {
+    pub gdscode: i64,
+    pub name: &'static str,
+    pub sqlstate: &'static str,
+    pub explanation: &'static str,
+}
+
+const fn code(
+    gdscode: i64,
+    name: &'static str,
+    sqlstate: &'static str,
+    explanation: &'static str,
+) -> Code {
+    Code {
+        gdscode,
+        name,
+        sqlstate,
+        explanation,
+    }
+}
+
+/// The codes most often seen in traces, by number.
+const CODES: &[Code] = &[
+    code(335544321, "arith_except", "22000", "a calculation overflowed, or a value didn't fit its column or variable"),
+    code(335544333, "bug_check", "XX000", "the server hit an internal inconsistency; check firebird.log, the database may need validating"),
+    code(335544334, "convert_error", "22018", "a string couldn't be converted to the type it was used as, e.g. a number or a date"),
+    code(335544336, "deadlock", "40001", "the transaction was chosen to resolve a deadlock or an update conflict; retry it"),
+    code(335544342, "integ_fail", "27000", "a trigger cancelled the action to preserve data integrity"),
+    code(335544344, "io_error", "58030", "the server couldn't read or write a database file"),
+    code(335544345, "lock_conflict", "40001", "the record is locked by another transaction, and this one was started NO WAIT"),
+    code(335544347, "not_valid", "23000", "a value broke a NOT NULL or domain constraint"),
+    code(335544348, "no_cur_rec", "22000", "a cursor was used where it isn't positioned on a record"),
+    code(335544349, "no_dup", "23000", "a value already exists in a unique index"),
+    code(335544351, "no_meta_update", "2F000", "a metadata change failed, often because the object is in use"),
+    code(335544352, "no_priv", "28000", "the user or role lacks a privilege on the object"),
+    code(335544361, "read_only_trans", "25006", "a read-only transaction tried to change data"),
+    code(335544375, "unavailable", "08001", "the database or server is unavailable"),
+    code(335544436, "sqlerr", "42000", "a statement failed; the following codes say why"),
+    code(335544451, "update_conflict", "40001", "the record was changed by a concurrent transaction since this one started; retry it"),
+    code(335544466, "foreign_key", "23000", "a FOREIGN KEY constraint was violated"),
+    code(335544472, "login", "28000", "the user name or password is wrong, or the user isn't defined"),
+    code(335544510, "lock_timeout", "40001", "the record stayed locked by another transaction for longer than the lock timeout"),
+    code(335544517, "except", "HY000", "a user-defined exception was raised by PSQL code"),
+    code(335544558, "check_constraint", "23000", "a CHECK constraint was violated"),
+    code(335544569, "dsql_error", "42000", "the statement couldn't be prepared; the following codes say why"),
+    code(335544578, "dsql_field_err", "42S22", "the statement names a column that doesn't exist"),
+    code(335544580, "dsql_relation_err", "42S02", "the statement names a table or view that doesn't exist"),
+    code(335544634, "dsql_token_unk_err", "42000", "the statement has a syntax error"),
+    code(335544665, "unique_key_violation", "23000", "a PRIMARY KEY or UNIQUE constraint was violated"),
+    code(335544721, "network_error", "08006", "the server couldn't be reached over the network"),
+    code(335544778, "exception_integer_divide_by_zero", "22012", "an integer was divided by zero"),
+    code(335544794, "cancelled", "HY008", "the operation was cancelled, e.g. by fb_cancel_operation or a statement timeout"),
+    code(335544838, "foreign_key_target_doesnt_exist", "23000", "the referenced master record doesn't exist"),
+    code(335544839, "foreign_key_references_present", "23000", "detail records still reference the record"),
+    code(335544856, "att_shutdown", "08003", "the connection was shut down, e.g. by the database going offline or an idle timeout"),
+    code(335544878, "concurrent_transaction", "40001", "names the transaction holding the conflicting record version"),
+    code(335544914, "string_truncation", "22001", "a string was longer than its column or variable allows"),
+];
+
+pub fn lookup(gdscode: i64) -> Option<&'static Code> {
+    CODES
+        .binary_search_by_key(&gdscode, |c| c.gdscode)
+        .ok()
+        .map(|i| &CODES[i])
+}
+
+/// The first status code among an event's body lines, decoded.
+pub fn decode(lines: &[String]) -> Option<ErrorCode> {
+    let gdscode = lines.iter().find_map(|line| status_code(line))?;
+    let known = lookup(gdscode);
+    Some(ErrorCode {
+        gdscode,
+        name: known.map(|c| c.name.into()),
+        sqlstate: known.map(|c| c.sqlstate.into()),
+    })
+}
+
+/// The code of a status line, e.g. `335544345 : lock conflict on no wait transaction`.
+pub fn status_code(line: &str) -> Option<i64> {
+    let (code, _) = line.trim().split_once(" : ")?;
+    code.parse().ok()
+}
+
+/// What the event's status code means, for people, e.g. `lock_conflict (SQLSTATE 40001):
+/// the record is locked by another transaction, ...`.
+pub fn explain(event: &Event) -> Option<String> {
+    let code = lookup(event.error_code.as_ref()?.gdscode)?;
+    Some(format!(
+        "{} (SQLSTATE {}): {}",
+        code.name, code.sqlstate, code.explanation
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn decodes_the_first_status_code() {
+        assert!(CODES.windows(2).all(|w| w[0].gdscode < w[1].gdscode));
+
+        let mut parser = Parser::default();
+        for line in [
+            "2024-01-15T10:23:45.3450 (1234:00007F12AB) ERROR AT JStatement::execute",
+            "\t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)",
+            "\t/usr/bin/isql:4567",
+            "335544345 : lock conflict on no wait transaction",
+            "335544878 : concurrent transaction number is 99",
+        ] {
+            parser.push(line);
+        }
+        let event = parser.finish().unwrap();
+        assert_eq!(
+            event.error_code,
+            Some(ErrorCode {
+                gdscode: 335544345,
+                name: Some("lock_conflict".into()),
+                sqlstate: Some("40001".into()),
+            })
+        );
+        assert!(explain(&event)
+            .unwrap()
+            .starts_with("lock_conflict (SQLSTATE 40001): the record is locked"));
+
+        let unknown = decode(&["335599999 : something new".into()]).unwrap();
+        assert_eq!((unknown.gdscode, unknown.name), (335599999, None));
+        assert_eq!(decode(&["1 records fetched".into()]), None);
+    }
+}
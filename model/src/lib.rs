@@ -0,0 +1,16 @@
+//! The trace events of rsfbtrace, and the layout of its JSON output, for programs
+//! consuming what it captures, e.g. from a Kafka topic, with the same types rsfbtrace
+//! writes it with. The parser of fbtracemgr's text is here as well, to read traces
+//! saved from it.
+//!
+//! ```
+//! let line = r#"{"schema_version":1,"id":"5f2a","timestamp":"2024-01-15T10:23:45.3450","process":"(1234:00007F12AB)","kind":"EXECUTE_STATEMENT_FINISH","failed":false,"location":null,"attachment":null,"transaction":null,"statement":null,"params":[1,"ACME"],"records_fetched":1,"perf":null,"lines":[],"raw":"","tags":{},"replication":null,"error_code":null,"context":[]}"#;
+//! let event: rsfbtrace_model::schema::Event = serde_json::from_str(line).unwrap();
+//! assert_eq!(event.kind, rsfbtrace_model::event::EventKind::ExecuteStatementFinish);
+//! ```
+
+pub mod event;
+pub mod fingerprint;
+pub mod gdscode;
+pub mod parser;
+pub mod schema;
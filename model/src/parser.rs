@@ -0,0 +1,644 @@
+use crate::event::{
+    Attachment, ContextVar, Event, EventKind, Param, ParamValue, Perf, Statement, TableStats,
+    Transaction,
+};
+use crate::gdscode;
+
+/// The most text kept of one event. Lines past it are dropped, so one runaway event,
+/// e.g. with megabytes of parameters, can't take all the memory.
+pub const MAX_EVENT_BYTES: usize = 16 << 20;
+
+/// What the buffers of events and lines are shrunk back to after holding unusually large
+/// ones.
+pub const RETAINED_BYTES: usize = 64 << 10;
+
+/// The server's `max_sql_length` when the session's isn't known, e.g. for a trace read
+/// back from a file.
+pub const DEFAULT_MAX_SQL: usize = 300;
+
+/// Incremental parser for the text emitted by `fbtracemgr`.
+///
+/// Events are delimited by their header line, so an event is only complete once the
+/// header of the next one (or the end of the stream) has been seen. The lines of the
+/// event being collected are kept in one buffer, reused from event to event, and parsed
+/// from it once it's complete. The event owns its strings, so its text, statement and
+/// lines are still copied out of the buffer: what's saved is the allocation per line.
+#[derive(Debug, Default)]
+pub struct Parser {
+    /// The lines of the current event, each ended by a newline. Empty between events.
+    text: String,
+    /// Events whose header couldn't be made sense of, and were skipped.
+    pub unparsed: u64,
+    /// Events that were cut at `MAX_EVENT_BYTES`.
+    pub cut: u64,
+    cutting: bool,
+    /// The `max_sql_length` of the session, which statements the server cut are as long
+    /// as. `DEFAULT_MAX_SQL` if not known.
+    pub max_sql: Option<usize>,
+}
+
+impl Parser {
+    /// Feeds a single line (without the trailing newline), returning the previous
+    /// event if this line started a new one.
+    pub fn push(&mut self, line: &str) -> Option<Event> {
+        if is_header(line) {
+            let done = self.finish();
+            self.text.push_str(line);
+            self.text.push('\n');
+            return done;
+        }
+
+        // Anything before the first header (e.g. the "Trace session ID N started" banner)
+        // is not part of an event.
+        // Nor is the rest of an event once it was cut, as what follows the gap would be
+        // read as if it followed on.
+        if self.text.is_empty() || self.cutting {
+            return None;
+        }
+        if self.text.len() + line.len() >= MAX_EVENT_BYTES {
+            if !self.cutting {
+                self.cutting = true;
+                self.cut += 1;
+            }
+            return None;
+        }
+        self.text.push_str(line);
+        self.text.push('\n');
+        None
+    }
+
+    /// Whether lines are currently being collected into an event.
+    pub fn in_event(&self) -> bool {
+        !self.text.is_empty()
+    }
+
+    /// Flushes the event currently being accumulated.
+    pub fn finish(&mut self) -> Option<Event> {
+        if self.text.is_empty() {
+            return None;
+        }
+        let event = parse_block(&self.text, self.max_sql.unwrap_or(DEFAULT_MAX_SQL));
+        if event.is_none() {
+            self.unparsed += 1;
+        }
+        self.text.clear();
+        self.text.shrink_to(RETAINED_BYTES);
+        self.cutting = false;
+        event
+    }
+}
+
+/// Parses every event of a whole trace, e.g. one kept as text.
+pub fn parse_all(text: &str) -> Vec<Event> {
+    let mut parser = Parser::default();
+    let mut events: Vec<Event> = text.lines().filter_map(|l| parser.push(l)).collect();
+    events.extend(parser.finish());
+    events
+}
+
+/// Header lines look like `2024-01-15T10:23:45.1230 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH`.
+pub fn is_header(line: &str) -> bool {
+    let b = line.as_bytes();
+    b.len() > 20
+        && b[..4].iter().all(u8::is_ascii_digit)
+        && b[4] == b'-'
+        && b[7] == b'-'
+        && b[10] == b'T'
+        && line.contains(" (")
+}
+
+/// Parses the lines of an event, each ended by a newline.
+fn parse_block(block: &str, max_sql: usize) -> Option<Event> {
+    let mut lines = block
+        .strip_suffix('\n')
+        .unwrap_or(block)
+        .split('\n')
+        .peekable();
+    let header = lines.next()?;
+
+    let (timestamp, rest) = header.split_once(' ')?;
+    let rest = rest.strip_prefix('(')?;
+    let (process, name) = rest.split_once(") ")?;
+
+    let mut failed = false;
+    let mut name = name.trim();
+    for prefix in ["FAILED ", "UNAUTHORIZED "] {
+        if let Some(n) = name.strip_prefix(prefix) {
+            failed = true;
+            name = n;
+        }
+    }
+
+    let (kind, location) = if let Some(loc) = name.strip_prefix("ERROR AT ") {
+        (EventKind::Error, Some(loc.to_string()))
+    } else if let Some(loc) = name.strip_prefix("WARNING AT ") {
+        (EventKind::Warning, Some(loc.to_string()))
+    } else {
+        (EventKind::from_name(name), None)
+    };
+
+    let mut event = Event {
+        id: String::new(),
+        timestamp: timestamp.into(),
+        process: process.into(),
+        kind,
+        failed,
+        location,
+        attachment: None,
+        transaction: None,
+        statement: None,
+        records_fetched: None,
+        perf: None,
+        params: vec![],
+        tables: vec![],
+        tags: Default::default(),
+        replication: None,
+        error_code: None,
+        context: vec![],
+        lines: vec![],
+        raw: block.trim_end().into(),
+    };
+
+    if let Some(att) = lines.peek().and_then(|l| parse_attachment(l)) {
+        lines.next();
+        event.attachment = Some(att);
+
+        // The client process line, e.g. `\t/usr/bin/isql:4567`
+        if let Some(l) = lines.next_if(|l| is_info_line(l) && !l.contains("(TRA_")) {
+            if let Some(att) = &mut event.attachment {
+                let l = l.trim();
+                match l.rsplit_once(':') {
+                    Some((path, pid)) if pid.parse::<i64>().is_ok() => {
+                        att.process = Some(path.into());
+                        att.pid = pid.parse().ok();
+                    }
+                    _ => att.process = Some(l.into()),
+                }
+            }
+        }
+    }
+
+    if let Some(tra) = lines.peek().and_then(|l| parse_transaction(l)) {
+        lines.next();
+        event.transaction = Some(tra);
+    }
+
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim();
+
+        if let Some(id) = trimmed
+            .strip_prefix("Statement ")
+            .and_then(|s| s.strip_suffix(':'))
+            .and_then(|s| s.parse().ok())
+        {
+            lines.next_if(|l| l.starts_with("----"));
+
+            // Statements can have blank lines of their own, so only the plan, the
+            // parameters or the counters end one.
+            let mut sql = Span::default();
+            while let Some(l) = lines.next_if(|l| !is_plan_marker(l) && !is_param(l)) {
+                if l.is_empty() && sql_ended(lines.clone()) {
+                    break;
+                }
+                if !l.is_empty() {
+                    sql.extend(l);
+                }
+            }
+
+            let mut plan = None;
+            if lines.next_if(|l| is_plan_marker(l)).is_some() {
+                let mut p = Span::default();
+                while let Some(l) = lines.next_if(|l| !l.is_empty() && !is_param(l)) {
+                    p.extend(l);
+                }
+                plan = Some(p.text(block).into());
+            }
+
+            let sql = sql.text(block).to_string();
+            event.statement = Some(Statement {
+                id,
+                truncated: cut_at(&sql, max_sql),
+                sql,
+                plan,
+                fingerprint: None,
+            });
+        } else if is_param(line) {
+            // String values keep their line breaks, so a value can span several lines.
+            let mut text = Span::default();
+            text.extend(line);
+            while !param_complete(text.text(block)) {
+                match lines.next() {
+                    Some(l) => text.extend(l),
+                    None => break,
+                }
+            }
+            event.params.push(parse_param(text.text(block).into()));
+        } else if let Some(n) = trimmed
+            .strip_suffix(" records fetched")
+            .and_then(|n| n.parse().ok())
+        {
+            event.records_fetched = Some(n);
+        } else if let Some(perf) = parse_perf(trimmed) {
+            event.perf = Some(perf);
+        } else if is_table_header(line) {
+            event.lines.push(line.into());
+            let columns = table_columns(line);
+            while let Some(l) = lines.next_if(|l| !l.trim().is_empty()) {
+                event.lines.push(l.into());
+                event.tables.extend(parse_table_row(l, &columns));
+            }
+        } else if !trimmed.is_empty() {
+            event.lines.push(line.into());
+        }
+    }
+    event.error_code = gdscode::decode(&event.lines);
+    if event.kind == EventKind::SetContext {
+        event.context = event
+            .lines
+            .iter()
+            .filter_map(|l| parse_context(l))
+            .collect();
+    }
+
+    Some(event)
+}
+
+/// Whether the blank line before `rest` ends a statement's text: only the parameters,
+/// the counters or the status printed after a statement, if anything, follow it.
+fn sql_ended<'a>(rest: impl Iterator<Item = &'a str>) -> bool {
+    let next = rest.map(str::trim).find(|l| !l.is_empty());
+    next.is_none_or(|l| {
+        is_param(l)
+            || is_plan_marker(l)
+            || is_table_header(l)
+            || l.ends_with(" records fetched")
+            || parse_perf(l).is_some()
+            || gdscode::status_code(l).is_some()
+    })
+}
+
+/// Whether `sql` was cut by the server at `max_sql` bytes, which it does by keeping
+/// `max_sql - 3` of them and adding an ellipsis. Allows for the carriage returns taken
+/// off line ends and a character cut in two being replaced, so statements that simply
+/// end in "..." aren't taken for cut ones.
+fn cut_at(sql: &str, max_sql: usize) -> bool {
+    let line_ends = sql.matches('\n').count();
+    let shortest = max_sql.saturating_sub(line_ends + 2);
+    sql.ends_with("...") && (shortest..=max_sql + 2).contains(&sql.len())
+}
+
+/// Consecutive lines of an event's text, taken from it as one slice rather than joined.
+#[derive(Default)]
+struct Span {
+    /// Where the first line starts in memory, and where the last ends.
+    first: Option<usize>,
+    end: usize,
+}
+
+impl Span {
+    fn extend(&mut self, line: &str) {
+        let start = line.as_ptr() as usize;
+        self.first.get_or_insert(start);
+        self.end = start + line.len();
+    }
+
+    /// The lines in `block`, the text they were taken from, with their line breaks.
+    fn text<'a>(&self, block: &'a str) -> &'a str {
+        let base = block.as_ptr() as usize;
+        match self.first {
+            Some(first) => &block[first - base..self.end - base],
+            None => "",
+        }
+    }
+}
+
+/// Lines describing the attachment, process and transaction are indented with tabs.
+fn is_info_line(line: &str) -> bool {
+    line.starts_with('\t')
+}
+
+fn is_plan_marker(line: &str) -> bool {
+    line.starts_with("^^^")
+}
+
+fn is_param(line: &str) -> bool {
+    line.strip_prefix("param")
+        .and_then(|l| l.split_once(" = "))
+        .is_some_and(|(n, _)| n.parse::<u32>().is_ok())
+}
+
+/// Whether the value of a parameter line has its closing quote.
+fn param_complete(text: &str) -> bool {
+    match text.split_once(", \"") {
+        Some((_, value)) => value.ends_with('"'),
+        None => true,
+    }
+}
+
+/// Parses `param0 = integer, "1"`, `param1 = varchar(10), "ACME"` or
+/// `param2 = integer, <NULL>` into a typed value.
+fn parse_param(line: String) -> Param {
+    let (_, rest) = line.split_once(" = ").unwrap_or_default();
+    let (ty, value) = match rest.split_once(", \"") {
+        Some((ty, v)) => (ty, Some(v.strip_suffix('"').unwrap_or(v))),
+        None => (rest.strip_suffix(", <NULL>").unwrap_or(rest), None),
+    };
+
+    let value = match value {
+        None => ParamValue::Null,
+        Some(v) => match ty {
+            "boolean" => match v.trim_matches(['<', '>']) {
+                "true" => ParamValue::Bool(true),
+                "false" => ParamValue::Bool(false),
+                _ => ParamValue::Text(v.into()),
+            },
+            "smallint" | "integer" | "bigint" => match v.parse() {
+                Ok(n) => ParamValue::Int(n),
+                Err(_) => ParamValue::Number(v.into()),
+            },
+            _ if is_numeric_type(ty) && v.parse::<f64>().is_ok() => ParamValue::Number(v.into()),
+            _ => ParamValue::Text(v.into()),
+        },
+    };
+
+    Param {
+        ty: ty.into(),
+        value,
+        line,
+    }
+}
+
+fn is_numeric_type(ty: &str) -> bool {
+    [
+        "numeric", "decimal", "float", "double", "decfloat", "int128",
+    ]
+    .iter()
+    .any(|t| ty.starts_with(t))
+}
+
+pub fn is_table_header(line: &str) -> bool {
+    line.starts_with("Table") && line.contains("Natural")
+}
+
+/// The names of the counter columns and where they end, in characters. Counters are
+/// right-aligned below their name and left blank when zero, so they're read by position.
+fn table_columns(header: &str) -> Vec<(String, usize)> {
+    let mut columns = vec![];
+    let mut word = String::new();
+    for (i, c) in header.chars().chain([' ']).enumerate() {
+        if !c.is_whitespace() {
+            word.push(c);
+        } else if !word.is_empty() {
+            columns.push((std::mem::take(&mut word).to_lowercase(), i));
+        }
+    }
+    // The first column is the table name.
+    columns.into_iter().skip(1).collect()
+}
+
+/// Parses a row of the per-table counters, e.g. `CUSTOMERS    1204    3`.
+fn parse_table_row(row: &str, columns: &[(String, usize)]) -> Option<TableStats> {
+    if row.starts_with('*') {
+        return None;
+    }
+    let width = match columns {
+        [(_, a), (_, b), ..] => b - a,
+        _ => 10,
+    };
+    // Columns are counted in characters; rows are nearly always ASCII, where those are
+    // the bytes.
+    let offsets: Option<Vec<usize>> = (!row.is_ascii()).then(|| {
+        row.char_indices()
+            .map(|(i, _)| i)
+            .chain([row.len()])
+            .collect()
+    });
+    let at = |chars: usize| match &offsets {
+        Some(offsets) => offsets[chars.min(offsets.len() - 1)],
+        None => chars.min(row.len()),
+    };
+    let cell = |end: usize| &row[at(end.saturating_sub(width))..at(end)];
+
+    let first = columns.first()?.1;
+    let mut stats = TableStats {
+        table: row[..at(first.saturating_sub(width))].trim().into(),
+        ..Default::default()
+    };
+    if stats.table.is_empty() {
+        return None;
+    }
+
+    for (name, end) in columns {
+        let n = cell(*end).trim().parse().unwrap_or_default();
+        match name.as_str() {
+            "natural" => stats.natural = n,
+            "index" => stats.index = n,
+            "update" => stats.update = n,
+            "insert" => stats.insert = n,
+            "delete" => stats.delete = n,
+            "backout" => stats.backout = n,
+            "purge" => stats.purge = n,
+            "expunge" => stats.expunge = n,
+            _ => {}
+        }
+    }
+    Some(stats)
+}
+
+fn parse_attachment(line: &str) -> Option<Attachment> {
+    if !is_info_line(line) {
+        return None;
+    }
+
+    let line = line.trim();
+    let start = line.rfind(" (ATT_")?;
+    let inner = line[start + 2..].strip_suffix(')')?;
+    let mut parts = inner.split(", ");
+
+    let id = parts.next()?.strip_prefix("ATT_")?.parse().ok()?;
+    let (user, role) = parts
+        .next()
+        .map(|p| p.split_once(':').unwrap_or((p, "")))
+        .unwrap_or_default();
+
+    Some(Attachment {
+        id,
+        database: line[..start].into(),
+        user: user.into(),
+        role: role.into(),
+        charset: parts.next().unwrap_or_default().into(),
+        remote: parts.collect::<Vec<_>>().join(", "),
+        process: None,
+        pid: None,
+    })
+}
+
+/// A context variable being set, e.g. `[USER_SESSION] APP_USER = "jdoe"`, or cleared,
+/// `[USER_SESSION] APP_USER = NULL`.
+fn parse_context(line: &str) -> Option<ContextVar> {
+    let (namespace, rest) = line.trim().strip_prefix('[')?.split_once("] ")?;
+    let (name, value) = rest.split_once(" = ")?;
+    let value = match value {
+        "NULL" => None,
+        v => Some(v.strip_prefix('"')?.strip_suffix('"')?.into()),
+    };
+    Some(ContextVar {
+        namespace: namespace.into(),
+        name: name.into(),
+        value,
+    })
+}
+
+fn parse_transaction(line: &str) -> Option<Transaction> {
+    let inner = line.trim().strip_prefix("(TRA_")?.strip_suffix(')')?;
+    let (id, options) = inner.split_once(", ").unwrap_or((inner, ""));
+
+    Some(Transaction {
+        id: id.parse().ok()?,
+        options: options.into(),
+    })
+}
+
+/// Parses `5 ms, 10 read(s), 2 write(s), 30 fetch(es), 1 mark(s)`; every counter but
+/// the duration is optional.
+fn parse_perf(line: &str) -> Option<Perf> {
+    let mut parts = line.split(", ");
+    let mut perf = Perf {
+        duration_ms: parts.next()?.strip_suffix(" ms")?.parse().ok()?,
+        ..Default::default()
+    };
+
+    for part in parts {
+        let (n, unit) = part.split_once(' ')?;
+        let n = n.parse().ok()?;
+        match unit {
+            "read(s)" => perf.reads = n,
+            "write(s)" => perf.writes = n,
+            "fetch(es)" => perf.fetches = n,
+            "mark(s)" => perf.marks = n,
+            _ => return None,
+        }
+    }
+
+    Some(perf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_per_table_counters_by_position() {
+        let mut parser = Parser::default();
+        for line in [
+            "2024-01-15T10:23:45.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH",
+            "      5 ms, 10 read(s), 2 write(s), 30 fetch(es), 1 mark(s)",
+            "",
+            "Table                             Natural     Index    Update    Insert    Delete   Backout     Purge   Expunge",
+            "***************************************************************************************************************",
+            "CUSTOMERS                                        1",
+            "ORDERS                               1204         3                   2",
+        ] {
+            parser.push(line);
+        }
+        let event = parser.finish().unwrap();
+
+        assert_eq!(event.tables.len(), 2);
+        assert_eq!((event.tables[0].natural, event.tables[0].index), (0, 1));
+        assert_eq!(event.tables[1].table, "ORDERS");
+        assert_eq!(
+            (
+                event.tables[1].natural,
+                event.tables[1].index,
+                event.tables[1].insert
+            ),
+            (1204, 3, 2)
+        );
+        assert_eq!(event.lines.len(), 4);
+    }
+
+    #[test]
+    fn takes_multi_line_text_from_the_event_and_cuts_runaway_ones() {
+        let mut parser = Parser::default();
+        for line in [
+            "Trace session ID 5 started",
+            "2024-01-15T10:23:45.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH",
+            "",
+            "Statement 345:",
+            "-------------------------------------------------------------------------------",
+            "select *",
+            "from customers where name = ?",
+            "^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^",
+            "PLAN (CUSTOMERS NATURAL)",
+            "param0 = varchar(20), \"two",
+            "lines\"",
+            "      5 ms, 10 read(s)",
+        ] {
+            assert!(parser.push(line).is_none());
+        }
+        let event = parser.finish().unwrap();
+        let stmt = event.statement.unwrap();
+        assert_eq!(stmt.sql, "select *\nfrom customers where name = ?");
+        assert_eq!(stmt.plan.as_deref(), Some("PLAN (CUSTOMERS NATURAL)"));
+        assert_eq!(event.params[0].value, ParamValue::Text("two\nlines".into()));
+        assert!(
+            event.raw.starts_with("2024-01-15T10:23:45.3450") && event.raw.ends_with("read(s)")
+        );
+
+        let long = "x".repeat(MAX_EVENT_BYTES / 2);
+        parser.push("2024-01-15T10:23:46.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH");
+        for _ in 0..3 {
+            parser.push(&long);
+        }
+        let event = parser
+            .push("2024-01-15T10:23:47.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH")
+            .unwrap();
+        assert!(event.raw.len() < MAX_EVENT_BYTES);
+        assert_eq!(parser.cut, 1);
+        assert!(parser.text.capacity() < MAX_EVENT_BYTES);
+
+        // Lines short enough to fit after the cut are dropped with the rest of the event.
+        parser.push(&long);
+        parser.push(&long);
+        parser.push("      5 ms, 10 read(s)");
+        let event = parser.finish().unwrap();
+        assert!(event.raw.ends_with(&long));
+        assert!(!event.raw.contains("read(s)") && event.perf.is_none());
+        assert_eq!(parser.cut, 2);
+    }
+
+    #[test]
+    fn keeps_blank_lines_of_statements_and_tells_cut_ones_by_length() {
+        let statement = |sql: &str, max_sql| {
+            let mut parser = Parser {
+                max_sql: Some(max_sql),
+                ..Default::default()
+            };
+            for line in [
+                "2024-01-15T10:23:45.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH",
+                "",
+                "Statement 345:",
+                "-------------------------------------------------------------------------------",
+            ]
+            .into_iter()
+            .chain(sql.lines())
+            .chain(["", "param0 = integer, \"1\"", "", "1 records fetched"])
+            {
+                parser.push(line);
+            }
+            let event = parser.finish().unwrap();
+            (event.statement.unwrap(), event.lines)
+        };
+
+        let sql = "select *\n\nfrom customers\n\n\nwhere name = 'O''Brien' and id = ?";
+        let (stmt, lines) = statement(sql, 65536);
+        assert_eq!(stmt.sql, sql);
+        assert!(lines.is_empty());
+        assert!(!stmt.truncated);
+
+        let (stmt, _) = statement("select 'to be continued...'", 65536);
+        assert!(!stmt.truncated);
+        let (stmt, _) = statement(&format!("select '{}...", "x".repeat(289)), 300);
+        assert!(stmt.truncated);
+    }
+}
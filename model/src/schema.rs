@@ -0,0 +1,118 @@
+//! The JSON objects rsfbtrace writes, one per event, in the latest format version.
+
+use crate::event::{
+    self, Attachment, ContextVar, ErrorCode, EventKind, ParamValue, Perf, Replication, Statement,
+    Transaction,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The format version described here.
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    /// `VERSION`, for consumers reading events of several.
+    pub schema_version: u32,
+    pub id: String,
+    pub timestamp: String,
+    pub process: String,
+    pub kind: EventKind,
+    pub failed: bool,
+    pub location: Option<String>,
+    pub attachment: Option<Attachment>,
+    pub transaction: Option<Transaction>,
+    pub statement: Option<Statement>,
+    pub params: Vec<Param>,
+    pub records_fetched: Option<i64>,
+    pub perf: Option<Perf>,
+    pub lines: Vec<String>,
+    pub raw: String,
+    pub tags: BTreeMap<String, String>,
+    pub replication: Option<Replication>,
+    pub error_code: Option<ErrorCode>,
+    pub context: Vec<ContextVar>,
+}
+
+/// A parameter value. Numbers the server printed beyond f64's range or precision are
+/// kept as text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Param {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl From<&ParamValue> for Param {
+    fn from(value: &ParamValue) -> Self {
+        match value {
+            ParamValue::Null => Self::Null,
+            ParamValue::Bool(b) => Self::Bool(*b),
+            ParamValue::Int(n) => Self::Int(*n),
+            ParamValue::Number(n) => {
+                let canonical = match n.contains('.') {
+                    true => n.trim_end_matches('0').trim_end_matches('.'),
+                    false => n,
+                };
+                match n.parse::<f64>() {
+                    Ok(f) if f.to_string() == canonical => Self::Float(f),
+                    _ => Self::Text(n.clone()),
+                }
+            }
+            ParamValue::Text(t) => Self::Text(t.clone()),
+        }
+    }
+}
+
+impl From<&event::Event> for Event {
+    fn from(e: &event::Event) -> Self {
+        Self {
+            schema_version: VERSION,
+            id: e.id.clone(),
+            timestamp: e.timestamp.clone(),
+            process: e.process.clone(),
+            kind: e.kind.clone(),
+            failed: e.failed,
+            location: e.location.clone(),
+            attachment: e.attachment.clone(),
+            transaction: e.transaction.clone(),
+            statement: e.statement.clone(),
+            params: e.params.iter().map(|p| Param::from(&p.value)).collect(),
+            records_fetched: e.records_fetched,
+            perf: e.perf.clone(),
+            lines: e.lines.clone(),
+            raw: e.raw.clone(),
+            tags: e.tags.clone(),
+            replication: e.replication.clone(),
+            error_code: e.error_code.clone(),
+            context: e.context.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params_keep_their_json_types() {
+        let values = [
+            ParamValue::Null,
+            ParamValue::Bool(true),
+            ParamValue::Int(42),
+            ParamValue::Number("12.50".into()),
+            ParamValue::Number("12345678901234567890.123".into()),
+            ParamValue::Text("ACME".into()),
+        ];
+        let params: Vec<Param> = values.iter().map(Param::from).collect();
+        let json = serde_json::to_string(&params).unwrap();
+        assert_eq!(
+            json,
+            r#"[null,true,42,12.5,"12345678901234567890.123","ACME"]"#
+        );
+        assert_eq!(serde_json::from_str::<Vec<Param>>(&json).unwrap(), params);
+    }
+}
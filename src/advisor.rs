@@ -0,0 +1,357 @@
+//! Index suggestions from the statements seen during a trace.
+//!
+//! A statement whose plan reads a table `NATURAL` while its WHERE or ON clauses compare
+//! columns of that table is a candidate for an index on those columns. This only reads
+//! the SQL, not the schema, so suggestions can duplicate an index the optimizer chose
+//! not to use; they're a starting point for reading plans, not a replacement.
+
+use crate::event::{Event, EventKind};
+use crate::fingerprint::{tokenize, Token};
+use std::collections::HashMap;
+use std::io::{Result as IOResult, Write};
+
+/// How many suggestions the report lists.
+const REPORT_SIZE: usize = 10;
+
+/// Words that end a table reference in a FROM clause, so they aren't taken as an alias.
+const CLAUSE_WORDS: &[&str] = &[
+    "where", "join", "inner", "left", "right", "full", "outer", "cross", "natural", "on", "group",
+    "order", "having", "union", "plan", "rows", "for", "with", "first", "skip",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Predicate {
+    /// Compared with `=`, `IN` or `IS`, which an index can use on any of its columns.
+    Equality,
+    /// Any other comparison, which an index can only use on its last column.
+    Range,
+}
+
+#[derive(Debug, Default)]
+struct Candidate {
+    executions: u64,
+    duration_ms: i64,
+    natural_reads: i64,
+    example: String,
+}
+
+/// Collects candidate indexes by database, table and columns.
+#[derive(Debug, Default)]
+pub struct IndexAdvisor {
+    candidates: HashMap<(String, String, Vec<String>), Candidate>,
+}
+
+impl IndexAdvisor {
+    pub fn observe(&mut self, event: &Event) {
+        if event.kind != EventKind::ExecuteStatementFinish {
+            return;
+        }
+        let Some(stmt) = &event.statement else {
+            return;
+        };
+        let Some(plan) = &stmt.plan else {
+            return;
+        };
+        let database = event
+            .attachment
+            .as_ref()
+            .map(|a| a.database.clone())
+            .unwrap_or_default();
+
+        let query = Query::parse(&stmt.sql);
+        for scanned in natural_scans(plan) {
+            let Some(table) = query.table(&scanned) else {
+                continue;
+            };
+            let columns = query.index_columns(&scanned, table);
+            if columns.is_empty() {
+                continue;
+            }
+
+            let candidate = self
+                .candidates
+                .entry((database.clone(), table.to_string(), columns))
+                .or_default();
+            candidate.executions += 1;
+            candidate.duration_ms += event.perf.as_ref().map_or(0, |p| p.duration_ms);
+            candidate.natural_reads += event
+                .tables
+                .iter()
+                .filter(|t| t.table.eq_ignore_ascii_case(table))
+                .map(|t| t.natural)
+                .sum::<i64>();
+            if candidate.example.is_empty() {
+                candidate.example = stmt.fingerprint.clone().unwrap_or_else(|| stmt.sql.clone());
+            }
+        }
+    }
+
+    /// Lists the suggestions, those whose statements took longest first, as that's the
+    /// time an index could save at most.
+    pub fn write_report(&self, out: &mut impl Write) -> IOResult<()> {
+        if self.candidates.is_empty() {
+            return Ok(());
+        }
+
+        let mut candidates: Vec<_> = self.candidates.iter().collect();
+        candidates.sort_by(|a, b| {
+            (b.1.duration_ms, b.1.natural_reads)
+                .cmp(&(a.1.duration_ms, a.1.natural_reads))
+                .then_with(|| a.0.cmp(b.0))
+        });
+
+        writeln!(out, "Index suggestions:")?;
+        for ((database, table, columns), c) in candidates.iter().take(REPORT_SIZE) {
+            writeln!(
+                out,
+                "  {database}: consider CREATE INDEX ON {table} ({})",
+                columns.join(", ")
+            )?;
+            let mut observed = format!(
+                "{} executions scanning {table}, {} ms in total",
+                c.executions, c.duration_ms
+            );
+            if c.natural_reads > 0 {
+                observed.push_str(&format!(", {} natural reads", c.natural_reads));
+            }
+            writeln!(out, "    {observed}")?;
+            writeln!(out, "    e.g. {}", first_line(&c.example))?;
+        }
+        Ok(())
+    }
+}
+
+/// An identifier as the server prints it: unquoted names in upper case.
+fn identifier(token: &Token) -> Option<String> {
+    match token {
+        Token::Word(w) => Some(w.to_uppercase()),
+        Token::Quoted(q) => Some(q.clone()),
+        _ => None,
+    }
+}
+
+fn is_word(token: Option<&Token>, word: &str) -> bool {
+    matches!(token, Some(Token::Word(w)) if w.eq_ignore_ascii_case(word))
+}
+
+/// The relations a plan reads `NATURAL`, e.g. `O` in `PLAN JOIN (O NATURAL, C INDEX (PK_C))`.
+fn natural_scans(plan: &str) -> Vec<String> {
+    let tokens = tokenize(plan);
+    tokens
+        .windows(2)
+        .filter(|w| is_word(Some(&w[1]), "natural"))
+        .filter_map(|w| identifier(&w[0]))
+        .collect()
+}
+
+/// The tables and compared columns of a statement, as far as they can be told from its
+/// tokens.
+#[derive(Debug, Default)]
+struct Query {
+    /// Tables and their aliases, in upper case.
+    tables: Vec<(String, Option<String>)>,
+    /// Compared columns with the alias or table they were qualified with.
+    predicates: Vec<(Option<String>, String, Predicate)>,
+}
+
+impl Query {
+    fn parse(sql: &str) -> Self {
+        let tokens: Vec<Token> = tokenize(sql)
+            .into_iter()
+            .filter(|t| !matches!(t, Token::Comment(_)))
+            .collect();
+        let mut query = Self::default();
+
+        let mut i = 0;
+        let mut in_from = false;
+        let mut in_condition = false;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            if is_word(Some(token), "from") || is_word(Some(token), "join") {
+                in_from = true;
+                in_condition = false;
+                i += 1;
+                i += query.table_reference(&tokens[i..]);
+                continue;
+            }
+            if in_from && token.is_punct(",") {
+                i += 1;
+                i += query.table_reference(&tokens[i..]);
+                continue;
+            }
+            if is_word(Some(token), "where") || is_word(Some(token), "on") {
+                in_from = false;
+                in_condition = true;
+            } else if ["group", "order", "having", "union", "plan", "rows"]
+                .iter()
+                .any(|w| is_word(Some(token), w))
+            {
+                in_from = false;
+                in_condition = false;
+            } else if in_condition {
+                if let Some(consumed) = query.predicate(&tokens[i..]) {
+                    i += consumed;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        query
+    }
+
+    /// Reads `TABLE [AS] [ALIAS]`, returning the number of tokens consumed.
+    fn table_reference(&mut self, tokens: &[Token]) -> usize {
+        let Some(table) = tokens.first().and_then(identifier) else {
+            return 0;
+        };
+        let mut i = 1;
+        if is_word(tokens.get(i), "as") {
+            i += 1;
+        }
+        let alias = tokens
+            .get(i)
+            .filter(|t| !CLAUSE_WORDS.iter().any(|w| is_word(Some(t), w)))
+            .and_then(identifier);
+        if alias.is_some() {
+            i += 1;
+        }
+        self.tables.push((table, alias));
+        i
+    }
+
+    /// Reads a comparison starting with a column, e.g. `c.id = ?` or `name like ?`,
+    /// returning the number of tokens consumed.
+    fn predicate(&mut self, tokens: &[Token]) -> Option<usize> {
+        let (qualifier, column, mut i) = column_ref(tokens)?;
+        let op = tokens.get(i)?;
+        let kind = match op {
+            Token::Punct(p) if p == "=" => Predicate::Equality,
+            Token::Punct(p)
+                if ["<", ">", "<=", ">=", "<>", "!=", "^=", "~="].contains(&p.as_str()) =>
+            {
+                Predicate::Range
+            }
+            t if is_word(Some(t), "in") || is_word(Some(t), "is") => Predicate::Equality,
+            t if ["like", "starting", "between"]
+                .iter()
+                .any(|w| is_word(Some(t), w)) =>
+            {
+                Predicate::Range
+            }
+            _ => return None,
+        };
+        i += 1;
+        self.predicates.push((qualifier, column, kind));
+
+        // `a.x = b.y` makes both columns candidates, each for its own table.
+        if kind == Predicate::Equality {
+            if let Some((qualifier, column, n)) = column_ref(&tokens[i..]) {
+                self.predicates.push((qualifier, column, kind));
+                i += n;
+            }
+        }
+        Some(i)
+    }
+
+    /// The table a relation of the plan refers to, by alias or name.
+    fn table(&self, relation: &str) -> Option<&str> {
+        self.tables
+            .iter()
+            .find(|(t, a)| a.as_deref() == Some(relation) || (a.is_none() && t == relation))
+            .or_else(|| self.tables.iter().find(|(t, _)| t == relation))
+            .map(|(t, _)| t.as_str())
+    }
+
+    /// The columns of `table` (referred to as `relation`) to index: equality columns in
+    /// the order they appear, then a range column.
+    fn index_columns(&self, relation: &str, table: &str) -> Vec<String> {
+        let single_table = self.tables.len() == 1;
+        let of_table = |q: &Option<String>| match q {
+            Some(q) => q == relation || q == table,
+            None => single_table,
+        };
+
+        let mut columns: Vec<String> = vec![];
+        for kind in [Predicate::Equality, Predicate::Range] {
+            for (q, column, k) in &self.predicates {
+                if *k == kind && of_table(q) && !columns.contains(column) {
+                    columns.push(column.clone());
+                    if kind == Predicate::Range {
+                        return columns;
+                    }
+                }
+            }
+        }
+        columns
+    }
+}
+
+/// Reads `column` or `qualifier.column`, returning them and the number of tokens consumed.
+/// Placeholders and literals aren't columns.
+fn column_ref(tokens: &[Token]) -> Option<(Option<String>, String, usize)> {
+    let first = identifier(tokens.first()?)?;
+    if tokens.get(1).is_some_and(|t| t.is_punct(".")) {
+        let column = identifier(tokens.get(2)?)?;
+        return Some((Some(first), column, 3));
+    }
+    Some((None, first, 1))
+}
+
+fn first_line(sql: &str) -> &str {
+    sql.lines().next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{Perf, Statement, TableStats};
+
+    fn event(sql: &str, plan: &str) -> Event {
+        let mut event = crate::fixtures::event(&format!(
+            "{} EXECUTE_STATEMENT_FINISH\n\
+             \t/data/erp.fdb (ATT_12, ERP_APP:NONE, UTF8, TCPv4:10.0.0.5/51234)",
+            crate::fixtures::HEADER
+        ));
+        event.statement = Some(Statement {
+            id: 1,
+            sql: sql.into(),
+            plan: Some(plan.into()),
+            ..Default::default()
+        });
+        event.perf = Some(Perf {
+            duration_ms: 800,
+            ..Default::default()
+        });
+        event.tables = vec![TableStats {
+            table: "ORDERS".into(),
+            natural: 50000,
+            ..Default::default()
+        }];
+        event
+    }
+
+    #[test]
+    fn suggests_the_compared_columns_of_naturally_scanned_tables() {
+        let mut advisor = IndexAdvisor::default();
+        let sql = "select * from orders o join customers c on c.id = o.customer_id
+                   where o.status = 'open' and o.created_at > ? and c.region = 7";
+        advisor.observe(&event(sql, "PLAN JOIN (O NATURAL, C INDEX (PK_CUSTOMERS))"));
+        advisor.observe(&event(sql, "PLAN JOIN (O NATURAL, C INDEX (PK_CUSTOMERS))"));
+        // Already indexed.
+        advisor.observe(&event(
+            "select * from orders where id = ?",
+            "PLAN (ORDERS INDEX (PK_ORDERS))",
+        ));
+
+        let mut report = vec![];
+        advisor.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains(
+            "/data/erp.fdb: consider CREATE INDEX ON ORDERS (CUSTOMER_ID, STATUS, CREATED_AT)"
+        ));
+        assert!(
+            report.contains("2 executions scanning ORDERS, 1600 ms in total, 100000 natural reads")
+        );
+        assert_eq!(report.matches("CREATE INDEX").count(), 1);
+    }
+}
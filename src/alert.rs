@@ -0,0 +1,139 @@
+use crate::event::{Event, EventKind};
+use crate::format;
+use crate::sink::Sink;
+use crate::sweeps;
+use std::error::Error;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Where alerts are delivered.
+#[derive(Debug, Clone)]
+pub enum AlertTarget {
+    /// A shell command which receives the alert as JSON on stdin.
+    Command(String),
+    /// A URL the alert is POSTed to as JSON.
+    Webhook(String),
+}
+
+/// Fires an alert for slow statements, error events and troubled sweeps.
+///
+/// Alerts are delivered from a background thread so a slow hook can't hold up the trace.
+pub struct Alerter {
+    threshold: Option<Duration>,
+    sweeps: sweeps::Limits,
+    compat: u32,
+    tx: Option<Sender<String>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Alerter {
+    pub fn new(
+        threshold: Option<Duration>,
+        sweeps: sweeps::Limits,
+        compat: u32,
+        targets: Vec<AlertTarget>,
+    ) -> Self {
+        let (tx, rx) = channel::<String>();
+
+        let worker = std::thread::spawn(move || {
+            for payload in rx {
+                for target in &targets {
+                    if let Err(e) = deliver(target, &payload) {
+                        eprintln!("Failed to deliver alert to {target:?}: {e}");
+                    }
+                }
+            }
+        });
+
+        Self {
+            threshold,
+            sweeps,
+            compat,
+            tx: Some(tx),
+            worker: Some(worker),
+        }
+    }
+}
+
+/// Why `event` is worth an alert, if it is: an error, the server in distress, or a
+/// statement taking at least `threshold`.
+pub fn reason(event: &Event, threshold: Option<Duration>) -> Option<&'static str> {
+    if event.kind == EventKind::Error {
+        return Some("error");
+    }
+    if event.kind == EventKind::Replication && event.failed {
+        return Some("replication_error");
+    }
+    if event.kind == EventKind::ServerDistress {
+        return Some("server_distress");
+    }
+
+    match (threshold, &event.statement, &event.perf) {
+        (Some(t), Some(_), Some(p)) if p.duration_ms as u128 >= t.as_millis() => {
+            Some("slow_statement")
+        }
+        _ => None,
+    }
+}
+
+impl Sink for Alerter {
+    fn write(&mut self, event: &Event) -> Result<(), Box<dyn Error>> {
+        let reason = reason(event, self.threshold).or_else(|| sweeps::alert(event, &self.sweeps));
+        let Some(reason) = reason else {
+            return Ok(());
+        };
+
+        let json = format::to_json(event, self.compat)?;
+        let payload = format!(r#"{{"reason":"{reason}","event":{json}}}"#);
+        if let Some(tx) = &self.tx {
+            tx.send(payload)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        // Dropping the sender lets the worker drain the queue and exit.
+        self.tx = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        Ok(())
+    }
+}
+
+fn deliver(target: &AlertTarget, payload: &str) -> Result<(), Box<dyn Error>> {
+    match target {
+        AlertTarget::Command(cmd) => {
+            let mut child = shell(cmd).stdin(Stdio::piped()).spawn()?;
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(payload.as_bytes())?;
+            }
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(format!("alert command exited with {status}").into());
+            }
+        }
+        AlertTarget::Webhook(url) => {
+            ureq::post(url)
+                .set("Content-Type", "application/json")
+                .send_string(payload)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs `cmd` through the platform's shell.
+pub fn shell(cmd: &str) -> Command {
+    if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.args(["/C", cmd]);
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.args(["-c", cmd]);
+        c
+    }
+}
@@ -0,0 +1,441 @@
+//! Compacting old stores: rolling raw events up into hourly totals and dropping them, so
+//! a store can keep years of trends in a fraction of the space.
+
+use crate::error::AppError;
+use crate::event::EventKind;
+use crate::parser;
+use crate::sink::{sqlite::disk_size, Store};
+use crate::units;
+use clap::Subcommand;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Subcommand, Debug)]
+pub enum ArchiveCmd {
+    /// Roll events older than --keep-raw up into hourly totals and delete them
+    Compact(CompactArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CompactArgs {
+    /// The store to compact, e.g. sqlite:trace.db
+    store: Store,
+
+    /// Keep events younger than this as they are, e.g. 30d
+    #[arg(long, value_parser = units::parse_duration, default_value = "30d")]
+    keep_raw: Duration,
+
+    /// Also delete rollups older than this, e.g. 1095d [default: keep them]
+    #[arg(long, value_parser = units::parse_duration)]
+    keep_rollups: Option<Duration>,
+
+    /// Only print how many events would be rolled up
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// Rollups are keyed by the hour, as `2024-01-15T10`, a prefix of the event timestamps.
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS rollup_events (
+    hour TEXT NOT NULL,
+    database TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    events INTEGER NOT NULL,
+    failed INTEGER NOT NULL,
+    PRIMARY KEY (hour, database, kind)
+);
+
+CREATE TABLE IF NOT EXISTS rollup_statements (
+    hour TEXT NOT NULL,
+    database TEXT NOT NULL,
+    user TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    executions INTEGER NOT NULL,
+    failed INTEGER NOT NULL,
+    total_ms INTEGER NOT NULL,
+    max_ms INTEGER NOT NULL,
+    records_fetched INTEGER NOT NULL,
+    reads INTEGER NOT NULL,
+    writes INTEGER NOT NULL,
+    fetches INTEGER NOT NULL,
+    PRIMARY KEY (hour, database, user, fingerprint)
+);
+
+CREATE TABLE IF NOT EXISTS rollup_tables (
+    hour TEXT NOT NULL,
+    database TEXT NOT NULL,
+    table_name TEXT NOT NULL,
+    statements INTEGER NOT NULL,
+    natural_reads INTEGER NOT NULL,
+    index_reads INTEGER NOT NULL,
+    inserts INTEGER NOT NULL,
+    updates INTEGER NOT NULL,
+    deletes INTEGER NOT NULL,
+    PRIMARY KEY (hour, database, table_name)
+);
+"#;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct EventTotals {
+    events: i64,
+    failed: i64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct StatementTotals {
+    executions: i64,
+    failed: i64,
+    total_ms: i64,
+    max_ms: i64,
+    records_fetched: i64,
+    reads: i64,
+    writes: i64,
+    fetches: i64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct TableTotals {
+    statements: i64,
+    natural: i64,
+    index: i64,
+    insert: i64,
+    update: i64,
+    delete: i64,
+}
+
+/// The rollups of the events being compacted.
+#[derive(Debug, Default)]
+struct Rollups {
+    compacted: u64,
+    events: HashMap<(String, String, String), EventTotals>,
+    statements: HashMap<(String, String, String, String), StatementTotals>,
+    tables: HashMap<(String, String, String), TableTotals>,
+}
+
+impl Rollups {
+    fn rows(&self) -> usize {
+        self.events.len() + self.statements.len() + self.tables.len()
+    }
+}
+
+pub fn run(cmd: &ArchiveCmd) -> Result<(), AppError> {
+    match cmd {
+        ArchiveCmd::Compact(args) => compact(args),
+    }
+}
+
+fn compact(args: &CompactArgs) -> Result<(), AppError> {
+    let Store::Sqlite(path) = &args.store;
+    if !std::path::Path::new(path).exists() {
+        return Err(AppError::InvalidArgs(format!("There's no store at {path}")));
+    }
+    let cutoff = hour_before(args.keep_raw)?;
+    let rollup_cutoff = args.keep_rollups.map(hour_before).transpose()?;
+    let db = |e: rusqlite::Error| AppError::Dyn(Box::new(e));
+
+    let size_before = disk_size(path);
+    let mut conn = Connection::open(path).map_err(db)?;
+    let rollups = if args.dry_run {
+        roll_up(&conn, &cutoff).map_err(db)?
+    } else {
+        // Writing the store while a trace writes to it only has to wait for its batches.
+        conn.busy_timeout(Duration::from_secs(30)).map_err(db)?;
+        let tx = conn
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+            .map_err(db)?;
+        let rollups = roll_up(&tx, &cutoff).map_err(db)?;
+        save(&tx, &rollups).map_err(db)?;
+        delete(&tx, &cutoff, rollup_cutoff.as_deref()).map_err(db)?;
+        tx.commit().map_err(db)?;
+        // Deleted rows only free pages; the file shrinks once it's rewritten.
+        conn.execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE)")
+            .map_err(db)?;
+        rollups
+    };
+
+    let verb = if args.dry_run { "Would roll" } else { "Rolled" };
+    println!(
+        "{verb} up {} events from before {cutoff}:00 into {} hourly rows",
+        rollups.compacted,
+        rollups.rows()
+    );
+    if !args.dry_run {
+        println!(
+            "{path}: {} -> {}",
+            units::format_size(size_before),
+            units::format_size(disk_size(path))
+        );
+    }
+    Ok(())
+}
+
+/// The start of the hour `age` ago, in local time like the event timestamps, as a
+/// rollup hour.
+fn hour_before(age: Duration) -> Result<String, AppError> {
+    let age = chrono::Duration::from_std(age)
+        .map_err(|_| AppError::InvalidArgs(format!("{age:?} is too long an age")))?;
+    let time = chrono::Local::now().naive_local() - age;
+    Ok(time.format("%Y-%m-%dT%H").to_string())
+}
+
+/// Totals the events from before `cutoff`'s hour, per hour.
+fn roll_up(conn: &Connection, cutoff: &str) -> rusqlite::Result<Rollups> {
+    let mut stmt = conn.prepare(
+        "SELECT substr(e.timestamp, 1, 13), e.kind, e.failed, e.raw,
+            coalesce(a.database, ''), coalesce(a.user, ''), coalesce(s.fingerprint, s.sql),
+            s.duration_ms, s.records_fetched, s.reads, s.writes, s.fetches
+         FROM events e
+         LEFT JOIN attachments a ON a.id = e.attachment_id
+         LEFT JOIN statements s ON s.event_id = e.id
+         WHERE e.timestamp < ?1",
+    )?;
+    let mut rows = stmt.query([cutoff])?;
+
+    let mut rollups = Rollups::default();
+    while let Some(r) = rows.next()? {
+        let hour: String = r.get(0)?;
+        let kind: String = r.get(1)?;
+        let failed: bool = r.get(2)?;
+        let database: String = r.get(4)?;
+        rollups.compacted += 1;
+
+        let totals = rollups
+            .events
+            .entry((hour.clone(), database.clone(), kind.clone()))
+            .or_default();
+        totals.events += 1;
+        totals.failed += failed as i64;
+
+        // Only finished statements carry their counters; the statements and tables of
+        // procedures and triggers are already counted in the statement that ran them.
+        if EventKind::from_name(&kind) != EventKind::ExecuteStatementFinish {
+            continue;
+        }
+        let Some(fingerprint) = r.get::<_, Option<String>>(6)? else {
+            continue;
+        };
+        let count = |i| r.get::<_, Option<i64>>(i).map(Option::unwrap_or_default);
+        let duration = count(7)?;
+        let totals = rollups
+            .statements
+            .entry((hour.clone(), database.clone(), r.get(5)?, fingerprint))
+            .or_default();
+        totals.executions += 1;
+        totals.failed += failed as i64;
+        totals.total_ms += duration;
+        totals.max_ms = totals.max_ms.max(duration);
+        totals.records_fetched += count(8)?;
+        totals.reads += count(9)?;
+        totals.writes += count(10)?;
+        totals.fetches += count(11)?;
+
+        // The store doesn't keep the per-table counters apart, but the raw text has them.
+        let raw: String = r.get(3)?;
+        for t in parser::parse_all(&raw).into_iter().flat_map(|e| e.tables) {
+            let totals = rollups
+                .tables
+                .entry((hour.clone(), database.clone(), t.table))
+                .or_default();
+            totals.statements += 1;
+            totals.natural += t.natural;
+            totals.index += t.index;
+            totals.insert += t.insert;
+            totals.update += t.update;
+            totals.delete += t.delete;
+        }
+    }
+    Ok(rollups)
+}
+
+/// Adds the rollups to those of earlier compactions.
+fn save(conn: &Connection, rollups: &Rollups) -> rusqlite::Result<()> {
+    conn.execute_batch(SCHEMA)?;
+
+    let mut stmt = conn.prepare(
+        "INSERT INTO rollup_events (hour, database, kind, events, failed)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT DO UPDATE SET
+            events = events + excluded.events,
+            failed = failed + excluded.failed",
+    )?;
+    for ((hour, database, kind), t) in &rollups.events {
+        stmt.execute(params![hour, database, kind, t.events, t.failed])?;
+    }
+
+    let mut stmt = conn.prepare(
+        "INSERT INTO rollup_statements (hour, database, user, fingerprint, executions, failed,
+            total_ms, max_ms, records_fetched, reads, writes, fetches)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+         ON CONFLICT DO UPDATE SET
+            executions = executions + excluded.executions,
+            failed = failed + excluded.failed,
+            total_ms = total_ms + excluded.total_ms,
+            max_ms = max(max_ms, excluded.max_ms),
+            records_fetched = records_fetched + excluded.records_fetched,
+            reads = reads + excluded.reads,
+            writes = writes + excluded.writes,
+            fetches = fetches + excluded.fetches",
+    )?;
+    for ((hour, database, user, fingerprint), t) in &rollups.statements {
+        stmt.execute(params![
+            hour,
+            database,
+            user,
+            fingerprint,
+            t.executions,
+            t.failed,
+            t.total_ms,
+            t.max_ms,
+            t.records_fetched,
+            t.reads,
+            t.writes,
+            t.fetches
+        ])?;
+    }
+
+    let mut stmt = conn.prepare(
+        "INSERT INTO rollup_tables (hour, database, table_name, statements, natural_reads,
+            index_reads, inserts, updates, deletes)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+         ON CONFLICT DO UPDATE SET
+            statements = statements + excluded.statements,
+            natural_reads = natural_reads + excluded.natural_reads,
+            index_reads = index_reads + excluded.index_reads,
+            inserts = inserts + excluded.inserts,
+            updates = updates + excluded.updates,
+            deletes = deletes + excluded.deletes",
+    )?;
+    for ((hour, database, table), t) in &rollups.tables {
+        stmt.execute(params![
+            hour,
+            database,
+            table,
+            t.statements,
+            t.natural,
+            t.index,
+            t.insert,
+            t.update,
+            t.delete
+        ])?;
+    }
+    Ok(())
+}
+
+/// Deletes the events from before `cutoff`, with the attachments and transactions no
+/// event refers to any more, and rollups from before `rollup_cutoff`.
+fn delete(conn: &Connection, cutoff: &str, rollup_cutoff: Option<&str>) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM statements WHERE event_id IN (SELECT id FROM events WHERE timestamp < ?1)",
+        [cutoff],
+    )?;
+    conn.execute("DELETE FROM events WHERE timestamp < ?1", [cutoff])?;
+    conn.execute_batch(
+        "DELETE FROM transactions WHERE id NOT IN (
+            SELECT transaction_id FROM events WHERE transaction_id IS NOT NULL
+            UNION SELECT transaction_id FROM statements WHERE transaction_id IS NOT NULL);
+         DELETE FROM attachments WHERE id NOT IN (
+            SELECT attachment_id FROM events WHERE attachment_id IS NOT NULL
+            UNION SELECT attachment_id FROM statements WHERE attachment_id IS NOT NULL
+            UNION SELECT attachment_id FROM transactions);",
+    )?;
+
+    if let Some(cutoff) = rollup_cutoff {
+        for table in ["rollup_events", "rollup_statements", "rollup_tables"] {
+            conn.execute(&format!("DELETE FROM {table} WHERE hour < ?1"), [cutoff])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::parse_all;
+    use crate::sink::{sqlite::SqliteSink, Capture, Sink};
+
+    #[test]
+    fn rolls_old_events_up_by_hour() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.db").display().to_string();
+
+        let trace = "2024-01-15T10:23:45.1230 (1234:00007F12AB) ATTACH_DATABASE
+\t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)
+
+2024-01-15T10:23:45.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH
+\t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)
+\t\t(TRA_45, CONCURRENCY | WAIT | READ_WRITE)
+
+Statement 7:
+-------------------------------------------------------------------------------
+select * from orders
+
+0 records fetched
+    120 ms, 10 read(s), 2 write(s), 30 fetch(es), 1 mark(s)
+
+Table                             Natural     Index    Update    Insert    Delete   Backout     Purge   Expunge
+***************************************************************************************************************
+ORDERS                               5000
+
+2024-01-15T10:40:00.0000 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH
+\t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)
+\t\t(TRA_45, CONCURRENCY | WAIT | READ_WRITE)
+
+Statement 7:
+-------------------------------------------------------------------------------
+select * from orders
+
+0 records fetched
+    300 ms, 10 read(s), 2 write(s), 30 fetch(es), 1 mark(s)
+
+2030-01-01T00:00:00.0000 (1234:00007F12AB) DETACH_DATABASE
+\t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)
+";
+        let mut sink = SqliteSink::open(&path, &Capture::default()).unwrap();
+        for event in parse_all(trace) {
+            sink.write(&event).unwrap();
+        }
+        sink.finish().unwrap();
+        drop(sink);
+
+        let conn = Connection::open(&path).unwrap();
+        let rollups = roll_up(&conn, "2025-01-01T00").unwrap();
+        assert_eq!(rollups.compacted, 3);
+        save(&conn, &rollups).unwrap();
+        delete(&conn, "2025-01-01T00", None).unwrap();
+
+        let statement = conn
+            .query_row(
+                "SELECT hour, user, executions, total_ms, max_ms FROM rollup_statements",
+                [],
+                |r| {
+                    Ok((
+                        r.get::<_, String>(0)?,
+                        r.get::<_, String>(1)?,
+                        r.get::<_, i64>(2)?,
+                        r.get::<_, i64>(3)?,
+                        r.get::<_, i64>(4)?,
+                    ))
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            statement,
+            ("2024-01-15T10".into(), "SYSDBA".into(), 2, 420, 300)
+        );
+        let natural: i64 = conn
+            .query_row("SELECT natural_reads FROM rollup_tables", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(natural, 5000);
+
+        // The detach is still to come, so its attachment stays.
+        let left: (i64, i64) = conn
+            .query_row(
+                "SELECT (SELECT count(*) FROM events), (SELECT count(*) FROM attachments)",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(left, (1, 1));
+    }
+}
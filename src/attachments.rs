@@ -0,0 +1,172 @@
+//! The attachments seen in the trace, like MON$ATTACHMENTS but kept from the events, so
+//! it also covers the ones that came and went between two looks at the server.
+
+use crate::event::{Event, EventKind};
+use crate::fanout;
+use std::collections::BTreeMap;
+use std::io::{Result as IOResult, Write};
+
+#[derive(Debug, Default)]
+struct State {
+    user: String,
+    role: String,
+    process: String,
+    protocol: String,
+    remote: String,
+    /// When the attachment was first seen: its ATTACH_DATABASE, or the first event of
+    /// one that was already open when the trace started.
+    since: String,
+    statements: u64,
+    transactions: u64,
+    errors: u64,
+    detached: bool,
+}
+
+/// Every attachment seen, by database and attachment ID.
+#[derive(Debug, Default)]
+pub struct AttachmentMap {
+    attachments: BTreeMap<(String, i64), State>,
+}
+
+impl AttachmentMap {
+    pub fn observe(&mut self, event: &Event) {
+        let Some(att) = &event.attachment else {
+            return;
+        };
+        if event.kind == EventKind::AttachDatabase && event.failed {
+            return;
+        }
+        let state = self
+            .attachments
+            .entry((fanout::database_key(&event.tags, &att.database), att.id))
+            .or_insert_with(|| {
+                let (protocol, remote) = att.remote.split_once(':').unwrap_or((&att.remote, ""));
+                State {
+                    user: att.user.clone(),
+                    role: att.role.clone(),
+                    process: match (&att.process, att.pid) {
+                        (Some(p), Some(pid)) => format!("{p}:{pid}"),
+                        (Some(p), None) => p.clone(),
+                        (None, _) => String::new(),
+                    },
+                    protocol: protocol.to_string(),
+                    remote: remote.to_string(),
+                    since: event.timestamp.clone(),
+                    ..Default::default()
+                }
+            });
+
+        match event.kind {
+            EventKind::ExecuteStatementFinish => state.statements += 1,
+            EventKind::StartTransaction => state.transactions += 1,
+            EventKind::Error => state.errors += 1,
+            EventKind::DetachDatabase => state.detached = true,
+            _ => {}
+        }
+    }
+
+    /// Lists the attachments still open, and the detached ones too with `all`, oldest
+    /// first.
+    pub fn write_report(&self, out: &mut impl Write, all: bool) -> IOResult<()> {
+        let mut attachments: Vec<_> = self
+            .attachments
+            .iter()
+            .filter(|(_, s)| all || !s.detached)
+            .collect();
+        if attachments.is_empty() {
+            return Ok(());
+        }
+        attachments.sort_by(|a, b| a.1.since.cmp(&b.1.since).then_with(|| a.0.cmp(b.0)));
+
+        let open = attachments.iter().filter(|(_, s)| !s.detached).count();
+        writeln!(out, "Attachments ({open} open):")?;
+        writeln!(
+            out,
+            "  {:>8} {:<16} {:<12} {:<8} {:>10} {:>12} {:>6}  {:<26} {:<24} database",
+            "id",
+            "user",
+            "role",
+            "protocol",
+            "statements",
+            "transactions",
+            "errors",
+            "since",
+            "process"
+        )?;
+        for ((database, id), s) in attachments {
+            let state = if s.detached { " (detached)" } else { "" };
+            writeln!(
+                out,
+                "  {id:>8} {:<16} {:<12} {:<8} {:>10} {:>12} {:>6}  {:<26} {:<24} {database}{state}",
+                s.user,
+                s.role,
+                s.protocol,
+                s.statements,
+                s.transactions,
+                s.errors,
+                s.since,
+                match s.remote.as_str() {
+                    "" => s.process.clone(),
+                    remote => format!("{} @ {remote}", s.process),
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::parse_all;
+
+    #[test]
+    fn follows_attachments_through_the_trace() {
+        let trace = "2024-01-15T10:23:45.1230 (1234:00007F12AB) ATTACH_DATABASE
+\t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)
+\t/usr/bin/isql:4567
+
+2024-01-15T10:23:45.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH
+\t/data/erp.fdb (ATT_9, APP:SALES, UTF8, XNET:WS01)
+\tC:\\app\\erp.exe:880
+\t\t(TRA_45, CONCURRENCY | WAIT | READ_WRITE)
+
+Statement 7:
+-------------------------------------------------------------------------------
+select 1 from rdb$database
+1 records fetched
+      5 ms
+
+2024-01-15T10:23:46.0000 (1234:00007F12AB) DETACH_DATABASE
+\t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)
+\t/usr/bin/isql:4567
+";
+        let mut map = AttachmentMap::default();
+        for event in parse_all(trace) {
+            map.observe(&event);
+        }
+
+        let report = |all| {
+            let mut out = vec![];
+            map.write_report(&mut out, all).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let open = report(false);
+        let lines: Vec<&str> = open.lines().collect();
+        assert_eq!(lines.len(), 3, "{open}");
+        assert_eq!(lines[0], "Attachments (1 open):");
+        assert!(lines[2].trim_start().starts_with("9 APP "), "{open}");
+        assert!(lines[2].contains(" SALES "), "{open}");
+        assert!(lines[2].contains(" XNET "), "{open}");
+        assert!(lines[2].contains(r"C:\app\erp.exe:880 @ WS01"), "{open}");
+
+        let all = report(true);
+        assert!(
+            all.lines()
+                .nth(2)
+                .unwrap()
+                .ends_with("/data/erp.fdb (detached)"),
+            "{all}"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Configs for the server's own audit trace, which runs in the server for as long as it
+//! does and writes to log files, generated from the same options as an interactive trace.
+
+use crate::units;
+use std::io::{Result as IOResult, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(clap::Args, Debug)]
+pub struct GenAuditArgs {
+    /// The log file the server writes the database events to, on the server. Relative
+    /// paths are relative to the Firebird root
+    #[arg(long)]
+    log_file: PathBuf,
+
+    /// Rotate the log once it's this big, e.g. 100MB. The server renames it with a
+    /// timestamp and starts a new one. 0 never rotates it
+    #[arg(long, value_parser = units::parse_size, default_value = "100MB")]
+    max_log_size: u64,
+
+    /// Also audit the use of the service manager, e.g. backups and user management,
+    /// writing to this log file
+    #[arg(long)]
+    services_log_file: Option<PathBuf>,
+
+    /// Trace options for the audit, e.g. -- -e connections errors --include-filter '%DELETE%'
+    #[arg(last = true)]
+    pub trace_args: Vec<String>,
+}
+
+/// Writes the audit config, given the `<database>` section of the equivalent interactive
+/// trace.
+pub fn write(args: &GenAuditArgs, database: &str, out: &mut impl Write) -> IOResult<()> {
+    // The server takes the size in megabytes; anything smaller would never rotate.
+    let max_log_size = match args.max_log_size {
+        0 => 0,
+        size => size.div_ceil(1024 * 1024),
+    };
+    let log_settings = |file: &Path| {
+        format!(
+            "    log_filename {}\n    max_log_size {max_log_size}\n",
+            quote(file)
+        )
+    };
+
+    writeln!(out, "# Audit trace config generated by rsfbtrace.")?;
+    writeln!(
+        out,
+        "# Point AuditTraceConfigFile in firebird.conf at this file and restart the server."
+    )?;
+    let database = database.trim();
+    match database.rfind("</database>") {
+        Some(end) => write!(
+            out,
+            "{}{}{}",
+            &database[..end],
+            log_settings(&args.log_file),
+            &database[end..]
+        )?,
+        None => write!(out, "{database}")?,
+    }
+    writeln!(out)?;
+
+    if let Some(file) = &args.services_log_file {
+        writeln!(out)?;
+        writeln!(out, "<services>")?;
+        writeln!(out, "    enabled true")?;
+        write!(out, "{}", log_settings(file))?;
+        writeln!(out, "    log_services true")?;
+        writeln!(out, "    log_service_query true")?;
+        writeln!(out, "    log_errors true")?;
+        writeln!(out, "</services>")?;
+    }
+    Ok(())
+}
+
+/// Paths are quoted in case they contain spaces, as on Windows they often do.
+fn quote(path: &Path) -> String {
+    let path = path.display().to_string();
+    match path.contains(char::is_whitespace) {
+        true => format!("\"{path}\""),
+        false => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_log_files_to_the_trace_config() {
+        let args = GenAuditArgs {
+            log_file: "C:\\Firebird Logs\\audit.log".into(),
+            max_log_size: 1_500_000,
+            services_log_file: Some("/var/log/firebird/services.log".into()),
+            trace_args: vec![],
+        };
+        let database = "\n<database>\n    enabled true\n    log_errors true\n</database>";
+        let mut out = vec![];
+        write(&args, database, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(
+            out.contains(
+                "    log_errors true\n    log_filename \"C:\\Firebird Logs\\audit.log\"\n    \
+                 max_log_size 2\n</database>\n"
+            ),
+            "{out}"
+        );
+        assert!(
+            out.ends_with(
+                "<services>\n    enabled true\n    log_filename /var/log/firebird/services.log\n    \
+                 max_log_size 2\n    log_services true\n    log_service_query true\n    \
+                 log_errors true\n</services>\n"
+            ),
+            "{out}"
+        );
+    }
+}
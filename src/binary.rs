@@ -0,0 +1,195 @@
+//! The binary capture format of `--output-format binary`, for traces too long to keep as
+//! JSON lines: a header, then frames of events, each compressed with zstd.
+//!
+//! The header is the 8 bytes `RSFBTRC\0` and the format version of the events as a
+//! little-endian u32. Each frame is the number of events it holds and the length of
+//! its compressed bytes, both little-endian u32s, then those bytes: the events as lines
+//! of JSON, in the layout of the model crate's `schema`. A frame is written once it
+//! holds `FRAME_EVENTS` events or has been open for `FRAME_AGE`, so a capture that is
+//! cut short loses at most its last frame.
+
+use rsfbtrace_model::schema::{self, Event};
+use std::io::{BufRead, Error as IOError, Read, Result as IOResult, Write};
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 8] = b"RSFBTRC\0";
+
+const FRAME_EVENTS: u32 = 1000;
+
+const FRAME_AGE: Duration = Duration::from_secs(1);
+
+const LEVEL: i32 = 3;
+
+/// Whether `input` starts like a binary capture, without consuming it.
+pub fn is_capture(input: &mut impl BufRead) -> IOResult<bool> {
+    Ok(input.fill_buf()?.starts_with(MAGIC))
+}
+
+pub struct Writer<W: Write> {
+    out: W,
+    /// The events of the open frame, as lines of JSON.
+    frame: Vec<u8>,
+    events: u32,
+    opened: Instant,
+    written: u64,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut out: W) -> IOResult<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&schema::VERSION.to_le_bytes())?;
+        Ok(Self {
+            out,
+            frame: vec![],
+            events: 0,
+            opened: Instant::now(),
+            written: MAGIC.len() as u64 + 4,
+        })
+    }
+
+    pub fn write(&mut self, event: &Event) -> IOResult<()> {
+        if self.events == 0 {
+            self.opened = Instant::now();
+        }
+        serde_json::to_writer(&mut self.frame, event)?;
+        self.frame.push(b'\n');
+        self.events += 1;
+        if self.events >= FRAME_EVENTS || self.opened.elapsed() >= FRAME_AGE {
+            self.flush_frame()?;
+        }
+        Ok(())
+    }
+
+    fn flush_frame(&mut self) -> IOResult<()> {
+        if self.events == 0 {
+            return Ok(());
+        }
+        let compressed = zstd::encode_all(self.frame.as_slice(), LEVEL)?;
+        self.out.write_all(&self.events.to_le_bytes())?;
+        self.out
+            .write_all(&(compressed.len() as u32).to_le_bytes())?;
+        self.out.write_all(&compressed)?;
+        self.out.flush()?;
+        self.written += compressed.len() as u64 + 8;
+        self.frame.clear();
+        self.events = 0;
+        Ok(())
+    }
+
+    /// Writes the open frame, if any.
+    pub fn finish(&mut self) -> IOResult<()> {
+        self.flush_frame()
+    }
+
+    /// The bytes written so far, headers included.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+/// The events of a binary capture, in the order they were written.
+pub struct Reader<R: Read> {
+    input: R,
+    /// The events of the current frame not yet returned, last first.
+    pending: Vec<Event>,
+}
+
+impl<R: Read> Reader<R> {
+    /// Checks the header, refusing captures written by a later rsfbtrace.
+    pub fn new(mut input: R) -> IOResult<Self> {
+        let mut header = [0; 12];
+        input.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(IOError::other("not a binary capture"));
+        }
+        let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if version > schema::VERSION {
+            return Err(IOError::other(format!(
+                "the capture is in format version {version}, newer than this rsfbtrace's {}",
+                schema::VERSION
+            )));
+        }
+        Ok(Self {
+            input,
+            pending: vec![],
+        })
+    }
+
+    /// The events of the next frame, last first, or `None` at the end of the capture.
+    fn next_frame(&mut self) -> IOResult<Option<Vec<Event>>> {
+        let mut header = [0; 8];
+        match self.input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let count = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let mut compressed = vec![0; len as usize];
+        self.input.read_exact(&mut compressed)?;
+        let lines = zstd::decode_all(compressed.as_slice())?;
+        let mut events = lines
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<serde_json::Result<Vec<Event>>>()?;
+        if events.len() != count as usize {
+            return Err(IOError::other(format!(
+                "a frame of {count} events holds {}",
+                events.len()
+            )));
+        }
+        events.reverse();
+        Ok(Some(events))
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = IOResult<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            match self.next_frame() {
+                Ok(Some(events)) => self.pending = events,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.pending.pop().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{event, HEADER};
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let events: Vec<Event> = (0..FRAME_EVENTS + 5)
+            .map(|n| {
+                Event::from(&event(&format!(
+                    "{HEADER} EXECUTE_STATEMENT_FINISH\n\
+                     \t/data/erp.fdb (ATT_{n}, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)"
+                )))
+            })
+            .collect();
+
+        let mut writer = Writer::new(vec![]).unwrap();
+        for event in &events {
+            writer.write(event).unwrap();
+        }
+        writer.finish().unwrap();
+        let written = writer.written();
+        let bytes = writer.out;
+        assert_eq!(written, bytes.len() as u64);
+        assert!(is_capture(&mut bytes.as_slice()).unwrap());
+
+        let read: Vec<Event> = Reader::new(bytes.as_slice())
+            .unwrap()
+            .collect::<IOResult<_>>()
+            .unwrap();
+        assert_eq!(read, events);
+        assert!(Reader::new(&b"{\"id\": \"1\"}\n"[..]).is_err());
+    }
+}
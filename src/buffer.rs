@@ -0,0 +1,283 @@
+//! A bounded buffer between reading the trace and writing it. Events are taken off the
+//! pipe from fbtracemgr as fast as they arrive, so a slow sink, e.g. a webhook or a
+//! distant syslog collector, can't fill the pipe and make the server drop trace output.
+//!
+//! Once `capacity` events are waiting, the oldest are dropped, or with a spill directory
+//! the newest are appended to a file there instead, and read back once the sinks catch
+//! up.
+
+use crate::event::Event;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Result as IOResult, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+
+/// Share of the capacity beyond which a warning is printed, once.
+const WARN_FILL: f64 = 0.75;
+
+/// The events spilled to disk, as JSON lines, oldest first.
+struct Spill {
+    /// Kept so the file is removed with the buffer.
+    _file: NamedTempFile,
+    writer: BufWriter<File>,
+    reader: BufReader<File>,
+    /// Events written but not yet read back.
+    pending: usize,
+}
+
+impl Spill {
+    fn create(dir: &Path) -> IOResult<Self> {
+        let file = NamedTempFile::with_prefix_in("rsfbtrace-spill-", dir)?;
+        Ok(Self {
+            writer: BufWriter::new(file.reopen()?),
+            reader: BufReader::new(file.reopen()?),
+            _file: file,
+            pending: 0,
+        })
+    }
+
+    fn write(&mut self, event: &Event) -> IOResult<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Reads back up to `max` of the oldest events.
+    fn read(&mut self, max: usize, into: &mut VecDeque<Event>) -> IOResult<()> {
+        self.writer.flush()?;
+        let mut line = String::new();
+        while self.pending > 0 && into.len() < max {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            self.pending -= 1;
+            into.push_back(serde_json::from_str(&line)?);
+        }
+        // Start over once everything was read back, so the file doesn't keep growing.
+        if self.pending == 0 {
+            self.writer.get_ref().set_len(0)?;
+            self.writer.seek(SeekFrom::Start(0))?;
+            self.reader.seek(SeekFrom::Start(0))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    memory: VecDeque<Event>,
+    spill: Option<Spill>,
+    closed: bool,
+    peak: usize,
+    spilled: u64,
+    dropped: u64,
+    warned: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    ready: Condvar,
+    capacity: usize,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, event: Event) {
+        let mut state = self.lock();
+        let spilling = state.spill.as_ref().is_some_and(|s| s.pending > 0);
+        if spilling || state.memory.len() >= self.capacity {
+            if let Some(spill) = &mut state.spill {
+                match spill.write(&event) {
+                    Ok(()) => {
+                        state.spilled += 1;
+                        self.ready.notify_one();
+                        return;
+                    }
+                    Err(e) => {
+                        eprintln!("Unable to spill events to disk, dropping them instead: {e}");
+                        state.dropped += spill.pending as u64;
+                        state.spill = None;
+                    }
+                }
+            }
+            if state.memory.len() >= self.capacity {
+                state.memory.pop_front();
+                state.dropped += 1;
+            }
+        }
+        state.memory.push_back(event);
+        state.peak = state.peak.max(state.memory.len());
+        if !state.warned && state.memory.len() as f64 >= self.capacity as f64 * WARN_FILL {
+            state.warned = true;
+            eprintln!(
+                "Warning: the sinks are falling behind, {} of {} buffered events are waiting",
+                state.memory.len(),
+                self.capacity
+            );
+        }
+        self.ready.notify_one();
+    }
+
+    fn next(&self, state: &mut State) -> Option<Event> {
+        if state.memory.is_empty() {
+            if let Some(spill) = &mut state.spill {
+                if let Err(e) = spill.read(self.capacity, &mut state.memory) {
+                    eprintln!("Unable to read spilled events back: {e}");
+                    state.dropped += spill.pending as u64;
+                    state.spill = None;
+                }
+            }
+        }
+        state.memory.pop_front()
+    }
+}
+
+/// The receiving end of the buffer, used like the receiver of a channel.
+pub struct EventBuffer {
+    shared: Arc<Shared>,
+}
+
+/// How full the buffer is, and was.
+pub struct Stats {
+    pub in_memory: usize,
+    pub on_disk: usize,
+    pub capacity: usize,
+    pub peak: usize,
+    pub spilled: u64,
+    pub dropped: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Buffer: {} of {} events waiting ({:.0}%), {} on disk, peak {}, {} spilled, {} dropped",
+            self.in_memory,
+            self.capacity,
+            self.in_memory as f64 * 100.0 / self.capacity as f64,
+            self.on_disk,
+            self.peak,
+            self.spilled,
+            self.dropped
+        )
+    }
+}
+
+/// Starts a buffer holding up to `capacity` events in memory, spilling the rest to a
+/// file in `spill_dir` if given. Events sent to the sender are buffered until received,
+/// and the buffer disconnects once every sender is dropped and it's empty.
+pub fn start(capacity: usize, spill_dir: Option<&Path>) -> IOResult<(Sender<Event>, EventBuffer)> {
+    let state = State {
+        spill: spill_dir.map(Spill::create).transpose()?,
+        ..Default::default()
+    };
+    let shared = Arc::new(Shared {
+        state: Mutex::new(state),
+        ready: Condvar::new(),
+        capacity: capacity.max(1),
+    });
+
+    let (tx, rx) = channel();
+    let filler = shared.clone();
+    thread::spawn(move || {
+        for event in rx {
+            filler.push(event);
+        }
+        filler.lock().closed = true;
+        filler.ready.notify_all();
+    });
+    Ok((tx, EventBuffer { shared }))
+}
+
+impl EventBuffer {
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Event, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(event) = self.shared.next(&mut state) {
+                return Ok(event);
+            }
+            if state.closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            state = match self.shared.ready.wait_timeout(state, left) {
+                Ok((state, _)) => state,
+                Err(e) => e.into_inner().0,
+            };
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        let state = self.shared.lock();
+        Stats {
+            in_memory: state.memory.len(),
+            on_disk: state.spill.as_ref().map_or(0, |s| s.pending),
+            capacity: self.shared.capacity,
+            peak: state.peak,
+            spilled: state.spilled,
+            dropped: state.dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(n: usize) -> Event {
+        crate::fixtures::event(&format!(
+            "2024-01-15T10:23:45.{n:04} (1234:00007F12AB) ERROR AT JStatement::execute"
+        ))
+    }
+
+    fn drain(buffer: &EventBuffer) -> Vec<String> {
+        let mut timestamps = vec![];
+        while let Ok(e) = buffer.recv_timeout(Duration::from_secs(5)) {
+            timestamps.push(e.timestamp);
+        }
+        timestamps
+    }
+
+    #[test]
+    fn drops_or_spills_what_doesnt_fit() {
+        let sent: Vec<String> = (0..10).map(|n| event(n).timestamp).collect();
+
+        let (tx, buffer) = start(4, None).unwrap();
+        (0..10).for_each(|n| tx.send(event(n)).unwrap());
+        drop(tx);
+        // Nothing is received until the filler is done, so only the newest are left.
+        while !buffer.shared.lock().closed {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(drain(&buffer), sent[6..]);
+        let stats = buffer.stats();
+        assert_eq!((stats.peak, stats.dropped, stats.spilled), (4, 6, 0));
+
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, buffer) = start(4, Some(dir.path())).unwrap();
+        (0..10).for_each(|n| tx.send(event(n)).unwrap());
+        drop(tx);
+        assert_eq!(drain(&buffer), sent);
+        let stats = buffer.stats();
+        assert_eq!((stats.on_disk, stats.dropped), (0, 0));
+        assert_eq!(
+            stats.to_string().split(',').next(),
+            Some("Buffer: 0 of 4 events waiting (0%)")
+        );
+    }
+}
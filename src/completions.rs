@@ -0,0 +1,30 @@
+//! Shell completions and the man page, generated from the command line definition so
+//! they can't fall behind it.
+
+use crate::error::AppError;
+use clap::Command;
+use clap_complete::Shell;
+use std::io::Write;
+
+#[derive(clap::Args, Debug)]
+pub struct CompletionsArgs {
+    /// The shell to complete in
+    shell: Shell,
+}
+
+/// Prints the completion script, to be saved where the shell looks for them, e.g.
+/// `/etc/bash_completion.d/rsfbtrace`.
+pub fn completions(args: &CompletionsArgs, mut cmd: Command) -> Result<(), AppError> {
+    let name = cmd.get_name().to_string();
+    clap_complete::generate(args.shell, &mut cmd, name, &mut std::io::stdout());
+    Ok(())
+}
+
+/// Prints the man page in roff, e.g. to be saved as `/usr/share/man/man1/rsfbtrace.1`.
+pub fn manpage(cmd: Command) -> Result<(), AppError> {
+    let mut out = std::io::stdout().lock();
+    let cmd = cmd.version(env!("CARGO_PKG_VERSION"));
+    clap_mangen::Man::new(cmd).render(&mut out)?;
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,239 @@
+//! Firebird connection strings, e.g. `inet://db2:3051` or `xnet://`, as well as the
+//! classic `db2/3051`, and the database aliases of `databases.conf`.
+
+use std::io::Result as IOResult;
+use std::path::{Path, PathBuf};
+
+/// The port Firebird listens on unless configured otherwise.
+pub const DEFAULT_PORT: u16 = 3050;
+
+const PROTOCOLS: &[&str] = &["inet", "inet4", "inet6", "wnet", "xnet"];
+
+/// Where the `databases.conf` of a default installation is, if `$FIREBIRD` isn't set.
+#[cfg(not(windows))]
+const DEFAULT_DATABASES_CONF: &str = "/opt/firebird/databases.conf";
+#[cfg(windows)]
+const DEFAULT_DATABASES_CONF: &str = r"C:\Program Files\Firebird\Firebird_5_0\databases.conf";
+
+/// A `--host`, split into its parts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Server<'a> {
+    /// `inet`, `xnet` etc. for connection strings, `None` for `host/port`.
+    protocol: Option<String>,
+    host: &'a str,
+    port: Option<&'a str>,
+}
+
+fn parse(s: &str) -> Result<Server<'_>, String> {
+    let s = s.trim();
+    let Some((protocol, rest)) = s.split_once("://") else {
+        let (host, port) = match s.split_once('/') {
+            Some((host, port)) => (host, Some(port)),
+            None => (s, None),
+        };
+        if host.is_empty() || port.is_some_and(str::is_empty) {
+            return Err(format!("'{s}' is not a host, e.g. dbhost or dbhost/3051"));
+        }
+        return Ok(Server {
+            protocol: None,
+            host,
+            port,
+        });
+    };
+
+    let protocol = protocol.to_lowercase();
+    if !PROTOCOLS.contains(&protocol.as_str()) {
+        return Err(format!(
+            "'{protocol}' is not a protocol. Valid protocols are {}",
+            PROTOCOLS.join(", ")
+        ));
+    }
+    // The service manager is implied, but may be spelled out.
+    let rest = rest
+        .strip_suffix("/service_mgr")
+        .unwrap_or(rest)
+        .trim_end_matches('/');
+    if protocol == "xnet" {
+        return match rest {
+            "" => Ok(Server {
+                protocol: Some(protocol),
+                host: "",
+                port: None,
+            }),
+            _ => Err(format!(
+                "'{s}' names a host, but xnet only reaches the local server"
+            )),
+        };
+    }
+
+    // IPv6 addresses are bracketed, e.g. inet6://[::1]:3051.
+    let (host, port) = match rest.strip_prefix('[') {
+        Some(v6) => match v6.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => (host, port.strip_prefix(':').filter(|p| !p.is_empty())),
+            None => return Err(format!("'{s}' has an unclosed [")),
+        },
+        None => match rest.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (rest, None),
+        },
+    };
+    if host.is_empty() || port.is_some_and(|p| p.is_empty() || p.contains('/')) {
+        return Err(format!(
+            "'{s}' is not a connection string, e.g. inet://dbhost:3051"
+        ));
+    }
+    Ok(Server {
+        protocol: Some(protocol),
+        host,
+        port,
+    })
+}
+
+/// Checks a `--host`: a host name, `host/port` or a connection string.
+pub fn parse_host(s: &str) -> Result<String, String> {
+    parse(s).map(|_| s.trim().to_string())
+}
+
+/// The service manager of the server at `host`, e.g. `db2/3051:service_mgr` or
+/// `inet://db2:3051/service_mgr`.
+pub fn service_mgr(host: &str) -> String {
+    match parse(host) {
+        Ok(Server {
+            protocol: Some(protocol),
+            host,
+            port,
+        }) => {
+            let host = match host.contains(':') {
+                true => format!("[{host}]"),
+                false => host.to_string(),
+            };
+            let port = port.map_or(String::new(), |p| format!(":{p}"));
+            match protocol.as_str() {
+                "xnet" => "xnet://service_mgr".into(),
+                _ => format!("{protocol}://{host}{port}/service_mgr"),
+            }
+        }
+        _ => format!("{host}:service_mgr"),
+    }
+}
+
+/// The host and TCP port of the server at `host`, for an SSH tunnel to it.
+pub fn tcp_address(host: &str) -> Result<(String, u16), String> {
+    let server = parse(host)?;
+    if matches!(server.protocol.as_deref(), Some("xnet" | "wnet")) {
+        return Err(format!(
+            "'{host}' isn't reached over TCP, so it can't be tunnelled"
+        ));
+    }
+    let port = match server.port {
+        Some(port) => port.parse().map_err(|_| {
+            format!("'{port}' is not a port number. Services can't be named with --ssh.")
+        })?,
+        None => DEFAULT_PORT,
+    };
+    Ok((server.host.to_string(), port))
+}
+
+/// The `databases.conf` given, or else that of the local installation, if there is one.
+pub fn databases_conf(given: Option<&Path>) -> Option<PathBuf> {
+    if let Some(path) = given {
+        return Some(path.to_path_buf());
+    }
+    let path = match std::env::var_os("FIREBIRD") {
+        Some(root) => Path::new(&root).join("databases.conf"),
+        None => PathBuf::from(DEFAULT_DATABASES_CONF),
+    };
+    path.exists().then_some(path)
+}
+
+/// The aliases of a `databases.conf`, as names and database paths.
+pub fn read_aliases(path: &Path) -> IOResult<Vec<(String, String)>> {
+    let contents = std::fs::read_to_string(path)?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    Ok(parse_aliases(&contents, &dir.display().to_string()))
+}
+
+/// `root` is the directory `$(root)` and `$(dir_conf)` stand for: the Firebird
+/// installation, where the file usually is.
+fn parse_aliases(contents: &str, root: &str) -> Vec<(String, String)> {
+    let mut aliases = vec![];
+    // Settings of a single database, in braces after its alias.
+    let mut in_block = false;
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if in_block {
+            in_block = !line.starts_with('}');
+            continue;
+        }
+        if line.starts_with('{') {
+            in_block = !line.ends_with('}');
+            continue;
+        }
+        let Some((name, path)) = line.split_once('=') else {
+            continue;
+        };
+        let path = path
+            .trim()
+            .trim_matches('"')
+            .replace("$(root)", root)
+            .replace("$(dir_conf)", root);
+        aliases.push((name.trim().to_string(), path));
+    }
+    aliases
+}
+
+/// The database path of the alias `name`, if it is one. Aliases aren't case-sensitive.
+pub fn resolve_alias<'a>(name: &str, aliases: &'a [(String, String)]) -> Option<&'a str> {
+    aliases
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+        .map(|(_, path)| path.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_connection_strings_and_aliases() {
+        assert_eq!(service_mgr("db2"), "db2:service_mgr");
+        assert_eq!(service_mgr("db2/3051"), "db2/3051:service_mgr");
+        assert_eq!(
+            service_mgr("INET://db2:3051/service_mgr"),
+            "inet://db2:3051/service_mgr"
+        );
+        assert_eq!(
+            service_mgr("inet6://[fe80::1]:3051"),
+            "inet6://[fe80::1]:3051/service_mgr"
+        );
+        assert_eq!(service_mgr("xnet://"), "xnet://service_mgr");
+        assert!(parse_host("xnet://db2").is_err());
+        assert!(parse_host("tcp://db2").is_err());
+        assert!(parse_host("inet://db2:").is_err());
+
+        assert_eq!(tcp_address("inet://db2:3051"), Ok(("db2".into(), 3051)));
+        assert_eq!(tcp_address("db2"), Ok(("db2".into(), DEFAULT_PORT)));
+        assert!(tcp_address("xnet://").is_err());
+        assert!(tcp_address("db2/gds_db").is_err());
+
+        let aliases = parse_aliases(
+            "# Aliases\n\
+             employee = $(root)/examples/empbuild/employee.fdb\n\
+             erp = /data/erp.fdb   # the ERP\n\
+             {\n\
+             \x20   DefaultDbCachePages = 2048\n\
+             }\n\
+             crm = \"/data/crm db.fdb\"\n",
+            "/opt/firebird",
+        );
+        assert_eq!(
+            resolve_alias("EMPLOYEE", &aliases),
+            Some("/opt/firebird/examples/empbuild/employee.fdb")
+        );
+        assert_eq!(resolve_alias("erp", &aliases), Some("/data/erp.fdb"));
+        assert_eq!(resolve_alias("crm", &aliases), Some("/data/crm db.fdb"));
+        assert_eq!(resolve_alias("%erp%", &aliases), None);
+        assert_eq!(aliases.len(), 3);
+    }
+}
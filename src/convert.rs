@@ -0,0 +1,96 @@
+//! Converting captures between JSON lines and the binary format, e.g. to shrink a long
+//! capture for keeping, or to feed one to tools that read JSON.
+
+use crate::binary;
+use crate::error::AppError;
+use clap::ValueEnum;
+use rsfbtrace_model::schema::Event;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// One JSON object per line, as written by --output-format json
+    Json,
+    /// As written by --output-format binary
+    Binary,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ConvertArgs {
+    /// The capture to convert, in either format, or - for stdin
+    source: String,
+
+    /// The format to convert to
+    #[arg(long, value_enum)]
+    to: Format,
+
+    /// Write the capture to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+pub fn run(args: &ConvertArgs) -> Result<(), AppError> {
+    let mut input: Box<dyn BufRead> = match args.source.as_str() {
+        "-" => Box::new(std::io::stdin().lock()),
+        path => Box::new(BufReader::new(File::open(path)?)),
+    };
+    let out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+    let invalid = |reason: String| AppError::InvalidArgs(format!("{}: {reason}", args.source));
+
+    let events: Box<dyn Iterator<Item = Result<Event, AppError>> + '_> =
+        if binary::is_capture(&mut input)? {
+            let reader = binary::Reader::new(input).map_err(|e| invalid(e.to_string()))?;
+            Box::new(reader.map(move |e| e.map_err(|e| invalid(e.to_string()))))
+        } else {
+            Box::new(json_lines(input, &args.source))
+        };
+
+    let mut count = 0;
+    match args.to {
+        Format::Json => {
+            let mut out = out;
+            for event in events {
+                serde_json::to_writer(&mut out, &event?).map_err(std::io::Error::from)?;
+                out.write_all(b"\n")?;
+                count += 1;
+            }
+            out.flush()?;
+        }
+        Format::Binary => {
+            let mut writer = binary::Writer::new(out)?;
+            for event in events {
+                writer.write(&event?)?;
+                count += 1;
+            }
+            writer.finish()?;
+        }
+    }
+    eprintln!("Converted {count} events");
+    Ok(())
+}
+
+/// The events of a file of JSON lines, in the latest format version.
+fn json_lines<'a>(
+    input: impl BufRead + 'a,
+    source: &'a str,
+) -> impl Iterator<Item = Result<Event, AppError>> + 'a {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |l| !l.trim().is_empty()))
+        .map(move |(n, line)| {
+            let line = line?;
+            serde_json::from_str(&line).map_err(|e| {
+                AppError::InvalidArgs(format!(
+                    "{source}:{}: {e}. Only JSON in the latest format version can be \
+                     converted",
+                    n + 1
+                ))
+            })
+        })
+}
@@ -0,0 +1,722 @@
+//! Correlating events with the attachments and transactions they belong to.
+
+use crate::event::{Attachment, ContextVar, Event, EventKind, Perf, Statement};
+use crate::fanout;
+use crate::monitor::is_lock_conflict;
+use crate::redact::Redactor;
+use crate::shell::first_line;
+use std::collections::HashMap;
+use std::io::{Result as IOResult, Write};
+
+/// (database, attachment ID)
+type AttachmentKey = (String, i64);
+
+/// Follows attachments and transactions across events.
+///
+/// Details the server only reports in some events, like the client process or the
+/// options of a transaction, are copied into the later events of the same attachment or
+/// transaction. That includes the context variables set with `RDB$SET_CONTEXT`, which
+/// statements carry in `context` from when their attachment or transaction set them, e.g.
+/// a `REQUEST_ID` tying them to the application's logs. With summaries enabled, the statements of each transaction are totalled
+/// up and reported in a `TRANSACTION_SUMMARY` event once it ends.
+///
+/// Firebird logs the transactions a client runs in several databases as unrelated ones.
+/// Given a `distributed_key`, transactions setting that `USER_TRANSACTION` context
+/// variable to the same value are linked, and reported together in a
+/// `DISTRIBUTED_TRANSACTION` event once all of them have ended.
+#[derive(Debug, Default)]
+pub struct Correlator {
+    attachments: HashMap<AttachmentKey, Attachment>,
+    transactions: HashMap<(AttachmentKey, i64), OpenTransaction>,
+    /// The statement each attachment ran last.
+    last_statements: HashMap<AttachmentKey, Statement>,
+    /// The `USER_SESSION` variables each attachment has set.
+    contexts: HashMap<AttachmentKey, Vec<ContextVar>>,
+    summaries: bool,
+    distributed_key: Option<String>,
+    /// The participants of distributed transactions, by the value of `distributed_key`.
+    distributed: HashMap<String, Vec<Participant>>,
+    /// Lock conflicts by the fingerprints of the blocked and the blocking statement.
+    conflicts: HashMap<(String, String), Conflicts>,
+}
+
+/// How often one statement was blocked by another, with the text each was first seen
+/// with, as their fingerprints may not be readable, e.g. those of `token-hash`.
+#[derive(Debug, Default)]
+struct Conflicts {
+    count: u64,
+    blocked: Option<String>,
+    blocking: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct OpenTransaction {
+    options: String,
+    /// When START_TRANSACTION was seen, `None` if the transaction predates the trace.
+    started: Option<String>,
+    /// The timestamp of the first event seen in the transaction.
+    first_seen: String,
+    statements: u64,
+    perf: Perf,
+    last_statement: Option<Statement>,
+    /// The `USER_TRANSACTION` variables the transaction has set.
+    context: Vec<ContextVar>,
+    /// The value of the distributed key, if the transaction set it.
+    distributed: Option<String>,
+}
+
+/// A transaction taking part in a distributed transaction.
+#[derive(Debug)]
+struct Participant {
+    database: String,
+    attachment: i64,
+    transaction: i64,
+    /// The outcome, once the transaction ended, as (timestamp, outcome, statements, perf).
+    ended: Option<(String, &'static str, u64, Perf)>,
+    started: String,
+}
+
+impl Correlator {
+    pub fn new(summaries: bool, distributed_key: Option<String>) -> Self {
+        Self {
+            summaries,
+            distributed_key,
+            ..Default::default()
+        }
+    }
+
+    /// Links `event` to what's known about its attachment and transaction, returning the
+    /// summaries of the transactions the event ended.
+    pub fn observe(&mut self, event: &mut Event) -> Vec<Event> {
+        let mut derived = vec![];
+        if let Some(open) = self.track(event) {
+            if self.summaries {
+                derived.push(summary(event, &open));
+            }
+            derived.extend(self.end_participant(event, open));
+        }
+        derived
+    }
+
+    /// Updates the state of the event's attachment and transaction, returning the
+    /// transaction if the event ended it.
+    fn track(&mut self, event: &mut Event) -> Option<OpenTransaction> {
+        let att = event.attachment.as_mut()?;
+        let att_key = (fanout::database_key(&event.tags, &att.database), att.id);
+
+        match self.attachments.get(&att_key) {
+            Some(known) if att.process.is_none() => {
+                att.process = known.process.clone();
+                att.pid = known.pid;
+            }
+            Some(known) if known.process.is_some() => {}
+            _ => {
+                self.attachments.insert(att_key.clone(), att.clone());
+            }
+        }
+
+        if event.kind == EventKind::SetContext {
+            let vars = self.contexts.entry(att_key.clone()).or_default();
+            set_context(vars, &event.context, USER_SESSION);
+        } else if event.statement.is_some() {
+            event.context = self.contexts.get(&att_key).cloned().unwrap_or_default();
+        }
+
+        if event.kind == EventKind::DetachDatabase {
+            self.attachments.remove(&att_key);
+            self.last_statements.remove(&att_key);
+            self.contexts.remove(&att_key);
+            self.transactions.retain(|(a, _), _| a != &att_key);
+            // A participant that will never end leaves its distributed transaction
+            // incomplete.
+            self.distributed.retain(|_, ps| {
+                !ps.iter().any(|p| {
+                    p.ended.is_none() && p.database == att_key.0 && p.attachment == att_key.1
+                })
+            });
+            return None;
+        }
+        if let Some(stmt) = &event.statement {
+            self.last_statements.insert(att_key.clone(), stmt.clone());
+        }
+
+        let tra = event.transaction.as_mut()?;
+        let key = (att_key, tra.id);
+        if let Some(open) = self.transactions.get(&key) {
+            if tra.options.is_empty() {
+                tra.options = open.options.clone();
+            }
+        }
+        let open = self
+            .transactions
+            .entry(key.clone())
+            .or_insert_with(|| OpenTransaction {
+                options: tra.options.clone(),
+                first_seen: event.timestamp.clone(),
+                ..Default::default()
+            });
+        if let Some(stmt) = &event.statement {
+            open.last_statement = Some(stmt.clone());
+        }
+        if event.kind == EventKind::SetContext {
+            set_context(&mut open.context, &event.context, USER_TRANSACTION);
+        } else if event.statement.is_some() {
+            event.context.extend(open.context.iter().cloned());
+        }
+        if let Some(value) = self
+            .distributed_key
+            .as_deref()
+            .and_then(|k| context_value(event, k))
+        {
+            if open.distributed.as_ref() != Some(&value) {
+                let ((database, attachment), transaction) = key.clone();
+                self.distributed
+                    .entry(value.clone())
+                    .or_default()
+                    .push(Participant {
+                        database,
+                        attachment,
+                        transaction,
+                        ended: None,
+                        started: open.started.clone().unwrap_or(open.first_seen.clone()),
+                    });
+                open.distributed = Some(value);
+            }
+        }
+        match event.kind {
+            EventKind::StartTransaction => open.started = Some(event.timestamp.clone()),
+            EventKind::ExecuteStatementFinish => {
+                open.statements += 1;
+                if let Some(p) = &event.perf {
+                    open.perf.duration_ms += p.duration_ms;
+                    open.perf.reads += p.reads;
+                    open.perf.writes += p.writes;
+                    open.perf.fetches += p.fetches;
+                    open.perf.marks += p.marks;
+                }
+            }
+            EventKind::CommitTransaction | EventKind::RollbackTransaction => {
+                return self.transactions.remove(&key);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Records the end of a transaction taking part in a distributed transaction,
+    /// returning the `DISTRIBUTED_TRANSACTION` event once it was the last one open.
+    fn end_participant(&mut self, end: &Event, open: OpenTransaction) -> Option<Event> {
+        let value = open.distributed?;
+        let att = end.attachment.as_ref()?;
+        let tra = end.transaction.as_ref()?;
+
+        let participants = self.distributed.get_mut(&value)?;
+        let p = participants.iter_mut().find(|p| {
+            p.database == att.database && p.attachment == att.id && p.transaction == tra.id
+        })?;
+        p.ended = Some((
+            end.timestamp.clone(),
+            outcome(end),
+            open.statements,
+            open.perf,
+        ));
+        if participants.iter().any(|p| p.ended.is_none()) {
+            return None;
+        }
+
+        let participants = self.distributed.remove(&value)?;
+        let key = self.distributed_key.as_deref().unwrap_or_default();
+        Some(distributed(end, key, &value, &participants))
+    }
+
+    /// Turns a lock conflict error into a `LOCK_CONFLICT` event naming the statement that
+    /// was blocked and, if its transaction was seen in the trace, the one blocking it.
+    pub fn lock_conflict(&mut self, event: &Event) -> Option<Event> {
+        if !is_lock_conflict(event) {
+            return None;
+        }
+        let att = event.attachment.as_ref()?;
+        let att_key = (fanout::database_key(&event.tags, &att.database), att.id);
+
+        let statement = event
+            .statement
+            .clone()
+            .or_else(|| self.last_statements.get(&att_key).cloned());
+        let blocker_id = event.lines.iter().find_map(|l| {
+            l.split_once("concurrent transaction number is ")
+                .and_then(|(_, n)| n.trim().parse::<i64>().ok())
+        });
+        let blocker = blocker_id.and_then(|id| {
+            self.transactions
+                .iter()
+                .find(|(((db, _), tra), _)| *db == att_key.0 && *tra == id)
+        });
+
+        let mut lines = vec![conflict_kind(event).to_string()];
+        if let Some(stmt) = &statement {
+            lines.push(format!("blocked statement: {}", stmt.sql));
+        }
+        match (blocker_id, blocker) {
+            (Some(id), Some((((_, att_id), _), open))) => {
+                let user = self
+                    .attachments
+                    .get(&(att_key.0.clone(), *att_id))
+                    .map(|a| format!(", {}, {}", a.user, a.process.as_deref().unwrap_or("-")))
+                    .unwrap_or_default();
+                lines.push(format!("blocked by TRA_{id} of ATT_{att_id}{user}"));
+                if let Some(stmt) = &open.last_statement {
+                    lines.push(format!("blocking statement: {}", stmt.sql));
+                }
+            }
+            (Some(id), None) => lines.push(format!("blocked by TRA_{id}, not seen in the trace")),
+            (None, _) => {}
+        }
+
+        let key = |s: Option<&Statement>| {
+            s.map_or(String::new(), |s| {
+                s.fingerprint.clone().unwrap_or_else(|| s.sql.clone())
+            })
+        };
+        let blocking = blocker.and_then(|(_, o)| o.last_statement.as_ref());
+        let conflicts = self
+            .conflicts
+            .entry((key(statement.as_ref()), key(blocking)))
+            .or_default();
+        conflicts.count += 1;
+        if conflicts.blocked.is_none() {
+            conflicts.blocked = statement.as_ref().map(|s| s.sql.clone());
+        }
+        if conflicts.blocking.is_none() {
+            conflicts.blocking = blocking.map(|s| s.sql.clone());
+        }
+
+        Some(Event {
+            id: String::new(),
+            timestamp: event.timestamp.clone(),
+            process: event.process.clone(),
+            kind: EventKind::LockConflict,
+            failed: false,
+            location: event.location.clone(),
+            attachment: event.attachment.clone(),
+            transaction: event.transaction.clone(),
+            statement,
+            records_fetched: None,
+            perf: None,
+            params: vec![],
+            tables: vec![],
+            tags: event.tags.clone(),
+            replication: None,
+            error_code: event.error_code.clone(),
+            context: event.context.clone(),
+            raw: format!("{} LOCK_CONFLICT\n{}", event.timestamp, lines.join("\n")),
+            lines,
+        })
+    }
+
+    /// Writes the statement pairs that conflicted most often during the session, each as
+    /// the first text seen of it, redacted with `redactor`.
+    pub fn write_conflict_report(
+        &self,
+        out: &mut impl Write,
+        redactor: Option<&Redactor>,
+    ) -> IOResult<()> {
+        if self.conflicts.is_empty() {
+            return Ok(());
+        }
+
+        let mut pairs: Vec<_> = self.conflicts.iter().collect();
+        pairs.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(b.0)));
+
+        let text = |sql: &Option<String>| match sql {
+            Some(sql) => first_line(&redactor.map_or(sql.clone(), |r| r.sql(sql))),
+            None => "(unknown)".into(),
+        };
+        writeln!(out, "Lock conflicts by statement:")?;
+        for (_, conflicts) in pairs.iter().take(CONFLICT_REPORT_SIZE) {
+            writeln!(out, "{:>6}  {}", conflicts.count, text(&conflicts.blocked))?;
+            writeln!(out, "        blocked by {}", text(&conflicts.blocking))?;
+        }
+        Ok(())
+    }
+}
+
+/// How many statement pairs the conflict report lists.
+const CONFLICT_REPORT_SIZE: usize = 10;
+
+fn conflict_kind(event: &Event) -> &'static str {
+    let has = |code: &str| event.lines.iter().any(|l| l.trim_start().starts_with(code));
+    if has("335544336") {
+        "deadlock"
+    } else if has("335544451") {
+        "update conflict"
+    } else {
+        "lock conflict"
+    }
+}
+
+/// A `TRANSACTION_SUMMARY` event for the transaction `end` ended. Its duration is the
+/// time the transaction was open, the other counters are totals over its statements.
+fn summary(end: &Event, open: &OpenTransaction) -> Event {
+    let outcome = outcome(end);
+    let start = open.started.as_deref().unwrap_or(&open.first_seen);
+    let duration_ms = match (Event::parse_timestamp(start), end.time()) {
+        (Some(s), Some(e)) => (e - s).num_milliseconds(),
+        _ => 0,
+    };
+    let perf = Perf {
+        duration_ms,
+        ..open.perf
+    };
+
+    let lines = vec![
+        format!(
+            "{outcome} after {} statement(s), {}",
+            open.statements,
+            match &open.started {
+                Some(s) => format!("started at {s}"),
+                None => "started before the trace".into(),
+            }
+        ),
+        format!(
+            "{} ms, {} read(s), {} write(s), {} fetch(es), {} mark(s)",
+            perf.duration_ms, perf.reads, perf.writes, perf.fetches, perf.marks
+        ),
+    ];
+
+    Event {
+        id: String::new(),
+        timestamp: end.timestamp.clone(),
+        process: end.process.clone(),
+        kind: EventKind::TransactionSummary,
+        failed: false,
+        location: None,
+        attachment: end.attachment.clone(),
+        transaction: end.transaction.clone(),
+        statement: None,
+        records_fetched: None,
+        perf: Some(perf),
+        params: vec![],
+        tables: vec![],
+        tags: end.tags.clone(),
+        replication: None,
+        error_code: None,
+        context: vec![],
+        raw: format!(
+            "{} TRANSACTION_SUMMARY\n{}",
+            end.timestamp,
+            lines.join("\n")
+        ),
+        lines,
+    }
+}
+
+fn outcome(end: &Event) -> &'static str {
+    if end.kind == EventKind::CommitTransaction {
+        "commit"
+    } else {
+        "rollback"
+    }
+}
+
+const USER_SESSION: &str = "USER_SESSION";
+const USER_TRANSACTION: &str = "USER_TRANSACTION";
+
+/// Applies the changes a `SET_CONTEXT` event made in `namespace` to the variables set
+/// there, dropping those set to NULL.
+fn set_context(vars: &mut Vec<ContextVar>, changes: &[ContextVar], namespace: &str) {
+    for change in changes.iter().filter(|c| c.namespace == namespace) {
+        vars.retain(|v| v.name != change.name);
+        if change.value.is_some() {
+            vars.push(change.clone());
+        }
+    }
+}
+
+/// The value a `SET_CONTEXT` event sets `USER_TRANSACTION.<name>` to.
+fn context_value(event: &Event, name: &str) -> Option<String> {
+    if event.kind != EventKind::SetContext {
+        return None;
+    }
+    event.context.iter().find_map(|v| {
+        let value = v.value.as_ref().filter(|v| !v.is_empty())?;
+        (v.namespace == USER_TRANSACTION && v.name == name).then(|| value.clone())
+    })
+}
+
+/// A `DISTRIBUTED_TRANSACTION` event for `participants`, all of which have ended. Its
+/// duration is from the first start to the last end, the other counters are totals.
+fn distributed(end: &Event, key: &str, value: &str, participants: &[Participant]) -> Event {
+    let mut perf = Perf::default();
+    let mut lines = vec![];
+    let mut outcomes = vec![];
+    for p in participants {
+        let Some((_, outcome, statements, tra_perf)) = &p.ended else {
+            continue;
+        };
+        perf.reads += tra_perf.reads;
+        perf.writes += tra_perf.writes;
+        perf.fetches += tra_perf.fetches;
+        perf.marks += tra_perf.marks;
+        outcomes.push(*outcome);
+        lines.push(format!(
+            "{} (ATT_{}, TRA_{}): {outcome} after {statements} statement(s)",
+            p.database, p.attachment, p.transaction
+        ));
+    }
+
+    let first_start = participants
+        .iter()
+        .filter_map(|p| Event::parse_timestamp(&p.started))
+        .min();
+    let last_end = participants
+        .iter()
+        .filter_map(|p| Event::parse_timestamp(&p.ended.as_ref()?.0))
+        .max();
+    if let (Some(s), Some(e)) = (first_start, last_end) {
+        perf.duration_ms = (e - s).num_milliseconds();
+    }
+
+    // Only a commit everywhere is a successful distributed transaction.
+    let outcome = if outcomes.iter().all(|o| *o == "commit") {
+        "commit"
+    } else if outcomes.iter().all(|o| *o == "rollback") {
+        "rollback"
+    } else {
+        "mixed"
+    };
+    lines.insert(
+        0,
+        format!(
+            "{outcome} of {} transaction(s) with {key} = {value}",
+            participants.len()
+        ),
+    );
+    lines.push(format!(
+        "{} ms, {} read(s), {} write(s), {} fetch(es), {} mark(s)",
+        perf.duration_ms, perf.reads, perf.writes, perf.fetches, perf.marks
+    ));
+
+    Event {
+        id: String::new(),
+        timestamp: end.timestamp.clone(),
+        process: end.process.clone(),
+        kind: EventKind::DistributedTransaction,
+        failed: outcome == "mixed",
+        location: Some(value.into()),
+        attachment: None,
+        transaction: None,
+        statement: None,
+        records_fetched: None,
+        perf: Some(perf),
+        params: vec![],
+        tables: vec![],
+        tags: end.tags.clone(),
+        replication: None,
+        error_code: None,
+        context: vec![],
+        raw: format!(
+            "{} DISTRIBUTED_TRANSACTION\n{}",
+            end.timestamp,
+            lines.join("\n")
+        ),
+        lines,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{ATTACHMENT, TRANSACTION};
+
+    fn event(timestamp: &str, kind: &str, body: &str) -> Event {
+        crate::fixtures::event(&format!(
+            "{timestamp} (1234:00007F12AB) {kind}\n{ATTACHMENT}\n{TRANSACTION}\n{body}"
+        ))
+    }
+
+    #[test]
+    fn summarizes_ended_transactions() {
+        let mut correlator = Correlator::new(true, None);
+        let perf = "      5 ms, 10 read(s), 2 write(s), 30 fetch(es), 1 mark(s)";
+        let mut events = [
+            event("2024-01-15T10:23:45.0000", "START_TRANSACTION", ""),
+            event("2024-01-15T10:23:45.5000", "EXECUTE_STATEMENT_FINISH", perf),
+            event("2024-01-15T10:23:46.0000", "EXECUTE_STATEMENT_FINISH", perf),
+            event("2024-01-15T10:23:47.2500", "COMMIT_TRANSACTION", ""),
+        ];
+
+        let summaries: Vec<Event> = events
+            .iter_mut()
+            .flat_map(|e| correlator.observe(e))
+            .collect();
+
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].kind, EventKind::TransactionSummary);
+        assert_eq!(
+            summaries[0].perf,
+            Some(Perf {
+                duration_ms: 2250,
+                reads: 20,
+                writes: 4,
+                fetches: 60,
+                marks: 2
+            })
+        );
+        assert_eq!(
+            summaries[0].lines[0],
+            "commit after 2 statement(s), started at 2024-01-15T10:23:45.0000"
+        );
+        assert!(correlator.transactions.is_empty());
+    }
+
+    #[test]
+    fn names_the_blocking_statement() {
+        let mut correlator = Correlator::new(false, None);
+        let mut blocker = event(
+            "2024-01-15T10:23:45.0000",
+            "EXECUTE_STATEMENT_FINISH",
+            "Statement 1:\nupdate stock set qty = 1\n",
+        );
+        blocker.attachment.as_mut().unwrap().id = 13;
+        blocker.transaction.as_mut().unwrap().id = 99;
+        let mut error = event(
+            "2024-01-15T10:23:46.1000",
+            "ERROR AT JStatement::execute",
+            "Statement 2:\nupdate stock set qty = 2\n\n335544345 : lock conflict on no wait transaction\n335544878 : concurrent transaction number is 99",
+        );
+
+        // Fingerprinted as with token-hash, which the report shouldn't print.
+        for (event, hash) in [(&mut blocker, "5e1f"), (&mut error, "9a0c")] {
+            event.statement.as_mut().unwrap().fingerprint = Some(hash.into());
+        }
+        correlator.observe(&mut blocker);
+        correlator.observe(&mut error);
+        let conflict = correlator.lock_conflict(&error).expect("a conflict");
+
+        assert_eq!(conflict.kind, EventKind::LockConflict);
+        assert_eq!(
+            conflict.lines,
+            [
+                "lock conflict",
+                "blocked statement: update stock set qty = 2",
+                "blocked by TRA_99 of ATT_13, SYSDBA, -",
+                "blocking statement: update stock set qty = 1",
+            ]
+        );
+        let mut report = vec![];
+        let redactor = Redactor::new(crate::redact::Style::Mask, &[]);
+        correlator
+            .write_conflict_report(&mut report, Some(&redactor))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "Lock conflicts by statement:\n     1  update stock set qty = 0\n        blocked by update stock set qty = 0\n"
+        );
+    }
+
+    #[test]
+    fn links_distributed_transactions() {
+        let mut correlator = Correlator::new(false, Some("GTRID".into()));
+        let in_db = |db: &str, mut e: Event| {
+            e.attachment.as_mut().unwrap().database = db.into();
+            e
+        };
+        let set = "[USER_TRANSACTION] GTRID = \"r1\"";
+        let mut events = [
+            in_db(
+                "a.fdb",
+                event("2024-01-15T10:00:00.0000", "SET_CONTEXT", set),
+            ),
+            in_db(
+                "b.fdb",
+                event("2024-01-15T10:00:00.5000", "SET_CONTEXT", set),
+            ),
+            in_db(
+                "a.fdb",
+                event("2024-01-15T10:00:01.0000", "COMMIT_TRANSACTION", ""),
+            ),
+            in_db(
+                "b.fdb",
+                event("2024-01-15T10:00:02.0000", "COMMIT_TRANSACTION", ""),
+            ),
+        ];
+
+        let derived: Vec<Vec<Event>> = events.iter_mut().map(|e| correlator.observe(e)).collect();
+
+        assert!(derived[..3].iter().all(Vec::is_empty));
+        let [linked] = &derived[3][..] else {
+            panic!("expected one event, got {:?}", derived[3]);
+        };
+        assert_eq!(linked.kind, EventKind::DistributedTransaction);
+        assert_eq!(linked.perf.as_ref().unwrap().duration_ms, 2000);
+        assert_eq!(
+            linked.lines[..3],
+            [
+                "commit of 2 transaction(s) with GTRID = r1",
+                "a.fdb (ATT_12, TRA_45): commit after 0 statement(s)",
+                "b.fdb (ATT_12, TRA_45): commit after 0 statement(s)",
+            ]
+        );
+        assert!(correlator.distributed.is_empty());
+    }
+
+    #[test]
+    fn carries_context_variables_onto_statements() {
+        let mut correlator = Correlator::default();
+        let statement = "Statement 7:\n\nselect * from orders";
+        let mut events = [
+            event(
+                "2024-01-15T10:00:00.0000",
+                "SET_CONTEXT",
+                "[USER_SESSION] APP_USER = \"jdoe\"",
+            ),
+            event(
+                "2024-01-15T10:00:00.1000",
+                "SET_CONTEXT",
+                "[USER_TRANSACTION] REQUEST_ID = \"r-1\"",
+            ),
+            event(
+                "2024-01-15T10:00:00.2000",
+                "EXECUTE_STATEMENT_FINISH",
+                statement,
+            ),
+            event("2024-01-15T10:00:01.0000", "COMMIT_TRANSACTION", ""),
+            event(
+                "2024-01-15T10:00:02.0000",
+                "EXECUTE_STATEMENT_FINISH",
+                statement,
+            ),
+            event(
+                "2024-01-15T10:00:03.0000",
+                "SET_CONTEXT",
+                "[USER_SESSION] APP_USER = NULL",
+            ),
+            event(
+                "2024-01-15T10:00:04.0000",
+                "EXECUTE_STATEMENT_FINISH",
+                statement,
+            ),
+        ];
+        for e in &mut events {
+            correlator.observe(e);
+        }
+
+        let vars = |e: &Event| -> Vec<String> {
+            e.context
+                .iter()
+                .map(|v| format!("{}.{}={:?}", v.namespace, v.name, v.value))
+                .collect()
+        };
+        assert_eq!(vars(&events[5]), ["USER_SESSION.APP_USER=None"]);
+        assert_eq!(
+            vars(&events[2]),
+            [
+                "USER_SESSION.APP_USER=Some(\"jdoe\")",
+                "USER_TRANSACTION.REQUEST_ID=Some(\"r-1\")"
+            ]
+        );
+        // The transaction's variables went with it.
+        assert_eq!(vars(&events[4]), ["USER_SESSION.APP_USER=Some(\"jdoe\")"]);
+        assert!(events[6].context.is_empty());
+    }
+}
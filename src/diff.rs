@@ -0,0 +1,293 @@
+//! Comparing the statements of two stores, e.g. captured before and after adding an
+//! index or upgrading the server, to see which got slower or faster.
+
+use crate::error::AppError;
+use crate::report::percentile;
+use crate::shell::first_line;
+use crate::sink::{sqlite, Store};
+use clap::ValueEnum;
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+#[derive(clap::Args, Debug)]
+pub struct DiffArgs {
+    /// The store captured first, e.g. sqlite:before.db
+    before: Store,
+
+    /// The store to compare with it, e.g. sqlite:after.db
+    after: Store,
+
+    /// How much the mean duration has to change, in percent, for a statement to count as
+    /// slower or faster
+    #[arg(long, default_value_t = 20.0)]
+    threshold: f64,
+
+    /// Leave out statements executed fewer times than this
+    #[arg(long, default_value_t = 1)]
+    min_executions: u64,
+
+    /// How many statements each section lists
+    #[arg(long, default_value_t = 20)]
+    top: usize,
+
+    #[arg(long, value_enum, default_value_t = DiffFormat::Text)]
+    format: DiffFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DiffFormat {
+    /// Tables of the statements in each section
+    Text,
+    /// An object with an array per section
+    Json,
+}
+
+/// The executions of a statement fingerprint in one store.
+#[derive(Debug, Clone, PartialEq)]
+struct Stats {
+    executions: u64,
+    mean_ms: f64,
+    p95_ms: i64,
+    reads: f64,
+}
+
+impl Stats {
+    fn total_ms(&self) -> f64 {
+        self.mean_ms * self.executions as f64
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "executions": self.executions,
+            "mean_ms": (self.mean_ms * 10.0).round() / 10.0,
+            "p95_ms": self.p95_ms,
+            "reads_per_execution": self.reads.round(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Diff {
+    /// Statements in both stores, with their stats before and after.
+    slower: Vec<(String, Stats, Stats)>,
+    faster: Vec<(String, Stats, Stats)>,
+    new: Vec<(String, Stats)>,
+    gone: Vec<(String, Stats)>,
+}
+
+pub fn run(args: &DiffArgs) -> Result<(), AppError> {
+    if args.threshold.is_nan() || args.threshold < 0.0 {
+        return Err(AppError::InvalidArgs(
+            "--threshold can't be negative".into(),
+        ));
+    }
+    let (Store::Sqlite(before), Store::Sqlite(after)) = (&args.before, &args.after);
+    let load = |path| load(path, args.min_executions).map_err(|e| AppError::Dyn(Box::new(e)));
+    let diff = compare(load(before)?, load(after)?, args.threshold);
+
+    match args.format {
+        DiffFormat::Json => println!("{}", to_json(&diff, args.top)),
+        DiffFormat::Text => print_text(&diff, args.top),
+    }
+    Ok(())
+}
+
+fn load(path: &str, min_executions: u64) -> rusqlite::Result<HashMap<String, Stats>> {
+    let conn = Connection::open(path)?;
+    if let Some(note) = sqlite::thinning_note(&conn) {
+        eprintln!("{path}: {note}");
+    }
+    let mut stmt = conn.prepare(
+        "SELECT coalesce(s.fingerprint, s.sql), s.duration_ms, s.reads
+         FROM statements s
+         JOIN events e ON e.id = s.event_id
+         WHERE e.kind = 'EXECUTE_STATEMENT_FINISH' AND s.duration_ms IS NOT NULL",
+    )?;
+    let mut executions: HashMap<String, (Vec<i64>, i64)> = HashMap::new();
+    let mut rows = stmt.query([])?;
+    while let Some(r) = rows.next()? {
+        let (durations, reads) = executions.entry(r.get(0)?).or_default();
+        durations.push(r.get(1)?);
+        *reads += r.get::<_, Option<i64>>(2)?.unwrap_or_default();
+    }
+
+    Ok(executions
+        .into_iter()
+        .filter(|(_, (durations, _))| durations.len() as u64 >= min_executions)
+        .map(|(key, (mut durations, reads))| {
+            durations.sort_unstable();
+            let n = durations.len() as f64;
+            let stats = Stats {
+                executions: durations.len() as u64,
+                mean_ms: durations.iter().sum::<i64>() as f64 / n,
+                p95_ms: percentile(&durations, 0.95),
+                reads: reads as f64 / n,
+            };
+            (key, stats)
+        })
+        .collect())
+}
+
+/// Sorts the statements of both stores into the sections of a diff, each with the
+/// statements making the most difference to the server's total time first.
+fn compare(
+    before: HashMap<String, Stats>,
+    mut after: HashMap<String, Stats>,
+    threshold: f64,
+) -> Diff {
+    let mut diff = Diff::default();
+    for (key, old) in before {
+        let Some(new) = after.remove(&key) else {
+            diff.gone.push((key, old));
+            continue;
+        };
+        // Durations are whole milliseconds, so a change below one says nothing.
+        let change = new.mean_ms - old.mean_ms;
+        if change.abs() < 1.0 || change.abs() < old.mean_ms * threshold / 100.0 {
+            continue;
+        }
+        if change > 0.0 {
+            diff.slower.push((key, old, new));
+        } else {
+            diff.faster.push((key, old, new));
+        }
+    }
+    diff.new = after.into_iter().collect();
+
+    let impact = |(_, old, new): &(String, Stats, Stats)| {
+        ((new.mean_ms - old.mean_ms) * new.executions as f64).abs()
+    };
+    diff.slower.sort_by(|a, b| impact(b).total_cmp(&impact(a)));
+    diff.faster.sort_by(|a, b| impact(b).total_cmp(&impact(a)));
+    diff.new
+        .sort_by(|a, b| b.1.total_ms().total_cmp(&a.1.total_ms()));
+    diff.gone
+        .sort_by(|a, b| b.1.total_ms().total_cmp(&a.1.total_ms()));
+    diff
+}
+
+fn to_json(diff: &Diff, top: usize) -> Value {
+    let changed = |rows: &[(String, Stats, Stats)]| -> Vec<Value> {
+        rows.iter()
+            .take(top)
+            .map(|(key, old, new)| {
+                json!({
+                    "statement": key,
+                    "before": old.to_json(),
+                    "after": new.to_json(),
+                    "change_percent": (change_percent(old, new) * 10.0).round() / 10.0,
+                })
+            })
+            .collect()
+    };
+    let single = |rows: &[(String, Stats)]| -> Vec<Value> {
+        rows.iter()
+            .take(top)
+            .map(|(key, stats)| json!({ "statement": key, "stats": stats.to_json() }))
+            .collect()
+    };
+    json!({
+        "slower": changed(&diff.slower),
+        "faster": changed(&diff.faster),
+        "new": single(&diff.new),
+        "gone": single(&diff.gone),
+    })
+}
+
+fn change_percent(old: &Stats, new: &Stats) -> f64 {
+    if old.mean_ms == 0.0 {
+        return 100.0;
+    }
+    (new.mean_ms - old.mean_ms) / old.mean_ms * 100.0
+}
+
+fn print_text(diff: &Diff, top: usize) {
+    for (title, rows) in [("Slower", &diff.slower), ("Faster", &diff.faster)] {
+        println!("{title}: {}", rows.len());
+        if rows.is_empty() {
+            continue;
+        }
+        println!(
+            "{:>10} {:>10} {:>8} {:>8} {:>8}  statement",
+            "before_ms", "after_ms", "change", "before", "after"
+        );
+        for (key, old, new) in rows.iter().take(top) {
+            println!(
+                "{:>10.1} {:>10.1} {:>+7.0}% {:>8} {:>8}  {}",
+                old.mean_ms,
+                new.mean_ms,
+                change_percent(old, new),
+                old.executions,
+                new.executions,
+                first_line(key)
+            );
+        }
+        println!();
+    }
+
+    for (title, rows) in [("New", &diff.new), ("Gone", &diff.gone)] {
+        println!("{title}: {}", rows.len());
+        if rows.is_empty() {
+            continue;
+        }
+        println!(
+            "{:>6} {:>10} {:>10}  statement",
+            "count", "avg_ms", "p95_ms"
+        );
+        for (key, stats) in rows.iter().take(top) {
+            println!(
+                "{:>6} {:>10.1} {:>10}  {}",
+                stats.executions,
+                stats.mean_ms,
+                stats.p95_ms,
+                first_line(key)
+            );
+        }
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(executions: u64, mean_ms: f64) -> Stats {
+        Stats {
+            executions,
+            mean_ms,
+            p95_ms: mean_ms as i64,
+            reads: 0.0,
+        }
+    }
+
+    #[test]
+    fn sorts_statements_into_sections() {
+        let before = HashMap::from([
+            ("select a".to_string(), stats(100, 10.0)),
+            ("select b".to_string(), stats(100, 50.0)),
+            ("select c".to_string(), stats(100, 10.0)),
+            ("select d".to_string(), stats(5, 2.0)),
+        ]);
+        let after = HashMap::from([
+            ("select a".to_string(), stats(100, 11.0)),
+            ("select b".to_string(), stats(100, 5.0)),
+            ("select c".to_string(), stats(100, 30.0)),
+            ("select e".to_string(), stats(1, 7.0)),
+        ]);
+
+        let diff = compare(before, after, 20.0);
+        let keys = |rows: Vec<&String>| rows.into_iter().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            keys(diff.slower.iter().map(|r| &r.0).collect()),
+            ["select c"]
+        );
+        assert_eq!(
+            keys(diff.faster.iter().map(|r| &r.0).collect()),
+            ["select b"]
+        );
+        assert_eq!(keys(diff.new.iter().map(|r| &r.0).collect()), ["select e"]);
+        assert_eq!(keys(diff.gone.iter().map(|r| &r.0).collect()), ["select d"]);
+        assert_eq!(change_percent(&diff.slower[0].1, &diff.slower[0].2), 200.0);
+    }
+}
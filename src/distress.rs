@@ -0,0 +1,202 @@
+//! A safety valve for tracing a server in trouble: when attachments start failing or the
+//! service manager stops answering, the trace is made lighter or paused, so it doesn't
+//! add to an outage.
+
+use crate::event::{Event, EventKind};
+use crate::throttle::Rate;
+use crate::tracemgr::{self, Connection};
+use clap::ValueEnum;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the service manager is asked whether it still answers.
+const PROBE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A service call taking longer than this counts as failed.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Probes failing in a row before the server counts as in distress, as one can be bad
+/// luck.
+const PROBE_FAILURES: u32 = 2;
+
+/// Each raise multiplies the `time_threshold` by this, up to `MAX_TIME_THRESHOLD_MS`.
+pub const RAISE_FACTOR: u64 = 10;
+pub const MAX_TIME_THRESHOLD_MS: u64 = 10_000;
+
+/// What `--on-distress` does once the server looks in distress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Action {
+    /// Restart the session with a 10 times higher time_threshold, up to 10s, so only
+    /// the slowest statements are reported. Past that, pause
+    Raise,
+    /// Stop the session for --distress-pause, then start it again
+    Pause,
+}
+
+/// Watches the trace and the service manager for signs of distress.
+pub struct Watch {
+    max_failures: Rate,
+    /// When the recent failed attachments and service calls happened.
+    failures: VecDeque<Instant>,
+    /// Probes of the service manager that failed since the last one that didn't.
+    failed_probes: Arc<AtomicU32>,
+}
+
+impl Watch {
+    /// Starts probing the service manager of `conn`, until `stop` is set.
+    pub fn start(conn: &Connection, max_failures: Rate, stop: Arc<AtomicBool>) -> Self {
+        let failed_probes = Arc::new(AtomicU32::new(0));
+        let failed = Arc::clone(&failed_probes);
+        let conn = conn.clone();
+        thread::spawn(move || {
+            let mut next = Instant::now() + PROBE_INTERVAL;
+            while !stop.load(Ordering::SeqCst) {
+                if Instant::now() < next {
+                    thread::sleep(Duration::from_millis(250));
+                    continue;
+                }
+                if tracemgr::service_answers(&conn, PROBE_TIMEOUT) {
+                    failed.store(0, Ordering::SeqCst);
+                } else {
+                    failed.fetch_add(1, Ordering::SeqCst);
+                }
+                next = Instant::now() + PROBE_INTERVAL;
+            }
+        });
+        Self::new(max_failures, failed_probes)
+    }
+
+    fn new(max_failures: Rate, failed_probes: Arc<AtomicU32>) -> Self {
+        Self {
+            max_failures,
+            failures: VecDeque::new(),
+            failed_probes,
+        }
+    }
+
+    /// Notes a failed attachment or service call, returning why the server is in
+    /// distress if there are now more of them than `--distress-failures` allows.
+    pub fn observe(&mut self, event: &Event) -> Option<String> {
+        self.observe_at(event, Instant::now())
+    }
+
+    fn observe_at(&mut self, event: &Event, now: Instant) -> Option<String> {
+        let connecting = matches!(
+            event.kind,
+            EventKind::AttachDatabase
+                | EventKind::AttachService
+                | EventKind::StartService
+                | EventKind::QueryService
+        );
+        if !(event.failed && connecting) {
+            return None;
+        }
+
+        self.failures.push_back(now);
+        while self
+            .failures
+            .front()
+            .is_some_and(|&t| now - t > self.max_failures.per)
+        {
+            self.failures.pop_front();
+        }
+        if self.failures.len() as u64 <= self.max_failures.events {
+            return None;
+        }
+        let failures = self.failures.len();
+        self.failures.clear();
+        Some(format!(
+            "{failures} attachments or service calls failed, more than {} allows",
+            self.max_failures
+        ))
+    }
+
+    /// Why the server is in distress according to the probes of the service manager, if
+    /// it is.
+    pub fn check(&self) -> Option<String> {
+        let failed = self.failed_probes.load(Ordering::SeqCst);
+        (failed >= PROBE_FAILURES).then(|| {
+            format!(
+                "the service manager didn't answer within {}s {failed} times in a row",
+                PROBE_TIMEOUT.as_secs()
+            )
+        })
+    }
+}
+
+/// A `SERVER_DISTRESS` event, for the outputs and alerts, saying why the server looks in
+/// distress and what was done about it.
+pub fn event(reason: &str, relief: &str, tags: &BTreeMap<String, String>) -> Event {
+    let timestamp = chrono::Local::now()
+        .naive_local()
+        .format("%Y-%m-%dT%H:%M:%S%.3f")
+        .to_string();
+    let lines = vec![format!("Server in distress: {reason}"), relief.to_string()];
+    Event {
+        id: String::new(),
+        raw: format!("{timestamp} SERVER_DISTRESS\n{}", lines.join("\n")),
+        timestamp,
+        process: String::new(),
+        kind: EventKind::ServerDistress,
+        failed: false,
+        location: None,
+        attachment: None,
+        transaction: None,
+        statement: None,
+        records_fetched: None,
+        perf: None,
+        params: vec![],
+        tables: vec![],
+        tags: tags.clone(),
+        replication: None,
+        error_code: None,
+        context: vec![],
+        lines,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_attachments_and_probes_mean_distress() {
+        let failed = crate::fixtures::bare("FAILED ATTACH_DATABASE");
+        let attached = crate::fixtures::bare("ATTACH_DATABASE");
+
+        let probes = Arc::new(AtomicU32::new(0));
+        let rate = crate::throttle::parse_rate("3/m").unwrap();
+        let mut watch = Watch::new(rate, Arc::clone(&probes));
+        let start = Instant::now();
+        let minutes = |m: u64| start + Duration::from_secs(60 * m);
+
+        assert_eq!(watch.observe_at(&attached, start), None);
+        for _ in 0..3 {
+            assert_eq!(watch.observe_at(&failed, start), None);
+        }
+        // The failures of the first minute have expired by the third.
+        assert_eq!(watch.observe_at(&failed, minutes(2)), None);
+        for _ in 0..2 {
+            assert_eq!(watch.observe_at(&failed, minutes(2)), None);
+        }
+        assert_eq!(
+            watch.observe_at(&failed, minutes(2)).as_deref(),
+            Some("4 attachments or service calls failed, more than 3/m allows")
+        );
+
+        probes.store(1, Ordering::SeqCst);
+        assert_eq!(watch.check(), None);
+        probes.store(2, Ordering::SeqCst);
+        assert!(watch.check().is_some());
+
+        let event = event(
+            "it's on fire",
+            "Pausing the trace for 300s",
+            &BTreeMap::new(),
+        );
+        assert_eq!(crate::alert::reason(&event, None), Some("server_distress"));
+    }
+}
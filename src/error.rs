@@ -0,0 +1,70 @@
+use crate::LEGAL_OPTS;
+use std::error::Error;
+use std::io::Error as IOError;
+use std::process::ExitCode;
+
+/// Everything that can end a run early. Each kind of failure has its own exit code so
+/// scripts can tell them apart.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("The specified event '{0}' is not a valid for subscription. Valid events are {LEGAL_OPTS:?}.")]
+    InvalidOpt(String),
+
+    #[error("{0}")]
+    InvalidArgs(String),
+
+    #[error("Unable to write the trace config {path}: {source}")]
+    ConfigWrite { path: String, source: IOError },
+
+    #[error("fbtracemgr was not found in $FIREBIRD, on PATH or in the usual Firebird install locations. Make sure the Firebird command line utilities are installed, or give the path with --fbtracemgr-path.")]
+    TraceMgrNotFound,
+
+    #[error("The server rejected the credentials: {0}")]
+    AuthFailed(String),
+
+    #[error("The server rejected the trace: {0}")]
+    ConfigRejected(String),
+
+    #[error("The session's config differs from {0}")]
+    ConfigMismatch(String),
+
+    #[error("Unable to open an SSH tunnel to {0}: {1}")]
+    SshTunnel(String, String),
+
+    #[error("The bundle {0} is invalid: {1}")]
+    BundleInvalid(String, String),
+
+    #[error("The preset {0} couldn't be used: {1}")]
+    PresetInvalid(String, String),
+
+    #[error("The policy {0} couldn't be used: {1}")]
+    PolicyInvalid(String, String),
+
+    #[error("The policy forbids this trace: {0}")]
+    PolicyDenied(String),
+
+    #[error(transparent)]
+    Io(#[from] IOError),
+
+    #[error("{0}")]
+    Dyn(Box<dyn Error>),
+}
+
+impl AppError {
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            Self::InvalidOpt(_) | Self::InvalidArgs(_) => 2,
+            Self::ConfigWrite { .. } => 3,
+            Self::TraceMgrNotFound => 4,
+            Self::AuthFailed(_) => 5,
+            Self::ConfigRejected(_) => 6,
+            Self::ConfigMismatch(_) => 7,
+            Self::BundleInvalid(..) => 8,
+            Self::SshTunnel(..) => 9,
+            Self::PresetInvalid(..) => 10,
+            Self::PolicyInvalid(..) => 11,
+            Self::PolicyDenied(_) => 12,
+            Self::Io(_) | Self::Dyn(_) => 1,
+        })
+    }
+}
@@ -0,0 +1,407 @@
+//! Incident bundles: the events of a store packaged with everything needed to tell how
+//! they were captured, in a single archive that can be checked for tampering.
+//!
+//! A bundle is a zstd-compressed tar containing
+//! - `events.jsonl`, the selected events as recorded in the store,
+//! - `captures/<id>.conf`, the trace config of each capture the events came from,
+//! - `manifest.json`, with the tool and server versions, the selection and annotations,
+//! - `SHA256SUMS`, the checksums of all of the above, as written by `sha256sum`.
+//!
+//! With `--aggregate`, only statistics with differential privacy are exported instead,
+//! see `privacy`.
+
+use crate::error::AppError;
+use crate::expr::{self, Expr};
+use crate::parser;
+use crate::privacy::{Aggregates, Budget};
+use crate::sink::Store;
+use crate::units;
+use rusqlite::{params, Connection, Row};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Incremented when the layout of a bundle changes.
+const BUNDLE_VERSION: u32 = 1;
+
+const CHECKSUMS: &str = "SHA256SUMS";
+
+#[derive(clap::Args, Debug)]
+pub struct ExportArgs {
+    /// The store to export events from, e.g. sqlite:trace.db
+    #[arg(required_unless_present = "verify")]
+    store: Option<Store>,
+
+    /// Write the events as a bundle to this file, e.g. incident-123.tar.zst
+    #[arg(long, required_unless_present_any = ["verify", "aggregate"])]
+    bundle: Option<PathBuf>,
+
+    /// Write only aggregate statistics, with noise added and rare statements left out, to
+    /// this JSON file instead of a bundle
+    #[arg(long, conflicts_with = "bundle")]
+    aggregate: Option<PathBuf>,
+
+    /// The privacy budget of --aggregate. Smaller values add more noise
+    #[arg(long, default_value_t = 1.0)]
+    epsilon: f64,
+
+    /// Leave statements seen fewer times than this out of --aggregate
+    #[arg(long, default_value_t = 10)]
+    min_count: u64,
+
+    /// Cap statement durations at this for --aggregate, e.g. 10s. Longer ones count as this
+    #[arg(long, value_parser = units::parse_duration, default_value = "10s")]
+    max_duration: Duration,
+
+    /// Only export events matching this expression, see --where of a trace. Events
+    /// synthesized by rsfbtrace, e.g. LOCK_SNAPSHOT, never match
+    #[arg(long = "where", value_parser = parse_where)]
+    where_expr: Option<(String, Expr)>,
+
+    /// Only export events at or after this time, e.g. 2024-01-15T10:00:00
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Only export events before this time
+    #[arg(long)]
+    until: Option<String>,
+
+    /// Only export the events of this capture, as numbered in the store's captures table
+    #[arg(long)]
+    capture: Option<i64>,
+
+    /// Add an annotation to the bundle, e.g. "Checkout stalled from 10:20". Can be repeated
+    #[arg(long = "note")]
+    notes: Vec<String>,
+
+    /// Check the checksums of an existing bundle instead of exporting
+    #[arg(long, value_name = "BUNDLE", conflicts_with_all = ["store", "bundle", "aggregate"])]
+    verify: Option<PathBuf>,
+}
+
+pub fn run(args: &ExportArgs) -> Result<(), AppError> {
+    if let Some(path) = &args.verify {
+        let files = verify(path)?;
+        println!("{}: {files} files OK", path.display());
+        return Ok(());
+    }
+
+    let Some(Store::Sqlite(store)) = &args.store else {
+        unreachable!("clap requires a store without --verify");
+    };
+    if let Some(path) = &args.aggregate {
+        return export_aggregates(store, path, args);
+    }
+    let Some(bundle) = &args.bundle else {
+        unreachable!("clap requires --bundle without --verify or --aggregate");
+    };
+    let files = collect(store, args).map_err(|e| AppError::Dyn(Box::new(e)))?;
+    let events = files.get("events.jsonl").map_or(0, |e| e.lines().count());
+    write_bundle(bundle, &files)?;
+    eprintln!("Exported {events} events to {}", bundle.display());
+    Ok(())
+}
+
+/// Reads the selected events and their captures from the store, returning the contents
+/// of the bundle by path.
+fn collect(store: &str, args: &ExportArgs) -> rusqlite::Result<BTreeMap<String, String>> {
+    let conn = Connection::open(store)?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {EVENT_COLUMNS}
+             FROM events
+             WHERE (?1 IS NULL OR timestamp >= ?1)
+               AND (?2 IS NULL OR timestamp < ?2)
+               AND (?3 IS NULL OR capture_id = ?3)
+             ORDER BY id"
+    ))?;
+    let rows = stmt.query_map(params![args.since, args.until, args.capture], event_row)?;
+
+    let mut events = String::new();
+    let mut captures = BTreeSet::new();
+    for row in rows {
+        let (mut event, raw) = row?;
+        if !args
+            .where_expr
+            .as_ref()
+            .is_none_or(|(_, w)| matches(w, &raw))
+        {
+            continue;
+        }
+        if let Some(id) = event["capture"].as_i64() {
+            captures.insert(id);
+        }
+        event["raw"] = json!(raw);
+        events.push_str(&event.to_string());
+        events.push('\n');
+    }
+
+    let mut files = BTreeMap::new();
+    let mut capture_info = vec![];
+    for id in captures {
+        let (started_at, tool_version, server_version, config, tags) = conn.query_row(
+            "SELECT started_at, tool_version, server_version, config, tags
+             FROM captures WHERE id = ?1",
+            [id],
+            |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    r.get::<_, String>(1)?,
+                    r.get::<_, Option<String>>(2)?,
+                    r.get::<_, String>(3)?,
+                    r.get::<_, Option<String>>(4)?,
+                ))
+            },
+        )?;
+        let config_path = format!("captures/{id}.conf");
+        capture_info.push(json!({
+            "id": id,
+            "started_at": started_at,
+            "tool_version": tool_version,
+            "server_version": server_version,
+            "tags": tags.and_then(|t| serde_json::from_str::<Value>(&t).ok()),
+            "config": config_path,
+        }));
+        files.insert(config_path, config);
+    }
+
+    let manifest = json!({
+        "bundle_version": BUNDLE_VERSION,
+        "created_at": chrono::Local::now().format("%Y-%m-%dT%H:%M:%S").to_string(),
+        "tool": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+        "store": store,
+        "selection": selection(args),
+        "events": events.lines().count(),
+        "captures": capture_info,
+        "annotations": args.notes,
+    });
+    files.insert("events.jsonl".into(), events);
+    files.insert(
+        "manifest.json".into(),
+        serde_json::to_string_pretty(&manifest).unwrap_or_default() + "\n",
+    );
+    Ok(files)
+}
+
+fn export_aggregates(store: &str, path: &Path, args: &ExportArgs) -> Result<(), AppError> {
+    if !(args.epsilon > 0.0 && args.epsilon.is_finite()) {
+        return Err(AppError::InvalidArgs(
+            "--epsilon must be a positive number".into(),
+        ));
+    }
+    let budget = Budget {
+        epsilon: args.epsilon,
+        min_count: args.min_count,
+        max_duration_ms: args.max_duration.as_millis().try_into().unwrap_or(i64::MAX),
+    };
+
+    let aggregates = aggregate(store, args, &budget).map_err(|e| AppError::Dyn(Box::new(e)))?;
+    let mut released = aggregates.release(&budget, &mut rand::thread_rng());
+    released["privacy"] = json!({
+        "epsilon": budget.epsilon,
+        "unit": "event",
+        "min_count": budget.min_count,
+        "max_duration_ms": budget.max_duration_ms,
+    });
+    released["selection"] = selection(args);
+    released["annotations"] = json!(args.notes);
+
+    std::fs::write(
+        path,
+        serde_json::to_string_pretty(&released).unwrap_or_default() + "\n",
+    )?;
+    eprintln!(
+        "Exported aggregates of {} statements to {}",
+        released["statements"].as_array().map_or(0, Vec::len),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Totals the selected events of the store by kind, and their statements by
+/// fingerprint.
+fn aggregate(store: &str, args: &ExportArgs, budget: &Budget) -> rusqlite::Result<Aggregates> {
+    let conn = Connection::open(store)?;
+    let mut stmt = conn.prepare(
+        "SELECT e.kind, s.fingerprint, s.duration_ms, e.raw
+         FROM events e
+         LEFT JOIN statements s ON s.event_id = e.id
+         WHERE (?1 IS NULL OR e.timestamp >= ?1)
+           AND (?2 IS NULL OR e.timestamp < ?2)
+           AND (?3 IS NULL OR e.capture_id = ?3)",
+    )?;
+    let mut rows = stmt.query(params![args.since, args.until, args.capture])?;
+
+    let mut aggregates = Aggregates::default();
+    while let Some(row) = rows.next()? {
+        if let Some((_, w)) = &args.where_expr {
+            if !matches(w, &row.get::<_, String>(3)?) {
+                continue;
+            }
+        }
+        aggregates.add_event(&row.get::<_, String>(0)?);
+        if let Some(fingerprint) = row.get::<_, Option<String>>(1)? {
+            let duration = row.get::<_, Option<i64>>(2)?.unwrap_or_default();
+            aggregates.add_statement(&fingerprint, duration, budget);
+        }
+    }
+    Ok(aggregates)
+}
+
+/// The options selecting events, as recorded with an export.
+fn selection(args: &ExportArgs) -> Value {
+    json!({
+        "where": args.where_expr.as_ref().map(|(text, _)| text),
+        "since": args.since,
+        "until": args.until,
+        "capture": args.capture,
+    })
+}
+
+/// The columns of `events` read by `event_row`.
+pub const EVENT_COLUMNS: &str = "uid, capture_id, timestamp, kind, failed, location, tags, raw";
+
+/// An event as exported, and its raw text, which `raw` is set to once the event is
+/// selected.
+pub fn event_row(r: &Row) -> rusqlite::Result<(Value, String)> {
+    Ok((
+        json!({
+            "id": r.get::<_, Option<String>>(0)?,
+            "capture": r.get::<_, Option<i64>>(1)?,
+            "timestamp": r.get::<_, String>(2)?,
+            "kind": r.get::<_, String>(3)?,
+            "failed": r.get::<_, bool>(4)?,
+            "location": r.get::<_, Option<String>>(5)?,
+            "tags": r
+                .get::<_, Option<String>>(6)?
+                .and_then(|t| serde_json::from_str::<Value>(&t).ok()),
+        }),
+        r.get::<_, String>(7)?,
+    ))
+}
+
+/// Keeps the expression as written, for the manifest.
+fn parse_where(s: &str) -> Result<(String, Expr), expr::ParseError> {
+    Ok((s.to_string(), expr::parse(s)?))
+}
+
+/// Events are stored as trace text, so they're parsed again for `--where`.
+fn matches(expr: &Expr, raw: &str) -> bool {
+    parser::parse_all(raw)
+        .first()
+        .is_some_and(|e| expr.matches(e))
+}
+
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn checksums(files: &BTreeMap<String, String>) -> String {
+    files
+        .iter()
+        .map(|(path, contents)| format!("{}  {path}\n", sha256(contents.as_bytes())))
+        .collect()
+}
+
+fn write_bundle(path: &Path, files: &BTreeMap<String, String>) -> Result<(), AppError> {
+    let sums = checksums(files);
+    let mtime = chrono::Utc::now().timestamp().max(0) as u64;
+
+    let encoder = zstd::Encoder::new(File::create(path)?, 0)?;
+    let mut tar = tar::Builder::new(encoder);
+    for (name, contents) in files
+        .iter()
+        .map(|(n, c)| (n.as_str(), c.as_str()))
+        .chain([(CHECKSUMS, sums.as_str())])
+    {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        tar.append_data(&mut header, name, contents.as_bytes())?;
+    }
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Checks that every file of the bundle is listed in its checksums and matches them,
+/// returning the number of files checked.
+fn verify(path: &Path) -> Result<usize, AppError> {
+    let invalid = |reason: String| AppError::BundleInvalid(path.display().to_string(), reason);
+
+    let mut files = BTreeMap::new();
+    let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(path)?)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut contents = vec![];
+        entry.read_to_end(&mut contents)?;
+        files.insert(name, contents);
+    }
+
+    let sums = files
+        .remove(CHECKSUMS)
+        .ok_or_else(|| invalid(format!("{CHECKSUMS} is missing")))?;
+    let mut expected: BTreeMap<String, String> = BTreeMap::new();
+    for line in String::from_utf8_lossy(&sums).lines() {
+        let Some((sum, name)) = line.split_once("  ") else {
+            return Err(invalid(format!("malformed line in {CHECKSUMS}: {line}")));
+        };
+        expected.insert(name.into(), sum.into());
+    }
+
+    for (name, contents) in &files {
+        match expected.remove(name) {
+            Some(sum) if sum == sha256(contents) => {}
+            Some(_) => return Err(invalid(format!("{name} doesn't match its checksum"))),
+            None => return Err(invalid(format!("{name} isn't listed in {CHECKSUMS}"))),
+        }
+    }
+    if let Some(name) = expected.keys().next() {
+        return Err(invalid(format!("{name} is missing")));
+    }
+    Ok(files.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_detects_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.tar.zst");
+        let mut files = BTreeMap::new();
+        files.insert("events.jsonl".to_string(), "{}\n".to_string());
+        files.insert("manifest.json".to_string(), "{}\n".to_string());
+
+        write_bundle(&path, &files).unwrap();
+        assert_eq!(verify(&path).unwrap(), 2);
+
+        // A bundle whose checksums were written for other contents.
+        let sums = checksums(&files);
+        files.insert("events.jsonl".into(), "{\"changed\":true}\n".into());
+        let tampered = dir.path().join("tampered.tar.zst");
+        let mut tar =
+            tar::Builder::new(zstd::Encoder::new(File::create(&tampered).unwrap(), 0).unwrap());
+        for (name, contents) in files.iter().chain([(&CHECKSUMS.to_string(), &sums)]) {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, name, contents.as_bytes())
+                .unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+
+        assert!(matches!(
+            verify(&tampered),
+            Err(AppError::BundleInvalid(_, reason)) if reason.contains("events.jsonl")
+        ));
+    }
+}
@@ -0,0 +1,501 @@
+//! The expression language of `--where`, e.g.
+//! `duration > 500ms && rows == 0 && user != "SYSDBA"`.
+//!
+//! Expressions combine comparisons of event fields with `&&`, `||`, `!` and
+//! parentheses. Comparisons are `==`, `!=`, `<`, `<=`, `>`, `>=` and `~`, which tests
+//! whether a string contains another, ignoring case. Numbers may carry a duration unit
+//! (`ms`, `s`, `m`, `h`); durations are compared in milliseconds. A comparison with a
+//! field the event doesn't have, or of values of different types, is false.
+
+use crate::event::Event;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Cmp(Operand, CmpOp, Operand),
+    /// A bare operand, e.g. `failed`.
+    Truthy(Operand),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Field(Field),
+    Value(Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Num(f64),
+    Str(String),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Kind,
+    Timestamp,
+    Failed,
+    Location,
+    Attachment,
+    Database,
+    User,
+    Role,
+    Charset,
+    Remote,
+    Process,
+    Transaction,
+    Statement,
+    Sql,
+    Plan,
+    Rows,
+    Duration,
+    Reads,
+    Writes,
+    Fetches,
+    Marks,
+    Gdscode,
+    Sqlstate,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("kind", Field::Kind),
+    ("timestamp", Field::Timestamp),
+    ("failed", Field::Failed),
+    ("location", Field::Location),
+    ("attachment", Field::Attachment),
+    ("database", Field::Database),
+    ("user", Field::User),
+    ("role", Field::Role),
+    ("charset", Field::Charset),
+    ("remote", Field::Remote),
+    ("process", Field::Process),
+    ("transaction", Field::Transaction),
+    ("statement", Field::Statement),
+    ("sql", Field::Sql),
+    ("plan", Field::Plan),
+    ("rows", Field::Rows),
+    ("duration", Field::Duration),
+    ("reads", Field::Reads),
+    ("writes", Field::Writes),
+    ("fetches", Field::Fetches),
+    ("marks", Field::Marks),
+    ("gdscode", Field::Gdscode),
+    ("sqlstate", Field::Sqlstate),
+];
+
+impl Field {
+    fn value(self, e: &Event) -> Option<Value> {
+        let num = |n: i64| Some(Value::Num(n as f64));
+        let str = |s: &str| Some(Value::Str(s.into()));
+        let att = e.attachment.as_ref();
+        let perf = e.perf.as_ref();
+
+        match self {
+            Self::Kind => str(e.kind.name()),
+            Self::Timestamp => str(&e.timestamp),
+            Self::Failed => Some(Value::Bool(e.failed)),
+            Self::Location => str(e.location.as_deref()?),
+            Self::Attachment => num(att?.id),
+            Self::Database => str(&att?.database),
+            Self::User => str(&att?.user),
+            Self::Role => str(&att?.role),
+            Self::Charset => str(&att?.charset),
+            Self::Remote => str(&att?.remote),
+            Self::Process => str(att?.process.as_deref()?),
+            Self::Transaction => num(e.transaction.as_ref()?.id),
+            Self::Statement => num(e.statement.as_ref()?.id),
+            Self::Sql => str(&e.statement.as_ref()?.sql),
+            Self::Plan => str(e.statement.as_ref()?.plan.as_deref()?),
+            Self::Rows => num(e.records_fetched?),
+            Self::Duration => num(perf?.duration_ms),
+            Self::Reads => num(perf?.reads),
+            Self::Writes => num(perf?.writes),
+            Self::Fetches => num(perf?.fetches),
+            Self::Marks => num(perf?.marks),
+            Self::Gdscode => num(e.error_code.as_ref()?.gdscode),
+            Self::Sqlstate => str(e.error_code.as_ref()?.sqlstate.as_deref()?),
+        }
+    }
+}
+
+impl Operand {
+    fn value(&self, e: &Event) -> Option<Value> {
+        match self {
+            Self::Field(f) => f.value(e),
+            Self::Value(v) => Some(v.clone()),
+        }
+    }
+}
+
+impl Expr {
+    pub fn matches(&self, e: &Event) -> bool {
+        match self {
+            Self::And(a, b) => a.matches(e) && b.matches(e),
+            Self::Or(a, b) => a.matches(e) || b.matches(e),
+            Self::Not(a) => !a.matches(e),
+            Self::Truthy(o) => match o.value(e) {
+                Some(Value::Bool(b)) => b,
+                Some(Value::Num(n)) => n != 0.0,
+                Some(Value::Str(s)) => !s.is_empty(),
+                None => false,
+            },
+            Self::Cmp(a, op, b) => match (a.value(e), b.value(e)) {
+                (Some(a), Some(b)) => compare(&a, *op, &b),
+                _ => false,
+            },
+        }
+    }
+}
+
+fn compare(a: &Value, op: CmpOp, b: &Value) -> bool {
+    use std::cmp::Ordering;
+
+    let ordering = match (a, b) {
+        (Value::Str(a), Value::Str(b)) if op == CmpOp::Contains => {
+            return a.to_lowercase().contains(&b.to_lowercase());
+        }
+        (Value::Num(a), Value::Num(b)) => a.partial_cmp(b),
+        (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) if matches!(op, CmpOp::Eq | CmpOp::Ne) => Some(a.cmp(b)),
+        _ => None,
+    };
+
+    match (ordering, op) {
+        (Some(o), CmpOp::Eq) => o == Ordering::Equal,
+        (Some(o), CmpOp::Ne) => o != Ordering::Equal,
+        (Some(o), CmpOp::Lt) => o == Ordering::Less,
+        (Some(o), CmpOp::Le) => o != Ordering::Greater,
+        (Some(o), CmpOp::Gt) => o == Ordering::Greater,
+        (Some(o), CmpOp::Ge) => o != Ordering::Less,
+        _ => false,
+    }
+}
+
+/// An error in a `--where` expression, at a byte offset into it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub pos: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.pos + 1)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Num(f64),
+    Str(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+const OPS: &[&str] = &["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "~"];
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let err = |pos, message: &str| ParseError {
+        pos,
+        message: message.into(),
+    };
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(pos, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' {
+            chars.next();
+            tokens.push((
+                pos,
+                if c == '(' {
+                    Token::LParen
+                } else {
+                    Token::RParen
+                },
+            ));
+        } else if c == '"' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) => s.push(c),
+                        None => return Err(err(pos, "unterminated string")),
+                    },
+                    Some((_, c)) => s.push(c),
+                    None => return Err(err(pos, "unterminated string")),
+                }
+            }
+            tokens.push((pos, Token::Str(s)));
+        } else if c.is_ascii_digit() {
+            let mut end = pos;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '.') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let mut n: f64 = match input[pos..end].parse() {
+                Ok(n) => n,
+                Err(_) => return Err(err(pos, "invalid number")),
+            };
+
+            let unit_start = end;
+            while let Some(&(i, c)) = chars.peek() {
+                if !c.is_ascii_alphabetic() {
+                    break;
+                }
+                end = i + 1;
+                chars.next();
+            }
+            n *= match &input[unit_start..end] {
+                "" | "ms" => 1.0,
+                "s" => 1000.0,
+                "m" => 60_000.0,
+                "h" => 3_600_000.0,
+                _ => return Err(err(unit_start, "unknown unit, expected ms, s, m or h")),
+            };
+            tokens.push((pos, Token::Num(n)));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut end = pos;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = i + 1;
+                chars.next();
+            }
+            tokens.push((pos, Token::Ident(input[pos..end].into())));
+        } else if let Some(op) = OPS.iter().find(|op| input[pos..].starts_with(**op)) {
+            for _ in 0..op.len() {
+                chars.next();
+            }
+            tokens.push((pos, Token::Op(op)));
+        } else {
+            return Err(err(pos, &format!("unexpected '{c}'")));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |(p, _)| *p)
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        ParseError {
+            pos: self.offset(),
+            message: message.into(),
+        }
+    }
+
+    fn eat_op(&mut self, op: &'static str) -> bool {
+        if self.peek() == Some(&Token::Op(op)) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.and()?;
+        while self.eat_op("||") {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.not()?;
+        while self.eat_op("&&") {
+            left = Expr::And(Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, ParseError> {
+        if self.eat_op("!") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ParseError> {
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let expr = self.or()?;
+            if self.peek() != Some(&Token::RParen) {
+                return Err(self.error("expected ')'"));
+            }
+            self.pos += 1;
+            return Ok(expr);
+        }
+
+        let left = self.operand()?;
+        let op = match self.peek() {
+            Some(Token::Op("==")) => CmpOp::Eq,
+            Some(Token::Op("!=")) => CmpOp::Ne,
+            Some(Token::Op("<")) => CmpOp::Lt,
+            Some(Token::Op("<=")) => CmpOp::Le,
+            Some(Token::Op(">")) => CmpOp::Gt,
+            Some(Token::Op(">=")) => CmpOp::Ge,
+            Some(Token::Op("~")) => CmpOp::Contains,
+            _ => return Ok(Expr::Truthy(left)),
+        };
+        self.pos += 1;
+        Ok(Expr::Cmp(left, op, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, ParseError> {
+        let operand = match self.peek() {
+            Some(Token::Num(n)) => Operand::Value(Value::Num(*n)),
+            Some(Token::Str(s)) => Operand::Value(Value::Str(s.clone())),
+            Some(Token::Ident(i)) if i == "true" || i == "false" => {
+                Operand::Value(Value::Bool(i == "true"))
+            }
+            Some(Token::Ident(i)) => match FIELDS.iter().find(|(name, _)| name == i) {
+                Some((_, f)) => Operand::Field(*f),
+                None => {
+                    let names: Vec<_> = FIELDS.iter().map(|(n, _)| *n).collect();
+                    return Err(self.error(&format!(
+                        "unknown field '{i}', expected one of {}",
+                        names.join(", ")
+                    )));
+                }
+            },
+            _ => return Err(self.error("expected a field or value")),
+        };
+        self.pos += 1;
+        Ok(operand)
+    }
+}
+
+/// Parses a `--where` expression.
+pub fn parse(input: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        pos: 0,
+        end: input.len(),
+    };
+    let expr = parser.or()?;
+    if parser.peek().is_some() {
+        return Err(parser.error("unexpected input"));
+    }
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, bare};
+
+    const STATEMENT: &str = "2024-01-15T10:23:45.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH
+\t/data/erp.fdb (ATT_12, ERP_APP:NONE, UTF8, TCPv4:10.0.0.5/51234)
+\t/opt/erp/erp.bin:4567
+\t\t(TRA_45, CONCURRENCY | WAIT | READ_WRITE)
+
+Statement 789:
+-------------------------------------------------------------------------------
+select * from customers where id = ?
+^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
+PLAN (CUSTOMERS NATURAL)
+
+0 records fetched
+    1200 ms, 10 read(s), 2 write(s), 30 fetch(es), 1 mark(s)
+";
+
+    fn event() -> Event {
+        fixtures::event(STATEMENT)
+    }
+
+    fn eval(expr: &str) -> bool {
+        parse(expr).unwrap().matches(&event())
+    }
+
+    #[test]
+    fn compares_numbers_with_units() {
+        assert!(eval("duration > 500ms"));
+        assert!(eval("duration > 1s"));
+        assert!(!eval("duration >= 2s"));
+        assert!(eval("duration == 1200"));
+        assert!(eval("rows == 0 && reads <= 10"));
+    }
+
+    #[test]
+    fn compares_strings() {
+        assert!(eval(r#"user != "SYSDBA""#));
+        assert!(eval(r#"user == "ERP_APP""#));
+        assert!(eval(r#"sql ~ "CUSTOMERS""#));
+        assert!(eval(
+            r#"plan ~ "natural" && kind == "EXECUTE_STATEMENT_FINISH""#
+        ));
+        assert!(!eval(r#"database ~ "hr.fdb""#));
+    }
+
+    #[test]
+    fn combines_with_precedence() {
+        // && binds tighter than ||
+        assert!(eval(r#"user == "SYSDBA" || rows == 0 && duration > 1s"#));
+        assert!(!eval(r#"(user == "SYSDBA" || rows == 0) && duration > 2s"#));
+        assert!(eval("!failed && !(reads > 100)"));
+    }
+
+    #[test]
+    fn missing_fields_and_mixed_types_never_match() {
+        let init = bare("TRACE_INIT");
+        assert!(!parse(r#"user == "SYSDBA""#).unwrap().matches(&init));
+        assert!(!parse(r#"user != "SYSDBA""#).unwrap().matches(&init));
+        assert!(!parse("duration").unwrap().matches(&init));
+
+        assert!(!eval(r#"duration == "1200""#));
+        assert!(!eval("failed < true"));
+    }
+
+    #[test]
+    fn reports_errors_with_positions() {
+        let err = |e: &str| parse(e).unwrap_err().to_string();
+        assert_eq!(
+            err("duration > 5parsecs"),
+            "unknown unit, expected ms, s, m or h at position 13"
+        );
+        assert!(err("speed > 1").starts_with("unknown field 'speed', expected one of kind,"));
+        assert_eq!(err("(rows == 0"), "expected ')' at position 11");
+        assert_eq!(err("rows == "), "expected a field or value at position 9");
+        assert_eq!(err(r#"user == "x"#), "unterminated string at position 9");
+        assert_eq!(err("rows == 0 0"), "unexpected input at position 11");
+    }
+}
@@ -0,0 +1,298 @@
+//! Tracing several servers at once, e.g. the nodes of a replicated or sharded
+//! deployment: an identical session is started on each, and their events are merged into
+//! one stream, tagged with the `host` they came from.
+
+use crate::error::AppError;
+use crate::event::Event;
+use crate::session::{self, Recorded};
+use crate::tracemgr::{self, Connection, Echo};
+use crate::tunnel::Tunnel;
+use crate::Args;
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Result as IOResult};
+use std::path::Path;
+use std::process::{Child, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// The tag naming the server of each event, when tracing more than one.
+pub const HOST_TAG: &str = "host";
+
+/// The hosts of `--host`, given any number of times, and of `--hosts-file`, one per
+/// line. Blank lines and `#` comments are skipped.
+pub fn hosts(given: &[String], file: Option<&Path>) -> Result<Vec<String>, AppError> {
+    let mut hosts = given.to_vec();
+    if let Some(path) = file {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            AppError::InvalidArgs(format!("Unable to read {}: {e}", path.display()))
+        })?;
+        for line in contents.lines() {
+            let host = line.split('#').next().unwrap_or_default().trim();
+            if !host.is_empty() {
+                hosts.push(crate::connstr::parse_host(host).map_err(AppError::InvalidArgs)?);
+            }
+        }
+    }
+    let mut seen = vec![];
+    hosts.retain(|h| {
+        let new = !seen.contains(h);
+        seen.push(h.clone());
+        new
+    });
+    Ok(hosts)
+}
+
+/// The database an attachment is in, told apart by server when tracing several, as
+/// replicas and shards tend to share paths and attachment IDs.
+pub fn database_key(tags: &BTreeMap<String, String>, database: &str) -> String {
+    match tags.get(HOST_TAG) {
+        Some(host) => format!("{host}:{database}"),
+        None => database.to_string(),
+    }
+}
+
+/// A session on one of the servers.
+struct Session {
+    conn: Connection,
+    /// The host events are tagged with, when there's more than one.
+    tag: Option<String>,
+    child: Option<Child>,
+    id: Arc<AtomicI64>,
+    failure: Arc<Mutex<Option<String>>>,
+    reader: Option<JoinHandle<IOResult<()>>>,
+    stderr: Option<JoinHandle<String>>,
+    recorded: Option<[Option<Recorded>; 2]>,
+    _tunnel: Option<Tunnel>,
+}
+
+/// The sessions of a trace, one per server.
+pub struct Sessions {
+    sessions: Vec<Session>,
+}
+
+impl Sessions {
+    /// Connects to each of the servers, through its own `--ssh` tunnel if needed.
+    pub fn connect(args: &Args) -> Result<Self, AppError> {
+        let hosts: Vec<Option<String>> = match args.hosts.len() {
+            0 | 1 => vec![args.conn.host.clone()],
+            _ => args.hosts.iter().cloned().map(Some).collect(),
+        };
+        let tagged = hosts.len() > 1;
+        let mut sessions = vec![];
+        for host in hosts {
+            let mut conn = args.conn.clone();
+            conn.host = host;
+            let tunnel = conn.open_tunnel()?;
+            sessions.push(Session {
+                tag: tagged.then(|| conn.server_name().to_string()),
+                conn,
+                child: None,
+                id: Arc::new(AtomicI64::new(0)),
+                failure: Arc::new(Mutex::new(None)),
+                reader: None,
+                stderr: None,
+                recorded: None,
+                _tunnel: tunnel,
+            });
+        }
+        Ok(Self { sessions })
+    }
+
+    /// The connections to the servers, e.g. to check them before starting.
+    pub fn conns(&self) -> impl Iterator<Item = &Connection> {
+        self.sessions.iter().map(|s| &s.conn)
+    }
+
+    /// Starts the sessions, sending their events to `tx`. `stop` is set once every
+    /// session's output has ended.
+    pub fn start(
+        &mut self,
+        args: &Args,
+        config: &Path,
+        tx: &Sender<Event>,
+        echo: Echo,
+        stop: &Arc<AtomicBool>,
+    ) -> Result<(), AppError> {
+        let running = Arc::new(AtomicUsize::new(self.sessions.len()));
+        for session in &mut self.sessions {
+            let mut child = match tracemgr::start_command(&session.conn, args, config)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+            {
+                Ok(c) => c,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    self.stop();
+                    return Err(AppError::TraceMgrNotFound);
+                }
+                Err(e) => {
+                    self.stop();
+                    return Err(AppError::Io(e));
+                }
+            };
+
+            // fbtracemgr reports problems on stderr; pass it through, but keep it to
+            // explain a failed exit.
+            session.stderr = child
+                .stderr
+                .take()
+                .map(|stderr| thread::spawn(move || tracemgr::read_stderr(stderr)));
+
+            let tx = match &session.tag {
+                Some(host) => tagging(tx.clone(), host.clone()),
+                None => tx.clone(),
+            };
+            let (id, failure) = (session.id.clone(), session.failure.clone());
+            let (stop, running) = (stop.clone(), running.clone());
+            let max_sql = args.max_sql;
+            session.reader = Some(match child.stdout.take() {
+                Some(stdout) => thread::spawn(move || {
+                    let result =
+                        tracemgr::read_trace(stdout, &tx, &id, &failure, echo, Some(max_sql));
+                    if running.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                    result
+                }),
+                None => thread::spawn(move || {
+                    if running.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                    Ok(())
+                }),
+            });
+            session.child = Some(child);
+        }
+        Ok(())
+    }
+
+    /// The ID of the session `event` came from, or of the first for events from
+    /// elsewhere, e.g. the server log.
+    pub fn id(&self, event: &Event) -> i64 {
+        let host = event.tags.get(HOST_TAG);
+        self.sessions
+            .iter()
+            .find(|s| s.tag.is_some() && s.tag.as_ref() == host)
+            .unwrap_or(&self.sessions[0])
+            .id
+            .load(Ordering::SeqCst)
+    }
+
+    /// The ID of the first session, the only one when watching for distress.
+    pub fn first_id(&self) -> i64 {
+        self.sessions[0].id.load(Ordering::SeqCst)
+    }
+
+    /// Whether any server has announced its session yet.
+    pub fn any_started(&self) -> bool {
+        self.sessions
+            .iter()
+            .any(|s| s.id.load(Ordering::SeqCst) > 0)
+    }
+
+    /// Whether any server reported an error.
+    pub fn failed(&self) -> bool {
+        self.sessions
+            .iter()
+            .any(|s| s.failure.lock().is_ok_and(|f| f.is_some()))
+    }
+
+    /// Records the config of each session once the server announced it, for `session
+    /// show`, and its `--name`.
+    pub fn record(&mut self, args: &Args, config: &str) {
+        for s in self.sessions.iter_mut().filter(|s| s.recorded.is_none()) {
+            let id = s.id.load(Ordering::SeqCst);
+            if id > 0 {
+                let config = session::record_config(&s.conn, id, config);
+                let name = session::record_state(&s.conn, args.name.as_deref(), id);
+                s.recorded = Some([config, name]);
+            }
+        }
+    }
+
+    /// Stops every session.
+    pub fn stop(&mut self) {
+        for s in &mut self.sessions {
+            if let Some(child) = &mut s.child {
+                tracemgr::stop_trace(&s.conn, s.id.load(Ordering::SeqCst), child);
+            }
+        }
+    }
+
+    pub fn kill(&mut self) {
+        for child in self.sessions.iter_mut().filter_map(|s| s.child.as_mut()) {
+            let _ = child.kill();
+        }
+    }
+
+    /// Waits for the sessions to end, returning how fbtracemgr exited for each, with
+    /// what it wrote to stderr. Fails on the first error reading a trace, or reported by
+    /// a server.
+    pub fn finish(&mut self) -> Result<Vec<(ExitStatus, String)>, AppError> {
+        let mut exits = vec![];
+        for s in &mut self.sessions {
+            if let Some(Ok(Err(e))) = s.reader.take().map(|r| r.join()) {
+                return Err(AppError::Io(e));
+            }
+            let Some(child) = &mut s.child else {
+                continue;
+            };
+            let status = child.wait().map_err(AppError::Io)?;
+            let stderr = s
+                .stderr
+                .take()
+                .and_then(|s| s.join().ok())
+                .unwrap_or_default();
+
+            // fbtracemgr exits successfully even when the server refused the session, so
+            // the banner it printed decides the outcome.
+            if let Some(banner) = s.failure.lock().ok().and_then(|mut f| f.take()) {
+                let banner = match &s.tag {
+                    Some(host) => format!("{host}: {banner}"),
+                    None => banner,
+                };
+                return Err(tracemgr::trace_failure(&s.conn, &banner, status));
+            }
+            exits.push((status, stderr));
+        }
+        Ok(exits)
+    }
+}
+
+/// A sender tagging each event with the `host` it came from.
+fn tagging(tx: Sender<Event>, host: String) -> Sender<Event> {
+    let (tagged, rx) = channel::<Event>();
+    thread::spawn(move || {
+        for mut event in rx {
+            event.tags.insert(HOST_TAG.into(), host.clone());
+            if tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    tagged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_hosts_from_flags_and_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("hosts");
+        std::fs::write(
+            &file,
+            "# The shards\ndb2\n\ninet://db3:3051  # the new one\ndb1\n",
+        )
+        .unwrap();
+
+        let hosts = hosts(&["db1".into()], Some(&file)).unwrap();
+        assert_eq!(hosts, ["db1", "db2", "inet://db3:3051"]);
+
+        std::fs::write(&file, "tcp://db4\n").unwrap();
+        assert!(super::hosts(&[], Some(&file)).is_err());
+    }
+}
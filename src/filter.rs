@@ -0,0 +1,176 @@
+//! Client-side filtering of parsed events.
+
+use crate::event::Event;
+use clap::ValueEnum;
+
+/// Keeps only the events of matching attachments.
+///
+/// Firebird can only filter attachments by ID server-side, so users, roles and client
+/// processes are matched after parsing. Events that don't belong to an attachment, like
+/// `TRACE_INIT` or server log entries, always pass.
+#[derive(Debug, Default)]
+pub struct AttachmentFilter {
+    users: Vec<String>,
+    roles: Vec<String>,
+    processes: Vec<String>,
+}
+
+impl AttachmentFilter {
+    pub fn new(users: Vec<String>, roles: Vec<String>, processes: Vec<String>) -> Self {
+        Self {
+            users,
+            roles,
+            processes,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !(self.users.is_empty() && self.roles.is_empty() && self.processes.is_empty())
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        let Some(att) = &event.attachment else {
+            return true;
+        };
+
+        // Unquoted user and role names are case-insensitive in Firebird.
+        let any_eq = |names: &[String], value: &str| {
+            names.is_empty() || names.iter().any(|n| n.eq_ignore_ascii_case(value))
+        };
+
+        any_eq(&self.users, &att.user)
+            && any_eq(&self.roles, &att.role)
+            && (self.processes.is_empty()
+                || att
+                    .process
+                    .as_deref()
+                    .is_some_and(|p| self.processes.iter().any(|f| process_matches(f, p))))
+    }
+}
+
+/// A process matches by its full path or just its file name, e.g. `isql` matches
+/// `/usr/bin/isql` and both `erp` and `erp.exe` match `C:\ERP\erp.exe`.
+fn process_matches(filter: &str, process: &str) -> bool {
+    let name = process.rsplit(['/', '\\']).next().unwrap_or(process);
+    let stem = name.rsplit_once('.').map_or(name, |(s, _)| s);
+    [process, name, stem]
+        .iter()
+        .any(|p| p.eq_ignore_ascii_case(filter))
+}
+
+/// What a statement does, going by its first keywords.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StatementType {
+    /// SELECT, including WITH ... SELECT
+    Select,
+    Insert,
+    Update,
+    Delete,
+    /// MERGE and UPDATE OR INSERT
+    Merge,
+    /// CREATE, ALTER, DROP, RECREATE and the like, including GRANT and REVOKE
+    Ddl,
+    /// EXECUTE PROCEDURE and EXECUTE BLOCK
+    Execute,
+    /// Anything else, e.g. SET TRANSACTION or COMMIT
+    Other,
+}
+
+impl StatementType {
+    pub fn of(sql: &str) -> Self {
+        let mut words = words(sql);
+        let first = words.next().unwrap_or_default().to_ascii_uppercase();
+        match first.as_str() {
+            "SELECT" | "WITH" => Self::Select,
+            "INSERT" => Self::Insert,
+            "UPDATE" => match words.next() {
+                Some(w) if w.eq_ignore_ascii_case("OR") => Self::Merge,
+                _ => Self::Update,
+            },
+            "DELETE" => Self::Delete,
+            "MERGE" => Self::Merge,
+            "CREATE" | "ALTER" | "DROP" | "RECREATE" | "COMMENT" | "DECLARE" | "GRANT"
+            | "REVOKE" => Self::Ddl,
+            "EXECUTE" => match words.next() {
+                Some(w)
+                    if w.eq_ignore_ascii_case("PROCEDURE") || w.eq_ignore_ascii_case("BLOCK") =>
+                {
+                    Self::Execute
+                }
+                _ => Self::Other,
+            },
+            _ => Self::Other,
+        }
+    }
+}
+
+/// The words of a statement, skipping comments and opening parentheses, e.g. those of
+/// `(SELECT ...) UNION ...`.
+fn words(sql: &str) -> impl Iterator<Item = &str> {
+    let mut rest = sql;
+    std::iter::from_fn(move || loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '(');
+        if let Some(comment) = rest.strip_prefix("--") {
+            rest = comment.split_once('\n').map_or("", |(_, r)| r);
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.split_once("*/").map_or("", |(_, r)| r);
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return None;
+            }
+            let (word, r) = rest.split_at(end);
+            rest = r;
+            return Some(word);
+        }
+    })
+}
+
+/// Keeps only statements of the given types. Events without a statement, like commits,
+/// always pass.
+pub fn statement_type_matches(types: &[StatementType], event: &Event) -> bool {
+    match &event.statement {
+        Some(stmt) if !types.is_empty() => types.contains(&StatementType::of(&stmt.sql)),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_statements_by_their_first_keywords() {
+        for (sql, ty) in [
+            ("select * from customers", StatementType::Select),
+            (
+                "-- totals\n/* by region */ WITH r AS (SELECT 1 FROM rdb$database) SELECT * FROM r",
+                StatementType::Select,
+            ),
+            (
+                "(select 1 from rdb$database) union (select 2 from rdb$database)",
+                StatementType::Select,
+            ),
+            ("INSERT INTO orders VALUES (?)", StatementType::Insert),
+            ("update orders set state = 2", StatementType::Update),
+            (
+                "UPDATE OR INSERT INTO totals VALUES (?)",
+                StatementType::Merge,
+            ),
+            ("DELETE FROM orders", StatementType::Delete),
+            (
+                "create or alter procedure p as begin end",
+                StatementType::Ddl,
+            ),
+            ("GRANT SELECT ON orders TO erp_app", StatementType::Ddl),
+            ("EXECUTE PROCEDURE close_day(?)", StatementType::Execute),
+            ("execute block as begin end", StatementType::Execute),
+            ("SET TRANSACTION READ COMMITTED", StatementType::Other),
+            ("", StatementType::Other),
+        ] {
+            assert_eq!(StatementType::of(sql), ty, "{sql}");
+        }
+    }
+}
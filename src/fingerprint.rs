@@ -0,0 +1,25 @@
+//! Picking the fingerprints of `--fingerprint`. The fingerprinters themselves are part of
+//! the model crate, so consumers of the output can compute the same ones.
+
+use clap::ValueEnum;
+pub use rsfbtrace_model::fingerprint::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+    /// Replace literals with `?`, collapse whitespace and drop comments
+    LiteralStrip,
+    /// Like literal-strip, but also ignore casing and the length of IN lists
+    Structural,
+    /// A hash of the structural fingerprint, for compact keys
+    TokenHash,
+}
+
+impl Strategy {
+    pub fn fingerprinter(self) -> Box<dyn Fingerprinter> {
+        match self {
+            Self::LiteralStrip => Box::new(LiteralStrip),
+            Self::Structural => Box::new(Structural),
+            Self::TokenHash => Box::new(TokenHash),
+        }
+    }
+}
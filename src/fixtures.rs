@@ -0,0 +1,51 @@
+//! Trace text the tests share, as fbtracemgr prints it, and parsing it.
+
+use crate::event::Event;
+pub use crate::parser::parse_all;
+
+/// The timestamp and process of the fixtures' events.
+pub const HEADER: &str = "2024-01-15T10:23:45.3450 (1234:00007F12AB)";
+
+/// The attachment of the fixtures' events, by isql or an application.
+pub const ATTACHMENT: &str = "\t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)";
+
+pub const TRANSACTION: &str = "\t\t(TRA_45, CONCURRENCY | WAIT | READ_WRITE)";
+
+/// A statement finish of isql, with its plan, a parameter and its counters.
+pub const STATEMENT_FINISH: &str =
+    "2024-01-15T10:23:45.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH
+\t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)
+\t/usr/bin/isql:4567
+\t\t(TRA_45, CONCURRENCY | WAIT | READ_WRITE)
+
+Statement 789:
+-------------------------------------------------------------------------------
+select * from customers where id = ?
+^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
+PLAN (CUSTOMERS INDEX (PK_CUSTOMERS))
+param0 = integer, \"1\"
+
+1 records fetched
+      5 ms, 10 read(s), 2 write(s), 30 fetch(es), 1 mark(s)
+";
+
+/// The one event of `text`.
+pub fn event(text: &str) -> Event {
+    let mut events = parse_all(text);
+    assert_eq!(events.len(), 1, "{text}");
+    events.remove(0)
+}
+
+/// A statement finish in `ATTACHMENT` and `TRANSACTION` running `body`: its SQL, then
+/// e.g. its parameters and counters.
+pub fn statement(body: &str) -> Event {
+    event(&format!(
+        "{HEADER} EXECUTE_STATEMENT_FINISH\n{ATTACHMENT}\n{TRANSACTION}\n\nStatement 789:\n{}\n{body}",
+        "-".repeat(79)
+    ))
+}
+
+/// An event of `kind` with nothing but its header, e.g. `bare("ERROR AT JStatement::execute")`.
+pub fn bare(kind: &str) -> Event {
+    event(&format!("{HEADER} {kind}"))
+}
@@ -0,0 +1,263 @@
+//! Structured output formats.
+//!
+//! Downstream tools parse the JSON emitted by rsfbtrace, so the layout is versioned.
+//! Within a version, fields are never renamed, removed or change type; new fields may
+//! only be added in a new version, which consumers opt into with `--compat`, and the
+//! layouts of the older ones are then pinned here. The latest version is the model
+//! crate's `schema`, so consumers can deserialize it with the same types.
+
+use crate::event::Event;
+use clap::ValueEnum;
+use rsfbtrace_model::schema;
+
+/// The format version used when `--compat` isn't given.
+pub const LATEST: u32 = schema::VERSION;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// The trace text as emitted by the server
+    Raw,
+    /// The trace text, with parameters listed inline
+    Pretty,
+    /// One JSON object per event
+    Json,
+    /// Compressed frames of events in the latest JSON layout, for long captures to a file
+    Binary,
+}
+
+/// Serializes `event` in the given format version. Version 1 is the only one so far.
+pub fn to_json(event: &Event, compat: u32) -> serde_json::Result<String> {
+    debug_assert!((1..=LATEST).contains(&compat), "--compat is validated");
+    serde_json::to_string(&schema::Event::from(event))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, STATEMENT_FINISH as STATEMENT};
+    use serde_json::{json, Value};
+
+    fn parse(text: &str) -> Event {
+        fixtures::event(text)
+    }
+
+    fn v1(event: &Event) -> Value {
+        serde_json::from_str(&to_json(event, 1).unwrap()).unwrap()
+    }
+
+    /// The fields of v1 in the order they're written.
+    const V1_KEYS: &[&str] = &[
+        "schema_version",
+        "id",
+        "timestamp",
+        "process",
+        "kind",
+        "failed",
+        "location",
+        "attachment",
+        "transaction",
+        "statement",
+        "params",
+        "records_fetched",
+        "perf",
+        "lines",
+        "raw",
+        "tags",
+        "replication",
+        "error_code",
+        "context",
+    ];
+
+    #[test]
+    fn v1_statement_layout_is_pinned() {
+        let mut event = parse(STATEMENT);
+        event.assign_id(5, 3);
+        let mut value = v1(&event);
+        let keys: Vec<_> = value.as_object().unwrap().keys().cloned().collect();
+        assert_eq!(keys, V1_KEYS);
+        assert_eq!(value["raw"], json!(event.raw));
+        value.as_object_mut().unwrap().remove("raw");
+
+        assert_eq!(
+            value,
+            json!({
+                "schema_version": 1,
+                "id": "bc641e01e63437ef",
+                "timestamp": "2024-01-15T10:23:45.3450",
+                "process": "1234:00007F12AB",
+                "kind": "EXECUTE_STATEMENT_FINISH",
+                "failed": false,
+                "location": null,
+                "attachment": {
+                    "id": 12,
+                    "database": "/data/erp.fdb",
+                    "user": "SYSDBA",
+                    "role": "NONE",
+                    "charset": "UTF8",
+                    "remote": "TCPv4:10.0.0.5/51234",
+                    "process": "/usr/bin/isql",
+                    "pid": 4567
+                },
+                "transaction": {
+                    "id": 45,
+                    "options": "CONCURRENCY | WAIT | READ_WRITE"
+                },
+                "statement": {
+                    "id": 789,
+                    "sql": "select * from customers where id = ?",
+                    "plan": "PLAN (CUSTOMERS INDEX (PK_CUSTOMERS))",
+                    "truncated": false,
+                    "fingerprint": null
+                },
+                "params": [1],
+                "records_fetched": 1,
+                "perf": {
+                    "duration_ms": 5,
+                    "reads": 10,
+                    "writes": 2,
+                    "fetches": 30,
+                    "marks": 1
+                },
+                "lines": [],
+                "tags": {},
+                "replication": null,
+                "error_code": null,
+                "context": []
+            })
+        );
+    }
+
+    #[test]
+    fn v1_absent_sections_are_null() {
+        let event = parse(
+            "2024-01-15T10:23:45.1230 (1234:00007F12AB) TRACE_INIT\n\tSESSION_5 rust-fbtrace\n",
+        );
+        let value = v1(&event);
+
+        for field in [
+            "location",
+            "attachment",
+            "transaction",
+            "statement",
+            "records_fetched",
+            "perf",
+            "replication",
+            "error_code",
+        ] {
+            assert_eq!(value[field], Value::Null, "{field}");
+        }
+        assert_eq!(value["lines"], json!(["\tSESSION_5 rust-fbtrace"]));
+    }
+
+    #[test]
+    fn flags_truncated_statements() {
+        let mut event = parse(STATEMENT);
+        event.truncate_sql(20);
+        let value = v1(&event);
+
+        assert_eq!(value["statement"]["sql"], "select * f \u{2026} ere id = ?");
+        assert_eq!(value["statement"]["truncated"], true);
+        assert!(value["raw"]
+            .as_str()
+            .unwrap()
+            .contains("\nselect * f \u{2026} ere id = ?\n"));
+    }
+
+    #[test]
+    fn types_params() {
+        let event = parse(&STATEMENT.replace(
+            "param0 = integer, \"1\"\n",
+            "param0 = integer, \"1\"
+param1 = varchar(10), \"ACME\"
+param2 = integer, <NULL>
+param3 = numeric(18, 2), \"12.50\"
+param4 = boolean, \"<true>\"
+param5 = blob sub_type 1, \"two
+lines\"
+",
+        ));
+        let value = v1(&event);
+
+        assert_eq!(
+            value["params"],
+            json!([1, "ACME", null, 12.5, true, "two\nlines"])
+        );
+        assert_eq!(value["lines"], json!([]));
+        assert!(event.inline_params().contains(
+            "\nparams: [1, 'ACME', null, 12.50, true, 'two\nlines']\n\n1 records fetched"
+        ));
+    }
+
+    #[test]
+    fn writes_fingerprints_and_session_tags() {
+        let mut event = parse(STATEMENT);
+        event.statement.as_mut().unwrap().fingerprint = Some("5f2a9c".into());
+        event.tags.insert("ticket".into(), "OPS-123".into());
+        event.tags.insert("env".into(), "prod".into());
+        let value = v1(&event);
+
+        assert_eq!(value["statement"]["fingerprint"], "5f2a9c");
+        assert_eq!(value["tags"], json!({"env": "prod", "ticket": "OPS-123"}));
+    }
+
+    #[test]
+    fn writes_replication() {
+        let mut event = parse("2024-01-15T10:23:45.3450 (1234:00007F12AB) REPLICATION\n");
+        event.replication = Some(crate::event::Replication {
+            role: Some("replica".into()),
+            database: Some("/data/erp.fdb".into()),
+            severity: "ERROR".into(),
+            message: "Cannot connect to the primary".into(),
+        });
+        let value = v1(&event);
+
+        assert_eq!(value["kind"], "REPLICATION");
+        assert_eq!(
+            value["replication"],
+            json!({
+                "role": "replica",
+                "database": "/data/erp.fdb",
+                "severity": "ERROR",
+                "message": "Cannot connect to the primary"
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_status_codes() {
+        let event = parse(
+            "2024-01-15T10:23:45.3450 (1234:00007F12AB) ERROR AT JStatement::execute\n\
+             335544665 : violation of PRIMARY or UNIQUE KEY constraint \"PK_CUSTOMERS\"\n",
+        );
+        assert_eq!(
+            v1(&event)["error_code"],
+            json!({"gdscode": 335544665, "name": "unique_key_violation", "sqlstate": "23000"})
+        );
+    }
+
+    #[test]
+    fn writes_context_variables() {
+        let event = parse(
+            "2024-01-15T10:23:45.3450 (1234:00007F12AB) SET_CONTEXT\n\
+             \t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)\n\
+             \t\t(TRA_45, CONCURRENCY | WAIT | READ_WRITE)\n\
+             [USER_TRANSACTION] REQUEST_ID = \"r-1\"\n",
+        );
+        assert_eq!(
+            v1(&event)["context"],
+            json!([{"namespace": "USER_TRANSACTION", "name": "REQUEST_ID", "value": "r-1"}])
+        );
+    }
+
+    #[test]
+    fn latest_reads_back_into_the_model_schema() {
+        let mut event = parse(STATEMENT);
+        event.assign_id(5, 0);
+        event.tags.insert("ticket".into(), "OPS-123".into());
+
+        let json = to_json(&event, LATEST).unwrap();
+        let read: schema::Event = serde_json::from_str(&json).unwrap();
+        assert_eq!(read, schema::Event::from(&event));
+        assert_eq!(serde_json::to_string(&read).unwrap(), json);
+    }
+}
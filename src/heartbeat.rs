@@ -0,0 +1,211 @@
+//! A status line every `--heartbeat` while tracing, telling whether events are still
+//! flowing and the trace keeps up with them: throughput by kind, events that couldn't
+//! be parsed or were dropped, how full the buffer is and how long the session has run.
+
+use crate::buffer;
+use crate::event::{Event, EventKind};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How many kinds the status line names, the busiest first. The event has them all.
+const LINE_KINDS: usize = 3;
+
+pub struct Heartbeat {
+    every: Duration,
+    started: Instant,
+    last: Instant,
+    /// Events received since the last beat, by kind.
+    kinds: BTreeMap<String, u64>,
+}
+
+/// The state of the trace at a beat.
+pub struct Status {
+    uptime: Duration,
+    /// Events per second since the previous beat, by kind, the busiest first.
+    rates: Vec<(String, f64)>,
+    unparsed: u64,
+    dropped: u64,
+    buffer: buffer::Stats,
+}
+
+impl Heartbeat {
+    pub fn new(every: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            every,
+            started: now,
+            last: now,
+            kinds: BTreeMap::new(),
+        }
+    }
+
+    /// Counts an event received from the trace, whether it's kept or not.
+    pub fn observe(&mut self, event: &Event) {
+        *self.kinds.entry(event.kind.name().to_string()).or_default() += 1;
+    }
+
+    /// The status, if a beat is due. `unparsed` and `dropped` are totals since the
+    /// trace started.
+    pub fn beat(&mut self, unparsed: u64, dropped: u64, buffer: buffer::Stats) -> Option<Status> {
+        self.beat_at(Instant::now(), unparsed, dropped, buffer)
+    }
+
+    fn beat_at(
+        &mut self,
+        now: Instant,
+        unparsed: u64,
+        dropped: u64,
+        buffer: buffer::Stats,
+    ) -> Option<Status> {
+        if now - self.last < self.every {
+            return None;
+        }
+        let secs = (now - self.last).as_secs_f64();
+        self.last = now;
+        let mut rates: Vec<(String, f64)> = std::mem::take(&mut self.kinds)
+            .into_iter()
+            .map(|(kind, n)| (kind, n as f64 / secs))
+            .collect();
+        rates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Some(Status {
+            uptime: now - self.started,
+            rates,
+            unparsed,
+            dropped,
+            buffer,
+        })
+    }
+}
+
+impl Status {
+    fn total(&self) -> f64 {
+        self.rates.iter().fold(0.0, |total, (_, r)| total + r)
+    }
+
+    /// A `HEARTBEAT` event for structured output, with a `name: value` line per figure.
+    pub fn event(&self, tags: &BTreeMap<String, String>) -> Event {
+        let timestamp = chrono::Local::now()
+            .naive_local()
+            .format("%Y-%m-%dT%H:%M:%S%.3f")
+            .to_string();
+        let mut lines = vec![
+            format!("uptime: {}s", self.uptime.as_secs()),
+            format!("events: {:.1}/s", self.total()),
+        ];
+        lines.extend(
+            self.rates
+                .iter()
+                .map(|(kind, r)| format!("{kind}: {r:.1}/s")),
+        );
+        lines.extend([
+            format!("unparsed: {}", self.unparsed),
+            format!("dropped: {}", self.dropped + self.buffer.dropped),
+            format!(
+                "buffered: {} of {}, {} on disk",
+                self.buffer.in_memory, self.buffer.capacity, self.buffer.on_disk
+            ),
+        ]);
+        Event {
+            id: String::new(),
+            raw: format!("{timestamp} HEARTBEAT\n{}", lines.join("\n")),
+            timestamp,
+            process: String::new(),
+            kind: EventKind::Heartbeat,
+            failed: false,
+            location: None,
+            attachment: None,
+            transaction: None,
+            statement: None,
+            records_fetched: None,
+            perf: None,
+            params: vec![],
+            tables: vec![],
+            tags: tags.clone(),
+            replication: None,
+            error_code: None,
+            context: vec![],
+            lines,
+        }
+    }
+}
+
+/// E.g. `Heartbeat: up 1h 2m 5s, 41.2 events/s (EXECUTE_STATEMENT_FINISH 30.1/s, ...),
+/// 0 unparsed, 3 dropped, buffer 0% full`.
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.uptime.as_secs();
+        let uptime = match (secs / 3600, secs / 60 % 60, secs % 60) {
+            (0, 0, s) => format!("{s}s"),
+            (0, m, s) => format!("{m}m {s}s"),
+            (h, m, s) => format!("{h}h {m}m {s}s"),
+        };
+        write!(f, "Heartbeat: up {uptime}, {:.1} events/s", self.total())?;
+        if !self.rates.is_empty() {
+            let mut busiest: Vec<String> = self
+                .rates
+                .iter()
+                .take(LINE_KINDS)
+                .map(|(kind, r)| format!("{kind} {r:.1}/s"))
+                .collect();
+            if self.rates.len() > LINE_KINDS {
+                busiest.push("...".into());
+            }
+            write!(f, " ({})", busiest.join(", "))?;
+        }
+        write!(
+            f,
+            ", {} unparsed, {} dropped, buffer {:.0}% full",
+            self.unparsed,
+            self.dropped + self.buffer.dropped,
+            self.buffer.in_memory as f64 * 100.0 / self.buffer.capacity as f64
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::bare;
+
+    #[test]
+    fn reports_rates_since_the_last_beat() {
+        let finish = bare("EXECUTE_STATEMENT_FINISH");
+        let error = bare("ERROR AT JStatement::execute");
+        let stats = || buffer::Stats {
+            in_memory: 25,
+            on_disk: 0,
+            capacity: 100,
+            peak: 30,
+            spilled: 0,
+            dropped: 2,
+        };
+
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(10));
+        let start = heartbeat.started;
+        (0..30).for_each(|_| heartbeat.observe(&finish));
+        (0..5).for_each(|_| heartbeat.observe(&error));
+        assert!(heartbeat
+            .beat_at(start + Duration::from_secs(5), 0, 0, stats())
+            .is_none());
+
+        let status = heartbeat
+            .beat_at(start + Duration::from_secs(10), 1, 3, stats())
+            .unwrap();
+        assert_eq!(
+            status.to_string(),
+            "Heartbeat: up 10s, 3.5 events/s (EXECUTE_STATEMENT_FINISH 3.0/s, ERROR 0.5/s), \
+             1 unparsed, 5 dropped, buffer 25% full"
+        );
+        let event = status.event(&BTreeMap::new());
+        assert_eq!(event.kind, EventKind::Heartbeat);
+        assert!(event.lines.contains(&"ERROR: 0.5/s".to_string()));
+
+        let status = heartbeat
+            .beat_at(start + Duration::from_secs(3725), 1, 3, stats())
+            .unwrap();
+        assert!(status
+            .to_string()
+            .starts_with("Heartbeat: up 1h 2m 5s, 0.0 events/s, "));
+    }
+}
@@ -0,0 +1,256 @@
+//! Latency heatmaps of statements recorded in a store.
+
+use crate::error::AppError;
+use crate::event::Event;
+use crate::sink::{sqlite, Store};
+use crate::units;
+use clap::ValueEnum;
+use rusqlite::Connection;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Duration buckets double in size, so both fast lookups and slow reports stay visible.
+/// The last bucket holds everything slower.
+pub const DURATION_BUCKETS: usize = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HeatmapFormat {
+    /// Bucketed counts, as rows for a Grafana heatmap panel
+    Json,
+    /// A rendered heatmap per database
+    Svg,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct HeatmapArgs {
+    /// The store to read statements from, e.g. sqlite:trace.db
+    store: Store,
+
+    /// Width of each time bucket, e.g. 1m
+    #[arg(long, value_parser = units::parse_duration, default_value = "1m")]
+    bucket: Duration,
+
+    #[arg(long, value_enum, default_value_t = HeatmapFormat::Svg)]
+    format: HeatmapFormat,
+
+    /// Write the heatmap to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+/// Statement counts per time bucket and duration bucket.
+type Grid = BTreeMap<i64, [u64; DURATION_BUCKETS]>;
+
+/// The upper bound of duration bucket `i`, in milliseconds.
+pub fn bucket_bound(i: usize) -> Option<i64> {
+    (i + 1 < DURATION_BUCKETS).then(|| 1 << i)
+}
+
+pub fn duration_bucket(ms: i64) -> usize {
+    (0..DURATION_BUCKETS)
+        .find(|&i| bucket_bound(i).is_none_or(|b| ms < b))
+        .unwrap_or(DURATION_BUCKETS - 1)
+}
+
+pub fn run(args: &HeatmapArgs) -> Result<(), AppError> {
+    let Store::Sqlite(path) = &args.store;
+    let grids = match load(path, args.bucket) {
+        Ok(g) => g,
+        Err(e) => return Err(AppError::Dyn(Box::new(e))),
+    };
+
+    let bucket_secs = args.bucket.as_secs().max(1) as i64;
+    let output = match args.format {
+        HeatmapFormat::Json => to_json(&grids, bucket_secs).to_string(),
+        HeatmapFormat::Svg => to_svg(&grids, bucket_secs),
+    };
+
+    match &args.output {
+        Some(path) => std::fs::write(path, output).map_err(AppError::Io),
+        None => {
+            println!("{output}");
+            Ok(())
+        }
+    }
+}
+
+/// Reads the finished statements of each database into buckets.
+fn load(path: &str, bucket: Duration) -> rusqlite::Result<BTreeMap<String, Grid>> {
+    let conn = Connection::open(path)?;
+    if let Some(note) = sqlite::thinning_note(&conn) {
+        eprintln!("{path}: {note}");
+    }
+    let mut stmt = conn.prepare(
+        "SELECT e.timestamp, coalesce(a.database, ''), s.duration_ms
+         FROM statements s
+         JOIN events e ON e.id = s.event_id
+         LEFT JOIN attachments a ON a.id = s.attachment_id
+         WHERE e.kind = 'EXECUTE_STATEMENT_FINISH' AND s.duration_ms IS NOT NULL",
+    )?;
+
+    let bucket_secs = bucket.as_secs().max(1) as i64;
+    let mut grids: BTreeMap<String, Grid> = BTreeMap::new();
+    let rows = stmt.query_map([], |r| {
+        Ok((
+            r.get::<_, String>(0)?,
+            r.get::<_, String>(1)?,
+            r.get::<_, i64>(2)?,
+        ))
+    })?;
+
+    for row in rows {
+        let (timestamp, database, duration_ms) = row?;
+        let Some(t) = Event::parse_timestamp(&timestamp) else {
+            continue;
+        };
+        let secs = t.and_utc().timestamp();
+        let time_bucket = secs - secs.rem_euclid(bucket_secs);
+
+        let cells = grids
+            .entry(database)
+            .or_default()
+            .entry(time_bucket)
+            .or_insert([0; DURATION_BUCKETS]);
+        cells[duration_bucket(duration_ms)] += 1;
+    }
+
+    Ok(grids)
+}
+
+pub fn format_time(secs: i64) -> String {
+    chrono::DateTime::from_timestamp(secs, 0)
+        .map(|t| t.naive_utc().format("%Y-%m-%dT%H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+fn bound_label(i: usize) -> String {
+    match bucket_bound(i) {
+        Some(b) => b.to_string(),
+        None => "+Inf".into(),
+    }
+}
+
+/// One row per time bucket, with a count column per duration bucket named by its upper
+/// bound in milliseconds, as Grafana's heatmap panel expects for pre-bucketed data.
+/// Empty time buckets are included so gaps stay visible.
+fn to_json(grids: &BTreeMap<String, Grid>, bucket_secs: i64) -> Value {
+    let databases: Map<String, Value> = grids
+        .iter()
+        .map(|(database, grid)| {
+            let rows: Vec<Value> = time_buckets(grid, bucket_secs)
+                .map(|t| {
+                    let cells = grid.get(&t).copied().unwrap_or_default();
+                    let mut row = Map::new();
+                    row.insert("time".into(), json!(format_time(t)));
+                    for (i, count) in cells.iter().enumerate() {
+                        row.insert(bound_label(i), json!(count));
+                    }
+                    Value::Object(row)
+                })
+                .collect();
+            (database.clone(), Value::Array(rows))
+        })
+        .collect();
+
+    json!({
+        "bucket_seconds": bucket_secs,
+        "databases": databases,
+    })
+}
+
+fn time_buckets(grid: &Grid, bucket_secs: i64) -> impl Iterator<Item = i64> {
+    let first = grid.keys().next().copied().unwrap_or_default();
+    let last = grid.keys().next_back().copied().unwrap_or_default();
+    (first..=last).step_by(bucket_secs as usize)
+}
+
+const CELL: usize = 12;
+const LEFT: usize = 70;
+const TOP: usize = 30;
+const BOTTOM: usize = 40;
+
+fn to_svg(grids: &BTreeMap<String, Grid>, bucket_secs: i64) -> String {
+    let columns = grids
+        .values()
+        .map(|g| time_buckets(g, bucket_secs).count())
+        .max()
+        .unwrap_or_default();
+    let panel_height = TOP + DURATION_BUCKETS * CELL + BOTTOM;
+    // Wide enough for the time axis label.
+    let width = LEFT + (columns * CELL).max(360) + 20;
+    let height = panel_height * grids.len().max(1);
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" font-family="sans-serif" font-size="10">"#
+    );
+
+    for (n, (database, grid)) in grids.iter().enumerate() {
+        let y0 = n * panel_height;
+        let max = grid.values().flatten().copied().max().unwrap_or(1).max(1);
+        let _ = writeln!(
+            svg,
+            r#"<text x="{LEFT}" y="{}" font-size="12">{}</text>"#,
+            y0 + 18,
+            escape(database)
+        );
+
+        // Slow statements at the top, like a latency chart.
+        for i in 0..DURATION_BUCKETS {
+            let y = y0 + TOP + (DURATION_BUCKETS - 1 - i) * CELL;
+            let label = match bucket_bound(i) {
+                Some(b) => format!("< {b} ms"),
+                None => format!(">= {} ms", 1 << (DURATION_BUCKETS - 2)),
+            };
+            let _ = writeln!(
+                svg,
+                r#"<text x="{}" y="{}" text-anchor="end">{}</text>"#,
+                LEFT - 4,
+                y + CELL - 2,
+                escape(&label)
+            );
+        }
+
+        let times: Vec<i64> = time_buckets(grid, bucket_secs).collect();
+        for (col, t) in times.iter().enumerate() {
+            let Some(cells) = grid.get(t) else {
+                continue;
+            };
+            for (i, &count) in cells.iter().enumerate().filter(|(_, &c)| c > 0) {
+                // Log scale, so a few outliers aren't washed out by the bulk.
+                let intensity = ((count as f64).ln_1p() / (max as f64).ln_1p()).clamp(0.05, 1.0);
+                let _ = writeln!(
+                    svg,
+                    r#"<rect x="{}" y="{}" width="{CELL}" height="{CELL}" fill="rgb(200,30,30)" fill-opacity="{intensity:.3}"><title>{}: {count}</title></rect>"#,
+                    LEFT + col * CELL,
+                    y0 + TOP + (DURATION_BUCKETS - 1 - i) * CELL,
+                    format_time(*t),
+                );
+            }
+        }
+
+        let axis_y = y0 + TOP + DURATION_BUCKETS * CELL + 14;
+        if let (Some(first), Some(last)) = (times.first(), times.last()) {
+            let _ = writeln!(
+                svg,
+                r#"<text x="{LEFT}" y="{axis_y}">{} to {} UTC, {bucket_secs}s per column</text>"#,
+                format_time(*first),
+                format_time(*last)
+            );
+        }
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,124 @@
+//! Durations counted in buckets, in the manner of HDR histograms: exact below `SUB`
+//! milliseconds, then 64 buckets to each power of two, so a percentile is within 1/64 of
+//! the true value however many executions are counted, in a few KB.
+
+/// Durations below this are counted exactly; above it, each power of two is split into
+/// `SUB / 2` buckets.
+const SUB: u64 = 128;
+
+const HALF: u64 = SUB / 2;
+
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: i64,
+    max: i64,
+}
+
+impl Histogram {
+    /// Counts `n` executions of `ms`, e.g. for one kept of a sample standing for `n`.
+    pub fn record_n(&mut self, ms: i64, n: u64) {
+        if n == 0 {
+            return;
+        }
+        let i = index(ms.max(0) as u64);
+        if self.counts.len() <= i {
+            self.counts.resize(i + 1, 0);
+        }
+        self.counts[i] += n;
+        self.count += n;
+        self.sum += ms * n as i64;
+        self.max = self.max.max(ms);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> i64 {
+        self.sum
+    }
+
+    pub fn mean(&self) -> i64 {
+        self.sum / self.count.max(1) as i64
+    }
+
+    pub fn max(&self) -> i64 {
+        self.max
+    }
+
+    /// The value below which `p` of the durations fall, as the highest of its bucket, or
+    /// the longest duration if that is less.
+    pub fn percentile(&self, p: f64) -> i64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((self.count as f64 * p).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (i, &n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return (highest(i) as i64).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+fn index(v: u64) -> usize {
+    if v < SUB {
+        return v as usize;
+    }
+    // Keep the top 7 bits: 64 buckets between each power of two and the next.
+    let shift = 63 - v.leading_zeros() as u64 - HALF.trailing_zeros() as u64;
+    ((shift + 1) * HALF + ((v >> shift) - HALF)) as usize
+}
+
+/// The highest duration counted in bucket `i`.
+fn highest(i: usize) -> u64 {
+    let i = i as u64;
+    if i < SUB {
+        return i;
+    }
+    let shift = i / HALF - 1;
+    ((i % HALF + HALF + 1) << shift) - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::percentile;
+
+    #[test]
+    fn percentiles_are_within_a_bucket_of_the_exact_ones() {
+        for v in [0, 1, 127, 128, 129, 255, 256, 1000, 65_535, 1 << 40] {
+            let i = index(v);
+            assert!(highest(i) >= v && (i == 0 || highest(i - 1) < v), "{v}");
+        }
+
+        let mut histogram = Histogram::default();
+        let mut exact: Vec<i64> = (0..10_000).map(|n| (n * n) % 90_001).collect();
+        exact.iter().for_each(|&d| histogram.record_n(d, 1));
+        exact.sort_unstable();
+        for p in [0.5, 0.9, 0.99, 1.0] {
+            let (got, want) = (histogram.percentile(p), percentile(&exact, p));
+            assert!(
+                got >= want && got - want <= want / 64,
+                "p{p}: {got} vs {want}"
+            );
+        }
+        assert_eq!(histogram.count(), 10_000);
+        assert_eq!(histogram.max(), *exact.last().unwrap());
+        assert_eq!(histogram.sum(), exact.iter().sum::<i64>());
+        assert_eq!(Histogram::default().percentile(0.5), 0);
+
+        let mut weighted = Histogram::default();
+        weighted.record_n(10, 3);
+        weighted.record_n(1000, 1);
+        assert_eq!(
+            (weighted.count(), weighted.sum(), weighted.percentile(0.75)),
+            (4, 1030, 10)
+        );
+    }
+}
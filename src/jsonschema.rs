@@ -0,0 +1,286 @@
+//! A JSON Schema of the events in the latest format version, for consumers of the JSON
+//! output to validate it or generate bindings from.
+
+use crate::error::AppError;
+use rsfbtrace_model::schema;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+
+#[derive(clap::Args, Debug)]
+pub struct SchemaArgs {
+    /// Write the schema to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+pub fn run(args: &SchemaArgs) -> Result<(), AppError> {
+    let mut text = serde_json::to_string_pretty(&event_schema()).map_err(std::io::Error::from)?;
+    text.push('\n');
+    match &args.output {
+        Some(path) => std::fs::write(path, text).map_err(AppError::Io),
+        None => {
+            print!("{text}");
+            Ok(())
+        }
+    }
+}
+
+/// The kinds of event rsfbtrace knows. Others the server prints are passed through.
+const KINDS: &[&str] = &[
+    "TRACE_INIT",
+    "TRACE_FINI",
+    "ATTACH_DATABASE",
+    "DETACH_DATABASE",
+    "START_TRANSACTION",
+    "COMMIT_TRANSACTION",
+    "COMMIT_RETAINING",
+    "ROLLBACK_TRANSACTION",
+    "ROLLBACK_RETAINING",
+    "PREPARE_STATEMENT",
+    "FREE_STATEMENT",
+    "CLOSE_CURSOR",
+    "EXECUTE_STATEMENT_START",
+    "EXECUTE_STATEMENT_FINISH",
+    "EXECUTE_PROCEDURE_START",
+    "EXECUTE_PROCEDURE_FINISH",
+    "EXECUTE_FUNCTION_START",
+    "EXECUTE_FUNCTION_FINISH",
+    "EXECUTE_TRIGGER_START",
+    "EXECUTE_TRIGGER_FINISH",
+    "SET_CONTEXT",
+    "ERROR",
+    "WARNING",
+    "SWEEP_START",
+    "SWEEP_PROGRESS",
+    "SWEEP_FINISH",
+    "SWEEP_FAILED",
+    "ATTACH_SERVICE",
+    "START_SERVICE",
+    "QUERY_SERVICE",
+    "DETACH_SERVICE",
+    "SERVER_LOG",
+    "LOCK_SNAPSHOT",
+    "TRANSACTION_SUMMARY",
+    "LOCK_CONFLICT",
+    "DISTRIBUTED_TRANSACTION",
+    "REPLICATION",
+    "SERVER_DISTRESS",
+    "SWEEP_REPORT",
+    "HEARTBEAT",
+];
+
+/// An object with every one of `properties` required and no others.
+fn object(description: &str, properties: Value) -> Value {
+    let required: Vec<&String> = properties.as_object().unwrap().keys().collect();
+    json!({
+        "type": "object",
+        "description": description,
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+fn nullable(name: &str) -> Value {
+    json!({"anyOf": [{"$ref": format!("#/$defs/{name}")}, {"type": "null"}]})
+}
+
+pub fn event_schema() -> Value {
+    let string = json!({"type": "string"});
+    let integer = json!({"type": "integer"});
+    let optional_string = json!({"type": ["string", "null"]});
+    let optional_integer = json!({"type": ["integer", "null"]});
+
+    let mut event = object(
+        "An event of a Firebird trace, as written by rsfbtrace",
+        json!({
+            "schema_version": {"const": schema::VERSION},
+            "id": {
+                "type": "string",
+                "description": "Derived from the trace session, the event's position in it and its timestamp"
+            },
+            "timestamp": {"type": "string", "description": "As printed by the server, e.g. 2024-01-15T10:23:45.3450"},
+            "process": {"type": "string", "description": "The server process and thread, e.g. 1234:00007F12AB"},
+            "kind": {
+                "type": "string",
+                "description": "The event's kind. Kinds rsfbtrace doesn't know are passed through as the server printed them",
+                "examples": KINDS,
+            },
+            "failed": {"type": "boolean"},
+            "location": optional_string,
+            "attachment": nullable("Attachment"),
+            "transaction": nullable("Transaction"),
+            "statement": nullable("Statement"),
+            "params": {"type": "array", "items": {"type": ["null", "boolean", "integer", "number", "string"]}},
+            "records_fetched": optional_integer,
+            "perf": nullable("Perf"),
+            "lines": {"type": "array", "items": string},
+            "raw": {"type": "string", "description": "The event's text as the server printed it"},
+            "tags": {"type": "object", "additionalProperties": string},
+            "replication": nullable("Replication"),
+            "error_code": nullable("ErrorCode"),
+            "context": {"type": "array", "items": {"$ref": "#/$defs/ContextVar"}},
+        }),
+    );
+    let defs = json!({
+        "Attachment": object("The attachment an event belongs to", json!({
+            "id": integer,
+            "database": string,
+            "user": string,
+            "role": string,
+            "charset": string,
+            "remote": string,
+            "process": optional_string,
+            "pid": optional_integer,
+        })),
+        "Transaction": object("The transaction an event belongs to", json!({
+            "id": integer,
+            "options": {"type": "string", "description": "E.g. CONCURRENCY | WAIT | READ_WRITE"},
+        })),
+        "Statement": object("The statement an event belongs to", json!({
+            "id": integer,
+            "sql": string,
+            "plan": optional_string,
+            "truncated": {"type": "boolean", "description": "Whether sql was cut short"},
+            "fingerprint": optional_string,
+        })),
+        "Perf": object("Performance counters", json!({
+            "duration_ms": integer,
+            "reads": integer,
+            "writes": integer,
+            "fetches": integer,
+            "marks": integer,
+        })),
+        "Replication": object("Where a replication log entry comes from", json!({
+            "role": optional_string,
+            "database": optional_string,
+            "severity": string,
+            "message": string,
+        })),
+        "ErrorCode": object("The first status code of an error, decoded", json!({
+            "gdscode": integer,
+            "name": optional_string,
+            "sqlstate": optional_string,
+        })),
+        "ContextVar": object("A context variable in effect", json!({
+            "namespace": {"type": "string", "description": "USER_SESSION or USER_TRANSACTION"},
+            "name": string,
+            "value": optional_string,
+        })),
+    });
+    let event = event.as_object_mut().unwrap();
+    let mut root = serde_json::Map::new();
+    root.insert(
+        "$schema".into(),
+        json!("https://json-schema.org/draft/2020-12/schema"),
+    );
+    root.insert(
+        "title".into(),
+        json!(format!(
+            "rsfbtrace event, format version {}",
+            schema::VERSION
+        )),
+    );
+    root.append(event);
+    root.insert("$defs".into(), defs);
+    Value::Object(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::EventKind;
+    use crate::fixtures::{bare, event, STATEMENT_FINISH};
+    use rsfbtrace_model::event::{ContextVar, ErrorCode, Replication};
+
+    /// Checks `value` against the parts of JSON Schema `event_schema` uses.
+    fn check(value: &Value, schema: &Value, root: &Value, path: &str) -> Result<(), String> {
+        if let Some(r) = schema["$ref"].as_str() {
+            let name = r.trim_start_matches("#/$defs/");
+            return check(value, &root["$defs"][name], root, path);
+        }
+        if let Some(any) = schema["anyOf"].as_array() {
+            return match any.iter().any(|s| check(value, s, root, path).is_ok()) {
+                true => Ok(()),
+                false => Err(format!("{path}: {value} matches none of {any:?}")),
+            };
+        }
+        if schema.get("const").is_some_and(|c| c != value) {
+            return Err(format!("{path}: {value} isn't {}", schema["const"]));
+        }
+        let type_name = match value {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(n) if n.is_i64() => "integer",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        };
+        let types = match &schema["type"] {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => vec![type_name],
+        };
+        let widened = type_name == "integer" && types.contains(&"number");
+        if !types.contains(&type_name) && !widened {
+            return Err(format!("{path}: {type_name} isn't one of {types:?}"));
+        }
+        if let (Value::Object(o), Some(properties)) = (value, schema["properties"].as_object()) {
+            let keys: Vec<&String> = o.keys().collect();
+            if keys != properties.keys().collect::<Vec<_>>() {
+                return Err(format!("{path}: {keys:?} aren't the properties described"));
+            }
+            for (k, v) in o {
+                check(v, &properties[k], root, &format!("{path}.{k}"))?;
+            }
+        }
+        if let (Value::Array(a), Some(items)) = (value, schema.get("items")) {
+            for v in a {
+                check(v, items, root, &format!("{path}[]"))?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn describes_every_field_of_the_latest_version() {
+        // A double too, as numbers that aren't integers are described apart.
+        let trace = STATEMENT_FINISH.replace(
+            "param0 = integer, \"1\"\n",
+            "param0 = integer, \"1\"\nparam1 = double precision, \"2.5\"\n",
+        );
+        let mut full = schema::Event::from(&event(&trace));
+        full.location = Some("JStatement::execute".into());
+        full.tags.insert("ticket".into(), "OPS-123".into());
+        full.replication = Some(Replication::default());
+        full.error_code = Some(ErrorCode::default());
+        full.context.push(ContextVar {
+            namespace: "USER_SESSION".into(),
+            name: "APP_USER".into(),
+            value: None,
+        });
+        full.statement.as_mut().unwrap().fingerprint = Some("5f2a".into());
+
+        let root = event_schema();
+        check(&serde_json::to_value(&full).unwrap(), &root, &root, "event").unwrap();
+        let empty = schema::Event::from(&bare("TRACE_INIT"));
+        check(
+            &serde_json::to_value(&empty).unwrap(),
+            &root,
+            &root,
+            "event",
+        )
+        .unwrap();
+        let mut old = serde_json::to_value(&empty).unwrap();
+        old["schema_version"] = json!(9);
+        assert!(check(&old, &root, &root, "event").is_err());
+
+        for kind in KINDS {
+            assert!(
+                !matches!(EventKind::from_name(kind), EventKind::Other(_)),
+                "{kind}"
+            );
+        }
+    }
+}
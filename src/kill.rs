@@ -0,0 +1,109 @@
+//! Cancelling a runaway statement spotted in a trace. Deleting an attachment's rows
+//! from MON$STATEMENTS makes the server cancel what it's running, without closing the
+//! attachment or rolling back its transaction.
+
+use crate::error::AppError;
+use crate::monitor::Monitor;
+use std::io::Error as IOError;
+
+#[derive(clap::Args, Debug)]
+pub struct KillArgs {
+    /// The database the attachment is in, e.g. dbhost:/data/erp.fdb
+    #[arg(long)]
+    database: String,
+
+    /// Firebird username
+    #[arg(short, long, env = "ISC_USER", hide_env = true)]
+    user: String,
+
+    /// Firebird password
+    #[arg(short, long, env = "ISC_PASSWORD", hide_env = true)]
+    pass: String,
+
+    /// ID of the attachment, e.g. 12 for ATT_12 in the trace
+    #[arg(long)]
+    attachment: i64,
+
+    /// Only cancel this statement, e.g. 345 for `Statement 345:` in the trace, rather
+    /// than whatever the attachment is running
+    #[arg(long)]
+    statement: Option<i64>,
+
+    /// Print the SQL that would cancel the statements instead of running it
+    #[arg(long)]
+    dry_run: bool,
+}
+
+pub fn run(args: &KillArgs) -> Result<(), AppError> {
+    let script = script(args.attachment, args.statement);
+    if args.dry_run {
+        print!("{script}");
+        return Ok(());
+    }
+
+    let monitor = Monitor::new(args.database.clone(), args.user.clone(), args.pass.clone());
+    let output = monitor.query(&script).map_err(|e| {
+        AppError::Io(IOError::other(format!(
+            "Unable to cancel the statements of attachment {}: {e}",
+            args.attachment
+        )))
+    })?;
+    let cancelled = running(&output);
+    if cancelled.is_empty() {
+        return Err(AppError::InvalidArgs(match args.statement {
+            Some(id) => format!(
+                "Statement {id} of attachment {} isn't running",
+                args.attachment
+            ),
+            None => format!("Attachment {} isn't running any statement", args.attachment),
+        }));
+    }
+    for id in cancelled {
+        eprintln!("Cancelled statement {id} of attachment {}", args.attachment);
+    }
+    Ok(())
+}
+
+/// Lists the running statements the delete cancels, then deletes them. Idle statements
+/// are left alone, as deleting them would only free their MON$ rows.
+fn script(attachment: i64, statement: Option<i64>) -> String {
+    let mut filter = format!("MON$ATTACHMENT_ID = {attachment} AND MON$STATE <> 0");
+    if let Some(id) = statement {
+        filter.push_str(&format!(" AND MON$STATEMENT_ID = {id}"));
+    }
+    format!(
+        "SET LIST ON;\n\
+         SELECT MON$STATEMENT_ID FROM MON$STATEMENTS WHERE {filter};\n\
+         DELETE FROM MON$STATEMENTS WHERE {filter};\n\
+         COMMIT;\n"
+    )
+}
+
+/// The statement IDs isql listed, e.g. `MON$STATEMENT_ID                345`.
+fn running(output: &str) -> Vec<i64> {
+    output
+        .lines()
+        .filter_map(|l| l.strip_prefix("MON$STATEMENT_ID"))
+        .filter_map(|id| id.trim().parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancels_running_statements_of_the_attachment() {
+        let script = script(12, Some(345));
+        assert!(script.contains(
+            "DELETE FROM MON$STATEMENTS WHERE MON$ATTACHMENT_ID = 12 AND MON$STATE <> 0 \
+             AND MON$STATEMENT_ID = 345;"
+        ));
+        assert!(!super::script(12, None).contains("MON$STATEMENT_ID ="));
+
+        let output =
+            "\nMON$STATEMENT_ID                345\n\nMON$STATEMENT_ID                346\n";
+        assert_eq!(running(output), [345, 346]);
+        assert!(running("").is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+//! Finding the Firebird command line utilities, which are often installed off PATH, e.g.
+//! in `/opt/firebird/bin` or `C:\Program Files\Firebird\Firebird_5_0`.
+
+use std::env::consts::EXE_SUFFIX;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Where Firebird installs itself when it isn't packaged onto PATH.
+#[cfg(unix)]
+const INSTALL_DIRS: &[&str] = &[
+    "/opt/firebird/bin",
+    "/usr/local/firebird/bin",
+    "/usr/lib/firebird/bin",
+    "/Library/Frameworks/Firebird.framework/Resources/bin",
+];
+
+/// The Windows installer puts each version in its own directory under these, e.g.
+/// `Firebird_5_0`, with the utilities at the top (Firebird 3 and later) or in `bin`.
+#[cfg(windows)]
+const PROGRAM_FILES: &[&str] = &["ProgramFiles", "ProgramFiles(x86)"];
+
+/// The path to run the utility `name` from: in `explicit` (the file itself, or a
+/// directory holding it) if given, else in `$FIREBIRD`, on PATH or in the usual install
+/// locations. Falls back to the bare name, so running it fails as not found.
+pub fn tool(name: &str, explicit: Option<&Path>) -> PathBuf {
+    let file = format!("{name}{EXE_SUFFIX}");
+    candidates(
+        &file,
+        explicit,
+        std::env::var_os("FIREBIRD"),
+        std::env::var_os("PATH"),
+    )
+    .into_iter()
+    .find(|p| p.is_file())
+    .unwrap_or_else(|| name.into())
+}
+
+/// Where to look for `file`, in order.
+fn candidates(
+    file: &str,
+    explicit: Option<&Path>,
+    firebird: Option<OsString>,
+    path: Option<OsString>,
+) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    let mut found = vec![];
+    if let Some(explicit) = explicit {
+        if explicit.is_dir() {
+            dirs.push(explicit.to_path_buf());
+        } else {
+            // fbsvcmgr alongside the fbtracemgr given.
+            found.push(explicit.with_file_name(file));
+        }
+    }
+    if let Some(root) = firebird.filter(|r| !r.is_empty()) {
+        let root = PathBuf::from(root);
+        dirs.extend([root.join("bin"), root]);
+    }
+    dirs.extend(path.iter().flat_map(std::env::split_paths));
+    dirs.extend(install_dirs());
+    found.extend(dirs.into_iter().map(|d| d.join(file)));
+    found
+}
+
+#[cfg(unix)]
+fn install_dirs() -> Vec<PathBuf> {
+    INSTALL_DIRS.iter().map(PathBuf::from).collect()
+}
+
+/// The installed versions, newest first.
+#[cfg(windows)]
+fn install_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    for var in PROGRAM_FILES {
+        let Some(root) = std::env::var_os(var) else {
+            continue;
+        };
+        let Ok(entries) = std::fs::read_dir(Path::new(&root).join("Firebird")) else {
+            continue;
+        };
+        let mut versions: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect();
+        versions.sort_by(|a, b| b.cmp(a));
+        for version in versions {
+            dirs.extend([version.clone(), version.join("bin")]);
+        }
+    }
+    dirs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_where_told_then_in_firebird_then_on_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let given = dir.path().join("fbtracemgr");
+        let found = candidates(
+            "fbsvcmgr",
+            Some(&given),
+            Some("/fb".into()),
+            Some(std::env::join_paths(["/usr/bin", "/bin"]).unwrap()),
+        );
+        let expected: Vec<PathBuf> = [
+            dir.path().join("fbsvcmgr"),
+            "/fb/bin/fbsvcmgr".into(),
+            "/fb/fbsvcmgr".into(),
+            "/usr/bin/fbsvcmgr".into(),
+            "/bin/fbsvcmgr".into(),
+        ]
+        .into();
+        assert_eq!(found[..5], expected[..]);
+        assert!(found.len() > 5);
+
+        // A directory is searched rather than taken for the file.
+        let found = candidates("fbtracemgr", Some(dir.path()), None, None);
+        assert_eq!(found[0], given);
+
+        std::fs::write(&given, "").unwrap();
+        assert_eq!(tool("fbtracemgr", Some(&given)), given);
+        assert_eq!(
+            tool("rsfbtrace-no-such-tool", None),
+            PathBuf::from("rsfbtrace-no-such-tool")
+        );
+    }
+}
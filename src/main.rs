@@ -1,8 +1,97 @@
-use clap::Parser;
-use std::error::Error;
-use std::fs::OpenOptions;
-use std::io::{Error as IOError, Result as IOResult, Write};
-use std::process::Command;
+mod advisor;
+mod alert;
+mod archive;
+mod attachments;
+mod audit;
+mod binary;
+mod buffer;
+mod completions;
+mod connstr;
+mod convert;
+mod correlate;
+mod crypto;
+mod diff;
+mod distress;
+mod error;
+mod export;
+mod expr;
+mod fanout;
+mod filter;
+mod fingerprint;
+#[cfg(test)]
+mod fixtures;
+mod format;
+mod heartbeat;
+mod heatmap;
+mod histogram;
+mod jsonschema;
+mod kill;
+mod locate;
+mod monitor;
+mod picker;
+mod plans;
+mod policy;
+mod preset;
+mod privacy;
+mod raw;
+mod redact;
+mod replay;
+mod replica;
+mod report;
+mod selftest;
+mod serverlog;
+mod service;
+mod session;
+mod shell;
+mod sink;
+mod sweeps;
+mod tables;
+mod throttle;
+mod timezone;
+mod tracemgr;
+mod truncation;
+mod tunnel;
+mod units;
+mod validate;
+mod variants;
+
+use advisor::IndexAdvisor;
+use alert::{AlertTarget, Alerter};
+use attachments::AttachmentMap;
+use clap::{
+    Arg, ArgAction, ArgGroup, Args as _, CommandFactory, FromArgMatches, Parser, Subcommand,
+    ValueEnum,
+};
+use correlate::Correlator;
+use distress::Watch;
+use error::AppError;
+use fanout::Sessions;
+use filter::AttachmentFilter;
+use format::OutputFormat;
+use heartbeat::Heartbeat;
+use monitor::Monitor;
+use plans::PlanTracker;
+use redact::Redactor;
+use rsfbtrace_model::event::{self, Event, EventKind};
+use rsfbtrace_model::{gdscode, parser};
+use sink::{Capture, Output, Sink, Store};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Result as IOResult, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use sweeps::SweepTracker;
+use tables::TableReport;
+use tempfile::TempPath;
+use throttle::{Counts, Thinning, Throttle};
+use timezone::Clock;
+use tracemgr::Echo;
+use truncation::TruncationWatch;
 
 const OPT_CONNECTIONS: &str = "connections";
 const OPT_TRANSACTIONS: &str = "transactions";
@@ -18,7 +107,22 @@ const OPT_CONTEXT: &str = "context";
 const OPT_ERRORS: &str = "errors";
 const OPT_SWEEP: &str = "sweep";
 
+/// The `time_threshold` of the config: finish events faster than this aren't reported.
+const TIME_THRESHOLD_MS: u64 = 100;
+
+/// What `--production` limits the trace to, so tracing a busy server costs it little.
+const PRODUCTION_EVENTS: &[&str] = &[OPT_ERRORS, OPT_STATEMENT_FINISH];
+const PRODUCTION_TIME_THRESHOLD_MS: u64 = 1000;
+const PRODUCTION_MAX_SQL: usize = 4096;
+const PRODUCTION_MAX_ARG_LENGTH: usize = 40;
+const PRODUCTION_MAX_ARG_COUNT: usize = 10;
+const PRODUCTION_RATE_LIMIT: throttle::Rate = throttle::Rate {
+    events: 100,
+    per: Duration::from_secs(1),
+};
+
 const LEGAL_OPTS: &[&str] = &[
+    OPT_CONNECTIONS,
     OPT_TRANSACTIONS,
     OPT_STATEMENT_PREPARE,
     OPT_STATEMENT_FREE,
@@ -33,130 +137,1601 @@ const LEGAL_OPTS: &[&str] = &[
     OPT_SWEEP,
 ];
 
-const CONFIG_FILE_NAME: &str = "fbtrace.conf";
 const TRACE_NAME: &str = "rust-fbtrace";
 
-#[derive(Debug)]
-enum AppError {
-    InvalidOpt(String),
-    Dyn(Box<dyn Error>),
-    Io(IOError),
+/// How often the main loop wakes up to check limits and shutdown requests.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Set when the trace should be stopped from outside, e.g. by the service manager.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Set on Ctrl+C, which fbtracemgr handles itself, but ends a pause between sessions.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Parser, Debug)]
+#[command(author, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Cmd>,
+
+    #[command(flatten)]
+    trace: Option<Args>,
 }
 
-impl std::error::Error for AppError {
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        None
+impl Cli {
+    /// The command, with any trace flag without a conventional variable, e.g. `ISC_USER`,
+    /// defaulting to `RSFBTRACE_<FLAG>`, e.g. `RSFBTRACE_STORE` for `--store`.
+    fn command_with_env() -> clap::Command {
+        let cmd = Self::command().mut_args(|arg| match arg.get_long() {
+            Some(long) if arg.get_env().is_none() => {
+                let var = format!("RSFBTRACE_{}", long.to_uppercase().replace('-', "_"));
+                arg.env(var).hide_env(true)
+            }
+            _ => arg,
+        });
+
+        // A trace can be of several servers, which other commands don't support.
+        cmd.mut_arg("host", |arg| {
+            arg.action(ArgAction::Append).help(
+                "Optional remote hostname, e.g. dbhost, dbhost/3051, inet://dbhost:3051 or \
+                 xnet://. Given more than once, each server is traced",
+            )
+        })
     }
 
-    fn description(&self) -> &str {
-        "description() is deprecated; use Display"
+    /// Like `parse`, but flags default to their variables as in `command_with_env`, and a
+    /// `--preset` provides the defaults of the flags it sets.
+    fn parse_with_env() -> Result<Self, AppError> {
+        let mut cmd = Self::command_with_env();
+        let early = cmd.clone().ignore_errors(true).get_matches();
+        cmd = with_preset(cmd, &early)?;
+        let matches = cmd.get_matches();
+        Ok(Self::from_matches(&matches).unwrap_or_else(|e| e.exit()))
     }
 
-    fn cause(&self) -> Option<&dyn Error> {
-        self.source()
+    /// The trace flags of the command line, parsed again with the preset as it is now,
+    /// for `--watch-config`. Unlike `parse_with_env`, mistakes are returned.
+    fn reparse_trace() -> Result<Args, AppError> {
+        let mut cmd = Self::command_with_env();
+        let early = cmd.clone().ignore_errors(true).get_matches();
+        cmd = with_preset(cmd, &early)?;
+        let invalid = |e: clap::Error| {
+            let message = e.render().to_string();
+            let first = message.lines().next().unwrap_or_default();
+            AppError::InvalidArgs(first.trim_start_matches("error: ").to_string())
+        };
+        let matches = cmd.try_get_matches().map_err(invalid)?;
+        Self::from_matches(&matches)
+            .map_err(invalid)?
+            .trace
+            .ok_or_else(|| AppError::InvalidArgs("The command line isn't of a trace".into()))
     }
-}
 
-impl std::fmt::Display for AppError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::InvalidOpt(o) => {
-                write!(
-                    f,
-                    "The specified event '{o}' is not a valid for subscription. Valid events are {LEGAL_OPTS:?}."
-                )
-            }
-            Self::Dyn(d) => write!(f, "{d}"),
-            Self::Io(i) => write!(f, "{i}"),
+    fn from_matches(matches: &clap::ArgMatches) -> Result<Self, clap::Error> {
+        let mut cli = Self::from_arg_matches(matches)?;
+        if let Some(trace) = &mut cli.trace {
+            trace.hosts = matches
+                .get_many::<String>("host")
+                .map_or(vec![], |hosts| hosts.cloned().collect());
         }
+        Ok(cli)
     }
 }
 
-#[derive(Parser, Debug)]
-#[command(author, about, long_about = None)]
+/// Replaces a `--database-matcher` naming an alias with the database's path, as the
+/// server matches the path and not the alias it was attached by.
+fn resolve_alias(args: &mut Args) -> Result<(), AppError> {
+    let Some(matcher) = &args.database_matcher else {
+        return Ok(());
+    };
+    let Some(path) = connstr::databases_conf(args.databases_conf.as_deref()) else {
+        return Ok(());
+    };
+    let aliases = connstr::read_aliases(&path)
+        .map_err(|e| AppError::InvalidArgs(format!("Unable to read {}: {e}", path.display())))?;
+    if let Some(database) = connstr::resolve_alias(matcher, &aliases) {
+        args.database_matcher = Some(database.to_string());
+    }
+    Ok(())
+}
+
+/// Makes the `--preset` among `early`, the flags parsed leniently, provide the defaults
+/// of `cmd`'s flags. The preset has to be known before the other flags are parsed.
+fn with_preset(cmd: clap::Command, early: &clap::ArgMatches) -> Result<clap::Command, AppError> {
+    let Some(location) = early.try_get_one::<String>("preset").ok().flatten() else {
+        return Ok(cmd);
+    };
+    let sha256 = early.try_get_one::<String>("preset_sha256").ok().flatten();
+    let conn = tracemgr::Connection::from_arg_matches(early).ok();
+    let monitor_db = early.try_get_one::<String>("monitor_db").ok().flatten();
+    let mut probe = preset::Probe::new(conn, monitor_db.cloned());
+    let options = preset::load(location, sha256.map(String::as_str), &mut probe)?;
+    preset::apply(cmd, options).map_err(|reason| AppError::PresetInvalid(location.clone(), reason))
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Inspect trace sessions running on the server
+    #[command(subcommand)]
+    Session(session::SessionCmd),
+
+    /// Cancel a statement running on the server, e.g. a runaway one seen in a trace
+    Kill(kill::KillArgs),
+
+    /// Render a latency heatmap of the statements recorded in a store
+    Heatmap(heatmap::HeatmapArgs),
+
+    /// Package events recorded in a store into a verifiable incident bundle
+    Export(export::ExportArgs),
+
+    /// Query the events recorded in a store interactively
+    Shell(shell::ShellArgs),
+
+    /// Render an HTML report of the events recorded in a store
+    Report(report::ReportArgs),
+
+    /// Compare the statements recorded in two stores, e.g. before and after a change
+    Diff(diff::DiffArgs),
+
+    /// Re-execute the statements of a capture against a test database
+    Replay(replay::ReplayArgs),
+
+    /// Convert a capture between JSON lines and the binary format
+    Convert(convert::ConvertArgs),
+
+    /// Print a JSON Schema of the events of the JSON output, in the latest format version
+    Schema(jsonschema::SchemaArgs),
+
+    /// Trace a primary and its replica side by side, comparing how long each takes for
+    /// the same statements
+    CompareReplica(replica::CompareReplicaArgs),
+
+    /// Maintain stores kept for a long time
+    #[command(subcommand)]
+    Archive(archive::ArchiveCmd),
+
+    /// Print a config for the server's audit trace, written to log files on the server,
+    /// from the same options as a trace
+    GenAuditConfig(audit::GenAuditArgs),
+
+    /// Check that tracing works from here: fbtracemgr is found, the server answers, the
+    /// credentials are accepted and a trace session starts
+    Selftest(selftest::SelftestArgs),
+
+    /// Print a completion script for a shell
+    Completions(completions::CompletionsArgs),
+
+    /// Print the man page
+    Manpage,
+
+    /// Install a systemd unit or Windows service running the trace continuously
+    InstallService(service::InstallArgs),
+
+    /// Remove a service created by install-service
+    UninstallService(service::UninstallArgs),
+
+    /// Entry point used by the Windows service manager
+    #[cfg(windows)]
+    #[command(hide = true)]
+    RunService {
+        name: String,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        trace_args: Vec<String>,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+// clap leaves the group of a struct containing a flattened struct empty, which would make
+// `Cli::trace` always `None`. `--user` is required for a trace, so its presence is enough.
+#[group(args = ["user"])]
+#[command(group(ArgGroup::new("alert").args(["alert_cmd", "alert_webhook", "outputs", "sample", "rate_limit"]).multiple(true)))]
 struct Args {
-    /// Optional remote hostname
-    #[arg(long, default_value = None)]
-    host: Option<String>,
+    #[command(flatten)]
+    conn: tracemgr::Connection,
+
+    /// Every `--host` given, and those of `--hosts-file`, each traced in its own
+    /// session. The first is `conn.host`.
+    #[arg(skip)]
+    hosts: Vec<String>,
+
+    /// Also trace the servers listed in this file, one host per line
+    #[arg(long, value_name = "PATH")]
+    hosts_file: Option<PathBuf>,
+
+    /// Take defaults for the other options from this TOML file or URL
+    #[arg(long)]
+    preset: Option<String>,
+
+    /// Refuse the preset unless its contents have this SHA-256 checksum
+    #[arg(long, requires = "preset")]
+    preset_sha256: Option<String>,
+
+    /// Restart the trace with the new settings whenever the preset file is changed
+    #[arg(long, requires = "preset")]
+    watch_config: bool,
 
     /// Optional SQL filter
     #[arg(short, long)]
     include_filter: Option<String>,
 
-    /// Firebird username
-    #[arg(short, long)]
-    user: String,
+    /// Only trace attachments of these users
+    #[arg(long, value_delimiter = ',')]
+    filter_user: Vec<String>,
 
-    /// Firebird password
-    #[arg(short, long)]
-    pass: String,
+    /// Only trace attachments using these roles
+    #[arg(long, value_delimiter = ',')]
+    filter_role: Vec<String>,
+
+    /// Only trace attachments from these client processes, by path or file name
+    #[arg(long, value_delimiter = ',')]
+    filter_process: Vec<String>,
+
+    /// Only output statements of these types, e.g. select,update,delete. Events without
+    /// a statement, like commits, are kept
+    #[arg(long, value_enum, value_delimiter = ',')]
+    stmt_types: Vec<filter::StatementType>,
+
+    /// Only output events matching this expression, e.g. 'duration > 500ms && rows == 0'
+    #[arg(long = "where", value_parser = expr::parse)]
+    where_expr: Option<expr::Expr>,
+
+    /// Only output this share of statement events, e.g. 10%. Errors and statements
+    /// slower than --alert-threshold are always kept
+    #[arg(long, value_parser = throttle::parse_share)]
+    sample: Option<f64>,
+
+    /// Output no more statement events than this, e.g. 1000/s. Errors and statements
+    /// slower than --alert-threshold are always kept
+    #[arg(long, value_parser = throttle::parse_rate)]
+    rate_limit: Option<throttle::Rate>,
+
+    /// Trace a busy live server at little cost to it: only errors and statements taking
+    /// 1s or more, without plans or table counters, with statements and parameters cut
+    /// short, and at most 100 statement events per second unless --rate-limit says so
+    #[arg(long, conflicts_with_all = [
+        "events",
+        "print_plan",
+        "print_perf",
+        "log_blr_requests",
+        "log_dyn_requests",
+        "max_sql_limit",
+    ])]
+    production: bool,
+
+    /// Rewrite the literals of statements and the values of parameters before writing
+    /// them, so the trace can be shared without the data in it
+    #[arg(long)]
+    redact: bool,
+
+    /// How --redact rewrites values
+    #[arg(long, value_enum, default_value_t = redact::Style::Mask, requires = "redact")]
+    redact_style: redact::Style,
+
+    /// Keep the values of these columns and context variables, e.g. status,currency
+    #[arg(long, value_delimiter = ',', requires = "redact")]
+    redact_keep: Vec<String>,
 
     #[arg(short, long, default_value_t = 65536)]
     max_sql: usize,
 
-    /// Database matcher [default: all databases]
+    /// Restart the session with a higher --max-sql, up to this, when statements are cut
+    #[arg(long)]
+    max_sql_limit: Option<usize>,
+
+    /// Print the plan of each statement
+    #[arg(long)]
+    print_plan: bool,
+
+    /// Warn when a statement's plan differs from the one in this file, creating it if
+    /// it doesn't exist
+    #[arg(long, requires = "print_plan")]
+    plan_baseline: Option<PathBuf>,
+
+    /// Write the plans seen during the trace to this file, for use with --plan-baseline
+    #[arg(long, requires = "print_plan")]
+    save_plans: Option<PathBuf>,
+
+    /// Print the reads and writes of each table a statement touched
+    #[arg(long)]
+    print_perf: bool,
+
+    /// Suggest indexes for statements scanning tables, when the trace ends
+    #[arg(long, requires = "print_plan")]
+    advise_indexes: bool,
+
+    /// Print these summaries when the trace ends
+    #[arg(long, value_enum, value_delimiter = ',')]
+    report: Vec<EndReport>,
+
+    /// List the open attachments on stderr this often while tracing, e.g. 30s
+    #[arg(long, value_parser = units::parse_duration)]
+    show_attachments: Option<Duration>,
+
+    /// Log BLR requests compiled or executed by the server
+    #[arg(long)]
+    log_blr_requests: bool,
+
+    /// Print the BLR of logged BLR requests
+    #[arg(long)]
+    print_blr: bool,
+
+    /// Log DYN requests executed by the server
+    #[arg(long)]
+    log_dyn_requests: bool,
+
+    /// Print the DYN of logged DYN requests
+    #[arg(long)]
+    print_dyn: bool,
+
+    /// Maximum length of printed BLR, in bytes
+    #[arg(long, default_value_t = 500)]
+    max_blr_length: usize,
+
+    /// Maximum length of printed DYN, in bytes
+    #[arg(long, default_value_t = 500)]
+    max_dyn_length: usize,
+
+    /// Maximum length of each printed statement or procedure parameter
+    #[arg(long, default_value_t = 80)]
+    max_arg_length: usize,
+
+    /// Maximum number of parameters printed per statement or procedure
+    #[arg(long, default_value_t = 30)]
+    max_arg_count: usize,
+
+    /// Add this line to the trace config, for a server parameter rsfbtrace has no option
+    /// for, e.g. 'log_initfini false'. Can be given more than once
+    #[arg(long, value_name = "LINE", value_parser = raw::parse)]
+    raw_option: Vec<String>,
+
+    /// Shorten SQL written to stdout to this many characters, keeping its start and end
+    #[arg(long)]
+    truncate_sql: Option<usize>,
+
+    /// How statements differing only in their literals are grouped
+    #[arg(long, value_enum, default_value_t = fingerprint::Strategy::LiteralStrip)]
+    fingerprint: fingerprint::Strategy,
+
+    /// Database matcher [default: all databases]. An alias from databases.conf traces the
+    /// database it names
     #[arg(short, long, default_value = None)]
     database_matcher: Option<String>,
 
+    /// The databases.conf to read aliases from [default: the local server's, if any]
+    #[arg(long)]
+    databases_conf: Option<PathBuf>,
+
+    /// The events to trace. Picked interactively if omitted
     #[arg(short, long, num_args(1..))]
     events: Vec<String>,
+
+    /// Also write parsed events to a store, e.g. sqlite:trace.db
+    #[arg(long)]
+    store: Option<Store>,
+
+    /// Also send events to syslog or the Windows event log, e.g. syslog:udp://loghost:514
+    #[arg(long = "output", alias = "sink", value_name = "OUTPUT")]
+    outputs: Vec<Output>,
+
+    /// Alert on statements taking at least this long, e.g. 2000ms
+    #[arg(long, value_parser = units::parse_duration, requires = "alert")]
+    alert_threshold: Option<Duration>,
+
+    /// Alert on sweeps running at least this long, e.g. 30m, once while they run and
+    /// again when they end
+    #[arg(long, value_parser = units::parse_duration, requires = "alert")]
+    alert_sweep_duration: Option<Duration>,
+
+    /// Alert on sweeps leaving more than this many transactions between the oldest
+    /// interesting and the oldest snapshot
+    #[arg(long, value_name = "N", requires = "alert")]
+    alert_transaction_gap: Option<i64>,
+
+    /// Shell command run for each alert, receiving the event as JSON on stdin
+    #[arg(long)]
+    alert_cmd: Option<String>,
+
+    /// URL each alert is POSTed to as JSON
+    #[arg(long)]
+    alert_webhook: Option<String>,
+
+    /// Also stream events as JSON lines to the stdin of this shell command, restarting it
+    /// if it exits, e.g. 'python3 enrich.py --env prod'
+    #[arg(long)]
+    pipe_to: Option<String>,
+
+    /// Serve a page showing the trace live, and its events as JSON over a WebSocket at
+    /// /events, on this address, e.g. 127.0.0.1:8080
+    #[arg(long, value_name = "ADDR")]
+    serve: Option<String>,
+
+    /// Only let those giving this token, of at least 16 letters and digits, watch --serve,
+    /// as http://ADDR/?token=TOKEN. Needed to serve on an address other machines can reach
+    #[arg(long, env = "RSFBTRACE_SERVE_TOKEN", hide_env_values = true, requires = "serve", value_parser = sink::serve::parse_token)]
+    serve_token: Option<String>,
+
+    /// Events held in memory while the sinks are busy. Beyond this the oldest are dropped,
+    /// unless --buffer-spill is given
+    #[arg(long, default_value_t = 100_000)]
+    buffer_events: usize,
+
+    /// Write events that don't fit in the buffer to a temporary file in this directory,
+    /// instead of dropping them
+    #[arg(long, value_name = "DIR")]
+    buffer_spill: Option<PathBuf>,
+
+    /// Print how full the buffer is on stderr this often while tracing, e.g. 30s
+    #[arg(long, value_parser = units::parse_duration)]
+    show_buffer: Option<Duration>,
+
+    /// Report throughput, dropped events and buffer usage this often while tracing, e.g.
+    /// 1m: on stderr, or as a HEARTBEAT event with --output-format json
+    #[arg(long, value_parser = units::parse_duration)]
+    heartbeat: Option<Duration>,
+
+    /// Tail this firebird.log or replication.log and interleave its entries into the events
+    #[arg(long)]
+    server_log: Vec<PathBuf>,
+
+    /// Write the trace config to this file and keep it, instead of a temporary file
+    #[arg(long)]
+    keep_config: Option<PathBuf>,
+
+    /// Print the trace config and fbtracemgr command without starting the trace
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Stop the trace after this long, e.g. 10m
+    #[arg(long, value_parser = units::parse_duration)]
+    duration: Option<Duration>,
+
+    /// Warn when nothing was captured this long after the session started. 0 disables it
+    #[arg(long, value_parser = units::parse_duration, default_value = "30s")]
+    warn_empty_after: Duration,
+
+    /// Stop the trace after this many events
+    #[arg(long)]
+    max_events: Option<u64>,
+
+    /// Stop the trace after writing this much to stdout and the store, e.g. 2G
+    #[arg(long, value_parser = units::parse_size)]
+    max_output: Option<u64>,
+
+    /// Stop the trace after the server sent this much trace text, e.g. 500M
+    #[arg(long, value_parser = units::parse_size)]
+    max_capture_cost: Option<u64>,
+
+    /// When attachments or service calls fail often, or the service manager stops
+    /// answering, restart the session reporting only slower statements, or pause it, and
+    /// alert
+    #[arg(long, value_enum)]
+    on_distress: Option<distress::Action>,
+
+    /// Failed attachments and service calls beyond which the server is in distress
+    #[arg(long, value_parser = throttle::parse_rate, default_value = "20/m", requires = "on_distress")]
+    distress_failures: throttle::Rate,
+
+    /// How long --on-distress pause stops the session for
+    #[arg(long, value_parser = units::parse_duration, default_value = "5m", requires = "on_distress")]
+    distress_pause: Duration,
+
+    /// The `time_threshold` of the config, raised by --on-distress.
+    #[arg(skip = TIME_THRESHOLD_MS)]
+    time_threshold: u64,
+
+    /// Emit a TRANSACTION_SUMMARY event with the totals of each transaction when it ends
+    #[arg(long)]
+    transaction_summaries: bool,
+
+    /// Emit a SWEEP_REPORT event with the duration and transaction counters of each sweep
+    /// when it ends
+    #[arg(long)]
+    sweep_reports: bool,
+
+    /// Link transactions in different databases that set this USER_TRANSACTION context
+    /// variable to the same value, and emit a DISTRIBUTED_TRANSACTION event once all ended
+    #[arg(long)]
+    distributed_key: Option<String>,
+
+    /// Emit a LOCK_CONFLICT event with the statements involved in each lock conflict, and
+    /// list the most frequent ones when the trace ends
+    #[arg(long)]
+    lock_conflicts: bool,
+
+    /// Database to query MON$ tables on for extra context, e.g. dbhost:/data/erp.fdb
+    #[arg(long)]
+    monitor_db: Option<String>,
+
+    /// Name the session, e.g. billing-slow, to stop it with `session stop billing-slow`.
+    /// Only one session can run under a name
+    #[arg(long, value_parser = session::parse_name)]
+    name: Option<String>,
+
+    /// Tag the session, e.g. ticket=OPS-123. Tags are part of the session name on the
+    /// server and added to every event
+    #[arg(long = "tag", value_parser = session::parse_tag)]
+    tags: Vec<(String, String)>,
+
+    /// Run the trace even though the policy forbids it, recording this reason, e.g.
+    /// 'OPS-123 outage, approved by J. Doe'
+    #[arg(long, value_name = "REASON")]
+    override_policy: Option<String>,
+
+    /// How events are written to stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Raw)]
+    output_format: OutputFormat,
+
+    /// Structured output format version to emit
+    #[arg(long, default_value_t = format::LATEST, value_parser = clap::value_parser!(u32).range(1..=format::LATEST as i64))]
+    compat: u32,
+
+    /// Write the timestamps of structured output and stores in this zone, as RFC 3339,
+    /// e.g. UTC, Europe/Berlin or +02:00. Raw output keeps the server's
+    #[arg(long, value_parser = timezone::parse_zone)]
+    timezone: Option<timezone::Zone>,
+
+    /// The zone of the server's clock, if it isn't this machine's
+    #[arg(long, value_parser = timezone::parse_zone, default_value = "local", requires = "timezone")]
+    server_timezone: timezone::Zone,
+
+    /// How far the server's clock is ahead of this machine's, e.g. 1500ms or -2s, or auto
+    /// to estimate it from when events arrive
+    #[arg(long, value_parser = timezone::parse_skew, allow_hyphen_values = true, requires = "timezone")]
+    clock_skew: Option<timezone::Skew>,
+}
+
+fn main() -> ExitCode {
+    let result = Cli::parse_with_env().and_then(|cli| match cli.command {
+        Some(Cmd::Session(c)) => session::run(c),
+        Some(Cmd::Kill(a)) => kill::run(&a),
+        Some(Cmd::Heatmap(a)) => heatmap::run(&a),
+        Some(Cmd::Export(a)) => export::run(&a),
+        Some(Cmd::Shell(a)) => shell::run(&a),
+        Some(Cmd::Report(a)) => report::run(&a),
+        Some(Cmd::Diff(a)) => diff::run(&a),
+        Some(Cmd::Replay(a)) => replay::run(&a),
+        Some(Cmd::Convert(a)) => convert::run(&a),
+        Some(Cmd::Schema(a)) => jsonschema::run(&a),
+        Some(Cmd::Archive(c)) => archive::run(&c),
+        Some(Cmd::Selftest(a)) => selftest::run(a),
+        Some(Cmd::Completions(a)) => completions::completions(&a, Cli::command()),
+        Some(Cmd::Manpage) => completions::manpage(Cli::command()),
+        Some(Cmd::CompareReplica(a)) => replica::run(a),
+        Some(Cmd::GenAuditConfig(a)) => gen_audit_config(&a),
+        Some(Cmd::InstallService(a)) => service::install(&a),
+        Some(Cmd::UninstallService(a)) => service::uninstall(&a),
+        #[cfg(windows)]
+        Some(Cmd::RunService { name, trace_args }) => service::run(&name, &trace_args),
+        None => match cli.trace {
+            Some(args) => run_trace(args),
+            None => Ok(()),
+        },
+    });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            e.exit_code()
+        }
+    }
+}
+
+fn run_trace(mut args: Args) -> Result<(), AppError> {
+    prepare(&mut args)?;
+
+    if args.dry_run {
+        print_warnings(&args, None);
+        return dry_run(&args);
+    }
+
+    // Ctrl+C is delivered to fbtracemgr as well, which ends the session and closes its
+    // output; keep running until then so the sinks can be flushed.
+    if let Err(e) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst)) {
+        return Err(AppError::Dyn(Box::new(e)));
+    }
+
+    let mut watcher = match (&args.preset, args.watch_config) {
+        (Some(location), true) => Some(preset::Watcher::new(location)?),
+        _ => None,
+    };
+    let mut outputs = None;
+    let started = Instant::now();
+    let (mut duration, mut max_events) = (args.duration, args.max_events);
+    let mut seen = 0;
+    while let Some(restart) = run_session(&mut args, &mut outputs, watcher.as_mut())? {
+        seen += restart.seen;
+        if INTERRUPTED.load(Ordering::SeqCst) || SHUTDOWN.load(Ordering::SeqCst) {
+            return outputs.map_or(Ok(()), finish_outputs);
+        }
+        if let Some(reloaded) = restart.reload {
+            eprintln!("Restarting the trace with the changed preset");
+            args = *reloaded;
+            (duration, max_events) = (args.duration, args.max_events);
+            if !args.watch_config {
+                watcher = None;
+            }
+        } else if let Some(pause) = restart.pause {
+            if !wait_out(pause, duration.map(|d| started + d)) {
+                return Ok(());
+            }
+            eprintln!("Resuming the trace");
+        } else if restart.max_sql != args.max_sql {
+            eprintln!("Restarting the trace with --max-sql {}", restart.max_sql);
+        } else {
+            eprintln!(
+                "Restarting the trace with a time_threshold of {} ms",
+                restart.time_threshold
+            );
+        }
+        args.max_sql = restart.max_sql;
+        args.time_threshold = restart.time_threshold;
+        args.duration = duration.map(|d| d.saturating_sub(started.elapsed()));
+        args.max_events = max_events.map(|m| m.saturating_sub(seen));
+    }
+    Ok(())
 }
 
-fn main() -> Result<(), AppError> {
-    let args: Args = Args::parse();
+/// Checks the trace options and completes them, e.g. with the events to trace if none
+/// were given, and refuses them if they break the policy.
+fn prepare(args: &mut Args) -> Result<(), AppError> {
+    args.conn.read_pass_file()?;
+    args.hosts = fanout::hosts(&args.hosts, args.hosts_file.as_deref())?;
+    if let Some(first) = args.hosts.first() {
+        args.conn.host = Some(first.clone());
+    }
+    if args.hosts.len() > 1 {
+        if args.on_distress.is_some() {
+            return Err(AppError::InvalidArgs(
+                "--on-distress watches a single server. Trace the hosts separately to use it."
+                    .into(),
+            ));
+        }
+        if args.tags.iter().any(|(k, _)| k == fanout::HOST_TAG) {
+            return Err(AppError::InvalidArgs(format!(
+                "The {} tag is set to the server of each event when tracing several",
+                fanout::HOST_TAG
+            )));
+        }
+    }
+    resolve_alias(args)?;
+
+    if args.production {
+        args.events = PRODUCTION_EVENTS.iter().map(|e| e.to_string()).collect();
+        args.time_threshold = args.time_threshold.max(PRODUCTION_TIME_THRESHOLD_MS);
+        args.max_sql = args.max_sql.min(PRODUCTION_MAX_SQL);
+        args.max_arg_length = args.max_arg_length.min(PRODUCTION_MAX_ARG_LENGTH);
+        args.max_arg_count = args.max_arg_count.min(PRODUCTION_MAX_ARG_COUNT);
+        args.rate_limit.get_or_insert(PRODUCTION_RATE_LIMIT);
+    }
+    if args.events.is_empty() {
+        args.events = picker::pick_events()?;
+    }
     for event in &args.events {
         if !LEGAL_OPTS.contains(&event.as_str()) {
-            let err = AppError::InvalidOpt(event.into());
-            println!("{err}");
-            return Err(err);
+            return Err(AppError::InvalidOpt(event.into()));
+        }
+    }
+
+    if args.transaction_summaries
+        && !(args.events.iter().any(|e| e == OPT_TRANSACTIONS)
+            && args.events.iter().any(|e| e == OPT_STATEMENT_FINISH))
+    {
+        return Err(AppError::InvalidArgs(format!(
+            "--transaction-summaries needs the {OPT_TRANSACTIONS} and {OPT_STATEMENT_FINISH} events"
+        )));
+    }
+
+    if args.distributed_key.is_some()
+        && !(args.events.iter().any(|e| e == OPT_TRANSACTIONS)
+            && args.events.iter().any(|e| e == OPT_CONTEXT))
+    {
+        return Err(AppError::InvalidArgs(format!(
+            "--distributed-key needs the {OPT_TRANSACTIONS} and {OPT_CONTEXT} events"
+        )));
+    }
+
+    if args.advise_indexes && !args.events.iter().any(|e| e == OPT_STATEMENT_FINISH) {
+        return Err(AppError::InvalidArgs(format!(
+            "--advise-indexes needs the {OPT_STATEMENT_FINISH} events"
+        )));
+    }
+
+    if args.report.contains(&EndReport::Tables)
+        && !(args.print_perf && args.events.iter().any(|e| e == OPT_STATEMENT_FINISH))
+    {
+        return Err(AppError::InvalidArgs(format!(
+            "--report tables needs --print-perf and the {OPT_STATEMENT_FINISH} events"
+        )));
+    }
+
+    let sweep_alerts = args.alert_sweep_duration.is_some() || args.alert_transaction_gap.is_some();
+    if (args.sweep_reports || sweep_alerts || args.report.contains(&EndReport::Sweeps))
+        && !args.events.iter().any(|e| e == OPT_SWEEP)
+    {
+        return Err(AppError::InvalidArgs(format!(
+            "--sweep-reports, --report sweeps and the sweep alerts need the {OPT_SWEEP} events"
+        )));
+    }
+
+    if args.lock_conflicts && !args.events.iter().any(|e| e == OPT_ERRORS) {
+        return Err(AppError::InvalidArgs(format!(
+            "--lock-conflicts needs the {OPT_ERRORS} events"
+        )));
+    }
+
+    if args.output_format == OutputFormat::Binary && std::io::stdout().is_terminal() {
+        return Err(AppError::InvalidArgs(
+            "--output-format binary isn't for a terminal; redirect stdout to a file".into(),
+        ));
+    }
+
+    if args.max_sql_limit.is_some_and(|max| max < args.max_sql) {
+        return Err(AppError::InvalidArgs(
+            "--max-sql-limit can't be lower than --max-sql".into(),
+        ));
+    }
+
+    policy::enforce(args, !args.dry_run)
+}
+
+/// The trace options as they are with the preset changed, checked as at the start, for
+/// `--watch-config`.
+fn reload() -> Result<Args, AppError> {
+    let mut args = Cli::reparse_trace()?;
+    if args.events.is_empty() && !args.production {
+        return Err(AppError::InvalidArgs("No events are selected".into()));
+    }
+    prepare(&mut args)?;
+    Ok(args)
+}
+
+/// Why a session was stopped to start another.
+struct Restart {
+    max_sql: usize,
+    time_threshold: u64,
+    /// How long to wait before starting the next session.
+    pause: Option<Duration>,
+    /// Events the stopped session kept, counting towards `--max-events`.
+    seen: u64,
+    /// The options to restart with, when `--watch-config` saw the preset change.
+    reload: Option<Box<Args>>,
+}
+
+/// Waits between sessions, returning false if the trace should end instead, for Ctrl+C,
+/// a shutdown request or reaching `deadline`.
+fn wait_out(pause: Duration, deadline: Option<Instant>) -> bool {
+    let resume = Instant::now() + pause;
+    while Instant::now() < resume {
+        if INTERRUPTED.load(Ordering::SeqCst)
+            || SHUTDOWN.load(Ordering::SeqCst)
+            || deadline.is_some_and(|d| Instant::now() >= d)
+        {
+            return false;
+        }
+        thread::sleep(TICK);
+    }
+    true
+}
+
+/// Runs one trace session, until it ends or has to be restarted with other options. The
+/// outputs are opened unless they're still open from the previous session.
+fn run_session(
+    args: &mut Args,
+    outputs: &mut Option<Outputs>,
+    mut watcher: Option<&mut preset::Watcher>,
+) -> Result<Option<Restart>, AppError> {
+    let config = write_config(args)?;
+    let mut sessions = Sessions::connect(args)?;
+    let databases = args
+        .database_matcher
+        .as_ref()
+        .and_then(|_| sessions.conns().next())
+        .and_then(tracemgr::attached_databases);
+    print_warnings(args, databases.as_deref());
+    if let Some(name) = &args.name {
+        for conn in sessions.conns() {
+            session::check_name_free(conn, name)?;
         }
     }
 
-    if let Err(e) = write_config_file(&args) {
-        return Err(AppError::Io(e));
+    let filter = AttachmentFilter::new(
+        args.filter_user.clone(),
+        args.filter_role.clone(),
+        args.filter_process.clone(),
+    );
+
+    let tags: BTreeMap<String, String> = args.tags.iter().cloned().collect();
+    let mut monitor = args
+        .monitor_db
+        .as_ref()
+        .map(|db| Monitor::new(db.clone(), args.conn.user.clone(), args.conn.pass().into()));
+
+    let open = match outputs {
+        Some(o) => o,
+        None => outputs.insert(open_outputs(
+            args,
+            &config,
+            monitor.as_ref(),
+            &tags,
+            &filter,
+        )?),
+    };
+    let (sinks, echo) = (&mut open.sinks, open.echo);
+
+    let sweep_limits = sweeps::Limits {
+        max_duration: args.alert_sweep_duration,
+        max_gap: args.alert_transaction_gap,
+    };
+    let redactor = args
+        .redact
+        .then(|| Redactor::new(args.redact_style, &args.redact_keep));
+    let redact = |e: &mut Event| {
+        if let Some(r) = &redactor {
+            r.redact(e);
+        }
+    };
+    let clock = args.timezone.clone().map(|zone| {
+        let skew = args
+            .clock_skew
+            .unwrap_or(timezone::Skew::Fixed(Default::default()));
+        Clock::new(args.server_timezone.clone(), zone, skew)
+    });
+    let mut throttle = Throttle::new(thinning(args));
+    let fingerprinter = args.fingerprint.fingerprinter();
+    let mut plans = match &args.plan_baseline {
+        Some(path) => Some(PlanTracker::with_baseline(path).map_err(AppError::InvalidArgs)?),
+        None if args.print_plan => Some(PlanTracker::default()),
+        None => None,
     };
+    let mut correlator = Correlator::new(args.transaction_summaries, args.distributed_key.clone());
+    let mut advisor = args.advise_indexes.then(IndexAdvisor::default);
+    // Sweep alerts are raised on the reports, so they're emitted for those too.
+    let emit_sweeps =
+        args.sweep_reports || sweep_limits.max_duration.is_some() || sweep_limits.max_gap.is_some();
+    let mut sweeps = (emit_sweeps || args.report.contains(&EndReport::Sweeps))
+        .then(|| SweepTracker::new(sweep_limits, emit_sweeps));
+    let mut table_report = args
+        .report
+        .contains(&EndReport::Tables)
+        .then(TableReport::default);
+    let mut attachments = (args.show_attachments.is_some()
+        || args.report.contains(&EndReport::Attachments))
+    .then(AttachmentMap::default);
+    let mut next_listing = args.show_attachments.map(|every| Instant::now() + every);
+    let mut next_stats = args.show_buffer.map(|every| Instant::now() + every);
+    let mut heartbeat = args.heartbeat.map(Heartbeat::new);
+
+    // The trace is read on its own thread so other sources (e.g. the server log) can be
+    // interleaved into the same stream of events.
+    let stop = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = buffer::start(args.buffer_events, args.buffer_spill.as_deref())?;
+    sessions.start(args, config.path(), &tx, echo, &stop)?;
+
+    for path in &args.server_log {
+        serverlog::tail(path.clone(), tx.clone(), stop.clone(), echo == Echo::Lines);
+    }
+    drop(tx);
+
+    let mut watch = args
+        .on_distress
+        .zip(sessions.conns().next())
+        .map(|(_, conn)| Watch::start(conn, args.distress_failures, stop.clone()));
+
+    let deadline = args.duration.map(|d| Instant::now() + d);
+    let mut seen = 0;
+    // Position in the stream, counting snapshots, used to derive event IDs.
+    let mut stamper = Stamper { clock, seq: 0 };
+    // Bytes of trace text read, whether the events were kept or not.
+    let mut received = 0;
+    // Events the server sent, whether kept or not, and when to warn if none were kept.
+    let mut traced = 0;
+    let mut empty_check = None;
+    // Why the trace was stopped, once it has been.
+    let mut ended: Option<String> = None;
+    // Set by the size limits, after which nothing more is written while the session ends.
+    let mut full = false;
+    let mut announced = false;
+    let mut recorded_config = vec![];
+    write_config_file(args, &mut recorded_config)?;
+    let recorded_config = String::from_utf8_lossy(&recorded_config).into_owned();
+    let mut truncations = TruncationWatch::default();
+    // The session to start next, once statements were cut too often or the server is in
+    // distress.
+    let mut restart: Option<Restart> = None;
+
+    loop {
+        if ended.is_none() && watcher.as_mut().is_some_and(|w| w.changed()) {
+            match reload() {
+                Ok(reloaded) => {
+                    let reason = "The preset changed";
+                    eprintln!("{reason}, stopping the trace");
+                    sessions.stop();
+                    ended = Some(reason.into());
+                    restart = Some(Restart {
+                        max_sql: reloaded.max_sql,
+                        time_threshold: reloaded.time_threshold,
+                        pause: None,
+                        seen: 0,
+                        reload: Some(Box::new(reloaded)),
+                    });
+                }
+                Err(e) => eprintln!("Not applying the changed preset: {e}"),
+            }
+        }
+        if !announced && sessions.any_started() {
+            announced = true;
+            empty_check = Some(Instant::now() + args.warn_empty_after)
+                .filter(|_| !args.warn_empty_after.is_zero());
+        }
+        sessions.record(args, &recorded_config);
+
+        if empty_check.is_some_and(|at| Instant::now() >= at) {
+            empty_check = None;
+            if seen == 0 && ended.is_none() {
+                let secs = args.warn_empty_after.as_secs();
+                eprintln!("Warning: {}", validate::empty_capture(args, traced, secs));
+            }
+        }
+
+        if let Some(at) = next_listing.filter(|&at| Instant::now() >= at) {
+            next_listing = args.show_attachments.map(|every| at + every);
+            if let Some(a) = &attachments {
+                let _ = a.write_report(&mut std::io::stderr(), false);
+            }
+        }
+        if let Some(at) = next_stats.filter(|&at| Instant::now() >= at) {
+            next_stats = args.show_buffer.map(|every| at + every);
+            eprintln!("{}", rx.stats());
+        }
+        let unparsed = tracemgr::UNPARSED.load(Ordering::SeqCst);
+        let dropped = throttle.counts.dropped();
+        if let Some(status) = heartbeat
+            .as_mut()
+            .and_then(|h| h.beat(unparsed, dropped, rx.stats()))
+        {
+            if matches!(
+                args.output_format,
+                OutputFormat::Json | OutputFormat::Binary
+            ) {
+                let mut event = status.event(&tags);
+                stamper.stamp(&mut event, Stamp::Local, sessions.first_id());
+                if let Err(e) = write_event(&event, sinks) {
+                    sessions.kill();
+                    return Err(e);
+                }
+            } else {
+                eprintln!("{status}");
+            }
+        }
+
+        let mut event = match rx.recv_timeout(TICK) {
+            Ok(e) => e,
+            Err(RecvTimeoutError::Timeout) => {
+                if ended.is_some() {
+                    continue;
+                }
+                if let Some(reason) = watch.as_ref().and_then(Watch::check) {
+                    let id = sessions.first_id();
+                    let (reason, next) =
+                        relieve(args, &reason, &tags, id, &mut stamper, sinks, echo)?;
+                    sessions.stop();
+                    ended = Some(reason);
+                    restart = Some(next);
+                    continue;
+                }
+                let reason = if sessions.failed() {
+                    // Some servers keep the session open after rejecting part of the
+                    // config, so don't wait for fbtracemgr to give up on its own.
+                    "The server reported an error"
+                } else if SHUTDOWN.load(Ordering::SeqCst) {
+                    "Shutdown requested"
+                } else if deadline.is_some_and(|d| Instant::now() >= d) {
+                    "Duration limit reached"
+                } else {
+                    continue;
+                };
+                eprintln!("{reason}, stopping the trace");
+                sessions.stop();
+                ended = Some(reason.into());
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if full {
+            continue;
+        }
+        event.tags.extend(tags.clone());
+        let session_id = sessions.id(&event);
+        stamper.stamp(&mut event, Stamp::Server, session_id);
+
+        // Followed by a blank line in the trace.
+        received += event.raw.len() as u64 + 2;
+        if !matches!(event.kind, EventKind::ServerLog | EventKind::Replication) {
+            traced += 1;
+        }
+        if let Some(h) = &mut heartbeat {
+            h.observe(&event);
+        }
+        if ended.is_none() && args.max_capture_cost.is_some_and(|m| received >= m) {
+            let reason = format!(
+                "Capture cost limit of {} reached",
+                units::format_size(args.max_capture_cost.unwrap_or_default())
+            );
+            eprintln!("{reason}, stopping the trace");
+            sessions.stop();
+            ended = Some(reason);
+            full = true;
+            continue;
+        }
+
+        if let Some(stmt) = &mut event.statement {
+            stmt.fingerprint = Some(fingerprinter.fingerprint(&stmt.sql));
+        }
+        if let Some(cut) = truncations.observe(&event) {
+            let raised = args
+                .max_sql_limit
+                .and_then(|max| truncation::raised(args.max_sql, max));
+            match raised {
+                Some(max_sql) if ended.is_none() => {
+                    let reason = format!("Statements cut at {} characters", args.max_sql);
+                    eprintln!("{reason}, stopping the trace");
+                    sessions.stop();
+                    ended = Some(reason);
+                    restart = Some(Restart {
+                        max_sql,
+                        time_threshold: args.time_threshold,
+                        pause: None,
+                        seen: 0,
+                        reload: None,
+                    });
+                }
+                Some(_) => {}
+                None => eprintln!(
+                    "Warning: '{}' was cut at {} characters {} times; a higher --max-sql or \
+                     --max-sql-limit would keep it whole",
+                    shell::first_line(&redactor.as_ref().map_or(cut.into(), |r| r.sql(cut))),
+                    args.max_sql,
+                    truncation::CUTS_BEFORE_RAISING
+                ),
+            }
+        }
+        if let Some(reason) = watch
+            .as_mut()
+            .and_then(|w| w.observe(&event).or_else(|| w.check()))
+        {
+            if ended.is_none() {
+                let id = sessions.id(&event);
+                let (reason, next) = relieve(args, &reason, &tags, id, &mut stamper, sinks, echo)?;
+                sessions.stop();
+                ended = Some(reason);
+                restart = Some(next);
+            }
+        }
+        if let Some(warning) = plans.as_mut().and_then(|p| p.check(&event)) {
+            eprintln!("{warning}");
+        }
+
+        // Transactions and attachments are followed through every event, not only the
+        // ones written.
+        if let Some(a) = &mut attachments {
+            a.observe(&event);
+        }
+        let summaries = correlator.observe(&mut event);
+        let sweep = sweeps.as_mut().and_then(|s| s.observe(&event));
+        let conflict = match args.lock_conflicts {
+            true => correlator.lock_conflict(&event),
+            false => None,
+        };
+        let selected = |e: &Event| {
+            filter.matches(e)
+                && filter::statement_type_matches(&args.stmt_types, e)
+                && args.where_expr.as_ref().is_none_or(|w| w.matches(e))
+        };
+
+        let keep = selected(&event) && throttle.keep(&event);
+        let derived: Vec<Event> = conflict
+            .into_iter()
+            .chain(summaries)
+            .chain(sweep)
+            .filter(selected)
+            .collect();
+        if !keep && derived.is_empty() {
+            continue;
+        }
+
+        if keep {
+            if let Some(m) = &mut monitor {
+                m.complete_sql(&mut event);
+                if let Some(stmt) = &mut event.statement {
+                    stmt.fingerprint = Some(fingerprinter.fingerprint(&stmt.sql));
+                }
+            }
+            if let Some(a) = &mut advisor {
+                a.observe(&event);
+            }
+            if let Some(t) = &mut table_report {
+                t.observe(&event);
+            }
+            redact(&mut event);
+            if let Err(e) = write_event(&event, sinks) {
+                sessions.kill();
+                return Err(e);
+            }
+
+            if let Some(mut snapshot) = monitor.as_mut().and_then(|m| m.lock_snapshot(&event)) {
+                redact(&mut snapshot);
+                stamper.stamp(&mut snapshot, Stamp::Derived, sessions.id(&event));
+                if echo == Echo::Lines {
+                    println!("{}", snapshot.raw);
+                }
+                if let Err(e) = write_event(&snapshot, sinks) {
+                    sessions.kill();
+                    return Err(e);
+                }
+            }
+        }
+
+        for mut derived in derived {
+            redact(&mut derived);
+            stamper.stamp(&mut derived, Stamp::Derived, sessions.id(&event));
+            if echo == Echo::Lines {
+                println!("{}", derived.raw);
+            }
+            if let Err(e) = write_event(&derived, sinks) {
+                sessions.kill();
+                return Err(e);
+            }
+        }
+
+        seen += 1;
+        if ended.is_some() {
+            continue;
+        }
+        let reason = if args.max_events.is_some_and(|m| seen >= m) {
+            "Event limit reached".to_string()
+        } else if let Some(max) = args.max_output.filter(|m| output_size(sinks) >= *m) {
+            full = true;
+            format!("Output limit of {} reached", units::format_size(max))
+        } else {
+            continue;
+        };
+        eprintln!("{reason}, stopping the trace");
+        sessions.stop();
+        ended = Some(reason);
+    }
 
-    let _ = match Command::new("fbtracemgr")
-        .args([
-            "-SE",
-            &args
-                .host
-                .as_ref() // required because .map_or takes an owned
-                .map_or("service_mgr".into(), |x| format!("{x}:service_mgr"))
-                .as_str(),
-            "-USER",
-            &args.user,
-            "-PASS",
-            &args.pass,
-            "-START",
-            "-NAME",
-            TRACE_NAME,
-            "-CONFIG",
-            CONFIG_FILE_NAME,
-        ])
-        .spawn()
+    if throttle.counts != Counts::default() {
+        for sink in sinks.iter_mut() {
+            sink.thinned(&throttle.counts).map_err(AppError::Dyn)?;
+        }
+    }
+    // Unless the trace goes on with the changed preset, the outputs are done with.
+    let written = output_size(sinks);
+    let reloading = restart.as_ref().is_some_and(|r| r.reload.is_some())
+        && !INTERRUPTED.load(Ordering::SeqCst)
+        && !SHUTDOWN.load(Ordering::SeqCst);
+    if !reloading {
+        if let Some(o) = outputs.take() {
+            finish_outputs(o)?;
+        }
+    }
+    let _ = correlator.write_conflict_report(&mut std::io::stderr(), redactor.as_ref());
+    if let Some(a) = &advisor {
+        let _ = a.write_report(&mut std::io::stderr());
+    }
+    if let Some(t) = &table_report {
+        let _ = t.write_report(&mut std::io::stderr());
+    }
+    if let Some(s) = &sweeps {
+        let _ = s.write_report(&mut std::io::stderr());
+    }
+    if let Some(a) = attachments
+        .as_ref()
+        .filter(|_| args.report.contains(&EndReport::Attachments))
     {
-        Ok(mut r) => r.wait(),
-        Err(e) => return Err(AppError::Dyn(Box::new(e))),
+        let _ = a.write_report(&mut std::io::stderr(), true);
+    }
+    if let Some(summary) = throttle.summary() {
+        eprintln!("{summary}");
+    }
+    let stats = rx.stats();
+    if stats.spilled > 0 || stats.dropped > 0 {
+        eprintln!("{stats}");
+    }
+    if let Some(reason) = &ended {
+        eprintln!(
+            "Capture ended: {reason}. {seen} events, {} written, {} read from the server",
+            units::format_size(written),
+            units::format_size(received),
+        );
+    }
+
+    let new_baseline = args.plan_baseline.as_ref().filter(|p| !p.exists());
+    if let Some(p) = plans.as_ref() {
+        for path in args.save_plans.iter().chain(new_baseline) {
+            if let Err(e) = p.save(path) {
+                eprintln!("Unable to save the plans to {}: {e}", path.display());
+            }
+        }
+    }
+
+    // A session stopped by us, or fbtracemgr interrupted with Ctrl+C, isn't a failure.
+    for (status, stderr) in sessions.finish()? {
+        if !(status.success() || ended.is_some() || status.code().is_none()) {
+            return Err(tracemgr::trace_failure(&args.conn, &stderr, status));
+        }
+    }
+    Ok(restart.map(|r| Restart { seen, ..r }))
+}
+
+/// Where a trace's events go, and what's echoed to stdout besides. They're kept open
+/// across sessions restarted by `--watch-config`, so a changed preset doesn't start
+/// another capture in the store or another alert pipe.
+struct Outputs {
+    sinks: Vec<Box<dyn Sink>>,
+    echo: Echo,
+}
+
+fn open_outputs(
+    args: &Args,
+    config: &TraceConfig,
+    monitor: Option<&Monitor>,
+    tags: &BTreeMap<String, String>,
+    filter: &AttachmentFilter,
+) -> Result<Outputs, AppError> {
+    // The trace is passed through as it's read unless events are dropped or changed, or
+    // the output is limited, in which case it's written per event after parsing.
+    let mut sinks: Vec<Box<dyn Sink>> = vec![];
+    let echo = match args.output_format {
+        OutputFormat::Raw
+            if args.truncate_sql.is_some()
+                || filter.is_active()
+                || !args.stmt_types.is_empty()
+                || args.where_expr.is_some()
+                || args.sample.is_some()
+                || args.rate_limit.is_some()
+                || args.redact
+                || args.max_output.is_some()
+                || args.watch_config =>
+        {
+            sinks.push(Box::new(sink::stdout::Raw::new(args.truncate_sql, false)));
+            Echo::OutsideEvents
+        }
+        OutputFormat::Raw => Echo::Lines,
+        OutputFormat::Pretty => {
+            sinks.push(Box::new(sink::stdout::Raw::new(args.truncate_sql, true)));
+            Echo::OutsideEvents
+        }
+        OutputFormat::Json | OutputFormat::Binary => Echo::Off,
     };
+    if args.output_format == OutputFormat::Binary {
+        sinks.push(Box::new(sink::stdout::Binary::new(args.truncate_sql)?));
+    }
+    if args.output_format == OutputFormat::Json {
+        sinks.push(Box::new(sink::stdout::JsonLines::new(
+            args.compat,
+            args.truncate_sql,
+        )));
+    }
+    if let Some(store) = &args.store {
+        let capture = Capture {
+            config: std::fs::read_to_string(config.path())?,
+            tags: tags.clone(),
+            server_version: monitor.and_then(|m| match m.server_version() {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    eprintln!("Unable to query the server version: {e}");
+                    None
+                }
+            }),
+            thinning: (args.sample.is_some() || args.rate_limit.is_some()).then(|| thinning(args)),
+        };
+        match store.open(&capture) {
+            Ok(s) => sinks.push(s),
+            Err(e) => return Err(AppError::Dyn(e)),
+        }
+    }
+
+    for output in &args.outputs {
+        match output.open(args.compat, args.alert_threshold) {
+            Ok(s) => sinks.push(s),
+            Err(e) => return Err(AppError::Dyn(e)),
+        }
+    }
+
+    if let Some(command) = &args.pipe_to {
+        match sink::pipe::PipeSink::open(command, args.compat) {
+            Ok(s) => sinks.push(Box::new(s)),
+            Err(e) => return Err(AppError::Dyn(e)),
+        }
+    }
+
+    if let Some(addr) = &args.serve {
+        match sink::serve::ServeSink::open(addr, args.serve_token.as_deref()) {
+            Ok(s) => sinks.push(Box::new(s)),
+            Err(e) => return Err(AppError::Dyn(e)),
+        }
+    }
+
+    let alert_targets: Vec<AlertTarget> = args
+        .alert_cmd
+        .iter()
+        .map(|c| AlertTarget::Command(c.clone()))
+        .chain(
+            args.alert_webhook
+                .iter()
+                .map(|u| AlertTarget::Webhook(u.clone())),
+        )
+        .collect();
+    if !alert_targets.is_empty() {
+        sinks.push(Box::new(Alerter::new(
+            args.alert_threshold,
+            sweeps::Limits {
+                max_duration: args.alert_sweep_duration,
+                max_gap: args.alert_transaction_gap,
+            },
+            args.compat,
+            alert_targets,
+        )));
+    }
+
+    Ok(Outputs { sinks, echo })
+}
+
+/// How `--sample` and `--rate-limit` thin out the statement events.
+fn thinning(args: &Args) -> Thinning {
+    Thinning {
+        sample: args.sample,
+        rate: args.rate_limit,
+        alert_threshold: args.alert_threshold,
+    }
+}
 
+fn finish_outputs(outputs: Outputs) -> Result<(), AppError> {
+    for mut sink in outputs.sinks {
+        sink.finish().map_err(AppError::Dyn)?;
+    }
     Ok(())
 }
 
-fn write_config_file(args: &Args) -> IOResult<()> {
-    let mut f = match OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(CONFIG_FILE_NAME)
-    {
-        Ok(fi) => fi,
-        Err(e) => {
-            println!("{e} at open file");
-            return Err(e);
+/// Reacts to the server being in distress for `reason`: decides how the next session
+/// makes less work for it, and tells the outputs and alerts. Returns why this session is
+/// stopped, and the next one.
+fn relieve(
+    args: &Args,
+    reason: &str,
+    tags: &BTreeMap<String, String>,
+    session_id: i64,
+    stamper: &mut Stamper,
+    sinks: &mut [Box<dyn Sink>],
+    echo: Echo,
+) -> Result<(String, Restart), AppError> {
+    let raised =
+        (args.time_threshold * distress::RAISE_FACTOR).min(distress::MAX_TIME_THRESHOLD_MS);
+    let (relief, next) = match args.on_distress {
+        Some(distress::Action::Raise) if raised > args.time_threshold => (
+            format!("Raising the time_threshold to {raised} ms"),
+            Restart {
+                max_sql: args.max_sql,
+                time_threshold: raised,
+                pause: None,
+                seen: 0,
+                reload: None,
+            },
+        ),
+        _ => (
+            format!("Pausing the trace for {}s", args.distress_pause.as_secs()),
+            Restart {
+                max_sql: args.max_sql,
+                time_threshold: args.time_threshold,
+                pause: Some(args.distress_pause),
+                seen: 0,
+                reload: None,
+            },
+        ),
+    };
+
+    let mut event = distress::event(reason, &relief, tags);
+    stamper.stamp(&mut event, Stamp::Local, session_id);
+    if echo == Echo::Lines {
+        println!("{}", event.raw);
+    }
+    write_event(&event, sinks)?;
+    eprintln!("Server in distress: {reason}. {relief}");
+    Ok((format!("Server in distress: {reason}"), next))
+}
+
+/// Where the time of an event about to be written comes from.
+#[derive(Debug, Clone, Copy)]
+enum Stamp {
+    /// The server's clock, for traced events.
+    Server,
+    /// This machine's clock, for the events rsfbtrace makes itself.
+    Local,
+    /// The event it's derived from, whose timestamp is already converted.
+    Derived,
+}
+
+/// Numbers the events written in a session and puts their timestamps in --timezone.
+struct Stamper {
+    clock: Option<Clock>,
+    seq: u64,
+}
+
+impl Stamper {
+    /// Converts the timestamp of `event`, then gives it its ID. The ID is derived from
+    /// the timestamp, so it's always derived from the one the sinks write.
+    fn stamp(&mut self, event: &mut Event, from: Stamp, session_id: i64) {
+        if let Some(c) = &mut self.clock {
+            match from {
+                Stamp::Server => c.convert(event),
+                Stamp::Local => c.now(event),
+                Stamp::Derived => {}
+            }
+        }
+        event.assign_id(session_id, self.seq);
+        self.seq += 1;
+    }
+}
+
+fn write_event(event: &Event, sinks: &mut [Box<dyn Sink>]) -> Result<(), AppError> {
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.write(event) {
+            return Err(AppError::Dyn(e));
+        }
+    }
+    Ok(())
+}
+
+/// What the sinks have added to stdout and disk so far.
+fn output_size(sinks: &[Box<dyn Sink>]) -> u64 {
+    sinks.iter().map(|s| s.written()).sum()
+}
+
+fn print_warnings(args: &Args, databases: Option<&[String]>) {
+    for warning in validate::warnings(args, databases) {
+        eprintln!("Warning: {warning}");
+    }
+}
+
+/// Prints the trace config and the fbtracemgr command instead of starting the trace.
+fn dry_run(args: &Args) -> Result<(), AppError> {
+    // An explicit --keep-config is still written, so it can be copied to the server.
+    let path = match &args.keep_config {
+        Some(_) => write_config(args)?.path().to_path_buf(),
+        None => PathBuf::from("<temporary file>"),
+    };
+
+    let mut config = vec![];
+    write_config_file(args, &mut config)?;
+
+    println!("# Trace config");
+    println!("{}", String::from_utf8_lossy(&config).trim());
+    println!();
+    println!("# Command");
+    if let Some(ssh) = &args.conn.ssh {
+        println!("# Run through an SSH tunnel to {ssh}");
+    }
+    println!(
+        "{}",
+        tracemgr::display_command(&tracemgr::start_command(&args.conn, args, &path))
+    );
+    Ok(())
+}
+
+/// Prints the audit config for the trace options of `audit`. They're parsed as for a
+/// trace, presets included, but need no connection as the server runs the audit itself.
+fn gen_audit_config(audit: &audit::GenAuditArgs) -> Result<(), AppError> {
+    let argv = std::iter::once("rsfbtrace".to_string()).chain(audit.trace_args.iter().cloned());
+    let cmd = Args::augment_args(clap::Command::new("rsfbtrace"))
+        .mut_arg("user", |_| {
+            Arg::new("user").short('u').long("user").default_value("")
+        })
+        .mut_arg("pass", |_| Arg::new("pass").short('p').long("pass"));
+    let early = cmd
+        .clone()
+        .ignore_errors(true)
+        .get_matches_from(argv.clone());
+    let cmd = with_preset(cmd, &early)?;
+    let mut args = cmd
+        .try_get_matches_from(argv)
+        .and_then(|m| Args::from_arg_matches(&m))
+        .unwrap_or_else(|e| e.exit());
+
+    if args.events.is_empty() {
+        return Err(AppError::InvalidArgs(
+            "The audit needs events, e.g. -- -e connections errors".into(),
+        ));
+    }
+    for event in &args.events {
+        if !LEGAL_OPTS.contains(&event.as_str()) {
+            return Err(AppError::InvalidOpt(event.into()));
+        }
+    }
+
+    resolve_alias(&mut args)?;
+    policy::enforce(&args, true)?;
+    let mut database = vec![];
+    write_config_file(&args, &mut database)?;
+    audit::write(
+        audit,
+        &String::from_utf8_lossy(&database),
+        &mut std::io::stdout(),
+    )?;
+    Ok(())
+}
+
+/// Summaries printed to stderr when the trace ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum EndReport {
+    /// The tables with the most natural reads, from --print-perf
+    Tables,
+    /// Every attachment seen, with its user, role, client and statement counts
+    Attachments,
+    /// Every sweep seen, with its duration and transaction counters
+    Sweeps,
+}
+
+/// The trace config file handed to fbtracemgr.
+enum TraceConfig {
+    /// Removed when dropped.
+    Temp(TempPath),
+    /// Written to `--keep-config` and left in place.
+    Kept(PathBuf),
+}
+
+impl TraceConfig {
+    fn path(&self) -> &Path {
+        match self {
+            Self::Temp(p) => p,
+            Self::Kept(p) => p,
         }
+    }
+}
+
+/// Writes the trace config. It contains the filter patterns, so it is only readable by
+/// the current user.
+fn write_config(args: &Args) -> Result<TraceConfig, AppError> {
+    let config_write = |path: &Path, source| AppError::ConfigWrite {
+        path: path.display().to_string(),
+        source,
     };
 
+    if let Some(path) = &args.keep_config {
+        return match create_private_file(path).and_then(|mut f| write_config_file(args, &mut f)) {
+            Ok(()) => Ok(TraceConfig::Kept(path.clone())),
+            Err(e) => Err(config_write(path, e)),
+        };
+    }
+
+    // tempfile creates the file with 0600 on Unix.
+    let mut f = match tempfile::Builder::new()
+        .prefix("rsfbtrace-")
+        .suffix(".conf")
+        .tempfile()
+    {
+        Ok(f) => f,
+        Err(e) => return Err(config_write(&std::env::temp_dir(), e)),
+    };
+    match write_config_file(args, f.as_file_mut()) {
+        Ok(()) => Ok(TraceConfig::Temp(f.into_temp_path())),
+        Err(e) => Err(config_write(f.path(), e)),
+    }
+}
+
+/// Creates or truncates a file, readable only by the current user on Unix.
+fn create_private_file(path: &Path) -> IOResult<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+fn write_config_file(args: &Args, f: &mut impl Write) -> IOResult<()> {
     macro_rules! e {
         ($event:expr) => {{
             let temp: String = $event.into();
@@ -188,18 +1763,18 @@ fn write_config_file(args: &Args) -> IOResult<()> {
     log_context {}
     log_errors {}
     log_sweep {}
-    print_plan false
-    print_perf false
-    log_blr_requests false
-    print_blr false
-    log_dyn_requests false
-    print_dyn false
-    time_threshold 100
+    print_plan {}
+    print_perf {}
+    log_blr_requests {}
+    print_blr {}
+    log_dyn_requests {}
+    print_dyn {}
+    time_threshold {}
     max_sql_length {}
-    max_blr_length 500
-    max_dyn_length 500
-    max_arg_length 80
-    max_arg_count 30
+    max_blr_length {}
+    max_dyn_length {}
+    max_arg_length {}
+    max_arg_count {}{}
 </database>"#,
             db_pattern,
             if let Some(inc) = &args.include_filter {
@@ -220,7 +1795,22 @@ fn write_config_file(args: &Args) -> IOResult<()> {
             e!(OPT_CONTEXT),
             e!(OPT_ERRORS),
             e!(OPT_SWEEP),
-            &args.max_sql
+            args.print_plan,
+            args.print_perf,
+            args.log_blr_requests,
+            args.print_blr,
+            args.log_dyn_requests,
+            args.print_dyn,
+            args.time_threshold,
+            &args.max_sql,
+            args.max_blr_length,
+            args.max_dyn_length,
+            args.max_arg_length,
+            args.max_arg_count,
+            args.raw_option
+                .iter()
+                .map(|line| format!("\n    {line}"))
+                .collect::<String>(),
         )
         .as_bytes(),
     )?;
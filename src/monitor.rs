@@ -0,0 +1,209 @@
+use crate::event::{Event, EventKind};
+use crate::locate;
+use std::collections::HashMap;
+use std::io::{Error as IOError, Result as IOResult, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const ISQL: &str = "isql";
+
+/// gdscodes reported for lock conflicts, deadlocks and update conflicts.
+const LOCK_CONFLICT_CODES: &[&str] = &["335544345", "335544336", "335544451", "335544878"];
+
+/// Conflicts tend to arrive in bursts; one snapshot per interval is enough to see them.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+const LOCK_SNAPSHOT_SQL: &str = r#"
+SET LIST ON;
+SELECT a.MON$ATTACHMENT_ID, a.MON$USER, a.MON$REMOTE_ADDRESS, a.MON$REMOTE_PROCESS,
+       t.MON$TRANSACTION_ID, t.MON$STATE, t.MON$TIMESTAMP, t.MON$ISOLATION_MODE,
+       t.MON$LOCK_TIMEOUT, t.MON$READ_ONLY
+FROM MON$TRANSACTIONS t
+JOIN MON$ATTACHMENTS a ON a.MON$ATTACHMENT_ID = t.MON$ATTACHMENT_ID
+ORDER BY t.MON$TIMESTAMP;
+SELECT s.MON$ATTACHMENT_ID, s.MON$TRANSACTION_ID, s.MON$STATEMENT_ID, s.MON$STATE,
+       s.MON$TIMESTAMP, s.MON$SQL_TEXT
+FROM MON$STATEMENTS s
+WHERE s.MON$STATE <> 0;
+"#;
+
+/// An `isql` command connected to `database`, reading its script from stdin.
+pub fn isql(database: &str, user: &str, pass: &str) -> Command {
+    let mut cmd = Command::new(locate::tool(ISQL, None));
+    cmd.args(["-q", "-user", user, "-password", pass, database]);
+    cmd
+}
+
+/// A regular connection to a database, used to query the MON$ tables while tracing.
+pub struct Monitor {
+    database: String,
+    user: String,
+    pass: String,
+    last_snapshot: Option<Instant>,
+    /// Full SQL already fetched, by attachment and statement. A prepared statement is
+    /// usually executed many times.
+    full_sql: HashMap<(i64, i64), String>,
+}
+
+impl Monitor {
+    pub fn new(database: String, user: String, pass: String) -> Self {
+        Self {
+            database,
+            user,
+            pass,
+            last_snapshot: None,
+            full_sql: HashMap::new(),
+        }
+    }
+
+    /// Runs a script through isql, returning its output.
+    pub fn query(&self, sql: &str) -> IOResult<String> {
+        let mut child = isql(&self.database, &self.user, &self.pass)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(sql.as_bytes())?;
+        }
+
+        let out = child.wait_with_output()?;
+        if !out.status.success() {
+            return Err(IOError::other(
+                String::from_utf8_lossy(&out.stderr).trim().to_string(),
+            ));
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).into())
+    }
+
+    /// The server's engine version, e.g. `5.0.1`.
+    pub fn server_version(&self) -> IOResult<String> {
+        let output = self.query(
+            "SET LIST ON;\nSELECT RDB$GET_CONTEXT('SYSTEM', 'ENGINE_VERSION') AS ENGINE_VERSION FROM RDB$DATABASE;\n",
+        )?;
+        output
+            .lines()
+            .find_map(|l| l.strip_prefix("ENGINE_VERSION"))
+            .map(|v| v.trim().to_string())
+            .ok_or_else(|| IOError::other("isql didn't return the engine version"))
+    }
+
+    /// The page cache of the database, in pages.
+    pub fn page_buffers(&self) -> IOResult<u64> {
+        let output = self.query("SET LIST ON;\nSELECT MON$PAGE_BUFFERS FROM MON$DATABASE;\n")?;
+        output
+            .lines()
+            .find_map(|l| l.strip_prefix("MON$PAGE_BUFFERS"))
+            .and_then(|v| v.trim().parse().ok())
+            .ok_or_else(|| IOError::other("isql didn't return the page buffers"))
+    }
+
+    /// Replaces the SQL of a statement truncated by the server with its full text from
+    /// MON$STATEMENTS. This only works while the statement is still prepared, so it is
+    /// best effort.
+    pub fn complete_sql(&mut self, event: &mut Event) {
+        let (Some(att), Some(stmt)) = (&event.attachment, &mut event.statement) else {
+            return;
+        };
+        // Attachment IDs are only unique within a database.
+        if !stmt.truncated || !self.database.ends_with(&att.database) {
+            return;
+        }
+
+        let key = (att.id, stmt.id);
+        if !self.full_sql.contains_key(&key) {
+            let sql = format!(
+                "SET LIST ON;\nSET BLOB ALL;\nSELECT MON$SQL_TEXT FROM MON$STATEMENTS WHERE MON$ATTACHMENT_ID = {} AND MON$STATEMENT_ID = {};\n",
+                att.id, stmt.id
+            );
+            let output = match self.query(&sql) {
+                Ok(o) => o,
+                Err(e) => {
+                    eprintln!("Unable to fetch the full SQL of statement {}: {e}", stmt.id);
+                    return;
+                }
+            };
+
+            // In list mode, the blob ID is printed next to the column name and the
+            // text follows on the next lines.
+            let mut lines = output
+                .lines()
+                .skip_while(|l| !l.starts_with("MON$SQL_TEXT"));
+            if lines.next().is_none() {
+                return;
+            }
+            let text = lines.collect::<Vec<_>>().join("\n");
+            self.full_sql.insert(key, text.trim_end().into());
+        }
+
+        let full = &self.full_sql[&key];
+        if full.is_empty() {
+            return;
+        }
+        event.raw = event.raw.replacen(&stmt.sql, full, 1);
+        stmt.sql = full.clone();
+        stmt.truncated = false;
+    }
+
+    /// Captures the transactions and active statements on the server when `event` is a
+    /// lock conflict, returning them as a `LOCK_SNAPSHOT` event.
+    pub fn lock_snapshot(&mut self, event: &Event) -> Option<Event> {
+        if !is_lock_conflict(event) {
+            return None;
+        }
+        if self
+            .last_snapshot
+            .is_some_and(|t| t.elapsed() < SNAPSHOT_INTERVAL)
+        {
+            return None;
+        }
+        self.last_snapshot = Some(Instant::now());
+
+        let output = match self.query(LOCK_SNAPSHOT_SQL) {
+            Ok(o) => o,
+            Err(e) => {
+                eprintln!("Unable to capture lock snapshot: {e}");
+                return None;
+            }
+        };
+
+        let lines: Vec<String> = output
+            .lines()
+            .map(str::trim_end)
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+            .collect();
+
+        Some(Event {
+            id: String::new(),
+            timestamp: event.timestamp.clone(),
+            process: event.process.clone(),
+            kind: EventKind::LockSnapshot,
+            failed: false,
+            location: Some(self.database.clone()),
+            attachment: event.attachment.clone(),
+            transaction: event.transaction.clone(),
+            statement: None,
+            records_fetched: None,
+            perf: None,
+            params: vec![],
+            tables: vec![],
+            tags: event.tags.clone(),
+            replication: None,
+            error_code: None,
+            context: vec![],
+            raw: format!("{} LOCK_SNAPSHOT\n{}", event.timestamp, lines.join("\n")),
+            lines,
+        })
+    }
+}
+
+pub fn is_lock_conflict(event: &Event) -> bool {
+    event.kind == EventKind::Error
+        && event.lines.iter().any(|l| {
+            LOCK_CONFLICT_CODES
+                .iter()
+                .any(|c| l.trim_start().starts_with(c))
+        })
+}
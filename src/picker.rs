@@ -0,0 +1,67 @@
+//! Choosing the events to trace interactively when `--events` is omitted.
+
+use crate::error::AppError;
+use crate::{
+    LEGAL_OPTS, OPT_CONNECTIONS, OPT_CONTEXT, OPT_ERRORS, OPT_PROCEDURE_FINISH,
+    OPT_PROCEDURE_START, OPT_STATEMENT_FINISH, OPT_STATEMENT_FREE, OPT_STATEMENT_PREPARE,
+    OPT_STATEMENT_START, OPT_SWEEP, OPT_TRANSACTIONS, OPT_TRIGGER_FINISH, OPT_TRIGGER_START,
+};
+use dialoguer::theme::ColorfulTheme;
+use dialoguer::MultiSelect;
+use std::io::IsTerminal;
+
+/// Picked unless unchecked, as a useful first look at a server.
+const DEFAULT_EVENTS: &[&str] = &[OPT_CONNECTIONS, OPT_TRANSACTIONS, OPT_STATEMENT_FINISH];
+
+fn description(event: &str) -> &'static str {
+    match event {
+        OPT_CONNECTIONS => "Attachments connecting to and detaching from databases",
+        OPT_TRANSACTIONS => "Transactions starting, committing and rolling back",
+        OPT_STATEMENT_PREPARE => "Statements being prepared",
+        OPT_STATEMENT_FREE => "Statements being freed",
+        OPT_STATEMENT_START => "Statements starting to execute",
+        OPT_STATEMENT_FINISH => "Statements finishing, with their plan and performance",
+        OPT_PROCEDURE_START => "Stored procedures starting",
+        OPT_PROCEDURE_FINISH => "Stored procedures finishing, with their performance",
+        OPT_TRIGGER_START => "Triggers starting",
+        OPT_TRIGGER_FINISH => "Triggers finishing, with their performance",
+        OPT_CONTEXT => "Context variables being set",
+        OPT_ERRORS => "Errors and warnings returned to clients",
+        OPT_SWEEP => "Sweeps of a database",
+        _ => "",
+    }
+}
+
+/// Asks which events to trace. Without a terminal to ask on, e.g. in a service or
+/// script, this fails instead of tracing nothing.
+pub fn pick_events() -> Result<Vec<String>, AppError> {
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        return Err(AppError::InvalidArgs(format!(
+            "No --events were given. Pass the events to trace, e.g. --events {OPT_STATEMENT_FINISH}, from {LEGAL_OPTS:?}."
+        )));
+    }
+
+    let items: Vec<String> = LEGAL_OPTS
+        .iter()
+        .map(|e| format!("{e:<20} {}", description(e)))
+        .collect();
+    let defaults: Vec<bool> = LEGAL_OPTS
+        .iter()
+        .map(|e| DEFAULT_EVENTS.contains(e))
+        .collect();
+
+    let picked = MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Events to trace (space to toggle, enter to start)")
+        .items(&items)
+        .defaults(&defaults)
+        .report(false)
+        .interact_opt()
+        .map_err(|e| AppError::Dyn(Box::new(e)))?;
+
+    match picked {
+        Some(picked) if !picked.is_empty() => {
+            Ok(picked.into_iter().map(|i| LEGAL_OPTS[i].into()).collect())
+        }
+        _ => Err(AppError::InvalidArgs("No events were picked".into())),
+    }
+}
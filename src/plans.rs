@@ -0,0 +1,120 @@
+//! Detecting statements whose plan changes, e.g. after an index was dropped.
+
+use crate::event::Event;
+use similar::TextDiff;
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Remembers the plan of each statement fingerprint and reports when it changes.
+///
+/// Plans are compared with the one seen last during the session and with the baseline,
+/// a JSON object mapping fingerprints to plans, typically saved from an earlier trace.
+#[derive(Debug, Default)]
+pub struct PlanTracker {
+    baseline: HashMap<String, String>,
+    current: BTreeMap<String, String>,
+}
+
+impl PlanTracker {
+    /// Loads the baseline from `path`. A missing file is an empty baseline, so a first
+    /// run can create it.
+    pub fn with_baseline(path: &Path) -> Result<Self, String> {
+        let baseline = match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|e| format!("Unable to read the plan baseline {}: {e}", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                return Err(format!(
+                    "Unable to read the plan baseline {}: {e}",
+                    path.display()
+                ))
+            }
+        };
+        Ok(Self {
+            baseline,
+            ..Default::default()
+        })
+    }
+
+    /// Records the plan of the event's statement, returning a warning with a diff if it
+    /// differs from the statement's previous or baseline plan.
+    pub fn check(&mut self, event: &Event) -> Option<String> {
+        let stmt = event.statement.as_ref()?;
+        let fingerprint = stmt.fingerprint.as_ref()?;
+        let plan = normalize(stmt.plan.as_deref()?);
+
+        let previous = match self.current.get(fingerprint) {
+            Some(p) => Some((p, "earlier in this session")),
+            None => self
+                .baseline
+                .get(fingerprint)
+                .map(|p| (p, "in the baseline")),
+        };
+        let Some((previous, source)) = previous.filter(|(p, _)| **p != plan) else {
+            self.current.insert(fingerprint.clone(), plan);
+            return None;
+        };
+
+        let diff = TextDiff::from_lines(previous.as_str(), plan.as_str())
+            .unified_diff()
+            .header("previous plan", "new plan")
+            .to_string();
+        let warning = format!(
+            "{} The plan of statement {} changed from the one {source}:\n  {}\n{}",
+            event.timestamp,
+            stmt.id,
+            fingerprint,
+            diff.trim_end()
+        );
+        self.current.insert(fingerprint.clone(), plan);
+        Some(warning)
+    }
+
+    /// Writes the plans seen during the session, merged over the baseline, as a new
+    /// baseline.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut plans: BTreeMap<&String, &String> = self.baseline.iter().collect();
+        plans.extend(self.current.iter());
+        let json = serde_json::to_string_pretty(&plans).map_err(std::io::Error::other)?;
+        std::fs::write(path, json + "\n")
+    }
+}
+
+/// Plans are compared line by line, ignoring trailing whitespace and blank lines.
+fn normalize(plan: &str) -> String {
+    plan.lines()
+        .map(str::trim_end)
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.to_string() + "\n")
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Statement;
+
+    fn event(plan: &str) -> Event {
+        let mut event = crate::fixtures::bare("EXECUTE_STATEMENT_FINISH");
+        event.statement = Some(Statement {
+            id: 1,
+            sql: "select * from t where id = 1".into(),
+            plan: Some(plan.into()),
+            truncated: false,
+            fingerprint: Some("select * from t where id = ?".into()),
+        });
+        event
+    }
+
+    #[test]
+    fn warns_when_the_plan_changes() {
+        let mut plans = PlanTracker::default();
+        assert_eq!(plans.check(&event("PLAN (T INDEX (PK_T))")), None);
+        assert_eq!(plans.check(&event("PLAN (T INDEX (PK_T))  ")), None);
+
+        let warning = plans.check(&event("PLAN (T NATURAL)")).expect("a warning");
+        assert!(warning.ends_with("-PLAN (T INDEX (PK_T))\n+PLAN (T NATURAL)"));
+        assert_eq!(plans.check(&event("PLAN (T NATURAL)")), None);
+    }
+}
@@ -0,0 +1,445 @@
+//! A policy administrators deploy to forbid captures known to hurt, e.g. tracing every
+//! statement start on a production server without an --include-filter. Breaking it
+//! takes --override-policy with a reason, which is written to an audit log.
+//!
+//! ```toml
+//! audit-log = "/var/log/rsfbtrace/overrides.log"
+//!
+//! [hosts]
+//! prod = ["db1.example.com", "erp-*"]
+//!
+//! [[rules]]
+//! name = "prod-statement-start"
+//! hosts = ["prod"]
+//! events = ["statement_start"]
+//! require = ["include-filter"]
+//! message = "Statement starts on production need an --include-filter"
+//! ```
+
+use crate::connstr;
+use crate::error::AppError;
+use crate::session;
+use crate::sink;
+use crate::tracemgr::Connection;
+use crate::{Args, LEGAL_OPTS};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+/// Where the policy is deployed. Only administrators should be able to write it.
+#[cfg(not(windows))]
+const DEFAULT_PATH: &str = "/etc/rsfbtrace/policy.toml";
+#[cfg(windows)]
+const DEFAULT_PATH: &str = r"C:\ProgramData\rsfbtrace\policy.toml";
+
+/// The options a rule can require, all of which narrow what the server traces or for
+/// how long. Filters applied by rsfbtrace, e.g. --where and --sample, don't spare the
+/// server the work and can't satisfy a rule.
+const REQUIRABLE: &[&str] = &[
+    "include-filter",
+    "database-matcher",
+    "filter-user",
+    "filter-role",
+    "filter-process",
+    "duration",
+    "max-events",
+    "max-output",
+    "max-capture-cost",
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Policy {
+    /// Where overrides are recorded [default: overrides.log in the state directory].
+    audit_log: Option<PathBuf>,
+    /// Host name patterns by label.
+    #[serde(default)]
+    hosts: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    name: String,
+    /// The labels of the hosts the rule is for [default: every host].
+    #[serde(default)]
+    hosts: Vec<String>,
+    /// The rule is broken by tracing any of these events [default: any event].
+    #[serde(default)]
+    events: Vec<String>,
+    /// ...unless all of these options are given. Without any, the events are forbidden.
+    #[serde(default)]
+    require: Vec<String>,
+    message: Option<String>,
+}
+
+impl Policy {
+    fn parse(contents: &str) -> Result<Self, String> {
+        let policy: Self = toml::from_str(contents).map_err(|e| e.message().to_string())?;
+        for rule in &policy.rules {
+            if let Some(label) = rule.hosts.iter().find(|l| !policy.hosts.contains_key(*l)) {
+                return Err(format!("rule {}: no hosts are labelled {label}", rule.name));
+            }
+            if let Some(event) = rule
+                .events
+                .iter()
+                .find(|e| !LEGAL_OPTS.contains(&e.as_str()))
+            {
+                return Err(format!("rule {}: {event} is not an event", rule.name));
+            }
+            if let Some(option) = rule
+                .require
+                .iter()
+                .find(|o| !REQUIRABLE.contains(&o.as_str()))
+            {
+                return Err(format!(
+                    "rule {}: {option} can't be required. Options that can are {}",
+                    rule.name,
+                    REQUIRABLE.join(", ")
+                ));
+            }
+        }
+        Ok(policy)
+    }
+
+    /// The rules a capture of `events` on `server` breaks, `given` telling which options
+    /// it has.
+    fn broken(
+        &self,
+        server: &Server,
+        events: &[String],
+        given: impl Fn(&str) -> bool,
+    ) -> Vec<&Rule> {
+        let on_host = |rule: &Rule| {
+            rule.hosts.is_empty()
+                || rule.hosts.iter().any(|label| {
+                    self.hosts[label]
+                        .iter()
+                        .any(|pattern| server.matches(pattern))
+                })
+        };
+        self.rules
+            .iter()
+            .filter(|rule| on_host(rule))
+            .filter(|rule| rule.events.is_empty() || rule.events.iter().any(|e| events.contains(e)))
+            .filter(|rule| rule.require.is_empty() || !rule.require.iter().all(|o| given(o)))
+            .collect()
+    }
+}
+
+/// A server as the rules see it: the names it's known by, and its addresses.
+#[derive(Debug, Default)]
+struct Server {
+    names: Vec<String>,
+    addresses: Vec<IpAddr>,
+}
+
+impl Server {
+    /// The server `server`, e.g. `db1`, `inet://10.0.0.5:3051` or `localhost`, which
+    /// is the machine named `local` if it's a loopback address. It's known by what was
+    /// given, its host and, if it resolves, its addresses, so a rule for `db1` also holds
+    /// for db1's address or for `localhost` on db1.
+    fn new(server: &str, local: &str) -> Self {
+        let mut names = vec![normalized(server)];
+        if let Ok((host, _)) = connstr::tcp_address(server) {
+            names.push(normalized(&host));
+        }
+        let addresses = names.last().map_or(vec![], |host| addresses(host));
+        if addresses.iter().any(IpAddr::is_loopback)
+            || names.last().is_some_and(|h| h == "localhost")
+        {
+            names.push(normalized(local));
+        }
+        names.dedup();
+        Self { names, addresses }
+    }
+
+    /// Whether the host `pattern` is this server, by name, or by address for a pattern
+    /// naming one host.
+    fn matches(&self, pattern: &str) -> bool {
+        let pattern = normalized(pattern);
+        self.names.iter().any(|name| wildcard(&pattern, name))
+            || (!pattern.contains('*')
+                && addresses(&pattern)
+                    .iter()
+                    .any(|a| self.addresses.contains(a)))
+    }
+}
+
+/// Host names aren't case-sensitive, and may end in the root's dot.
+fn normalized(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+/// The addresses `host` resolves to, if it does.
+fn addresses(host: &str) -> Vec<IpAddr> {
+    if let Ok(address) = host.trim_matches(['[', ']']).parse() {
+        return vec![address];
+    }
+    (host, 0)
+        .to_socket_addrs()
+        .map(|a| a.map(|a| a.ip()).collect())
+        .unwrap_or_default()
+}
+
+/// Whether `name` matches `pattern`, in which `*` stands for any text. Host names
+/// aren't case-sensitive.
+fn wildcard(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (pattern.to_lowercase(), name.to_lowercase());
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+fn given(args: &Args, option: &str) -> bool {
+    match option {
+        "include-filter" => args.include_filter.is_some(),
+        "database-matcher" => args.database_matcher.is_some(),
+        "filter-user" => !args.filter_user.is_empty(),
+        "filter-role" => !args.filter_role.is_empty(),
+        "filter-process" => !args.filter_process.is_empty(),
+        "duration" => args.duration.is_some(),
+        "max-events" => args.max_events.is_some(),
+        "max-output" => args.max_output.is_some(),
+        "max-capture-cost" => args.max_capture_cost.is_some(),
+        _ => false,
+    }
+}
+
+/// A trace to check against the policy.
+pub struct Trace<'a> {
+    /// Every server it's started on.
+    pub servers: Vec<String>,
+    pub conn: &'a Connection,
+    pub events: &'a [String],
+    /// Whether it has each of the `REQUIRABLE` options.
+    pub given: &'a dyn Fn(&str) -> bool,
+    pub override_policy: Option<&'a str>,
+}
+
+/// Refuses the trace `args` describe if it breaks the policy, unless it's overridden,
+/// which is recorded when `audit` is set.
+pub fn enforce(args: &Args, audit: bool) -> Result<(), AppError> {
+    // Every server of a trace of several has to be allowed.
+    let servers: Vec<String> = match args.hosts.len() {
+        0 | 1 => vec![args.conn.server_name().to_string()],
+        _ => args.hosts.clone(),
+    };
+    check(
+        &Trace {
+            servers,
+            conn: &args.conn,
+            events: &args.events,
+            given: &|o| given(args, o),
+            override_policy: args.override_policy.as_deref(),
+        },
+        audit,
+    )
+}
+
+/// Refuses `trace` if it breaks the policy, unless it's overridden, which is recorded
+/// when `audit` is set. Every command starting a trace session checks it. Without a
+/// policy file, anything goes.
+pub fn check(trace: &Trace, audit: bool) -> Result<(), AppError> {
+    let path = PathBuf::from(DEFAULT_PATH);
+    let invalid = |reason: String| AppError::PolicyInvalid(path.display().to_string(), reason);
+    let policy = match std::fs::read_to_string(&path) {
+        Ok(contents) => Policy::parse(&contents).map_err(invalid)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(invalid(e.to_string())),
+    };
+
+    // Through a tunnel, the loopback address is the machine at its far end.
+    let local = match &trace.conn.ssh {
+        Some(ssh) => ssh.rsplit('@').next().unwrap_or(ssh).to_string(),
+        None => sink::syslog::hostname(),
+    };
+    let mut broken: Vec<&Rule> = vec![];
+    let mut hosts = vec![];
+    for server in &trace.servers {
+        let rules = policy.broken(&Server::new(server, &local), trace.events, trace.given);
+        if !rules.is_empty() {
+            hosts.push(server.as_str());
+        }
+        for rule in rules {
+            if !broken.iter().any(|r| std::ptr::eq(*r, rule)) {
+                broken.push(rule);
+            }
+        }
+    }
+    if broken.is_empty() {
+        return Ok(());
+    }
+
+    let describe = |rule: &Rule| match &rule.message {
+        Some(message) => format!("{}: {message}", rule.name),
+        None if rule.require.is_empty() => format!("{}: forbids these events", rule.name),
+        None => format!("{}: needs {}", rule.name, rule.require.join(", ")),
+    };
+    let Some(reason) = trace.override_policy else {
+        let rules: Vec<String> = broken.iter().map(|r| describe(r)).collect();
+        return Err(AppError::PolicyDenied(format!(
+            "{}. Give --override-policy with a reason to run it anyway; the override is \
+             recorded.",
+            rules.join("; ")
+        )));
+    };
+    for rule in &broken {
+        eprintln!("Overriding the policy rule {}", describe(rule));
+    }
+    if audit {
+        let log = policy
+            .audit_log
+            .clone()
+            .or_else(|| session::state_dir().map(|d| d.join("overrides.log")))
+            .ok_or_else(|| invalid("no audit-log is set, and there's no state directory".into()))?;
+        record(&log, &hosts.join(", "), &broken, reason).map_err(|e| {
+            AppError::PolicyDenied(format!(
+                "The override couldn't be recorded in {}: {e}",
+                log.display()
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+/// Appends the override to the audit log as a line of JSON.
+fn record(log: &Path, server: &str, broken: &[&Rule], reason: &str) -> std::io::Result<()> {
+    if let Some(dir) = log.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let entry = serde_json::json!({
+        "time": chrono::Local::now().to_rfc3339(),
+        "user": std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_default(),
+        "host": server,
+        "rules": broken.iter().map(|r| &r.name).collect::<Vec<_>>(),
+        "reason": reason,
+        "command": command_line(std::env::args()),
+    });
+    let mut file = OpenOptions::new().create(true).append(true).open(log)?;
+    writeln!(file, "{entry}")
+}
+
+/// The command line, without the password.
+fn command_line(args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut masked = vec![];
+    let mut after_pass = false;
+    for arg in args {
+        let arg = match arg.as_str() {
+            _ if after_pass => "***".into(),
+            "-p" | "--pass" => arg,
+            _ if arg.starts_with("--pass=") => "--pass=***".into(),
+            _ if arg.starts_with("-p") && !arg.starts_with("--") => "-p***".into(),
+            _ => arg,
+        };
+        after_pass = !after_pass && (arg == "-p" || arg == "--pass");
+        masked.push(arg);
+    }
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_rules_to_labelled_hosts() {
+        let policy = Policy::parse(
+            r#"
+            [hosts]
+            prod = ["db1", "ERP-*.example.com"]
+
+            [[rules]]
+            name = "prod-statement-start"
+            hosts = ["prod"]
+            events = ["statement_start"]
+            require = ["include-filter"]
+
+            [[rules]]
+            name = "no-triggers"
+            events = ["trigger_start", "trigger_finish"]
+            "#,
+        )
+        .unwrap();
+        let events = vec!["statement_start".to_string(), "errors".to_string()];
+        let broken = |names: &[&str], given: bool| {
+            let server = Server {
+                names: names.iter().map(|n| normalized(n)).collect(),
+                addresses: vec![],
+            };
+            policy
+                .broken(&server, &events, |_| given)
+                .iter()
+                .map(|r| r.name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            broken(&["erp-3.example.com"], false),
+            ["prod-statement-start"]
+        );
+        assert!(broken(&["erp-3.example.com"], true).is_empty());
+        assert!(broken(&["test-db1"], false).is_empty());
+        assert_eq!(broken(&["inet://db1:3051", "db1"], false).len(), 1);
+        assert_eq!(broken(&["DB1."], false).len(), 1);
+
+        // By address, and the loopback address as the machine it is.
+        let local = Server::new("inet://127.0.0.1:3051", "db1");
+        assert_eq!(local.names, ["inet://127.0.0.1:3051", "127.0.0.1", "db1"]);
+        assert!(local.matches("localhost") && local.matches("DB1"));
+        assert!(Server::new("localhost", "db1").matches("127.0.0.1"));
+        assert!(!Server::new("10.0.0.5", "db1").matches("db1"));
+
+        assert!(wildcard("*", ""));
+        assert!(wildcard("a*b*c", "aXbYc"));
+        assert!(!wildcard("a*bc", "abc-bc-x"));
+        assert!(!wildcard("ab*ba", "aba"));
+
+        let command = [
+            "rsfbtrace",
+            "-u",
+            "sysdba",
+            "-p",
+            "secret",
+            "--pass=x",
+            "-px",
+            "-e",
+        ];
+        assert_eq!(
+            command_line(command.into_iter().map(String::from)),
+            [
+                "rsfbtrace",
+                "-u",
+                "sysdba",
+                "-p",
+                "***",
+                "--pass=***",
+                "-p***",
+                "-e"
+            ]
+        );
+
+        let err = Policy::parse("[[rules]]\nname = \"x\"\nhosts = [\"prod\"]\n").unwrap_err();
+        assert_eq!(err, "rule x: no hosts are labelled prod");
+        let err = Policy::parse("[[rules]]\nname = \"x\"\nrequire = [\"where\"]\n").unwrap_err();
+        assert!(err.starts_with("rule x: where can't be required"), "{err}");
+    }
+}
@@ -0,0 +1,404 @@
+//! Capture presets: TOML files of trace options, read from a path or URL so a team can
+//! publish vetted captures, e.g. for hunting deadlocks, for others to run as is.
+//!
+//! A preset's keys are long option names, e.g. `events = ["statement_finish", "errors"]`
+//! or `lock-conflicts = true`. They become the defaults of those options, so anything
+//! given on the command line or in the environment still takes precedence.
+//!
+//! Values can refer to the server the trace is for and to the environment, e.g.
+//! `max-sql = "{{server.page_cache}}"` or `name = "{{env.USER}}-deadlocks"`, so one
+//! preset adapts to wherever it's run. The server is only asked if a value needs it.
+
+use crate::error::AppError;
+use crate::monitor::Monitor;
+use crate::tracemgr::{self, Connection};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use toml::Value;
+
+/// Options a preset can't set: where to connect, and where events and files go, are up
+/// to whoever runs it. A preset from a URL is otherwise free to run commands or send
+/// the events elsewhere.
+const DENIED: &[&str] = &[
+    "host",
+    "user",
+    "pass",
+    "pass-file",
+    "role",
+    "ssh",
+    "monitor-db",
+    "store",
+    "output",
+    "alert-cmd",
+    "alert-webhook",
+    "pipe-to",
+    "serve",
+    "serve-token",
+    "server-log",
+    "keep-config",
+    "plan-baseline",
+    "save-plans",
+    "preset",
+    "preset-sha256",
+];
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often `--watch-config` looks at the preset file.
+const WATCH_EVERY: Duration = Duration::from_secs(1);
+
+/// Presets shipped with rsfbtrace, used by name, e.g. `--preset replication`.
+const BUILT_IN: &[(&str, &str)] = &[(
+    "replication",
+    r#"
+description = """
+Replication problems: the errors, lock conflicts, connections and transactions around them.
+Add --server-log for the replication.log of the primary and replica, and their firebird.log.
+"""
+events = ["connections", "transactions", "errors"]
+lock-conflicts = true
+"#,
+)];
+
+/// The variables a preset's values can use, in `{{...}}`.
+const VARIABLES: &str =
+    "server.version, server.major, server.host, server.page_cache and env.<NAME>";
+
+/// The values of a preset's variables, found out when first used.
+pub struct Probe {
+    /// The connection given on the command line, if it was complete enough to parse.
+    conn: Option<Connection>,
+    monitor_db: Option<String>,
+    values: HashMap<String, String>,
+}
+
+impl Probe {
+    pub fn new(conn: Option<Connection>, monitor_db: Option<String>) -> Self {
+        Self {
+            conn,
+            monitor_db,
+            values: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, name: &str) -> Result<String, String> {
+        if let Some(value) = self.values.get(name) {
+            return Ok(value.clone());
+        }
+        let value = match name.split_once('.') {
+            Some(("env", var)) => std::env::var(var)
+                .map_err(|_| format!("the environment variable {var} isn't set"))?,
+            Some(("server", "host")) => self.conn()?.server_name().to_string(),
+            Some(("server", "version")) => self.version()?,
+            Some(("server", "major")) => {
+                let version = self.get("server.version")?;
+                version.split('.').next().unwrap_or_default().to_string()
+            }
+            Some(("server", "page_cache")) => {
+                let monitor = self.monitor()?;
+                monitor
+                    .page_buffers()
+                    .map_err(|e| format!("unable to query the page cache: {e}"))?
+                    .to_string()
+            }
+            _ => {
+                return Err(format!(
+                    "there's no variable '{name}'. Known are {VARIABLES}"
+                ))
+            }
+        };
+        self.values.insert(name.to_string(), value.clone());
+        Ok(value)
+    }
+
+    fn conn(&mut self) -> Result<&mut Connection, String> {
+        let conn = self
+            .conn
+            .as_mut()
+            .ok_or("the server variables need --user and --pass")?;
+        conn.read_pass_file().map_err(|e| e.to_string())?;
+        Ok(conn)
+    }
+
+    fn monitor(&mut self) -> Result<Monitor, String> {
+        let database = self
+            .monitor_db
+            .clone()
+            .ok_or("server.page_cache needs --monitor-db")?;
+        let conn = self.conn()?;
+        Ok(Monitor::new(
+            database,
+            conn.user.clone(),
+            conn.pass().into(),
+        ))
+    }
+
+    /// The server's version, from `--monitor-db` if given, as that's the engine of the
+    /// database traced, or else from the service manager.
+    fn version(&mut self) -> Result<String, String> {
+        if self.monitor_db.is_some() {
+            return self
+                .monitor()?
+                .server_version()
+                .map_err(|e| format!("unable to query the server version: {e}"));
+        }
+        let conn = self.conn()?;
+        let _tunnel = conn.open_tunnel().map_err(|e| e.to_string())?;
+        tracemgr::server_version(conn).ok_or_else(|| "unable to query the server version".into())
+    }
+}
+
+/// The options of the preset at `location`, a built-in preset's name, a path or an
+/// http(s) URL, as long names and values, with its variables filled in from `probe`. If
+/// `sha256` is given, the preset's contents must have that checksum.
+pub fn load(
+    location: &str,
+    sha256: Option<&str>,
+    probe: &mut Probe,
+) -> Result<Vec<(String, Vec<String>)>, AppError> {
+    let invalid = |reason: String| AppError::PresetInvalid(location.into(), reason);
+
+    let contents = if let Some((_, contents)) = BUILT_IN.iter().find(|(n, _)| *n == location) {
+        contents.to_string()
+    } else if location.starts_with("https://") || location.starts_with("http://") {
+        ureq::get(location)
+            .timeout(FETCH_TIMEOUT)
+            .call()
+            .map_err(|e| invalid(e.to_string()))?
+            .into_string()
+            .map_err(|e| invalid(e.to_string()))?
+    } else {
+        std::fs::read_to_string(location).map_err(|e| invalid(e.to_string()))?
+    };
+
+    if let Some(expected) = sha256 {
+        let actual: String = Sha256::digest(contents.as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(invalid(format!(
+                "its SHA-256 is {actual}, not {expected} as pinned by --preset-sha256"
+            )));
+        }
+    }
+
+    let mut options = parse(&contents).map_err(invalid)?;
+    for (name, values) in &mut options {
+        for value in values {
+            *value = expand(value, |var| probe.get(var))
+                .map_err(|e| invalid(format!("'{name}': {e}")))?;
+        }
+    }
+    Ok(options)
+}
+
+/// Notices a preset file being changed, for `--watch-config`. Built-in presets and
+/// URLs can't be watched.
+pub struct Watcher {
+    path: PathBuf,
+    /// When the file was last modified and its size, as last seen.
+    seen: Option<(SystemTime, u64)>,
+    next_check: Instant,
+}
+
+impl Watcher {
+    pub fn new(location: &str) -> Result<Self, AppError> {
+        let remote = location.starts_with("https://") || location.starts_with("http://");
+        if remote || BUILT_IN.iter().any(|(n, _)| *n == location) {
+            return Err(AppError::InvalidArgs(format!(
+                "--watch-config needs the preset to be a file, which {location} isn't"
+            )));
+        }
+        let path = PathBuf::from(location);
+        Ok(Self {
+            seen: signature(&path),
+            path,
+            next_check: Instant::now() + WATCH_EVERY,
+        })
+    }
+
+    /// Whether the file changed since the last call that said so. It's looked at once
+    /// every `WATCH_EVERY` at most, and not while it's missing, as it briefly is when
+    /// some editors save.
+    pub fn changed(&mut self) -> bool {
+        let now = Instant::now();
+        if now < self.next_check {
+            return false;
+        }
+        self.next_check = now + WATCH_EVERY;
+        match signature(&self.path) {
+            Some(current) if Some(current) != self.seen => {
+                self.seen = Some(current);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn signature(path: &Path) -> Option<(SystemTime, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Replaces the `{{name}}` variables of `value` with what `lookup` says they are.
+fn expand(
+    value: &str,
+    mut lookup: impl FnMut(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| format!("'{value}' has an unclosed {{{{"))?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&lookup(rest[start + 2..start + end].trim())?);
+        rest = &rest[start + end + 2..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Makes the preset's `options` the defaults of `cmd`'s flags.
+pub fn apply(
+    mut cmd: clap::Command,
+    options: Vec<(String, Vec<String>)>,
+) -> Result<clap::Command, String> {
+    for (name, values) in options {
+        let id = cmd
+            .get_arguments()
+            .find(|a| a.get_long() == Some(name.as_str()))
+            .map(|a| a.get_id().clone())
+            .ok_or_else(|| format!("there's no --{name} option"))?;
+        cmd = cmd.mut_arg(id, |a| a.default_values(values));
+    }
+    Ok(cmd)
+}
+
+fn parse(contents: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let table: toml::Table = contents
+        .parse()
+        .map_err(|e: toml::de::Error| e.message().to_string())?;
+
+    let mut options = vec![];
+    for (key, value) in table {
+        // Presets can describe themselves for whoever reads them.
+        if key == "description" {
+            continue;
+        }
+        // Lines for the trace config, e.g. `[raw]` then `log_initfini = false`.
+        if let ("raw", Value::Table(raw)) = (key.as_str(), &value) {
+            let lines = raw
+                .iter()
+                .map(|(name, value)| Ok(format!("{name} {}", scalar(value.clone())?)))
+                .collect::<Result<_, String>>()
+                .map_err(|e| format!("'raw' {e}"))?;
+            options.push(("raw-option".into(), lines));
+            continue;
+        }
+        let name = key.replace('_', "-");
+        if DENIED.contains(&name.as_str()) {
+            return Err(format!("a preset can't set '{key}'"));
+        }
+        let values = match value {
+            Value::Array(items) => items.into_iter().map(scalar).collect(),
+            value => scalar(value).map(|v| vec![v]),
+        }
+        .map_err(|e| format!("'{key}' {e}"))?;
+        options.push((name, values));
+    }
+    Ok(options)
+}
+
+fn scalar(value: Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Integer(n) => Ok(n.to_string()),
+        Value::Float(n) => Ok(n.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        other => Err(format!(
+            "must be a string, number or boolean, not {}",
+            other.type_str()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_options_and_refuses_where_events_go() {
+        let options = parse(
+            r#"
+            description = "Deadlocks and the statements involved"
+            events = ["transactions", "statement_finish", "errors"]
+            lock_conflicts = true
+            max-arg-count = 50
+
+            [raw]
+            log_initfini = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            options,
+            [
+                (
+                    "events".into(),
+                    vec![
+                        "transactions".into(),
+                        "statement_finish".into(),
+                        "errors".into()
+                    ]
+                ),
+                ("lock-conflicts".into(), vec!["true".into()]),
+                ("max-arg-count".into(), vec!["50".into()]),
+                ("raw-option".into(), vec!["log_initfini false".into()]),
+            ]
+        );
+
+        for (name, contents) in BUILT_IN {
+            assert!(parse(contents).is_ok(), "{name}");
+        }
+
+        let err = parse("alert-webhook = 'https://example.com/collect'").unwrap_err();
+        assert!(err.contains("can't set 'alert-webhook'"), "{err}");
+
+        let lookup = |name: &str| match name {
+            "server.major" => Ok("5".to_string()),
+            _ => Err(format!("there's no variable '{name}'")),
+        };
+        assert_eq!(
+            expand("fb{{ server.major }}-{{server.major}}", lookup).as_deref(),
+            Ok("fb5-5")
+        );
+        assert!(expand("{{server.minor}}", lookup).is_err());
+        assert!(expand("{{server.major", lookup).is_err());
+    }
+
+    #[test]
+    fn notices_the_file_changing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preset.toml");
+        std::fs::write(&path, "events = [\"errors\"]\n").unwrap();
+        let location = path.to_str().unwrap();
+
+        let mut watcher = Watcher::new(location).unwrap();
+        watcher.next_check = Instant::now();
+        assert!(!watcher.changed());
+
+        std::fs::write(&path, "events = [\"errors\", \"transactions\"]\n").unwrap();
+        assert!(!watcher.changed(), "looked at again too soon");
+        watcher.next_check = Instant::now();
+        assert!(watcher.changed());
+        watcher.next_check = Instant::now();
+        assert!(!watcher.changed());
+
+        assert!(Watcher::new("https://example.com/preset.toml").is_err());
+    }
+}
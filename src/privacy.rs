@@ -0,0 +1,126 @@
+//! Aggregate statistics released with differential privacy, for sharing a workload
+//! without the events it's made of.
+//!
+//! Every number released has Laplace noise scaled to how much a single event could change
+//! it, and groups of statements are only released when their noisy count reaches a
+//! minimum, so whether any one event was recorded can't be told from the result. The
+//! privacy budget `epsilon` is split evenly between the event kind counts, the statement
+//! counts and the statement durations. This protects events, not users: a user running
+//! the same statement many times contributes as many events.
+
+use rand::Rng;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// How much privacy the released numbers cost, and what's suppressed.
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    pub epsilon: f64,
+    /// Statements seen fewer times than this, after noise, aren't released.
+    pub min_count: u64,
+    /// Durations are capped at this, which bounds what one event adds to a total.
+    pub max_duration_ms: i64,
+}
+
+/// Exact totals, before noise.
+#[derive(Debug, Default)]
+pub struct Aggregates {
+    kinds: BTreeMap<String, u64>,
+    /// Executions and total duration by fingerprint.
+    statements: BTreeMap<String, (u64, i64)>,
+}
+
+impl Aggregates {
+    pub fn add_event(&mut self, kind: &str) {
+        *self.kinds.entry(kind.into()).or_default() += 1;
+    }
+
+    pub fn add_statement(&mut self, fingerprint: &str, duration_ms: i64, budget: &Budget) {
+        let (count, duration) = self.statements.entry(fingerprint.into()).or_default();
+        *count += 1;
+        *duration += duration_ms.clamp(0, budget.max_duration_ms);
+    }
+
+    /// The noisy aggregates, with statements below the minimum count left out.
+    pub fn release(&self, budget: &Budget, rng: &mut impl Rng) -> Value {
+        let epsilon = budget.epsilon / 3.0;
+        let noisy = |value: f64, sensitivity: f64, rng: &mut _| {
+            (value + laplace(sensitivity / epsilon, rng))
+                .round()
+                .max(0.0) as u64
+        };
+
+        let kinds: Vec<Value> = self
+            .kinds
+            .iter()
+            .map(|(kind, count)| json!({ "kind": kind, "count": noisy(*count as f64, 1.0, rng) }))
+            .collect();
+
+        let mut statements = vec![];
+        let mut suppressed = 0;
+        for (fingerprint, (count, duration)) in &self.statements {
+            let executions = noisy(*count as f64, 1.0, rng);
+            let duration = noisy(*duration as f64, budget.max_duration_ms as f64, rng);
+            if executions < budget.min_count {
+                suppressed += 1;
+                continue;
+            }
+            statements.push(json!({
+                "fingerprint": fingerprint,
+                "executions": executions,
+                "total_duration_ms": duration,
+                "mean_duration_ms": duration / executions.max(1),
+            }));
+        }
+
+        json!({
+            "event_kinds": kinds,
+            "statements": statements,
+            "suppressed_statements": suppressed,
+        })
+    }
+}
+
+/// A sample of the Laplace distribution centered on 0 with the given scale.
+fn laplace(scale: f64, rng: &mut impl Rng) -> f64 {
+    let u: f64 = rng.gen_range(-0.5..0.5);
+    -scale * u.signum() * (1.0 - 2.0 * u.abs()).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn suppresses_rare_statements_and_keeps_common_ones_close() {
+        let budget = Budget {
+            epsilon: 3.0,
+            min_count: 10,
+            max_duration_ms: 1000,
+        };
+        let mut aggregates = Aggregates::default();
+        for _ in 0..1000 {
+            aggregates.add_event("EXECUTE_STATEMENT_FINISH");
+            aggregates.add_statement("select * from orders where id = ?", 20, &budget);
+        }
+        aggregates.add_event("EXECUTE_STATEMENT_FINISH");
+        aggregates.add_statement(
+            "select salary from staff where name = 'ana'",
+            60000,
+            &budget,
+        );
+
+        let released = aggregates.release(&budget, &mut StdRng::seed_from_u64(7));
+        let statements = released["statements"].as_array().unwrap();
+        assert_eq!(statements.len(), 1);
+        assert_eq!(released["suppressed_statements"], 1);
+
+        let common = &statements[0];
+        assert_eq!(common["fingerprint"], "select * from orders where id = ?");
+        let executions = common["executions"].as_u64().unwrap();
+        assert!((990..=1010).contains(&executions), "{executions}");
+        assert!(!released.to_string().contains("salary"));
+    }
+}
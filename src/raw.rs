@@ -0,0 +1,99 @@
+//! Lines added to the `<database>` section of the trace config as they're given, for
+//! server parameters rsfbtrace has no option for yet, e.g. `log_initfini false`.
+
+/// The parameters rsfbtrace writes itself, with the option that sets them. Setting one
+/// twice would leave it to the server which wins, and could undo a rule of the policy.
+const GENERATED: &[(&str, &str)] = &[
+    ("enabled", "the trace itself"),
+    ("include_filter", "--include-filter"),
+    ("log_connections", "-e connections"),
+    ("log_transactions", "-e transactions"),
+    ("log_statement_prepare", "-e statement_prepare"),
+    ("log_statement_free", "-e statement_free"),
+    ("log_statement_start", "-e statement_start"),
+    ("log_statement_finish", "-e statement_finish"),
+    ("log_procedure_start", "-e procedure_start"),
+    ("log_procedure_finish", "-e procedure_finish"),
+    ("log_trigger_start", "-e trigger_start"),
+    ("log_trigger_finish", "-e trigger_finish"),
+    ("log_context", "-e context"),
+    ("log_errors", "-e errors"),
+    ("log_sweep", "-e sweep"),
+    ("print_plan", "--print-plan"),
+    ("print_perf", "--print-perf"),
+    ("log_blr_requests", "--log-blr-requests"),
+    ("print_blr", "--print-blr"),
+    ("log_dyn_requests", "--log-dyn-requests"),
+    ("print_dyn", "--print-dyn"),
+    ("time_threshold", "--time-threshold"),
+    ("max_sql_length", "--max-sql"),
+    ("max_blr_length", "--max-blr-length"),
+    ("max_dyn_length", "--max-dyn-length"),
+    ("max_arg_length", "--max-arg-length"),
+    ("max_arg_count", "--max-arg-count"),
+    ("log_filename", "gen-audit-config --log-file"),
+    ("max_log_size", "gen-audit-config --max-log-size"),
+];
+
+/// Parses a `--raw-option`, `name value` or `name = value`, into the line written.
+pub fn parse(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.contains(['\n', '\r']) {
+        return Err("must be a single line".into());
+    }
+    let end = s
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or(s.len());
+    let (name, value) = s.split_at(end);
+    let value = value.trim_start();
+    let value = value.strip_prefix('=').unwrap_or(value).trim_start();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!(
+            "'{s}' isn't a parameter and its value, e.g. 'log_initfini false'"
+        ));
+    }
+    if value.is_empty() {
+        return Err(format!("'{name}' has no value"));
+    }
+    if let Some((_, option)) = GENERATED.iter().find(|(p, _)| p.eq_ignore_ascii_case(name)) {
+        return Err(format!("'{name}' is set by {option}"));
+    }
+    Ok(format!("{name} {value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cli;
+    use clap::Parser;
+
+    #[test]
+    fn passes_lines_through_but_not_the_parameters_rsfbtrace_sets() {
+        assert_eq!(parse("log_initfini false").unwrap(), "log_initfini false");
+        assert_eq!(
+            parse(" exclude_filter = \"%RDB$%\" ").unwrap(),
+            "exclude_filter \"%RDB$%\""
+        );
+        assert_eq!(
+            parse("log_errors false").unwrap_err(),
+            "'log_errors' is set by -e errors"
+        );
+        assert!(parse("log_initfini").is_err());
+        assert!(parse("log_initfini true\n</database>").is_err());
+        assert!(parse("<database> x").is_err());
+
+        // Every parameter of the generated config is known to be generated.
+        let argv = ["rsfbtrace", "-u", "SYSDBA", "-p", "x", "-e", "errors"];
+        let mut args = Cli::try_parse_from(argv).unwrap().trace.unwrap();
+        args.include_filter = Some("%ORDERS%".into());
+        let mut config = vec![];
+        crate::write_config_file(&args, &mut config).unwrap();
+        for line in String::from_utf8(config).unwrap().lines() {
+            let name = line.split_whitespace().next().unwrap_or("<");
+            assert!(
+                name.starts_with('<') || GENERATED.iter().any(|(p, _)| *p == name),
+                "{line}"
+            );
+        }
+    }
+}
@@ -0,0 +1,471 @@
+//! Rewriting the literals of captured SQL and the values of parameters before they are
+//! written, so a production trace can be shared without the customer data in it.
+//!
+//! Literals are recognized with the fingerprint tokenizer and replaced in place, leaving
+//! the rest of the statement as the server reported it. A literal compared with, or
+//! inserted into, one of the `--redact-keep` columns is left alone, as are comments.
+
+use crate::event::{Event, EventKind, Param, ParamValue};
+use crate::fingerprint::{self, Token};
+use crate::gdscode;
+use crate::parser;
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+
+/// Words that can stand between a column and the literal it's compared with, e.g. in
+/// `status not in ('a', 'b')` or `total between 1 and 5`.
+const COMPARISON_WORDS: &[&str] = &[
+    "and",
+    "between",
+    "containing",
+    "distinct",
+    "escape",
+    "from",
+    "in",
+    "is",
+    "like",
+    "not",
+    "similar",
+    "starting",
+    "to",
+    "with",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Style {
+    /// Replace strings with '***' and numbers with 0
+    Mask,
+    /// Replace the characters of strings with x and the digits of numbers with 9,
+    /// keeping their lengths
+    KeepLength,
+    /// Replace values with a short hash of them, so equal values can still be matched up
+    Hash,
+}
+
+pub struct Redactor {
+    style: Style,
+    /// Columns and context variables whose values are kept, lower-cased.
+    keep: Vec<String>,
+}
+
+impl Redactor {
+    pub fn new(style: Style, keep: &[String]) -> Self {
+        Self {
+            style,
+            keep: keep.iter().map(|c| c.to_lowercase()).collect(),
+        }
+    }
+
+    /// Redacts the SQL, parameters and values in the body of `event`, its other lines
+    /// and its raw text.
+    pub fn redact(&self, event: &mut Event) {
+        // The raw text is kept as the server sent it, so each change is made to it too.
+        let mut edits: Vec<(String, String)> = vec![];
+
+        if let Some(stmt) = &mut event.statement {
+            let sql = self.sql(&stmt.sql);
+            edits.push((std::mem::replace(&mut stmt.sql, sql.clone()), sql));
+        }
+        for param in &mut event.params {
+            let line = self.param(param);
+            edits.push((std::mem::replace(&mut param.line, line.clone()), line));
+        }
+        for var in &mut event.context {
+            if let Some(value) = &mut var.value {
+                if !self.keep.contains(&var.name.to_lowercase()) {
+                    *value = self.text(value);
+                }
+            }
+        }
+
+        if let Some(replication) = &mut event.replication {
+            replication.message = self.line(&replication.message);
+        }
+        // The value of the context variable linking its transactions, which it's kept
+        // under as its location, e.g. `commit of 2 transaction(s) with ORDER_ID = 4711`.
+        if let (EventKind::DistributedTransaction, Some(value), Some(first)) =
+            (&event.kind, &mut event.location, event.lines.first_mut())
+        {
+            let key = first
+                .strip_suffix(value.as_str())
+                .and_then(|l| l.strip_suffix(" = "))
+                .and_then(|l| l.rsplit(' ').next());
+            if key.is_some_and(|k| !self.keep.contains(&k.to_lowercase())) {
+                let redacted = self.text(value);
+                let line = format!("{}{redacted}", &first[..first.len() - value.len()]);
+                edits.push((std::mem::replace(first, line.clone()), line));
+                *value = redacted;
+            }
+        }
+
+        let mut in_sql_text = false;
+        let mut in_table = false;
+        for line in &mut event.lines {
+            in_table = in_table || parser::is_table_header(line);
+            let redacted = match event.kind {
+                // The counters of a statement's tables.
+                _ if in_table => None,
+                EventKind::LockConflict => line
+                    .split_once("statement: ")
+                    .map(|(label, sql)| format!("{label}statement: {}", self.sql(sql))),
+                // isql lists the text of a statement on the lines after its column name.
+                EventKind::LockSnapshot => {
+                    if line.starts_with("MON$") {
+                        in_sql_text = line.starts_with("MON$SQL_TEXT");
+                        None
+                    } else {
+                        in_sql_text.then(|| self.sql(line))
+                    }
+                }
+                EventKind::SetContext => self.context(line),
+                // rsfbtrace's own summaries, of counters, and the linked transactions,
+                // whose value was redacted above.
+                EventKind::TransactionSummary
+                | EventKind::ServerDistress
+                | EventKind::SweepReport
+                | EventKind::Heartbeat
+                | EventKind::DistributedTransaction => None,
+                _ => Some(self.line(line)),
+            };
+            if let Some(redacted) = redacted {
+                edits.push((std::mem::replace(line, redacted.clone()), redacted));
+            }
+        }
+
+        for (old, new) in edits {
+            if old != new {
+                event.raw = event.raw.replace(&old, &new);
+            }
+        }
+    }
+
+    /// `sql` with its literals redacted.
+    pub fn sql(&self, sql: &str) -> String {
+        let spans = fingerprint::spans(sql);
+        let columns = self.columns(&spans);
+
+        let mut out = String::with_capacity(sql.len());
+        let mut copied = 0;
+        for ((range, token), column) in spans.iter().zip(columns) {
+            if !matches!(token, Token::Str(_) | Token::Number(_)) || self.kept(column) {
+                continue;
+            }
+            out.push_str(&sql[copied..range.start]);
+            out.push_str(&self.literal(token));
+            copied = range.end;
+        }
+        out.push_str(&sql[copied..]);
+        out
+    }
+
+    fn kept(&self, column: Option<String>) -> bool {
+        column.is_some_and(|c| self.keep.contains(&c))
+    }
+
+    fn literal(&self, token: &Token) -> String {
+        match token {
+            Token::Str(s) => {
+                let value = s[1..s.len().saturating_sub(1).max(1)].replace("''", "'");
+                format!("'{}'", self.text(&value).replace('\'', "''"))
+            }
+            Token::Number(n) => self.number(n),
+            _ => unreachable!("only literals are redacted"),
+        }
+    }
+
+    fn text(&self, value: &str) -> String {
+        match self.style {
+            Style::Mask => "***".into(),
+            Style::KeepLength => "x".repeat(value.chars().count()),
+            Style::Hash => format!("h:{}", &hash(value)[..8]),
+        }
+    }
+
+    fn number(&self, value: &str) -> String {
+        match self.style {
+            Style::Mask => "0".into(),
+            Style::KeepLength => value
+                .chars()
+                .map(|c| match c {
+                    '.' | '+' | '-' | 'e' | 'E' | 'x' | 'X' => c,
+                    _ => '9',
+                })
+                .collect(),
+            // A number, so the statement stays valid.
+            Style::Hash => u32::from_str_radix(&hash(value)[..8], 16)
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+
+    /// The line of a parameter with its value redacted, which also redacts `param`.
+    fn param(&self, param: &mut Param) -> String {
+        let (value, text) = match &param.value {
+            ParamValue::Null | ParamValue::Bool(_) => return param.line.clone(),
+            ParamValue::Int(n) => {
+                let redacted = self.number(&n.to_string());
+                match redacted.parse() {
+                    Ok(n) => (ParamValue::Int(n), redacted),
+                    Err(_) => (ParamValue::Number(redacted.clone()), redacted),
+                }
+            }
+            ParamValue::Number(n) => {
+                let redacted = self.number(n);
+                (ParamValue::Number(redacted.clone()), redacted)
+            }
+            ParamValue::Text(t) => {
+                let redacted = self.text(t);
+                (ParamValue::Text(redacted.clone()), redacted)
+            }
+        };
+        param.value = value;
+        match param.line.split_once(", \"") {
+            Some((decl, _)) => format!("{decl}, \"{text}\""),
+            None => param.line.clone(),
+        }
+    }
+
+    /// A `[USER_SESSION] NAME = "value"` line with the value redacted, unless the
+    /// variable is kept.
+    fn context(&self, line: &str) -> Option<String> {
+        let (var, value) = line.split_once(" = ")?;
+        let value = value.strip_prefix('"')?.strip_suffix('"')?;
+        let name = var.rsplit(' ').next().unwrap_or(var).to_lowercase();
+        (!self.keep.contains(&name)).then(|| format!("{var} = \"{}\"", self.text(value)))
+    }
+
+    /// Any other line the server printed, e.g. of a status vector, a log entry or a
+    /// procedure's text, with whatever looks like a literal redacted. Status codes are
+    /// kept.
+    fn line(&self, line: &str) -> String {
+        if let Some(redacted) = self.status(line) {
+            return redacted;
+        }
+        match line.split_once(" : ") {
+            Some((code, message)) if gdscode::status_code(line).is_some() => {
+                format!("{code} : {}", self.sql(message))
+            }
+            _ => self.sql(line),
+        }
+    }
+
+    /// A line of an error's status vector with the values quoted in it redacted.
+    fn status(&self, line: &str) -> Option<String> {
+        if let Some((before, key)) = line.split_once("Problematic key value is ") {
+            return Some(format!(
+                "{before}Problematic key value is {}",
+                self.sql(key)
+            ));
+        }
+        let (before, rest) = line.split_once("from string \"")?;
+        let (value, after) = rest.rsplit_once('"')?;
+        Some(format!(
+            "{before}from string \"{}\"{after}",
+            self.text(value)
+        ))
+    }
+
+    /// The column each token's value is compared with or inserted into, if it's one of
+    /// the kept columns.
+    fn columns(&self, spans: &[(std::ops::Range<usize>, Token)]) -> Vec<Option<String>> {
+        let mut columns = vec![None; spans.len()];
+        if self.keep.is_empty() {
+            return columns;
+        }
+        let code: Vec<(usize, &Token)> = spans
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, t))| !matches!(t, Token::Comment(_)))
+            .map(|(i, (_, t))| (i, t))
+            .collect();
+
+        for (at, &(i, token)) in code.iter().enumerate() {
+            if matches!(token, Token::Str(_) | Token::Number(_)) {
+                columns[i] = compared_column(&code[..at]);
+            }
+        }
+
+        // `insert into t (a, b) values ('x', 1)` pairs the values with the columns.
+        let is_word = |t: &Token, w: &str| matches!(t, Token::Word(x) if x.eq_ignore_ascii_case(w));
+        for at in 0..code.len() {
+            if !(is_word(code[at].1, "values")
+                && code.get(at + 1).is_some_and(|t| t.1.is_punct("(")))
+            {
+                continue;
+            }
+            let names = column_list(&code[..at]);
+            let mut position = 0;
+            let mut depth = 0;
+            for &(i, token) in &code[at + 1..] {
+                match token {
+                    t if t.is_punct("(") => depth += 1,
+                    t if t.is_punct(")") => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    t if t.is_punct(",") && depth == 1 => position += 1,
+                    Token::Str(_) | Token::Number(_) => {
+                        columns[i] = names.get(position).cloned().flatten();
+                    }
+                    _ => {}
+                }
+            }
+        }
+        columns
+    }
+}
+
+/// The column a literal following `before` is compared with, e.g. `status` for
+/// `where o.status =`.
+fn compared_column(before: &[(usize, &Token)]) -> Option<String> {
+    for &(_, token) in before.iter().rev() {
+        match token {
+            Token::Str(_) | Token::Number(_) | Token::Placeholder => continue,
+            Token::Punct(p)
+                if matches!(
+                    p.as_str(),
+                    "," | "(" | "=" | "<>" | "!=" | "^=" | "~=" | "<" | ">" | "<=" | ">="
+                ) =>
+            {
+                continue
+            }
+            Token::Word(w) if COMPARISON_WORDS.contains(&w.to_lowercase().as_str()) => continue,
+            Token::Word(w) => return Some(w.to_lowercase()),
+            Token::Quoted(q) => return Some(q.trim_matches('"').to_lowercase()),
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// The columns listed in the parentheses `before` ends with, e.g. `(a, "B")`, in order.
+fn column_list(before: &[(usize, &Token)]) -> Vec<Option<String>> {
+    if !before.last().is_some_and(|(_, t)| t.is_punct(")")) {
+        return vec![];
+    }
+    let Some(open) = before.iter().rposition(|(_, t)| t.is_punct("(")) else {
+        return vec![];
+    };
+    before[open + 1..before.len() - 1]
+        .split(|(_, t)| t.is_punct(","))
+        .map(|column| match column.last().map(|(_, t)| t) {
+            Some(Token::Word(w)) => Some(w.to_lowercase()),
+            Some(Token::Quoted(q)) => Some(q.trim_matches('"').to_lowercase()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hash(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_literals_and_parameters_but_kept_columns() {
+        let redactor = Redactor::new(Style::Mask, &["STATUS".into(), "kind".into()]);
+        assert_eq!(
+            redactor.sql(
+                "select * from orders o where o.email = 'ana@example.com' -- 'comment'\n  \
+                 and o.status in ('OPEN', 'HELD') and total > 100.5"
+            ),
+            "select * from orders o where o.email = '***' -- 'comment'\n  \
+             and o.status in ('OPEN', 'HELD') and total > 0"
+        );
+        assert_eq!(
+            redactor.sql("insert into t (name, \"KIND\", n) values ('O''Brien', 'A', 42)"),
+            "insert into t (name, \"KIND\", n) values ('***', 'A', 0)"
+        );
+        assert_eq!(
+            Redactor::new(Style::KeepLength, &[]).sql("where name = 'O''Brien' and id = 1234"),
+            "where name = 'xxxxxxx' and id = 9999"
+        );
+        let hashed = Redactor::new(Style::Hash, &[]);
+        assert_eq!(hashed.sql("a = 'x' or b = 'x'").matches("'h:").count(), 2);
+        assert_eq!(hashed.sql("a = 'x'"), hashed.sql("a = 'x'"));
+
+        let mut event = crate::fixtures::statement(
+            "update customers set email = 'ana@example.com' where id = ?\n\
+             param0 = integer, \"4711\"\n\
+             param1 = varchar(10), \"ACME\"\n\
+             \n      5 ms",
+        );
+        event.context = ["APP_USER", "STATUS"]
+            .map(|name| crate::event::ContextVar {
+                namespace: "USER_SESSION".into(),
+                name: name.into(),
+                value: Some("ana".into()),
+            })
+            .into();
+        redactor.redact(&mut event);
+        let values: Vec<_> = event.context.iter().map(|v| v.value.as_deref()).collect();
+        assert_eq!(values, [Some("***"), Some("ana")]);
+        assert_eq!(
+            event.statement.unwrap().sql,
+            "update customers set email = '***' where id = ?"
+        );
+        assert_eq!(event.params[0].value, ParamValue::Int(0));
+        assert!(
+            !event.raw.contains("ana@") && !event.raw.contains("4711"),
+            "{}",
+            event.raw
+        );
+        assert!(
+            event.raw.contains("param0 = integer, \"0\""),
+            "{}",
+            event.raw
+        );
+    }
+
+    #[test]
+    fn redacts_the_other_lines_and_the_raw_text_of_every_kind() {
+        let redactor = Redactor::new(Style::Mask, &[]);
+        let redacted = |lines: &[&str]| {
+            let mut event = crate::fixtures::event(&lines.join("\n"));
+            redactor.redact(&mut event);
+            event
+        };
+
+        let event = redacted(&[
+            "2024-01-15T10:23:45.3450 (1234:00007F12AB) FAILED EXECUTE_STATEMENT_FINISH",
+            "\t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)",
+            "\t\t(TRA_45, CONCURRENCY | WAIT | READ_WRITE)",
+            "",
+            "Statement 67:",
+            "-------------------------------------------------------------------------------",
+            "insert into customers (email)",
+            "",
+            "values ('ana@example.com')",
+            "",
+            "335544665 : violation of PRIMARY or UNIQUE KEY constraint \"UQ_EMAIL\" on table \"CUSTOMERS\"",
+            "335545072 : Problematic key value is (\"EMAIL\" = 'ana@example.com')",
+            "      5 ms",
+        ]);
+        assert_eq!(
+            event.statement.unwrap().sql,
+            "insert into customers (email)\n\nvalues ('***')"
+        );
+        assert_eq!(
+            event.lines[1],
+            "335545072 : Problematic key value is (\"EMAIL\" = '***')"
+        );
+        assert!(!event.raw.contains("ana@"), "{}", event.raw);
+        assert!(event.raw.contains("335544665 : violation"), "{}", event.raw);
+
+        let event = redacted(&[
+            "2024-01-15T10:23:45.3450 (1234:00007F12AB) EXECUTE_PROCEDURE_FINISH",
+            "\t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)",
+            "Procedure P_NOTIFY('ana@example.com')",
+        ]);
+        assert_eq!(event.lines, ["Procedure P_NOTIFY('***')"]);
+        assert!(!event.raw.contains("ana@"), "{}", event.raw);
+    }
+}
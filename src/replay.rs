@@ -0,0 +1,456 @@
+//! Replaying captured statements against another database, e.g. a restored copy, to load
+//! test it or compare how it copes with the same workload.
+//!
+//! Each captured attachment is replayed on its own isql connection, so statements that
+//! ran concurrently still do. isql can't bind parameters, so the recorded values are
+//! written into the SQL as literals.
+
+use crate::binary;
+use crate::error::AppError;
+use crate::event::{Event, EventKind, Param, ParamValue};
+use crate::expr::{self, Expr};
+use crate::monitor;
+use crate::parser;
+use crate::sink::{sqlite, Store};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Statements starting with these words are replayed; anything else, e.g. DDL or
+/// `SET GENERATOR`, could change the target in ways a capture shouldn't.
+const REPLAYED: &[&str] = &["select", "with", "insert", "update", "delete", "merge"];
+
+/// How many distinct failures the summary lists.
+const REPORTED_FAILURES: usize = 5;
+
+#[derive(clap::Args, Debug)]
+pub struct ReplayArgs {
+    /// The capture to replay: a store, e.g. sqlite:trace.db, or a file of JSON lines as
+    /// written by --output-format json or found in an export bundle
+    source: String,
+
+    /// The database to replay against, e.g. testhost:/data/erp-copy.fdb
+    #[arg(long)]
+    database: String,
+
+    /// Firebird username
+    #[arg(short, long, env = "ISC_USER", hide_env = true)]
+    user: String,
+
+    /// Firebird password
+    #[arg(short, long, env = "ISC_PASSWORD", hide_env = true)]
+    pass: String,
+
+    /// How many times faster than captured to replay, e.g. 10. 0 replays without pauses
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+
+    /// Only replay statements matching this expression, see --where of a trace
+    #[arg(long = "where", value_parser = expr::parse)]
+    where_expr: Option<Expr>,
+
+    /// Commit work whose commit or rollback wasn't captured, instead of rolling it back
+    #[arg(long)]
+    commit: bool,
+
+    /// Print what would be run on each connection instead of replaying
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Execute(String),
+    Commit,
+    Rollback,
+}
+
+/// A step of the replay, at its offset from the first captured statement.
+#[derive(Debug)]
+struct Scheduled {
+    offset: Duration,
+    /// The captured attachment, by database and attachment ID.
+    connection: (String, i64),
+    step: Step,
+}
+
+pub fn run(args: &ReplayArgs) -> Result<(), AppError> {
+    if !(args.speed >= 0.0 && args.speed.is_finite()) {
+        return Err(AppError::InvalidArgs(
+            "--speed must be 0 or a positive number".into(),
+        ));
+    }
+
+    let events = load(&args.source)?;
+    let (steps, skipped) = schedule(&events, args.where_expr.as_ref());
+    if steps.is_empty() {
+        return Err(AppError::InvalidArgs(format!(
+            "{} has no statements to replay",
+            args.source
+        )));
+    }
+
+    if args.dry_run {
+        for s in &steps {
+            let (database, attachment) = &s.connection;
+            println!(
+                "-- +{:.3}s ATT_{attachment} {database}",
+                s.offset.as_secs_f64()
+            );
+            println!("{};", sql(&s.step));
+        }
+        return Ok(());
+    }
+
+    replay(args, &steps, skipped)
+}
+
+/// Reads the events of a store, by time, or of a JSON lines file or binary capture, in
+/// the order written.
+fn load(source: &str) -> Result<Vec<Event>, AppError> {
+    let raws: Vec<String> = match source.parse::<Store>() {
+        Ok(Store::Sqlite(path)) => stored(&path).map_err(|e| AppError::Dyn(Box::new(e)))?,
+        Err(_) => {
+            let mut input = BufReader::new(File::open(Path::new(source))?);
+            if binary::is_capture(&mut input)? {
+                let reader = binary::Reader::new(input)
+                    .map_err(|e| AppError::InvalidArgs(format!("{source}: {e}")))?;
+                reader
+                    .map(|e| e.map(|e| e.raw))
+                    .collect::<std::io::Result<_>>()
+                    .map_err(|e| AppError::InvalidArgs(format!("{source}: {e}")))?
+            } else {
+                lines(input, source)?
+            }
+        }
+    };
+
+    Ok(raws.iter().flat_map(|raw| parser::parse_all(raw)).collect())
+}
+
+/// The trace text of each event in a file of JSON lines.
+fn lines(mut input: impl Read, source: &str) -> Result<Vec<String>, AppError> {
+    let mut text = String::new();
+    input.read_to_string(&mut text)?;
+    let mut raws = vec![];
+    for (n, line) in text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+    {
+        let value: serde_json::Value = serde_json::from_str(line)
+            .map_err(|e| AppError::InvalidArgs(format!("{source}:{}: {e}", n + 1)))?;
+        if let Some(raw) = value["raw"].as_str() {
+            raws.push(raw.to_string());
+        }
+    }
+    Ok(raws)
+}
+
+fn stored(path: &str) -> rusqlite::Result<Vec<String>> {
+    let conn = Connection::open(path)?;
+    if let Some(note) = sqlite::thinning_note(&conn) {
+        eprintln!("{path}: {note}");
+    }
+    let mut stmt = conn.prepare("SELECT raw FROM events ORDER BY timestamp, id")?;
+    let rows = stmt.query_map([], |r| r.get(0))?;
+    rows.collect()
+}
+
+/// The steps to replay, and how many captured statements can't be.
+fn schedule(events: &[Event], filter: Option<&Expr>) -> (Vec<Scheduled>, usize) {
+    let mut steps: Vec<Scheduled> = vec![];
+    let mut skipped = 0;
+    let mut start = None;
+
+    for event in events {
+        let (Some(att), Some(time)) = (&event.attachment, event.time()) else {
+            continue;
+        };
+        let connection = (att.database.clone(), att.id);
+
+        let step = match event.kind {
+            EventKind::ExecuteStatementFinish => {
+                let Some(stmt) = &event.statement else {
+                    continue;
+                };
+                if !is_replayed(&stmt.sql) || filter.is_some_and(|f| !f.matches(event)) {
+                    continue;
+                }
+                match (stmt.truncated, inline_params(&stmt.sql, event)) {
+                    (false, Some(sql)) => Step::Execute(sql),
+                    _ => {
+                        skipped += 1;
+                        continue;
+                    }
+                }
+            }
+            EventKind::CommitTransaction | EventKind::CommitRetaining => Step::Commit,
+            EventKind::RollbackTransaction | EventKind::RollbackRetaining => Step::Rollback,
+            _ => continue,
+        };
+        // Ending a transaction nothing was replayed in is pointless.
+        if !matches!(step, Step::Execute(_)) && !steps.iter().any(|s| s.connection == connection) {
+            continue;
+        }
+
+        let start = *start.get_or_insert(time);
+        steps.push(Scheduled {
+            offset: (time - start).to_std().unwrap_or_default(),
+            connection,
+            step,
+        });
+    }
+    (steps, skipped)
+}
+
+fn is_replayed(sql: &str) -> bool {
+    let first = sql
+        .trim_start_matches(|c: char| c.is_whitespace() || c == '(')
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .unwrap_or_default();
+    REPLAYED.iter().any(|w| first.eq_ignore_ascii_case(w))
+}
+
+/// Replaces each `?` of `sql`, outside of strings, identifiers and comments, with the
+/// recorded value of its parameter. `None` if the parameters weren't all recorded, or
+/// one can't be written as a literal.
+fn inline_params(sql: &str, event: &Event) -> Option<String> {
+    let mut params = event.params.iter();
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                out.push(c);
+                for q in chars.by_ref() {
+                    out.push(q);
+                    // A doubled quote reopens right away, which works out the same.
+                    if q == c {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                out.push(c);
+                for q in chars.by_ref() {
+                    out.push(q);
+                    if q == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                out.push(c);
+                let mut prev = ' ';
+                for q in chars.by_ref() {
+                    out.push(q);
+                    if prev == '*' && q == '/' {
+                        break;
+                    }
+                    prev = q;
+                }
+            }
+            '?' => out.push_str(&literal(params.next()?)?),
+            c => out.push(c),
+        }
+    }
+    params.next().is_none().then_some(out)
+}
+
+/// `param` as a literal of its type, e.g. `TIMESTAMP '2024-01-15 10:23:45.1230'`. `None`
+/// for those the trace doesn't have the value of, e.g. blobs, which it only gives the ID
+/// of.
+fn literal(param: &Param) -> Option<String> {
+    let ty = param.ty.to_ascii_lowercase();
+    let text = match &param.value {
+        ParamValue::Null => return Some("NULL".into()),
+        ParamValue::Bool(_) | ParamValue::Int(_) | ParamValue::Number(_) => {
+            return Some(param.value.to_string())
+        }
+        ParamValue::Text(text) => text,
+    };
+    // Servers print timestamps as in ISO 8601, which their literals don't take.
+    match ["timestamp", "date", "time"]
+        .into_iter()
+        .find(|t| ty.starts_with(t))
+    {
+        Some(keyword) => Some(format!(
+            "{} '{}'",
+            keyword.to_uppercase(),
+            text.replacen('T', " ", 1)
+        )),
+        None if ["char", "varchar", "nchar", "nvarchar"]
+            .iter()
+            .any(|t| ty.starts_with(t)) =>
+        {
+            Some(param.value.to_string())
+        }
+        None => None,
+    }
+}
+
+fn sql(step: &Step) -> &str {
+    match step {
+        Step::Execute(sql) => sql.trim_end().trim_end_matches(';'),
+        Step::Commit => "COMMIT",
+        Step::Rollback => "ROLLBACK",
+    }
+}
+
+/// An isql process replaying one captured attachment.
+struct Session {
+    child: Child,
+    stdin: ChildStdin,
+    /// Collects the failures isql reports.
+    errors: JoinHandle<Vec<String>>,
+}
+
+impl Session {
+    fn open(args: &ReplayArgs) -> Result<Self, AppError> {
+        let mut child = monitor::isql(&args.database, &args.user, &args.pass)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let errors = std::thread::spawn(move || failures(stderr));
+        Ok(Self {
+            child,
+            stdin,
+            errors,
+        })
+    }
+}
+
+/// The failures in isql's error output, each starting with a `Statement failed` line.
+fn failures(stderr: impl Read) -> Vec<String> {
+    let mut failures: Vec<String> = vec![];
+    for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+        if line.starts_with("Statement failed") || failures.is_empty() {
+            failures.push(line);
+        } else if let Some(last) = failures.last_mut() {
+            last.push('\n');
+            last.push_str(&line);
+        }
+    }
+    failures
+}
+
+fn replay(args: &ReplayArgs, steps: &[Scheduled], skipped: usize) -> Result<(), AppError> {
+    let mut sessions: HashMap<&(String, i64), Session> = HashMap::new();
+    let started = Instant::now();
+    let mut executed = 0;
+
+    for s in steps {
+        if args.speed > 0.0 {
+            let due = s.offset.div_f64(args.speed);
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+
+        let session = match sessions.entry(&s.connection) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(e) => e.insert(Session::open(args)?),
+        };
+        // A session whose isql has exited already reports why through its errors.
+        if writeln!(session.stdin, "{};", sql(&s.step)).is_err() {
+            continue;
+        }
+        if matches!(s.step, Step::Execute(_)) {
+            executed += 1;
+        }
+    }
+
+    let end = if args.commit { "COMMIT" } else { "ROLLBACK" };
+    let connections = sessions.len();
+    let mut failures: Vec<String> = vec![];
+    for (_, mut session) in sessions {
+        let _ = writeln!(session.stdin, "{end};");
+        drop(session.stdin);
+        let _ = session.child.wait();
+        failures.extend(session.errors.join().unwrap_or_default());
+    }
+
+    let captured = steps.last().map_or(Duration::ZERO, |s| s.offset);
+    eprintln!(
+        "Replayed {executed} statements on {connections} connections in {:.1}s, captured over {:.1}s. {} failed, {skipped} couldn't be replayed",
+        started.elapsed().as_secs_f64(),
+        captured.as_secs_f64(),
+        failures.len(),
+    );
+    let mut reported: Vec<&String> = vec![];
+    for f in &failures {
+        if !reported.contains(&f) && reported.len() < REPORTED_FAILURES {
+            reported.push(f);
+            eprintln!("{f}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::statement;
+
+    #[test]
+    fn inlines_recorded_parameters_as_literals() {
+        let event = statement(
+            "update customers set name = ? where id = ? and note <> '?' -- why?\n\
+             param0 = varchar(10), \"O'Neil\"\n\
+             param1 = integer, \"7\"",
+        );
+        let sql = &event.statement.as_ref().unwrap().sql;
+
+        assert_eq!(
+            inline_params(sql, &event).unwrap(),
+            "update customers set name = 'O''Neil' where id = 7 and note <> '?' -- why?"
+        );
+        assert!(is_replayed(sql));
+        assert!(!is_replayed("create index x on customers (name)"));
+
+        let (steps, skipped) = schedule(&[event], None);
+        assert_eq!((steps.len(), skipped), (1, 0));
+    }
+
+    #[test]
+    fn writes_each_type_as_its_literal_and_skips_blobs() {
+        let event = statement(
+            "insert into orders values (?, ?, ?, ?, ?, ?)\n\
+             param0 = integer, <NULL>\n\
+             param1 = numeric(10,2), \"12.50\"\n\
+             param2 = timestamp, \"2024-01-15T10:23:45.1230\"\n\
+             param3 = date, \"2024-01-15\"\n\
+             param4 = time, \"10:23:45.1230\"\n\
+             param5 = boolean, \"<true>\"",
+        );
+        assert_eq!(
+            inline_params(&event.statement.as_ref().unwrap().sql, &event).unwrap(),
+            "insert into orders values (NULL, 12.50, TIMESTAMP '2024-01-15 10:23:45.1230', \
+             DATE '2024-01-15', TIME '10:23:45.1230', true)"
+        );
+
+        // The trace has the blob's ID rather than its contents.
+        let blob = statement(
+            "update documents set body = ? where id = ?\n\
+             param0 = blob sub_type 1, \"0000000A:00000001\"\n\
+             param1 = integer, \"7\"",
+        );
+        assert_eq!(
+            inline_params(&blob.statement.as_ref().unwrap().sql, &blob),
+            None
+        );
+        let (steps, skipped) = schedule(&[event, blob], None);
+        assert_eq!((steps.len(), skipped), (1, 1));
+    }
+}
@@ -0,0 +1,630 @@
+//! Tracing a primary and its replica side by side, to see which statements the replica
+//! runs slower for the same workload.
+
+use crate::connstr;
+use crate::error::AppError;
+use crate::event::{Event, EventKind};
+use crate::fingerprint::Strategy;
+use crate::policy;
+use crate::report::percentile;
+use crate::session::{self, Recorded};
+use crate::shell::first_line;
+use crate::tracemgr::{self, Connection, Echo};
+use crate::units;
+use console::{pad_str, Alignment};
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Write};
+use std::process::{Child, Stdio};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempPath;
+
+/// The `max_sql_length` of both sessions, high so statements are compared whole.
+const MAX_SQL: usize = 65536;
+
+#[derive(clap::Args, Debug)]
+pub struct CompareReplicaArgs {
+    #[command(flatten)]
+    conn: Connection,
+
+    /// The replica's host, traced with the same credentials as --host, the primary
+    #[arg(long, value_parser = connstr::parse_host)]
+    replica: String,
+
+    /// Only trace the databases matching this pattern, on both servers
+    #[arg(long)]
+    database_matcher: Option<String>,
+
+    /// How statements are told apart, see the trace's --fingerprint
+    #[arg(long, value_enum, default_value_t = Strategy::LiteralStrip)]
+    fingerprint: Strategy,
+
+    /// Compare the executions of this long until now
+    #[arg(long, value_parser = units::parse_duration, default_value = "5m")]
+    window: Duration,
+
+    /// How often the comparison is redrawn
+    #[arg(long, value_parser = units::parse_duration, default_value = "2s")]
+    refresh: Duration,
+
+    /// Stop after this long, e.g. 10m
+    #[arg(long, value_parser = units::parse_duration)]
+    duration: Option<Duration>,
+
+    /// How many statements are listed
+    #[arg(long, default_value_t = 20)]
+    top: usize,
+
+    /// Name both sessions, e.g. erp-replica, to stop them with `session stop erp-replica`.
+    /// Only one session can run under a name on each server
+    #[arg(long, value_parser = session::parse_name)]
+    name: Option<String>,
+
+    /// Tag both sessions, e.g. ticket=OPS-123, as part of their names on the servers
+    #[arg(long = "tag", value_parser = session::parse_tag)]
+    tags: Vec<(String, String)>,
+
+    /// Run the comparison even though the policy forbids it, recording this reason
+    #[arg(long, value_name = "REASON")]
+    override_policy: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Primary,
+    Replica,
+}
+
+/// The recent executions of a statement fingerprint, as when and how long, on each side,
+/// and its text as first seen on each.
+#[derive(Debug, Default)]
+struct Executions {
+    sql: String,
+    replica_sql: String,
+    primary: VecDeque<(Instant, i64)>,
+    replica: VecDeque<(Instant, i64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Stats {
+    executions: usize,
+    mean_ms: f64,
+    p95_ms: i64,
+}
+
+impl Stats {
+    fn of(executions: &VecDeque<(Instant, i64)>) -> Option<Self> {
+        let mut durations: Vec<i64> = executions.iter().map(|(_, ms)| *ms).collect();
+        if durations.is_empty() {
+            return None;
+        }
+        durations.sort_unstable();
+        Some(Self {
+            executions: durations.len(),
+            mean_ms: durations.iter().sum::<i64>() as f64 / durations.len() as f64,
+            p95_ms: percentile(&durations, 0.95),
+        })
+    }
+}
+
+/// A statement run on both servers.
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    sql: String,
+    replica_sql: String,
+    primary: Stats,
+    replica: Stats,
+}
+
+impl Row {
+    /// How many times as long the replica takes, unless the primary took no time at all.
+    fn ratio(&self) -> Option<f64> {
+        (self.primary.mean_ms > 0.0).then(|| self.replica.mean_ms / self.primary.mean_ms)
+    }
+
+    /// The time the replica spends on the primary's executions beyond what the primary
+    /// did, which orders the statements by how much they explain.
+    fn extra_ms(&self) -> f64 {
+        (self.replica.mean_ms - self.primary.mean_ms) * self.primary.executions as f64
+    }
+}
+
+/// The statements of both servers, by fingerprint, over the last `window`.
+struct Comparison {
+    window: Duration,
+    statements: HashMap<String, Executions>,
+}
+
+impl Comparison {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            statements: HashMap::new(),
+        }
+    }
+
+    fn observe(&mut self, side: Side, fingerprint: String, event: &Event, now: Instant) {
+        let (Some(stmt), Some(perf)) = (&event.statement, &event.perf) else {
+            return;
+        };
+        let executions = self.statements.entry(fingerprint).or_default();
+        let (sql, times) = match side {
+            Side::Primary => (&mut executions.sql, &mut executions.primary),
+            Side::Replica => (&mut executions.replica_sql, &mut executions.replica),
+        };
+        if sql.is_empty() {
+            sql.clone_from(&stmt.sql);
+        }
+        times.push_back((now, perf.duration_ms));
+    }
+
+    /// Drops what's older than the window, then returns the statements run on both sides,
+    /// with those the replica spends the most extra time on first, and how many were
+    /// only run on the primary and only on the replica.
+    fn rows(&mut self, now: Instant) -> (Vec<Row>, usize, usize) {
+        let window = self.window;
+        let expired = |side: &mut VecDeque<(Instant, i64)>| {
+            while side.front().is_some_and(|(t, _)| now - *t > window) {
+                side.pop_front();
+            }
+        };
+        for executions in self.statements.values_mut() {
+            expired(&mut executions.primary);
+            expired(&mut executions.replica);
+        }
+        self.statements
+            .retain(|_, e| !e.primary.is_empty() || !e.replica.is_empty());
+
+        let (mut rows, mut primary_only, mut replica_only) = (vec![], 0, 0);
+        for executions in self.statements.values() {
+            match (
+                Stats::of(&executions.primary),
+                Stats::of(&executions.replica),
+            ) {
+                (Some(primary), Some(replica)) => rows.push(Row {
+                    sql: executions.sql.clone(),
+                    replica_sql: executions.replica_sql.clone(),
+                    primary,
+                    replica,
+                }),
+                (Some(_), None) => primary_only += 1,
+                (None, Some(_)) => replica_only += 1,
+                (None, None) => {}
+            }
+        }
+        rows.sort_by(|a, b| {
+            b.extra_ms()
+                .total_cmp(&a.extra_ms())
+                .then_with(|| a.sql.cmp(&b.sql))
+        });
+        (rows, primary_only, replica_only)
+    }
+}
+
+/// Writes the line summing up the comparison.
+fn summary(
+    out: &mut impl Write,
+    (rows, primary_only, replica_only): &(Vec<Row>, usize, usize),
+) -> std::io::Result<()> {
+    // What the primary's executions of the shared statements would take on the replica.
+    let primary_ms: f64 = rows
+        .iter()
+        .map(|r| r.primary.mean_ms * r.primary.executions as f64)
+        .sum();
+    let replica_ms: f64 = rows
+        .iter()
+        .map(|r| r.replica.mean_ms * r.primary.executions as f64)
+        .sum();
+    write!(
+        out,
+        "{} statements on both servers, {primary_only} only on the primary, {replica_only} only on the replica.",
+        rows.len()
+    )?;
+    if primary_ms > 0.0 {
+        write!(
+            out,
+            " The replica takes {:.2}x as long for the same statements.",
+            replica_ms / primary_ms
+        )?;
+    }
+    writeln!(out)
+}
+
+/// Writes the comparison as a table, with a line summing it up.
+fn render(
+    out: &mut impl Write,
+    comparison: &(Vec<Row>, usize, usize),
+    top: usize,
+) -> std::io::Result<()> {
+    summary(out, comparison)?;
+    let rows = &comparison.0;
+    writeln!(
+        out,
+        "  {:>8} {:>9} {:>8}  {:>8} {:>9} {:>8}  {:>7}  statement",
+        "primary", "mean ms", "p95 ms", "replica", "mean ms", "p95 ms", "ratio"
+    )?;
+    for row in rows.iter().take(top) {
+        let ratio = row.ratio().map_or("-".into(), |r| format!("{r:.2}x"));
+        writeln!(
+            out,
+            "  {:>8} {:>9.1} {:>8}  {:>8} {:>9.1} {:>8}  {ratio:>7}  {}",
+            row.primary.executions,
+            row.primary.mean_ms,
+            row.primary.p95_ms,
+            row.replica.executions,
+            row.replica.mean_ms,
+            row.replica.p95_ms,
+            first_line(&row.sql)
+        )?;
+    }
+    Ok(())
+}
+
+/// The width of the ratios between the panes of the split view, with their borders.
+const GUTTER: usize = 13;
+
+/// Writes the comparison as two panes side by side, `width` wide, the primary's on the
+/// left and the replica's on the right, each statement on the same line of both and the
+/// ratio between them in the middle.
+fn render_split(
+    out: &mut impl Write,
+    comparison: &(Vec<Row>, usize, usize),
+    servers: (&str, &str),
+    top: usize,
+    width: usize,
+) -> std::io::Result<()> {
+    let pane = width.saturating_sub(GUTTER) / 2;
+    let line = |left: &str, middle: &str, right: &str| {
+        format!(
+            "{} │ {middle:>7} │ {}",
+            pad_str(left, pane, Alignment::Left, Some("…")),
+            pad_str(right, pane, Alignment::Left, Some("…"))
+        )
+        .trim_end()
+        .to_string()
+    };
+    let side = |stats: &Stats, sql: &str| {
+        format!(
+            "{:>8} {:>9.1} {:>8}  {}",
+            stats.executions,
+            stats.mean_ms,
+            stats.p95_ms,
+            first_line(sql)
+        )
+    };
+
+    summary(out, comparison)?;
+    let (primary, replica) = servers;
+    writeln!(
+        out,
+        "{}",
+        line(
+            &format!("primary {primary}"),
+            "",
+            &format!("replica {replica}")
+        )
+    )?;
+    let header = format!("{:>8} {:>9} {:>8}  statement", "runs", "mean ms", "p95 ms");
+    writeln!(out, "{}", line(&header, "ratio", &header))?;
+    for row in comparison.0.iter().take(top) {
+        let ratio = row.ratio().map_or("-".into(), |r| format!("{r:.2}x"));
+        writeln!(
+            out,
+            "{}",
+            line(
+                &side(&row.primary, &row.sql),
+                &ratio,
+                &side(&row.replica, &row.replica_sql)
+            )
+        )?;
+    }
+    Ok(())
+}
+
+/// A trace session on one of the servers.
+struct Trace {
+    conn: Connection,
+    child: Child,
+    /// The session's config and state, recorded once its ID is known, for `session show`
+    /// and `session stop`.
+    recorded: Option<[Option<Recorded>; 2]>,
+    session_id: Arc<AtomicI64>,
+    failure: Arc<Mutex<Option<String>>>,
+    events: Receiver<Event>,
+    reader: JoinHandle<std::io::Result<()>>,
+}
+
+impl Trace {
+    fn start(conn: Connection, name: &str, config: &TempPath) -> Result<Self, AppError> {
+        let mut child = match tracemgr::fbtracemgr(&conn)
+            .args(["-START", "-NAME", name, "-CONFIG"])
+            .arg(config)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(c) => c,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(AppError::TraceMgrNotFound),
+            Err(e) => return Err(AppError::Io(e)),
+        };
+
+        let session_id = Arc::new(AtomicI64::new(0));
+        let failure = Arc::new(Mutex::new(None));
+        let (tx, events) = channel();
+        let stdout = child.stdout.take();
+        let reader = {
+            let session_id = session_id.clone();
+            let failure = failure.clone();
+            thread::spawn(move || match stdout {
+                Some(s) => {
+                    tracemgr::read_trace(s, &tx, &session_id, &failure, Echo::Off, Some(MAX_SQL))
+                }
+                None => Ok(()),
+            })
+        };
+        Ok(Self {
+            conn,
+            child,
+            recorded: None,
+            session_id,
+            failure,
+            events,
+            reader,
+        })
+    }
+
+    /// Records the session once the server announced it, like those of a trace.
+    fn record(&mut self, name: Option<&str>, config: &str) {
+        let id = self.session_id.load(Ordering::SeqCst);
+        if self.recorded.is_none() && id > 0 {
+            self.recorded = Some([
+                session::record_config(&self.conn, id, config),
+                session::record_state(&self.conn, name, id),
+            ]);
+        }
+    }
+
+    fn stop(&mut self) {
+        let id = self.session_id.load(Ordering::SeqCst);
+        tracemgr::stop_trace(&self.conn, id, &mut self.child);
+    }
+
+    /// Waits for fbtracemgr to exit, returning why the server refused the session if it
+    /// did.
+    fn finish(mut self, server: &str) -> Result<(), AppError> {
+        let status = self.child.wait()?;
+        if let Ok(Err(e)) = self.reader.join() {
+            return Err(AppError::Io(e));
+        }
+        match self.failure.lock().ok().and_then(|mut f| f.take()) {
+            Some(banner) => Err(tracemgr::trace_failure(
+                &self.conn,
+                &format!("{server}: {banner}"),
+                status,
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+fn config(database_matcher: Option<&str>) -> String {
+    let database = match database_matcher {
+        Some(p) => format!("<database {p}>"),
+        None => "<database>".into(),
+    };
+    // Every statement is logged, as the fast ones on the primary are those to compare.
+    format!(
+        "{database}\n    enabled true\n    log_statement_finish true\n    time_threshold 0\n    \
+         max_sql_length {MAX_SQL}\n</database>\n"
+    )
+}
+
+fn write_config(config: &str) -> Result<TempPath, AppError> {
+    let config_write = |path: &std::path::Path, source| AppError::ConfigWrite {
+        path: path.display().to_string(),
+        source,
+    };
+    let mut f = tempfile::Builder::new()
+        .prefix("rsfbtrace-")
+        .suffix(".conf")
+        .tempfile()
+        .map_err(|e| config_write(&std::env::temp_dir(), e))?;
+    f.write_all(config.as_bytes())
+        .map_err(|e| config_write(f.path(), e))?;
+    Ok(f.into_temp_path())
+}
+
+pub fn run(mut args: CompareReplicaArgs) -> Result<(), AppError> {
+    args.conn.read_pass_file()?;
+    // Every statement finish is traced on both servers.
+    policy::check(
+        &policy::Trace {
+            servers: vec![args.conn.server_name().to_string(), args.replica.clone()],
+            conn: &args.conn,
+            events: &["statement_finish".into()],
+            given: &|option| match option {
+                "database-matcher" => args.database_matcher.is_some(),
+                "duration" => args.duration.is_some(),
+                _ => false,
+            },
+            override_policy: args.override_policy.as_deref(),
+        },
+        true,
+    )?;
+    let mut replica = args.conn.clone();
+    replica.host = Some(args.replica.clone());
+    let _tunnels = [args.conn.open_tunnel()?, replica.open_tunnel()?];
+    if let Some(name) = &args.name {
+        session::check_name_free(&args.conn, name)?;
+        session::check_name_free(&replica, name)?;
+    }
+    // Without a --name the sessions are still named as comparisons, to tell them from
+    // traces in `session list`.
+    let name = session::session_name(
+        Some(args.name.as_deref().unwrap_or("compare-replica")),
+        &args.tags,
+    );
+
+    if let Err(e) = ctrlc::set_handler(|| crate::INTERRUPTED.store(true, Ordering::SeqCst)) {
+        eprintln!("Unable to handle Ctrl+C: {e}");
+    }
+
+    let config = config(args.database_matcher.as_deref());
+    let config_path = write_config(&config)?;
+    let mut primary = Trace::start(args.conn.clone(), &name, &config_path)?;
+    let mut replica = Trace::start(replica, &name, &config_path)?;
+
+    let fingerprinter = args.fingerprint.fingerprinter();
+    let mut comparison = Comparison::new(args.window);
+    let mut term = console::Term::stdout();
+    let live = term.is_term();
+    let deadline = args.duration.map(|d| Instant::now() + d);
+    let mut next_draw = Instant::now();
+    let mut open = [true, true];
+    let mut stopped = false;
+
+    while open.iter().any(|o| *o) {
+        for (i, (trace, side)) in [(&primary, Side::Primary), (&replica, Side::Replica)]
+            .into_iter()
+            .enumerate()
+        {
+            loop {
+                match trace.events.try_recv() {
+                    Ok(event) if event.kind == EventKind::ExecuteStatementFinish => {
+                        let Some(stmt) = &event.statement else {
+                            continue;
+                        };
+                        let fingerprint = fingerprinter.fingerprint(&stmt.sql);
+                        comparison.observe(side, fingerprint, &event, Instant::now());
+                    }
+                    Ok(_) => {}
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        open[i] = false;
+                        break;
+                    }
+                }
+            }
+        }
+
+        primary.record(args.name.as_deref(), &config);
+        replica.record(args.name.as_deref(), &config);
+
+        let interrupted = crate::INTERRUPTED.load(Ordering::SeqCst);
+        if !stopped && (interrupted || deadline.is_some_and(|d| Instant::now() >= d)) {
+            primary.stop();
+            replica.stop();
+            stopped = true;
+        }
+        // Once one side is gone there's nothing left to compare.
+        if !stopped && open.iter().any(|o| !o) {
+            primary.stop();
+            replica.stop();
+            stopped = true;
+        }
+
+        if live && Instant::now() >= next_draw {
+            next_draw = Instant::now() + args.refresh;
+            let (height, width) = term.size();
+            // The summary and the headers take the first three lines.
+            let top = args.top.min((height as usize).saturating_sub(4));
+            let servers = (args.conn.server_name(), args.replica.as_str());
+            let mut screen = vec![];
+            let rows = comparison.rows(Instant::now());
+            render_split(&mut screen, &rows, servers, top, width as usize)?;
+            term.clear_screen()?;
+            term.write_all(&screen)?;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    primary.finish(args.conn.server_name())?;
+    replica.finish(&args.replica)?;
+
+    if live {
+        term.clear_screen()?;
+    }
+    render(
+        &mut std::io::stdout(),
+        &comparison.rows(Instant::now()),
+        args.top,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{Perf, Statement};
+
+    #[test]
+    fn lines_up_statements_by_fingerprint() {
+        let template = crate::fixtures::bare("EXECUTE_STATEMENT_FINISH");
+        let statement = |sql: &str, ms: i64| Event {
+            statement: Some(Statement {
+                sql: sql.into(),
+                ..Default::default()
+            }),
+            perf: Some(Perf {
+                duration_ms: ms,
+                ..Default::default()
+            }),
+            ..template.clone()
+        };
+
+        let mut comparison = Comparison::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let mut observe = |side, key: &str, ms, secs| {
+            let event = statement(key, ms);
+            comparison.observe(side, key.into(), &event, start + Duration::from_secs(secs));
+        };
+        observe(Side::Primary, "select a", 10, 0);
+        observe(Side::Primary, "select a", 10, 1);
+        observe(Side::Replica, "select a", 30, 2);
+        observe(Side::Primary, "select b", 100, 3);
+        observe(Side::Replica, "select b", 110, 3);
+        observe(Side::Primary, "select c", 5, 3);
+        // Expired by the time of the comparison.
+        observe(Side::Replica, "select d", 5, 0);
+
+        let rows = comparison.rows(start + Duration::from_secs(61));
+        let (shared, primary_only, replica_only) = &rows;
+        assert_eq!((*primary_only, *replica_only), (1, 0));
+        assert_eq!(
+            shared.iter().map(|r| r.sql.as_str()).collect::<Vec<_>>(),
+            ["select a", "select b"]
+        );
+        assert_eq!(shared[0].ratio(), Some(3.0));
+
+        let mut out = vec![];
+        render(&mut out, &rows, 10).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.starts_with(
+                "2 statements on both servers, 1 only on the primary, 0 only on the replica. \
+                 The replica takes 1.27x as long for the same statements.\n"
+            ),
+            "{out}"
+        );
+        assert!(
+            out.lines().nth(2).unwrap().ends_with("3.00x  select a"),
+            "{out}"
+        );
+
+        let mut out = vec![];
+        render_split(&mut out, &rows, ("db1", "db2"), 1, 91).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            [
+                "primary db1                             │         │ replica db2",
+                "    runs   mean ms   p95 ms  statement  │   ratio │     runs   mean ms   p95 ms  statement",
+                "       1      10.0       10  select a   │   3.00x │        1      30.0       30  select a",
+            ]
+        );
+    }
+}
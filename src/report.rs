@@ -0,0 +1,710 @@
+//! Self-contained HTML reports of a store, for sharing a trace with people who won't
+//! query it themselves.
+
+use crate::error::AppError;
+use crate::event::Event;
+use crate::heatmap::{bucket_bound, duration_bucket, escape, format_time, DURATION_BUCKETS};
+use crate::histogram::Histogram;
+use crate::parser;
+use crate::sink::Store;
+use crate::throttle::{self, Counts, Thinning};
+use crate::units;
+use crate::variants::{self, ChangeTag};
+use rusqlite::Connection;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(clap::Args, Debug)]
+pub struct ReportArgs {
+    /// The store to report on, e.g. sqlite:trace.db
+    store: Store,
+
+    /// Write the report to this file instead of stdout, e.g. report.html
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// How many statements, errors and transactions each table lists
+    #[arg(long, default_value_t = 20)]
+    top: usize,
+
+    /// Width of each bar of the error timeline, e.g. 1m
+    #[arg(long, value_parser = units::parse_duration, default_value = "1m")]
+    bucket: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    events: u64,
+    first: Option<String>,
+    last: Option<String>,
+    databases: u64,
+    attachments: u64,
+    captures: u64,
+    thinning: Option<ThinningSummary>,
+}
+
+/// How `--sample` and `--rate-limit` thinned out the statements of the captures.
+#[derive(Debug, Default)]
+struct ThinningSummary {
+    captures: u64,
+    /// The options of each, e.g. `--sample 10%`.
+    options: Vec<String>,
+    dropped: u64,
+    /// Captures cut short before their counts were written, scaled by the sample alone.
+    uncounted: u64,
+}
+
+/// How many executions each kept statement of a capture stands for.
+fn capture_scales(
+    conn: &Connection,
+) -> rusqlite::Result<(HashMap<i64, f64>, Option<ThinningSummary>)> {
+    let mut scales = HashMap::new();
+    let mut summary = ThinningSummary::default();
+    let mut stmt = conn.prepare(
+        "SELECT id, sample, rate_limit, kept_statements, sampled_statements, limited_statements
+         FROM captures WHERE sample IS NOT NULL OR rate_limit IS NOT NULL ORDER BY id",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(r) = rows.next()? {
+        let sample: Option<f64> = r.get(1)?;
+        let rate: Option<String> = r.get(2)?;
+        let counts = match (r.get(3)?, r.get(4)?, r.get(5)?) {
+            (Some(kept), Some(sampled), Some(limited)) => Some(Counts {
+                kept,
+                sampled,
+                limited,
+            }),
+            _ => None,
+        };
+        let scale = match &counts {
+            Some(c) => throttle::scale(c),
+            None => 1.0 / sample.unwrap_or(1.0),
+        };
+        scales.insert(r.get(0)?, scale);
+
+        summary.captures += 1;
+        summary.dropped += counts.map_or(0, |c| c.dropped());
+        summary.uncounted += counts.is_none() as u64;
+        let options = Thinning {
+            sample,
+            rate: rate.and_then(|r| throttle::parse_rate(&r).ok()),
+            alert_threshold: None,
+        }
+        .describe();
+        if !summary.options.contains(&options) {
+            summary.options.push(options);
+        }
+    }
+    Ok((scales, (summary.captures > 0).then_some(summary)))
+}
+
+/// The executions of a statement fingerprint. Those of thinned captures are estimated,
+/// each kept one counted as the executions it stands for.
+#[derive(Debug, Default)]
+struct StatementStats {
+    durations: Histogram,
+    reads: i64,
+    writes: i64,
+    /// The variances of the estimated count and total time, for their intervals.
+    count_variance: f64,
+    sum_variance: f64,
+    /// The fraction of an execution left over from scaling, so the counts add up.
+    carry: f64,
+}
+
+impl StatementStats {
+    /// Counts an execution kept of those `scale` stands for.
+    fn record(&mut self, duration: i64, reads: i64, writes: i64, scale: f64) -> u64 {
+        let weight = self.carry + scale;
+        let n = weight.floor() as u64;
+        self.carry = weight - n as f64;
+        self.durations.record_n(duration, n);
+        self.reads += reads * n as i64;
+        self.writes += writes * n as i64;
+        // Each kept with probability 1/scale, so counted with a variance of (scale - 1) * scale.
+        let variance = (scale - 1.0) * scale;
+        self.count_variance += variance;
+        self.sum_variance += variance * (duration * duration) as f64;
+        n
+    }
+}
+
+/// `value`, and its 95% interval if it's an estimate.
+fn estimate(value: impl std::fmt::Display, variance: f64) -> String {
+    match variance {
+        v if v > 0.0 => format!("{value} ±{:.0}", 1.96 * v.sqrt()),
+        _ => value.to_string(),
+    }
+}
+
+#[derive(Debug, Default)]
+struct ErrorStats {
+    count: u64,
+    last_seen: String,
+}
+
+#[derive(Debug)]
+struct LongTransaction {
+    started_at: String,
+    duration_ms: i64,
+    outcome: String,
+    database: String,
+    user: String,
+    process: String,
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    summary: Summary,
+    statements: HashMap<String, StatementStats>,
+    histogram: [u64; DURATION_BUCKETS],
+    /// Failed events per time bucket.
+    error_timeline: BTreeMap<i64, u64>,
+    errors: HashMap<String, ErrorStats>,
+    outcomes: BTreeMap<String, u64>,
+    transactions: Vec<LongTransaction>,
+}
+
+pub fn run(args: &ReportArgs) -> Result<(), AppError> {
+    let Store::Sqlite(path) = &args.store;
+    let bucket_secs = args.bucket.as_secs().max(1) as i64;
+    let report = load(path, bucket_secs).map_err(|e| AppError::Dyn(Box::new(e)))?;
+    let html = render(&report, path, args.top, bucket_secs);
+
+    match &args.output {
+        Some(path) => std::fs::write(path, html).map_err(AppError::Io),
+        None => {
+            print!("{html}");
+            Ok(())
+        }
+    }
+}
+
+fn load(path: &str, bucket_secs: i64) -> rusqlite::Result<Report> {
+    let conn = Connection::open(path)?;
+    let summary = conn.query_row(
+        "SELECT count(*), min(timestamp), max(timestamp),
+            (SELECT count(DISTINCT server || ':' || database) FROM attachments),
+            (SELECT count(*) FROM attachments),
+            (SELECT count(*) FROM captures)
+         FROM events",
+        [],
+        |r| {
+            Ok(Summary {
+                events: r.get(0)?,
+                first: r.get(1)?,
+                last: r.get(2)?,
+                databases: r.get(3)?,
+                attachments: r.get(4)?,
+                captures: r.get(5)?,
+                thinning: None,
+            })
+        },
+    )?;
+    let (scales, thinning) = capture_scales(&conn)?;
+    let mut report = Report {
+        summary: Summary {
+            thinning,
+            ..summary
+        },
+        ..Default::default()
+    };
+
+    let mut stmt = conn.prepare(
+        "SELECT coalesce(s.fingerprint, s.sql), s.duration_ms, s.reads, s.writes,
+            e.capture_id, coalesce(s.thinned, 0)
+         FROM statements s
+         JOIN events e ON e.id = s.event_id
+         WHERE e.kind = 'EXECUTE_STATEMENT_FINISH' AND s.duration_ms IS NOT NULL",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(r) = rows.next()? {
+        let duration: i64 = r.get(1)?;
+        let capture: Option<i64> = r.get(4)?;
+        let scale = match r.get::<_, bool>(5)? {
+            true => capture.and_then(|c| scales.get(&c)).copied().unwrap_or(1.0),
+            false => 1.0,
+        };
+        let stats = report.statements.entry(r.get(0)?).or_default();
+        let n = stats.record(
+            duration,
+            r.get::<_, Option<i64>>(2)?.unwrap_or_default(),
+            r.get::<_, Option<i64>>(3)?.unwrap_or_default(),
+            scale,
+        );
+        report.histogram[duration_bucket(duration)] += n;
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT timestamp, kind, raw FROM events
+         WHERE failed OR kind IN ('ERROR', 'WARNING', 'LOCK_CONFLICT')
+         ORDER BY id",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(r) = rows.next()? {
+        let timestamp: String = r.get(0)?;
+        if let Some(t) = Event::parse_timestamp(&timestamp) {
+            let secs = t.and_utc().timestamp();
+            *report
+                .error_timeline
+                .entry(secs - secs.rem_euclid(bucket_secs))
+                .or_default() += 1;
+        }
+        let message = error_message(&r.get::<_, String>(1)?, &r.get::<_, String>(2)?);
+        let stats = report.errors.entry(message).or_default();
+        stats.count += 1;
+        stats.last_seen = timestamp;
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT coalesce(outcome, 'open'), count(*) FROM transactions GROUP BY 1 ORDER BY 1",
+    )?;
+    report.outcomes = stmt
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut stmt = conn.prepare(
+        "SELECT t.started_at, t.ended_at, t.outcome, a.database, a.user, coalesce(a.process, '')
+         FROM transactions t
+         JOIN attachments a ON a.id = t.attachment_id
+         WHERE t.started_at IS NOT NULL AND t.ended_at IS NOT NULL",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(r) = rows.next()? {
+        let started_at: String = r.get(0)?;
+        let (Some(start), Some(end)) = (
+            Event::parse_timestamp(&started_at),
+            Event::parse_timestamp(&r.get::<_, String>(1)?),
+        ) else {
+            continue;
+        };
+        report.transactions.push(LongTransaction {
+            started_at,
+            duration_ms: (end - start).num_milliseconds(),
+            outcome: r.get(2)?,
+            database: r.get(3)?,
+            user: r.get(4)?,
+            process: r.get(5)?,
+        });
+    }
+    report
+        .transactions
+        .sort_by_key(|t| std::cmp::Reverse(t.duration_ms));
+
+    Ok(report)
+}
+
+/// What went wrong in a failed event: the server's message, e.g. `335544345 : lock
+/// conflict on no wait transaction`, or otherwise the kind and location.
+fn error_message(kind: &str, raw: &str) -> String {
+    let Some(event) = parser::parse_all(raw).pop() else {
+        return kind.into();
+    };
+    if let Some(line) = event.lines.iter().map(|l| l.trim()).find(|l| {
+        l.split_once(" : ")
+            .is_some_and(|(code, _)| code.chars().all(|c| c.is_ascii_digit()))
+    }) {
+        return line.into();
+    }
+    match &event.location {
+        Some(location) => format!("{kind} at {location}"),
+        None if event.failed => format!("{kind} FAILED"),
+        None => kind.into(),
+    }
+}
+
+/// The value below which `p` of the sorted values fall.
+pub fn percentile(sorted: &[i64], p: f64) -> i64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((sorted.len() as f64 * p).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1]
+}
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em auto; max-width: 70em; color: #222; }
+h1 { font-size: 1.6em; } h2 { font-size: 1.2em; margin-top: 2em; border-bottom: 1px solid #ccc; }
+table { border-collapse: collapse; width: 100%; font-size: 0.9em; }
+th, td { text-align: left; padding: 0.3em 0.6em; border-bottom: 1px solid #eee; vertical-align: top; }
+td.n, th.n { text-align: right; font-variant-numeric: tabular-nums; }
+code { font-size: 0.95em; white-space: pre-wrap; word-break: break-word; }
+dl { display: grid; grid-template-columns: max-content auto; gap: 0.2em 1em; }
+dt { color: #666; }
+del { background: #fdd; } ins { background: #dfd; text-decoration: none; }
+";
+
+fn render(report: &Report, store: &str, top: usize, bucket_secs: i64) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <title>rsfbtrace report: {store}</title><style>{STYLE}</style></head><body>\n\
+         <h1>Trace report: {store}</h1>\n",
+        store = escape(store)
+    );
+
+    let s = &report.summary;
+    let _ = writeln!(
+        html,
+        "<dl><dt>Events</dt><dd>{}</dd><dt>From</dt><dd>{}</dd><dt>To</dt><dd>{}</dd>\
+         <dt>Databases</dt><dd>{}</dd><dt>Attachments</dt><dd>{}</dd>\
+         <dt>Captures</dt><dd>{}</dd><dt>Generated</dt><dd>{} by rsfbtrace {}</dd></dl>",
+        s.events,
+        escape(s.first.as_deref().unwrap_or("-")),
+        escape(s.last.as_deref().unwrap_or("-")),
+        s.databases,
+        s.attachments,
+        s.captures,
+        chrono::Local::now().format("%Y-%m-%dT%H:%M:%S"),
+        env!("CARGO_PKG_VERSION"),
+    );
+    if let Some(t) = &s.thinning {
+        let _ = write!(
+            html,
+            "<p><strong>Statements were thinned out</strong> by {} in {} of the captures, \
+             dropping {} of them. Executions, totals, percentiles, reads and writes are \
+             estimated from those kept, with 95% intervals; failed statements and those slow \
+             enough to alert on were all kept.",
+            escape(&t.options.join(", ")),
+            t.captures,
+            t.dropped,
+        );
+        if t.uncounted > 0 {
+            let _ = write!(
+                html,
+                " {} capture(s) ended before counting what was dropped, so only their sample \
+                 is scaled for and what a rate limit dropped from them is missing.",
+                t.uncounted
+            );
+        }
+        html.push_str("</p>\n");
+    }
+
+    render_statements(&mut html, report, top);
+    render_variants(&mut html, report, top);
+    render_errors(&mut html, report, top, bucket_secs);
+    render_transactions(&mut html, report, top);
+
+    html.push_str("</body></html>\n");
+    html
+}
+
+fn render_statements(html: &mut String, report: &Report, top: usize) {
+    html.push_str("<h2>Top statements by total time</h2>\n");
+    if report.statements.is_empty() {
+        html.push_str("<p>No finished statements with timings were recorded.</p>\n");
+        return;
+    }
+
+    let mut statements: Vec<(&String, &StatementStats)> = report.statements.iter().collect();
+    statements.sort_by_key(|(sql, s)| (std::cmp::Reverse(s.durations.sum()), *sql));
+
+    html.push_str(
+        "<table><tr><th>Statement</th><th class=\"n\">Executions</th><th class=\"n\">Total ms</th>\
+         <th class=\"n\">Mean ms</th><th class=\"n\">p50 ms</th><th class=\"n\">p90 ms</th>\
+         <th class=\"n\">p99 ms</th><th class=\"n\">Max ms</th>\
+         <th class=\"n\">Reads</th><th class=\"n\">Writes</th></tr>\n",
+    );
+    for (sql, stats) in statements.iter().take(top) {
+        let d = &stats.durations;
+        let _ = writeln!(
+            html,
+            "<tr><td><code>{}</code></td><td class=\"n\">{}</td><td class=\"n\">{}</td>\
+             <td class=\"n\">{}</td><td class=\"n\">{}</td><td class=\"n\">{}</td>\
+             <td class=\"n\">{}</td><td class=\"n\">{}</td>\
+             <td class=\"n\">{}</td><td class=\"n\">{}</td></tr>",
+            escape(sql),
+            estimate(d.count(), stats.count_variance),
+            estimate(d.sum(), stats.sum_variance),
+            d.mean(),
+            d.percentile(0.5),
+            d.percentile(0.9),
+            d.percentile(0.99),
+            d.max(),
+            stats.reads,
+            stats.writes,
+        );
+    }
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>Statement durations</h2>\n");
+    let bars: Vec<(String, u64)> = report
+        .histogram
+        .iter()
+        .enumerate()
+        .map(|(i, &count)| {
+            let label = match bucket_bound(i) {
+                Some(b) => format!("< {b} ms"),
+                None => format!(">= {} ms", 1 << (DURATION_BUCKETS - 2)),
+            };
+            (label, count)
+        })
+        .collect();
+    html.push_str(&bar_chart(&bars));
+}
+
+/// Statements nearly the same as one another, with the words they differ in marked.
+fn render_variants(html: &mut String, report: &Report, top: usize) {
+    let mut statements: Vec<(&String, &StatementStats)> = report.statements.iter().collect();
+    statements.sort_by_key(|(sql, s)| (std::cmp::Reverse(s.durations.sum()), *sql));
+    statements.truncate(variants::MAX_CANDIDATES);
+    let sql: Vec<&str> = statements.iter().map(|(sql, _)| sql.as_str()).collect();
+    let found = variants::find(&sql);
+    if found.is_empty() {
+        return;
+    }
+
+    html.push_str("<h2>Similar statements</h2>\n");
+    html.push_str(
+        "<p>Statements differing in only a few words, e.g. their column list, often come \
+         from different code paths building the same query. Words only in the first are \
+         <del>struck</del>, words only in the second <ins>added</ins>.</p>\n",
+    );
+    html.push_str(
+        "<table><tr><th>Difference</th><th class=\"n\">Similarity</th>\
+         <th class=\"n\">Executions</th><th class=\"n\">Total ms</th></tr>\n",
+    );
+    for v in found.iter().take(top) {
+        let (first, second) = (statements[v.first], statements[v.second]);
+        let mut diff = String::new();
+        for (tag, text) in variants::word_diff(first.0, second.0) {
+            let text = escape(&text);
+            let _ = match tag {
+                ChangeTag::Equal => write!(diff, "{text}"),
+                ChangeTag::Delete => write!(diff, "<del>{text}</del>"),
+                ChangeTag::Insert => write!(diff, "<ins>{text}</ins>"),
+            };
+        }
+        let total = |s: &StatementStats| s.durations.sum();
+        let _ = writeln!(
+            html,
+            "<tr><td><code>{diff}</code></td><td class=\"n\">{:.0}%</td>\
+             <td class=\"n\">{} / {}</td><td class=\"n\">{} / {}</td></tr>",
+            v.similarity * 100.0,
+            first.1.durations.count(),
+            second.1.durations.count(),
+            total(first.1),
+            total(second.1),
+        );
+    }
+    html.push_str("</table>\n");
+}
+
+fn render_errors(html: &mut String, report: &Report, top: usize, bucket_secs: i64) {
+    html.push_str("<h2>Errors</h2>\n");
+    if report.errors.is_empty() {
+        html.push_str("<p>No errors, warnings or failed events were recorded.</p>\n");
+        return;
+    }
+
+    // Every bucket between the first and last error, so quiet periods show as gaps.
+    let first = report
+        .error_timeline
+        .keys()
+        .next()
+        .copied()
+        .unwrap_or_default();
+    let last = report
+        .error_timeline
+        .keys()
+        .next_back()
+        .copied()
+        .unwrap_or_default();
+    let bars: Vec<(String, u64)> = (first..=last)
+        .step_by(bucket_secs as usize)
+        .map(|t| {
+            // The time of day, as the date is given above.
+            let time = format_time(t);
+            let label = time.split_once('T').map_or(time.as_str(), |(_, t)| t);
+            (
+                label.to_string(),
+                report.error_timeline.get(&t).copied().unwrap_or_default(),
+            )
+        })
+        .collect();
+    let _ = writeln!(
+        html,
+        "<p>Failed events per {bucket_secs}s, {} to {}</p>",
+        format_time(first),
+        format_time(last)
+    );
+    html.push_str(&bar_chart(&bars));
+
+    let mut errors: Vec<_> = report.errors.iter().collect();
+    errors.sort_by_key(|(message, e)| (std::cmp::Reverse(e.count), *message));
+    html.push_str("<table><tr><th>Error</th><th class=\"n\">Count</th><th>Last seen</th></tr>\n");
+    for (message, e) in errors.iter().take(top) {
+        let _ = writeln!(
+            html,
+            "<tr><td><code>{}</code></td><td class=\"n\">{}</td><td>{}</td></tr>",
+            escape(message),
+            e.count,
+            escape(&e.last_seen)
+        );
+    }
+    html.push_str("</table>\n");
+}
+
+fn render_transactions(html: &mut String, report: &Report, top: usize) {
+    html.push_str("<h2>Transactions</h2>\n");
+    if report.outcomes.is_empty() {
+        html.push_str("<p>No transactions were recorded.</p>\n");
+        return;
+    }
+
+    let mut durations: Vec<i64> = report.transactions.iter().map(|t| t.duration_ms).collect();
+    durations.sort_unstable();
+    html.push_str("<dl>");
+    for (outcome, count) in &report.outcomes {
+        let _ = write!(html, "<dt>{}</dt><dd>{count}</dd>", escape(outcome));
+    }
+    if !durations.is_empty() {
+        let _ = write!(
+            html,
+            "<dt>Median</dt><dd>{} ms</dd><dt>p95</dt><dd>{} ms</dd><dt>Longest</dt><dd>{} ms</dd>",
+            percentile(&durations, 0.5),
+            percentile(&durations, 0.95),
+            durations.last().copied().unwrap_or_default()
+        );
+    }
+    html.push_str("</dl>\n");
+
+    if report.transactions.is_empty() {
+        return;
+    }
+    html.push_str(
+        "<table><tr><th>Started</th><th class=\"n\">Duration ms</th><th>Outcome</th>\
+         <th>Database</th><th>User</th><th>Process</th></tr>\n",
+    );
+    for t in report.transactions.iter().take(top) {
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td class=\"n\">{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&t.started_at),
+            t.duration_ms,
+            escape(&t.outcome),
+            escape(&t.database),
+            escape(&t.user),
+            escape(&t.process)
+        );
+    }
+    html.push_str("</table>\n");
+}
+
+const CHART_HEIGHT: usize = 160;
+const LABEL_HEIGHT: usize = 70;
+
+/// A bar per `(label, count)`, with the labels written upwards underneath.
+fn bar_chart(bars: &[(String, u64)]) -> String {
+    let max = bars.iter().map(|(_, c)| *c).max().unwrap_or(1).max(1);
+    let bar = (720 / bars.len().max(1)).clamp(4, 40);
+    let width = bar * bars.len() + 40;
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{}" font-family="sans-serif" font-size="10">"#,
+        CHART_HEIGHT + LABEL_HEIGHT
+    );
+    let _ = writeln!(svg, r#"<text x="0" y="10">{max}</text>"#);
+    // Label every bar when they fit, or about 20 of them otherwise.
+    let every = (bars.len() / 20).max(1);
+    for (i, (label, count)) in bars.iter().enumerate() {
+        let height = (*count as usize * (CHART_HEIGHT - 14)) / max as usize;
+        let x = 40 + i * bar;
+        let _ = writeln!(
+            svg,
+            r#"<rect x="{x}" y="{}" width="{}" height="{height}" fill="rgb(200,30,30)"><title>{}: {count}</title></rect>"#,
+            CHART_HEIGHT - height,
+            bar.saturating_sub(1).max(1),
+            escape(label)
+        );
+        if i % every == 0 {
+            let (lx, ly) = (x + bar / 2, CHART_HEIGHT + 4);
+            let _ = writeln!(
+                svg,
+                r#"<text x="{lx}" y="{ly}" transform="rotate(90 {lx} {ly})">{}</text>"#,
+                escape(label)
+            );
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::statement;
+    use crate::sink::{sqlite::SqliteSink, Capture, Sink};
+
+    #[test]
+    fn finds_the_server_message_of_an_error() {
+        let raw = "2024-01-15T10:23:45.3450 (1234:00007F12AB) ERROR AT JStatement::execute
+\t/data/erp.fdb (ATT_12, ERP_APP:NONE, UTF8, TCPv4:10.0.0.5/51234)
+\t/opt/erp/bin/erp:4711
+335544345 : lock conflict on no wait transaction
+335544382 : deadlock";
+        assert_eq!(
+            error_message("ERROR", raw),
+            "335544345 : lock conflict on no wait transaction"
+        );
+        assert_eq!(percentile(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10], 0.95), 10);
+        assert_eq!(percentile(&[1, 2, 3, 4], 0.5), 2);
+    }
+
+    #[test]
+    fn scales_up_the_statements_of_thinned_captures() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.db");
+        let path = path.to_str().unwrap();
+        let capture = Capture {
+            thinning: Some(Thinning {
+                sample: Some(0.25),
+                rate: None,
+                alert_threshold: Some(Duration::from_millis(500)),
+            }),
+            ..Default::default()
+        };
+        let mut sink = SqliteSink::open(path, &capture).unwrap();
+        for (ms, sql) in [
+            (120, "select * from customers"),
+            (900, "select * from orders"),
+        ] {
+            let body = format!(
+                "{sql}\n{}\n\n{ms} ms, 10 read(s), 2 write(s), 30 fetch(es), 1 mark(s)",
+                "^".repeat(79)
+            );
+            sink.write(&statement(&body)).unwrap();
+        }
+        let counts = Counts {
+            kept: 1,
+            sampled: 3,
+            limited: 0,
+        };
+        sink.thinned(&counts).unwrap();
+        sink.finish().unwrap();
+
+        let report = load(path, 60).unwrap();
+        let customers = &report.statements["select * from customers"];
+        assert_eq!(customers.durations.count(), 4);
+        assert_eq!((customers.durations.sum(), customers.reads), (480, 40));
+        assert_eq!(customers.count_variance, 12.0);
+        // Slow enough to alert on, so kept whatever the sample.
+        let orders = &report.statements["select * from orders"];
+        assert_eq!((orders.durations.count(), orders.count_variance), (1, 0.0));
+        assert_eq!(report.histogram.iter().sum::<u64>(), 5);
+
+        let html = render(&report, path, 10, 60);
+        assert!(html.contains("by --sample 25% in 1 of the captures, dropping 3 of them"));
+        assert!(html.contains("<td class=\"n\">4 ±7</td>"));
+        assert!(
+            crate::sink::sqlite::thinning_note(&Connection::open(path).unwrap())
+                .unwrap()
+                .starts_with("3 statement events were dropped")
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! Checking that tracing can work from here, step by step, so a broken setup is told
+//! apart from a quiet server: the utilities are found, the service manager answers, the
+//! credentials are accepted and a trace session starts and sees events.
+
+use crate::error::AppError;
+use crate::event::{Event, EventKind};
+use crate::monitor;
+use crate::policy;
+use crate::session;
+use crate::tracemgr::{self, Connection, Echo};
+use crate::units;
+use std::cell::Cell;
+use std::io::{Error as IOError, Write};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CONFIG: &str = "<database>\n    enabled true\n    log_connections true\n</database>\n";
+
+#[derive(clap::Args, Debug)]
+pub struct SelftestArgs {
+    #[command(flatten)]
+    conn: Connection,
+
+    /// Attach to this database with isql while tracing, e.g. dbhost:/data/erp.fdb, to
+    /// check that its events arrive
+    #[arg(long)]
+    database: Option<String>,
+
+    /// How long to wait for the server at each step
+    #[arg(long, value_parser = units::parse_duration, default_value = "15s")]
+    timeout: Duration,
+
+    /// Start the test session even though the policy forbids tracing connections,
+    /// recording this reason
+    #[arg(long, value_name = "REASON")]
+    override_policy: Option<String>,
+}
+
+fn ok(step: &str, detail: &str) {
+    println!("ok    {step:<16}{detail}");
+}
+
+/// Marks `step` failed, leaving the reason to be printed as the error.
+fn failed(step: &str, error: AppError) -> AppError {
+    println!("FAIL  {step}");
+    error
+}
+
+pub fn run(mut args: SelftestArgs) -> Result<(), AppError> {
+    args.conn.read_pass_file()?;
+    let _tunnel = args.conn.open_tunnel()?;
+    let conn = &args.conn;
+
+    let path = conn.tool("fbtracemgr");
+    if !path.is_file() {
+        return Err(failed("fbtracemgr", AppError::TraceMgrNotFound));
+    }
+    ok("fbtracemgr", &path.display().to_string());
+
+    if !tracemgr::service_answers(conn, args.timeout) {
+        let error = IOError::other(format!(
+            "The service manager of {} didn't answer {} within {}s",
+            conn.server_name(),
+            conn.tool("fbsvcmgr").display(),
+            args.timeout.as_secs()
+        ));
+        return Err(failed("service manager", error.into()));
+    }
+    let version = tracemgr::server_version(conn).unwrap_or_else(|| "unknown".into());
+    ok(
+        "service manager",
+        &format!("{}, Firebird {version}", conn.server_name()),
+    );
+
+    let sessions = session::list(conn).map_err(|e| failed("credentials", e))?;
+    ok(
+        "credentials",
+        &format!("{} sees {} trace sessions", conn.user, sessions.len()),
+    );
+
+    let step = Cell::new("trace");
+    policy::check(
+        &policy::Trace {
+            servers: vec![conn.server_name().to_string()],
+            conn,
+            events: &["connections".into()],
+            given: &|_| false,
+            override_policy: args.override_policy.as_deref(),
+        },
+        true,
+    )
+    .map_err(|e| failed("trace", e))?;
+    trace(&args, &step).map_err(|e| failed(step.get(), e))
+}
+
+/// Starts a session tracing attachments, and waits for it to start and, with
+/// `--database`, for the attachment made to it.
+fn trace(args: &SelftestArgs, step: &Cell<&str>) -> Result<(), AppError> {
+    let conn = &args.conn;
+    let mut config = tempfile::NamedTempFile::new()?;
+    config.write_all(CONFIG.as_bytes())?;
+    let mut child = tracemgr::fbtracemgr(conn)
+        .args(["-START", "-NAME"])
+        .arg(session::session_name(Some("selftest"), &[]))
+        .arg("-CONFIG")
+        .arg(config.path())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().expect("piped");
+    let stderr = child.stderr.take().expect("piped");
+
+    let session_id = AtomicI64::new(0);
+    let failure = Mutex::new(None);
+    let (tx, rx) = mpsc::channel::<Event>();
+    thread::scope(|s| {
+        let reader =
+            s.spawn(|| tracemgr::read_trace(stdout, &tx, &session_id, &failure, Echo::Off, None));
+        // Drained so fbtracemgr can't block writing to it, and kept for the failure.
+        let stderr = s.spawn(|| std::io::read_to_string(stderr).unwrap_or_default());
+
+        let deadline = Instant::now() + args.timeout;
+        let mut exited = None;
+        while session_id.load(Ordering::SeqCst) == 0 && exited.is_none() {
+            if Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+            exited = child.try_wait()?;
+        }
+        if exited.is_some() {
+            // The banner may still be on its way through the reader.
+            let _ = reader.join();
+        }
+        let id = session_id.load(Ordering::SeqCst);
+        let result = match exited {
+            _ if id > 0 => {
+                ok("trace", &format!("session {id} started"));
+                step.set("events");
+                wait_for_attach(args, &rx)
+            }
+            Some(status) => {
+                let output = failure.lock().ok().and_then(|f| f.clone());
+                let output = output.unwrap_or_else(|| stderr.join().unwrap_or_default());
+                return Err(tracemgr::trace_failure(conn, &output, status));
+            }
+            None => Err(IOError::other(format!(
+                "No trace session was started within {}s",
+                args.timeout.as_secs()
+            ))
+            .into()),
+        };
+        tracemgr::stop_trace(conn, id, &mut child);
+        let _ = child.wait();
+        result
+    })
+}
+
+fn wait_for_attach(args: &SelftestArgs, rx: &mpsc::Receiver<Event>) -> Result<(), AppError> {
+    let Some(database) = &args.database else {
+        println!("      Give --database to check that events arrive as well.");
+        return Ok(());
+    };
+    let mut isql = monitor::isql(database, &args.conn.user, args.conn.pass())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = isql.stdin.take() {
+        stdin.write_all(b"SELECT 1 FROM RDB$DATABASE;\n")?;
+    }
+    let output = isql.wait_with_output()?;
+    if !output.status.success() {
+        return Err(IOError::other(format!(
+            "isql couldn't attach to {database}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+        .into());
+    }
+
+    let deadline = Instant::now() + args.timeout;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(timeout) {
+            Ok(event) if event.kind == EventKind::AttachDatabase => {
+                let attachment = event.attachment.unwrap_or_default();
+                ok(
+                    "events",
+                    &format!(
+                        "ATTACH_DATABASE of {} by {}",
+                        attachment.database, attachment.user
+                    ),
+                );
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => {
+                return Err(IOError::other(format!(
+                    "isql attached to {database}, but the trace saw no attachment within {}s",
+                    args.timeout.as_secs()
+                ))
+                .into());
+            }
+        }
+    }
+}
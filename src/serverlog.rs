@@ -0,0 +1,218 @@
+use crate::event::{Event, EventKind, Replication};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+const MONTHS: &[&str] = &[
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// How replication.log prefixes its messages.
+const SEVERITIES: &[&str] = &["ERROR", "WARNING", "INFO", "VERBOSE"];
+
+/// Follows firebird.log or replication.log from its current end, sending each new entry
+/// as a `SERVER_LOG` or `REPLICATION` event until `stop` is set. Entries are also printed
+/// if `echo` is set.
+pub fn tail(path: PathBuf, tx: Sender<Event>, stop: Arc<AtomicBool>, echo: bool) {
+    thread::spawn(move || {
+        let mut pos = match std::fs::metadata(&path) {
+            Ok(m) => m.len(),
+            Err(e) => {
+                eprintln!("Unable to read {}: {e}", path.display());
+                return;
+            }
+        };
+        let mut pending = String::new();
+        let mut entry: Vec<String> = vec![];
+
+        while !stop.load(Ordering::SeqCst) {
+            thread::sleep(POLL_INTERVAL);
+
+            let Ok(mut f) = File::open(&path) else {
+                continue;
+            };
+            let len = f.metadata().map(|m| m.len()).unwrap_or(0);
+            if len < pos {
+                // The log was truncated or rotated
+                pos = 0;
+            }
+            if len == pos || f.seek(SeekFrom::Start(pos)).is_err() {
+                continue;
+            }
+
+            let mut buf = vec![];
+            if let Ok(n) = f.read_to_end(&mut buf) {
+                pos += n as u64;
+            }
+            pending.push_str(&String::from_utf8_lossy(&buf));
+
+            while let Some(i) = pending.find('\n') {
+                let line: String = pending.drain(..=i).collect();
+                let line = line.trim_end_matches(['\r', '\n']);
+
+                if is_header(line) || line.trim().is_empty() {
+                    if let Some(event) = parse_entry(&entry) {
+                        if echo {
+                            println!("{}", event.raw);
+                        }
+                        let _ = tx.send(event);
+                    }
+                    entry.clear();
+                }
+                if !line.trim().is_empty() {
+                    entry.push(line.into());
+                }
+            }
+        }
+    });
+}
+
+/// Entries start with an unindented `SERVER<TAB>Mon Jan 15 10:23:45 2024` line.
+fn is_header(line: &str) -> bool {
+    !line.starts_with(char::is_whitespace)
+        && line
+            .split_once('\t')
+            .is_some_and(|(_, date)| parse_timestamp(date).is_some())
+}
+
+fn parse_entry(entry: &[String]) -> Option<Event> {
+    let (header, body) = entry.split_first()?;
+    let (server, date) = header.split_once('\t')?;
+    let (server, replication) = replication(server.trim(), body);
+
+    Some(Event {
+        id: String::new(),
+        timestamp: parse_timestamp(date)?,
+        process: String::new(),
+        kind: match replication {
+            Some(_) => EventKind::Replication,
+            None => EventKind::ServerLog,
+        },
+        failed: replication.as_ref().is_some_and(|r| r.severity == "ERROR"),
+        location: Some(server.into()),
+        attachment: None,
+        transaction: None,
+        statement: None,
+        records_fetched: None,
+        perf: None,
+        params: vec![],
+        tables: vec![],
+        tags: Default::default(),
+        replication,
+        error_code: None,
+        context: vec![],
+        lines: body.iter().map(|l| l.trim().to_string()).collect(),
+        raw: entry.join("\n"),
+    })
+}
+
+/// Tells replication entries from others. replication.log names the role of the database
+/// after the server, e.g. `DBHOST (replica)`, and firebird.log only mentions replication
+/// in the message.
+fn replication<'a>(server: &'a str, body: &[String]) -> (&'a str, Option<Replication>) {
+    let (server, role) = match server.strip_suffix(')').and_then(|s| s.rsplit_once(" (")) {
+        Some((server, role)) if role == "primary" || role == "replica" => {
+            (server, Some(role.to_string()))
+        }
+        _ => (server, None),
+    };
+
+    let mut database = None;
+    let mut message = vec![];
+    for line in body.iter().map(|l| l.trim()) {
+        match line.strip_prefix("Database: ") {
+            Some(db) if database.is_none() => database = Some(db.to_string()),
+            _ => message.push(line),
+        }
+    }
+    let message = message.join("\n");
+    let lower = message.to_lowercase();
+    if role.is_none() && !lower.contains("replica") {
+        return (server, None);
+    }
+
+    let prefixed = SEVERITIES.iter().find_map(|s| {
+        let rest = message.strip_prefix(s)?.strip_prefix(':')?;
+        Some((s.to_string(), rest.trim_start().to_string()))
+    });
+    let (severity, message) = match prefixed {
+        Some(prefixed) => prefixed,
+        None if lower.contains("error") => ("ERROR".into(), message),
+        None => ("INFO".into(), message),
+    };
+
+    (
+        server,
+        Some(Replication {
+            role,
+            database,
+            severity,
+            message,
+        }),
+    )
+}
+
+/// Converts `Mon Jan 15 10:23:45 2024` to the trace's `2024-01-15T10:23:45.0000`.
+fn parse_timestamp(date: &str) -> Option<String> {
+    let mut parts = date.split_whitespace();
+    parts.next()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month)? + 1;
+    let day: u32 = parts.next()?.parse().ok()?;
+    let time = parts.next()?;
+    let year: u32 = parts.next()?.parse().ok()?;
+
+    if time.len() != 8 {
+        return None;
+    }
+    Some(format!("{year:04}-{month:02}-{day:02}T{time}.0000"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(text: &str) -> Option<Event> {
+        let lines: Vec<String> = text.lines().map(String::from).collect();
+        parse_entry(&lines)
+    }
+
+    #[test]
+    fn classifies_replication_entries() {
+        let event = entry(
+            "DBHOST (replica)\tMon Jan 15 10:23:45 2024
+\tDatabase: /data/erp-replica.fdb
+\tERROR: Cannot apply the journal segment 42: lock conflict on no wait transaction",
+        )
+        .unwrap();
+        assert_eq!(event.kind, EventKind::Replication);
+        assert!(event.failed);
+        assert_eq!(event.location.as_deref(), Some("DBHOST"));
+        assert_eq!(
+            event.replication,
+            Some(Replication {
+                role: Some("replica".into()),
+                database: Some("/data/erp-replica.fdb".into()),
+                severity: "ERROR".into(),
+                message: "Cannot apply the journal segment 42: lock conflict on no wait \
+                          transaction"
+                    .into(),
+            })
+        );
+
+        let event = entry(
+            "DBHOST\tMon Jan 15 10:23:45 2024
+\tINET/inet_error: read errno = 104, client host = app01",
+        )
+        .unwrap();
+        assert_eq!(event.kind, EventKind::ServerLog);
+        assert_eq!(event.replication, None);
+    }
+}
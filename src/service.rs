@@ -0,0 +1,336 @@
+use crate::error::AppError;
+use crate::Cli;
+use clap::{Parser, ValueEnum};
+use std::path::PathBuf;
+use std::process::Command;
+
+const DEFAULT_SERVICE_NAME: &str = "rsfbtrace";
+
+#[cfg(not(windows))]
+const DEFAULT_UNIT_DIR: &str = "/etc/systemd/system";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RestartPolicy {
+    Always,
+    OnFailure,
+    No,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct InstallArgs {
+    /// Name of the service
+    #[arg(long, default_value = DEFAULT_SERVICE_NAME)]
+    name: String,
+
+    /// File holding the Firebird password, passed to the trace as --pass-file
+    #[arg(long)]
+    credential_file: Option<PathBuf>,
+
+    /// When the service manager should restart the trace
+    #[arg(long, value_enum, default_value_t = RestartPolicy::OnFailure)]
+    restart: RestartPolicy,
+
+    /// Directory the systemd unit is written to
+    #[cfg(not(windows))]
+    #[arg(long, default_value = DEFAULT_UNIT_DIR)]
+    unit_dir: PathBuf,
+
+    /// Print the generated unit instead of installing it
+    #[arg(long)]
+    print: bool,
+
+    /// Trace options for the service, e.g. -- -u SYSDBA -e statement_finish
+    #[arg(last = true, required = true)]
+    trace_args: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct UninstallArgs {
+    /// Name of the service
+    #[arg(long, default_value = DEFAULT_SERVICE_NAME)]
+    name: String,
+
+    /// Directory the systemd unit was written to
+    #[cfg(not(windows))]
+    #[arg(long, default_value = DEFAULT_UNIT_DIR)]
+    unit_dir: PathBuf,
+}
+
+/// The trace options the service runs with, checked up front so a typo doesn't turn
+/// into a service stuck in a restart loop.
+fn service_trace_args(args: &InstallArgs) -> Result<Vec<String>, AppError> {
+    let mut trace_args = args.trace_args.clone();
+    if let Some(cred) = &args.credential_file {
+        trace_args.push("--pass-file".into());
+        trace_args.push(cred.display().to_string());
+    }
+
+    if let Err(e) =
+        Cli::try_parse_from(std::iter::once("rsfbtrace".into()).chain(trace_args.clone()))
+    {
+        e.exit();
+    }
+    if trace_args.iter().any(|a| a == "-p" || a == "--pass") {
+        eprintln!("Warning: the password will be visible in the service definition; consider --credential-file");
+    }
+
+    Ok(trace_args)
+}
+
+fn run_command(cmd: &mut Command) -> Result<(), AppError> {
+    match cmd.status() {
+        Ok(s) if s.success() => Ok(()),
+        Ok(s) => Err(AppError::Dyn(format!("{cmd:?} exited with {s}").into())),
+        Err(e) => Err(AppError::Dyn(Box::new(e))),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn install(args: &InstallArgs) -> Result<(), AppError> {
+    let trace_args = service_trace_args(args)?;
+    let exe = match std::env::current_exe() {
+        Ok(e) => e,
+        Err(e) => return Err(AppError::Io(e)),
+    };
+
+    let exec_start = std::iter::once(exe.display().to_string())
+        .chain(trace_args)
+        .map(|a| systemd_quote(&a))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let restart = match args.restart {
+        RestartPolicy::Always => "always",
+        RestartPolicy::OnFailure => "on-failure",
+        RestartPolicy::No => "no",
+    };
+
+    // SIGINT reaches fbtracemgr too, which ends the session the same way Ctrl+C does
+    // interactively, letting rsfbtrace flush its stores before exiting.
+    let unit = format!(
+        r#"[Unit]
+Description=rsfbtrace Firebird trace ({name})
+After=network-online.target firebird.service
+Wants=network-online.target
+
+[Service]
+Type=simple
+ExecStart={exec_start}
+StateDirectory={name}
+WorkingDirectory=/var/lib/{name}
+Restart={restart}
+RestartSec=10
+KillSignal=SIGINT
+
+[Install]
+WantedBy=multi-user.target
+"#,
+        name = args.name,
+    );
+
+    if args.print {
+        print!("{unit}");
+        return Ok(());
+    }
+
+    let path = args.unit_dir.join(format!("{}.service", args.name));
+    if let Err(e) = std::fs::write(&path, unit) {
+        return Err(AppError::Io(e));
+    }
+    println!("Wrote {}", path.display());
+
+    run_command(Command::new("systemctl").arg("daemon-reload"))?;
+    run_command(Command::new("systemctl").args(["enable", "--now", &args.name]))
+}
+
+#[cfg(not(windows))]
+pub fn uninstall(args: &UninstallArgs) -> Result<(), AppError> {
+    run_command(Command::new("systemctl").args(["disable", "--now", &args.name]))?;
+
+    let path = args.unit_dir.join(format!("{}.service", args.name));
+    if let Err(e) = std::fs::remove_file(&path) {
+        return Err(AppError::Io(e));
+    }
+    println!("Removed {}", path.display());
+
+    run_command(Command::new("systemctl").arg("daemon-reload"))
+}
+
+/// Quotes an ExecStart argument, escaping systemd's `%` specifiers and `$` expansion.
+#[cfg(not(windows))]
+fn systemd_quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    if escaped.is_empty() || escaped.contains(|c: char| c.is_whitespace() || c == '\'' || c == ';')
+    {
+        format!("\"{escaped}\"")
+    } else {
+        escaped
+    }
+}
+
+#[cfg(windows)]
+pub fn install(args: &InstallArgs) -> Result<(), AppError> {
+    let trace_args = service_trace_args(args)?;
+    let exe = match std::env::current_exe() {
+        Ok(e) => e,
+        Err(e) => return Err(AppError::Io(e)),
+    };
+
+    let bin_path = [
+        exe.display().to_string(),
+        "run-service".into(),
+        args.name.clone(),
+    ]
+    .into_iter()
+    .chain(trace_args)
+    .map(|a| windows_quote(&a))
+    .collect::<Vec<_>>()
+    .join(" ");
+
+    if args.print {
+        println!("{bin_path}");
+        return Ok(());
+    }
+
+    run_command(Command::new("sc.exe").args([
+        "create",
+        &args.name,
+        "binPath=",
+        &bin_path,
+        "start=",
+        "auto",
+        "DisplayName=",
+        &format!("rsfbtrace Firebird trace ({})", args.name),
+    ]))?;
+
+    // Windows never restarts a service that stopped cleanly, so `always` and
+    // `on-failure` both come down to failure actions. The failure flag makes a trace
+    // ending with an error count as a failure, not just a crash.
+    if args.restart != RestartPolicy::No {
+        run_command(Command::new("sc.exe").args([
+            "failure",
+            &args.name,
+            "reset=",
+            "86400",
+            "actions=",
+            "restart/10000/restart/10000/restart/60000",
+        ]))?;
+        run_command(Command::new("sc.exe").args(["failureflag", &args.name, "1"]))?;
+    }
+
+    run_command(Command::new("sc.exe").args(["start", &args.name]))
+}
+
+#[cfg(windows)]
+pub fn uninstall(args: &UninstallArgs) -> Result<(), AppError> {
+    // The service may already be stopped
+    let _ = Command::new("sc.exe").args(["stop", &args.name]).status();
+    run_command(Command::new("sc.exe").args(["delete", &args.name]))
+}
+
+#[cfg(windows)]
+fn windows_quote(arg: &str) -> String {
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"') {
+        format!("\"{}\"", arg.replace('"', "\\\""))
+    } else {
+        arg.into()
+    }
+}
+
+#[cfg(windows)]
+pub fn run(name: &str, trace_args: &[String]) -> Result<(), AppError> {
+    windows::run(name, trace_args)
+}
+
+#[cfg(windows)]
+mod windows {
+    use crate::error::AppError;
+    use crate::{Cli, SHUTDOWN};
+    use clap::Parser;
+    use std::ffi::OsString;
+    use std::sync::atomic::Ordering;
+    use std::sync::OnceLock;
+    use std::time::Duration;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    static SERVICE: OnceLock<(String, Vec<String>)> = OnceLock::new();
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    pub fn run(name: &str, trace_args: &[String]) -> Result<(), AppError> {
+        let _ = SERVICE.set((name.into(), trace_args.to_vec()));
+        match service_dispatcher::start(name, ffi_service_main) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(AppError::Dyn(Box::new(e))),
+        }
+    }
+
+    fn status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: if state == ServiceState::Running {
+                ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+            } else {
+                ServiceControlAccept::empty()
+            },
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        }
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let Some((name, trace_args)) = SERVICE.get() else {
+            return;
+        };
+
+        let handle = match service_control_handler::register(name, |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                SHUTDOWN.store(true, Ordering::SeqCst);
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }) {
+            Ok(h) => h,
+            Err(_) => return,
+        };
+        let _ = handle.set_service_status(status(ServiceState::Running, 0));
+
+        // Services start in System32, which is no place for relative paths like stores.
+        if let Some(dir) = std::env::var_os("ProgramData") {
+            let dir = std::path::Path::new(&dir).join("rsfbtrace").join(name);
+            if std::fs::create_dir_all(&dir).is_ok() {
+                let _ = std::env::set_current_dir(dir);
+            }
+        }
+
+        let result =
+            Cli::try_parse_from(std::iter::once("rsfbtrace".into()).chain(trace_args.clone()))
+                .map_err(|e| AppError::Dyn(Box::new(e)))
+                .and_then(|cli| match cli.trace {
+                    Some(args) => crate::run_trace(args),
+                    None => Ok(()),
+                });
+
+        let exit_code = match result {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("{e}");
+                1
+            }
+        };
+        let _ = handle.set_service_status(status(ServiceState::Stopped, exit_code));
+    }
+}
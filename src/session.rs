@@ -0,0 +1,669 @@
+//! Inspecting trace sessions running on the server.
+
+use crate::error::AppError;
+use crate::tracemgr::{self, Connection};
+use crate::TRACE_NAME;
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+
+#[derive(Subcommand, Debug)]
+pub enum SessionCmd {
+    /// List the trace sessions running on the server, with their tags
+    List(ListArgs),
+    /// Show a running trace session and the config it was started with
+    Show(ShowArgs),
+    /// Stop a trace session, by the --name it was started with or its ID
+    Stop(StopArgs),
+    /// Restart the trace of a session whose rsfbtrace ended without stopping it, e.g. in
+    /// a crash, with the options it was started with
+    Resume(ResumeArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ListArgs {
+    #[command(flatten)]
+    conn: Connection,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ShowArgs {
+    #[command(flatten)]
+    conn: Connection,
+
+    /// ID of the session, as listed by fbtracemgr -LIST
+    #[arg(long)]
+    id: i64,
+
+    /// Compare the session's config with this file
+    #[arg(long)]
+    diff: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct StopArgs {
+    #[command(flatten)]
+    conn: Connection,
+
+    /// Name or ID of the session
+    session: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ResumeArgs {
+    #[command(flatten)]
+    conn: Connection,
+
+    /// Name or ID of the session
+    session: String,
+}
+
+/// A session as listed by `fbtracemgr -LIST`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub id: i64,
+    /// `name`, `user`, `date` and `flags`, in the order the server lists them.
+    pub fields: Vec<(String, String)>,
+}
+
+impl SessionInfo {
+    fn field(&self, name: &str) -> &str {
+        self.fields
+            .iter()
+            .find(|(k, _)| k == name)
+            .map_or("", |(_, v)| v)
+    }
+
+    /// The `--name` of a session started by rsfbtrace, from its name on the server.
+    pub fn name(&self) -> Option<String> {
+        parse_session_name(self.field("name")).0
+    }
+
+    /// The tags of a session started by rsfbtrace, from its name on the server.
+    pub fn tags(&self) -> Vec<(String, String)> {
+        parse_session_name(self.field("name")).1
+    }
+}
+
+/// Parses a session `--name`, e.g. `billing-slow`. It's part of the session name next to
+/// the tags, so it's limited to the characters of a tag name.
+pub fn parse_name(s: &str) -> Result<String, String> {
+    if s.is_empty() || !s.chars().all(|c| c.is_alphanumeric() || "_-.".contains(c)) {
+        return Err(format!(
+            "'{s}' is not a valid session name. Use letters, digits, '_', '-' and '.'."
+        ));
+    }
+    Ok(s.into())
+}
+
+/// Parses a `--tag`, e.g. `ticket=OPS-123`. Tags end up in the session name, separated
+/// by spaces, so neither keys nor values may contain whitespace.
+pub fn parse_tag(s: &str) -> Result<(String, String), String> {
+    let Some((key, value)) = s.split_once('=') else {
+        return Err(format!(
+            "'{s}' is not a tag. Expected e.g. 'ticket=OPS-123'."
+        ));
+    };
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_alphanumeric() || "_-.".contains(c))
+    {
+        return Err(format!(
+            "'{key}' is not a valid tag name. Use letters, digits, '_', '-' and '.'."
+        ));
+    }
+    if value.is_empty() || value.contains(char::is_whitespace) {
+        return Err(format!(
+            "The value of tag '{key}' must not be empty or contain whitespace"
+        ));
+    }
+    Ok((key.into(), value.into()))
+}
+
+/// The name rsfbtrace gives its sessions on the server, e.g.
+/// `rust-fbtrace billing-slow ticket=OPS-123 operator=ana`.
+pub fn session_name(name: Option<&str>, tags: &[(String, String)]) -> String {
+    let mut session_name = TRACE_NAME.to_string();
+    if let Some(name) = name {
+        session_name.push_str(&format!(" {name}"));
+    }
+    for (k, v) in tags {
+        session_name.push_str(&format!(" {k}={v}"));
+    }
+    session_name
+}
+
+/// The `--name` and tags of a session started by rsfbtrace.
+fn parse_session_name(session_name: &str) -> (Option<String>, Vec<(String, String)>) {
+    let Some(rest) = session_name.strip_prefix(TRACE_NAME) else {
+        return (None, vec![]);
+    };
+    let mut words = rest.split_whitespace().peekable();
+    let name = words.next_if(|w| !w.contains('=')).map(String::from);
+    let tags = words
+        .filter_map(|t| t.split_once('='))
+        .map(|(k, v)| (k.into(), v.into()))
+        .collect();
+    (name, tags)
+}
+
+pub fn run(cmd: SessionCmd) -> Result<(), AppError> {
+    match cmd {
+        SessionCmd::List(args) => list_sessions(args),
+        SessionCmd::Show(args) => show(args),
+        SessionCmd::Stop(args) => stop(args),
+        SessionCmd::Resume(args) => resume(args),
+    }
+}
+
+fn list_sessions(mut args: ListArgs) -> Result<(), AppError> {
+    args.conn.read_pass_file()?;
+    let _tunnel = args.conn.open_tunnel()?;
+
+    let sessions = list(&args.conn)?;
+    for session in &sessions {
+        let tags: Vec<String> = session
+            .name()
+            .into_iter()
+            .chain(session.tags().iter().map(|(k, v)| format!("{k}={v}")))
+            .collect();
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            session.id,
+            session.field("user"),
+            session.field("date"),
+            session.field("flags"),
+            if tags.is_empty() {
+                session.field("name").to_string()
+            } else {
+                tags.join(" ")
+            }
+        );
+    }
+    reconcile(&args.conn, &sessions);
+    Ok(())
+}
+
+/// Compares the sessions recorded on this machine with those running on the server:
+/// the records of sessions that have ended are removed, and sessions still running
+/// after their rsfbtrace ended without stopping them are pointed out.
+fn reconcile(conn: &Connection, sessions: &[SessionInfo]) {
+    for (path, state) in recorded_states(conn) {
+        if !sessions.iter().any(|s| s.id == state.id) {
+            let _ = std::fs::remove_file(&path);
+            if let Some(config) = config_path(conn, state.id) {
+                let _ = std::fs::remove_file(config);
+            }
+            continue;
+        }
+        if process_running(state.pid) {
+            continue;
+        }
+        eprintln!(
+            "Session {} is still running, but process {} that started it on {} is not. \
+             `session resume {}` restarts its trace, `session stop {}` stops it.",
+            state.id,
+            state.pid,
+            state.started_at,
+            state.key(),
+            state.id,
+        );
+    }
+}
+
+/// Restarts the trace of a session whose rsfbtrace is gone. Firebird can't hand the
+/// output of a running session to another connection, so the session is stopped and
+/// the trace started again with the recorded command line.
+fn resume(mut args: ResumeArgs) -> Result<(), AppError> {
+    args.conn.read_pass_file()?;
+    let _tunnel = args.conn.open_tunnel()?;
+
+    let state = match args.session.parse::<i64>() {
+        Ok(id) => recorded_states(&args.conn)
+            .into_iter()
+            .map(|(_, s)| s)
+            .find(|s| s.id == id),
+        Err(_) => read_state(&args.conn, &args.session),
+    };
+    let Some(state) = state.filter(|s| !s.args.is_empty()) else {
+        return Err(AppError::InvalidArgs(format!(
+            "Session {} wasn't started by rsfbtrace on this machine, or not by a version \
+             recording its options",
+            args.session
+        )));
+    };
+    if process_running(state.pid) {
+        return Err(AppError::InvalidArgs(format!(
+            "Session {} is still read by process {}",
+            state.id, state.pid
+        )));
+    }
+
+    if list(&args.conn)?.iter().any(|s| s.id == state.id) {
+        let out = tracemgr::fbtracemgr(&args.conn)
+            .args(["-STOP", "-ID", &state.id.to_string()])
+            .output()?;
+        if !out.status.success() {
+            return Err(tracemgr::trace_failure(
+                &args.conn,
+                &String::from_utf8_lossy(&out.stderr),
+                out.status,
+            ));
+        }
+        eprintln!("Stopped session {}", state.id);
+    }
+    if let Some(path) = state_path(&args.conn, &state.key()) {
+        let _ = std::fs::remove_file(path);
+    }
+    if let Some(path) = config_path(&args.conn, state.id) {
+        let _ = std::fs::remove_file(path);
+    }
+
+    eprintln!("Resuming the trace started on {}", state.started_at);
+    let mut cmd = std::process::Command::new(std::env::current_exe()?);
+    cmd.args(&state.args);
+    // The password was left out of the record.
+    if !state.args.iter().any(|a| a.starts_with("--pass-file")) {
+        cmd.env("ISC_PASSWORD", args.conn.pass());
+    }
+    let status = cmd.status()?;
+    match status.code() {
+        Some(0) => Ok(()),
+        _ => Err(AppError::Dyn(
+            format!("The resumed trace exited with {status}").into(),
+        )),
+    }
+}
+
+/// Whether process `pid` is running on this machine.
+fn process_running(pid: u32) -> bool {
+    let pid = pid.to_string();
+    let out = if cfg!(windows) {
+        std::process::Command::new("tasklist")
+            .args(["/FI", &format!("PID eq {pid}"), "/NH"])
+            .output()
+    } else {
+        std::process::Command::new("kill")
+            .args(["-0", &pid])
+            .stderr(std::process::Stdio::null())
+            .output()
+    };
+    match out {
+        Ok(out) if cfg!(windows) => String::from_utf8_lossy(&out.stdout)
+            .split_whitespace()
+            .any(|w| w == pid),
+        Ok(out) => out.status.success(),
+        // Assume it is, rather than suggest taking over a session that's being read.
+        Err(_) => true,
+    }
+}
+
+/// Lists the trace sessions on the server.
+pub fn list(conn: &Connection) -> Result<Vec<SessionInfo>, AppError> {
+    let out = match tracemgr::fbtracemgr(conn).arg("-LIST").output() {
+        Ok(o) => o,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(AppError::TraceMgrNotFound),
+        Err(e) => return Err(AppError::Io(e)),
+    };
+    if !out.status.success() {
+        return Err(tracemgr::trace_failure(
+            conn,
+            &String::from_utf8_lossy(&out.stderr),
+            out.status,
+        ));
+    }
+    Ok(parse_list(&String::from_utf8_lossy(&out.stdout)))
+}
+
+/// Parses the `Session ID: N` blocks printed by `fbtracemgr -LIST`.
+fn parse_list(text: &str) -> Vec<SessionInfo> {
+    let mut sessions: Vec<SessionInfo> = vec![];
+    for line in text.lines() {
+        if let Some(id) = line
+            .strip_prefix("Session ID:")
+            .and_then(|id| id.trim().parse().ok())
+        {
+            sessions.push(SessionInfo { id, fields: vec![] });
+        } else if let (Some(session), Some((k, v))) = (sessions.last_mut(), line.split_once(':')) {
+            session.fields.push((k.trim().into(), v.trim().into()));
+        }
+    }
+    sessions
+}
+
+fn show(mut args: ShowArgs) -> Result<(), AppError> {
+    args.conn.read_pass_file()?;
+    let _tunnel = args.conn.open_tunnel()?;
+
+    let Some(session) = list(&args.conn)?.into_iter().find(|s| s.id == args.id) else {
+        return Err(AppError::InvalidArgs(format!(
+            "There is no trace session with ID {}",
+            args.id
+        )));
+    };
+
+    println!("Session {}", session.id);
+    for (k, v) in &session.fields {
+        println!("  {k}: {v}");
+    }
+    for (k, v) in session.tags() {
+        println!("  tag {k}: {v}");
+    }
+
+    // The services API doesn't report the config of a running session, so only sessions
+    // started by rsfbtrace on this machine, which records it, can be compared.
+    let Some(config) = recorded_config(&args.conn, session.id) else {
+        return Err(AppError::Dyn(
+            format!(
+                "The config of session {} is unknown: the server doesn't report it, and the session wasn't started by rsfbtrace on this machine",
+                session.id
+            )
+            .into(),
+        ));
+    };
+
+    let Some(path) = &args.diff else {
+        println!();
+        println!("{}", config.trim());
+        return Ok(());
+    };
+
+    let local = match std::fs::read_to_string(path) {
+        Ok(l) => l,
+        Err(e) => return Err(AppError::Io(e)),
+    };
+
+    let (running, local) = (normalize(&config), normalize(&local));
+    println!();
+    if running == local {
+        println!("The session's config matches {}", path.display());
+        return Ok(());
+    }
+
+    let diff = TextDiff::from_lines(&running, &local);
+    print!(
+        "{}",
+        diff.unified_diff().header(
+            &format!("session {}", session.id),
+            &path.display().to_string()
+        )
+    );
+    Err(AppError::ConfigMismatch(path.display().to_string()))
+}
+
+fn stop(mut args: StopArgs) -> Result<(), AppError> {
+    args.conn.read_pass_file()?;
+    let _tunnel = args.conn.open_tunnel()?;
+
+    let sessions = list(&args.conn)?;
+    let session = match args.session.parse::<i64>() {
+        Ok(id) => sessions.iter().find(|s| s.id == id),
+        Err(_) => find_named(&args.conn, &sessions, &args.session),
+    };
+    let Some(session) = session else {
+        return Err(AppError::InvalidArgs(format!(
+            "There is no trace session {}",
+            args.session
+        )));
+    };
+
+    let out = tracemgr::fbtracemgr(&args.conn)
+        .args(["-STOP", "-ID", &session.id.to_string()])
+        .output()?;
+    if !out.status.success() {
+        return Err(tracemgr::trace_failure(
+            &args.conn,
+            &String::from_utf8_lossy(&out.stderr),
+            out.status,
+        ));
+    }
+
+    let started_by = read_state(&args.conn, session.name().as_deref().unwrap_or_default())
+        .filter(|s| s.id == session.id)
+        .map_or(String::new(), |s| format!(", started by process {}", s.pid));
+    eprintln!("Stopped session {}{started_by}", session.id);
+    Ok(())
+}
+
+/// The running session started with `--name name`, preferring the one this machine
+/// recorded, as sessions started elsewhere can be given the same name.
+fn find_named<'a>(
+    conn: &Connection,
+    sessions: &'a [SessionInfo],
+    name: &str,
+) -> Option<&'a SessionInfo> {
+    let named = || {
+        sessions
+            .iter()
+            .filter(|s| s.name().as_deref() == Some(name))
+    };
+    read_state(conn, name)
+        .and_then(|state| named().find(|s| s.id == state.id))
+        .or_else(|| named().next())
+}
+
+/// Fails if a session named `name` is already running, so `session stop` can tell them
+/// apart.
+pub fn check_name_free(conn: &Connection, name: &str) -> Result<(), AppError> {
+    match list(conn)?
+        .iter()
+        .find(|s| s.name().as_deref() == Some(name))
+    {
+        Some(s) => Err(AppError::InvalidArgs(format!(
+            "A trace session named {name} is already running, with ID {}",
+            s.id
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Drops what doesn't change the meaning of a config: indentation, spacing and comments.
+fn normalize(config: &str) -> String {
+    config
+        .lines()
+        .map(|l| l.split('#').next().unwrap_or_default())
+        .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|l| !l.is_empty())
+        .map(|l| l + "\n")
+        .collect()
+}
+
+pub fn state_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(|d| PathBuf::from(d).join("rsfbtrace"))
+    } else if let Some(d) = std::env::var_os("XDG_STATE_HOME") {
+        Some(PathBuf::from(d).join("rsfbtrace"))
+    } else {
+        std::env::var_os("HOME").map(|d| PathBuf::from(d).join(".local/state/rsfbtrace"))
+    }
+}
+
+/// The server's name as part of a file name.
+fn host_key(conn: &Connection) -> String {
+    conn.server_name()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn config_path(conn: &Connection, id: i64) -> Option<PathBuf> {
+    let host = host_key(conn);
+    state_dir().map(|d| d.join("sessions").join(format!("{host}-{id}.conf")))
+}
+
+/// Where a named session's state is kept, e.g. `sessions/dbhost-billing-slow.json`.
+fn state_path(conn: &Connection, name: &str) -> Option<PathBuf> {
+    let host = host_key(conn);
+    state_dir().map(|d| d.join("sessions").join(format!("{host}-{name}.json")))
+}
+
+/// What's recorded about a session started on this machine, under its name or, for
+/// sessions without one, its ID.
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionState {
+    id: i64,
+    pid: u32,
+    started_at: String,
+    #[serde(default)]
+    name: Option<String>,
+    /// The command line of the trace, without the password.
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+impl SessionState {
+    /// What the session is recorded under.
+    fn key(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.id.to_string())
+    }
+}
+
+fn read_state(conn: &Connection, name: &str) -> Option<SessionState> {
+    let text = std::fs::read_to_string(state_path(conn, name)?).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+/// The sessions recorded for `conn`'s server, with the files they're recorded in.
+fn recorded_states(conn: &Connection) -> Vec<(PathBuf, SessionState)> {
+    let prefix = format!("{}-", host_key(conn));
+    let Some(Ok(entries)) = state_dir().map(|d| std::fs::read_dir(d.join("sessions"))) else {
+        return vec![];
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.extension().is_some_and(|x| x == "json")
+                && p.file_name()
+                    .is_some_and(|n| n.to_string_lossy().starts_with(&prefix))
+        })
+        .filter_map(|p| {
+            let state = serde_json::from_str(&std::fs::read_to_string(&p).ok()?).ok()?;
+            Some((p, state))
+        })
+        .collect()
+}
+
+fn recorded_config(conn: &Connection, id: i64) -> Option<String> {
+    std::fs::read_to_string(config_path(conn, id)?).ok()
+}
+
+/// A file recorded about a session started by this process, e.g. its config for
+/// `session show`. It is removed again when dropped.
+pub struct Recorded(PathBuf);
+
+impl Drop for Recorded {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Records the config session `id` was started with. Failing to do so only affects
+/// `session show`, so it's reported but not fatal.
+pub fn record_config(conn: &Connection, id: i64, config: &str) -> Option<Recorded> {
+    record(config_path(conn, id)?, config, "session config")
+}
+
+/// Records that session `id` was started by this process, with `--name name` if given,
+/// for `session stop`, and with which options, for `session resume`. Left behind when
+/// the process doesn't end cleanly, which is how `session list` knows.
+pub fn record_state(conn: &Connection, name: Option<&str>, id: i64) -> Option<Recorded> {
+    let state = SessionState {
+        id,
+        pid: std::process::id(),
+        started_at: chrono::Local::now().format("%Y-%m-%dT%H:%M:%S").to_string(),
+        name: name.map(String::from),
+        args: without_password(std::env::args().skip(1)),
+    };
+    let json = serde_json::to_string(&state).unwrap_or_default();
+    let key = name.map_or(id.to_string(), String::from);
+    record(state_path(conn, &key)?, &json, "session state")
+}
+
+/// A command line with the values of `--pass` and `--serve-token` left out.
+fn without_password(args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut kept = vec![];
+    let mut skip = false;
+    for arg in args {
+        if std::mem::take(&mut skip) {
+            continue;
+        }
+        match arg.as_str() {
+            "-p" | "--pass" | "--serve-token" => skip = true,
+            a if a.starts_with("--pass=")
+                || a.starts_with("--serve-token=")
+                || (a.starts_with("-p") && !a.starts_with("--")) => {}
+            _ => kept.push(arg),
+        }
+    }
+    kept
+}
+
+fn record(path: PathBuf, contents: &str, what: &str) -> Option<Recorded> {
+    let result = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| crate::create_private_file(&path))
+        .and_then(|mut f| f.write_all(contents.as_bytes()));
+
+    match result {
+        Ok(()) => Some(Recorded(path)),
+        Err(e) => {
+            eprintln!("Unable to record the {what} in {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_round_trip_through_the_session_name() {
+        let tags = vec![
+            parse_tag("ticket=OPS-123").unwrap(),
+            parse_tag("purpose=slow-checkout").unwrap(),
+        ];
+        let name = session_name(None, &tags);
+        assert_eq!(name, "rust-fbtrace ticket=OPS-123 purpose=slow-checkout");
+        assert_eq!(parse_session_name(&name), (None, tags.clone()));
+        assert_eq!(parse_session_name("other ticket=1"), (None, vec![]));
+
+        let name = session_name(Some("billing-slow"), &tags);
+        assert_eq!(
+            parse_session_name(&name),
+            (Some("billing-slow".into()), tags)
+        );
+        assert!(parse_tag("ticket").is_err());
+        assert!(parse_tag("ticket=two words").is_err());
+    }
+
+    #[test]
+    fn recorded_command_lines_leave_out_the_password() {
+        let args = "-u SYSDBA -p masterkey --pass=x -pmasterkey --pass y --pass-file pw -e errors \
+                    --serve 0.0.0.0:8080 --serve-token abcdefghijklmnop --serve-token=qrstuvwxyz012345";
+        assert_eq!(
+            without_password(args.split_whitespace().map(String::from)),
+            [
+                "-u",
+                "SYSDBA",
+                "--pass-file",
+                "pw",
+                "-e",
+                "errors",
+                "--serve",
+                "0.0.0.0:8080"
+            ]
+        );
+    }
+}
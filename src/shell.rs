@@ -0,0 +1,463 @@
+//! An interactive prompt for narrowing down the events of a store step by step.
+
+use crate::error::AppError;
+use crate::export::{event_row, EVENT_COLUMNS};
+use crate::sink::{sqlite, Store};
+use crate::variants;
+use rusqlite::{params_from_iter, Connection, OpenFlags};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::{BufRead, IsTerminal, Write};
+
+const HELP: &str = "\
+filter KEY=VALUE...   Only consider matching events. Keys are db, user, kind, remote,
+                      process, sql, fingerprint, since, until, capture and tag.NAME
+filter                Show the current filters
+filter clear          Remove all filters
+list [N]              List the first N matching events (default 20)
+top [N] [by METRIC]   The N statements with the highest METRIC (default 10 by total_ms).
+                      Metrics are count, total_ms, avg_ms, max_ms, reads, writes,
+                      fetches and marks
+variants [N]          The N pairs of statements nearly the same as one another (default
+                      10), marking words only in the first as [-...-] and only in the
+                      second as {+...+}
+show ID               Print an event, by its ID
+export last [FILE]    Write the events behind the last list, top or variants as JSON lines
+help                  Print this help
+quit                  Leave the shell";
+
+/// Statement metrics `top` can rank by, as SQL over `statements s`.
+const METRICS: &[(&str, &str)] = &[
+    ("count", "count(*)"),
+    ("total_ms", "sum(s.duration_ms)"),
+    ("avg_ms", "avg(s.duration_ms)"),
+    ("max_ms", "max(s.duration_ms)"),
+    ("reads", "sum(s.reads)"),
+    ("writes", "sum(s.writes)"),
+    ("fetches", "sum(s.fetches)"),
+    ("marks", "sum(s.marks)"),
+];
+
+#[derive(clap::Args, Debug)]
+pub struct ShellArgs {
+    /// The store to query, e.g. sqlite:archive.db. It can still be written to by a
+    /// running trace
+    store: Store,
+}
+
+pub fn run(args: &ShellArgs) -> Result<(), AppError> {
+    let Store::Sqlite(path) = &args.store;
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| AppError::Dyn(Box::new(e)))?;
+    if let Some(note) = sqlite::thinning_note(&conn) {
+        eprintln!("{path}: {note}");
+    }
+    let mut shell = Shell {
+        conn,
+        filters: BTreeMap::new(),
+        last: vec![],
+    };
+
+    let interactive = std::io::stdin().is_terminal();
+    if interactive {
+        println!("Type help for the commands.");
+    }
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        if interactive {
+            print!("rsfbtrace> ");
+            std::io::stdout().flush()?;
+        }
+        let Some(line) = lines.next() else {
+            break;
+        };
+        match shell.execute(line?.trim()) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => eprintln!("{e}"),
+        }
+    }
+    Ok(())
+}
+
+struct Shell {
+    conn: Connection,
+    filters: BTreeMap<String, String>,
+    /// The events behind the output of the last `list`, `top` or `variants`, for
+    /// `export last`.
+    last: Vec<i64>,
+}
+
+impl Shell {
+    /// Runs a command, returning whether to keep going.
+    fn execute(&mut self, line: &str) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let sql_error = |e: rusqlite::Error| e.to_string();
+        match words.as_slice() {
+            [] => {}
+            ["quit" | "exit"] => return Ok(false),
+            ["help"] => println!("{HELP}"),
+            ["filter"] => {
+                for (k, v) in &self.filters {
+                    println!("{k}={v}");
+                }
+            }
+            ["filter", "clear"] => self.filters.clear(),
+            ["filter", pairs @ ..] => {
+                for pair in pairs {
+                    let Some((k, v)) = pair.split_once('=') else {
+                        return Err(format!("Expected KEY=VALUE, got '{pair}'"));
+                    };
+                    filter_condition(k)?;
+                    self.filters.insert(k.into(), v.into());
+                }
+            }
+            ["list", rest @ ..] => {
+                let n = count(rest.first(), 20)?;
+                self.list(n).map_err(sql_error)?;
+            }
+            ["top", rest @ ..] => {
+                let (n, metric) = match rest {
+                    [] => (10, "total_ms"),
+                    ["by", m] => (10, *m),
+                    [n] => (count(Some(n), 10)?, "total_ms"),
+                    [n, "by", m] => (count(Some(n), 10)?, *m),
+                    _ => return Err("Expected top [N] [by METRIC]".into()),
+                };
+                let Some((_, expr)) = METRICS.iter().find(|(m, _)| *m == metric) else {
+                    return Err(format!(
+                        "Unknown metric '{metric}'. Expected one of {:?}.",
+                        METRICS.iter().map(|(m, _)| m).collect::<Vec<_>>()
+                    ));
+                };
+                self.top(n, expr).map_err(sql_error)?;
+            }
+            ["variants", rest @ ..] if rest.len() <= 1 => {
+                let n = count(rest.first(), 10)?;
+                self.variants(n).map_err(sql_error)?;
+            }
+            ["show", id] => self.show(id).map_err(sql_error)?,
+            ["export", "last", file @ ..] if file.len() <= 1 => self
+                .export_last(file.first().copied())
+                .map_err(|e| e.to_string())?,
+            _ => {
+                return Err(format!(
+                    "Unknown command '{line}'. Type help for the commands."
+                ))
+            }
+        }
+        Ok(true)
+    }
+
+    /// The filters as SQL over `events e`, `attachments a` and `statements s`.
+    fn conditions(&self) -> (String, Vec<String>) {
+        let mut sql = String::from("1 = 1");
+        let mut values = vec![];
+        for (k, v) in &self.filters {
+            // Keys were checked when the filter was added.
+            if let Ok(condition) = filter_condition(k) {
+                sql.push_str(" AND ");
+                sql.push_str(&condition);
+                values.push(match k.as_str() {
+                    "kind" => v.to_uppercase(),
+                    "db" | "remote" | "process" | "sql" | "fingerprint" => format!("%{v}%"),
+                    _ => v.clone(),
+                });
+            }
+        }
+        (sql, values)
+    }
+
+    fn list(&mut self, n: usize) -> rusqlite::Result<()> {
+        let (conditions, values) = self.conditions();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT e.id, coalesce(e.uid, CAST(e.id AS TEXT)), e.timestamp, e.kind, s.duration_ms, s.sql
+             FROM events e
+             LEFT JOIN attachments a ON a.id = e.attachment_id
+             LEFT JOIN statements s ON s.event_id = e.id
+             WHERE {conditions}
+             ORDER BY e.id
+             LIMIT {n}"
+        ))?;
+        let mut rows = stmt.query(params_from_iter(&values))?;
+
+        self.last.clear();
+        while let Some(r) = rows.next()? {
+            self.last.push(r.get(0)?);
+            let duration: Option<i64> = r.get(4)?;
+            let sql: Option<String> = r.get(5)?;
+            println!(
+                "{}  {}  {:<28} {:>8}  {}",
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, String>(3)?,
+                duration.map(|d| format!("{d} ms")).unwrap_or_default(),
+                sql.as_deref().map(first_line).unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+
+    /// Ranks statements by `metric`, an aggregate from `METRICS`.
+    fn top(&mut self, n: usize, metric: &str) -> rusqlite::Result<()> {
+        let (conditions, values) = self.conditions();
+        let from = format!(
+            "FROM statements s
+             JOIN events e ON e.id = s.event_id
+             LEFT JOIN attachments a ON a.id = s.attachment_id
+             WHERE s.duration_ms IS NOT NULL AND {conditions}"
+        );
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT coalesce(s.fingerprint, s.sql), count(*), sum(s.duration_ms),
+                    avg(s.duration_ms), max(s.duration_ms), {metric}
+                 {from}
+                 GROUP BY 1 ORDER BY 6 DESC LIMIT {n}"
+        ))?;
+        let mut rows = stmt.query(params_from_iter(&values))?;
+
+        let mut keys = vec![];
+        println!(
+            "{:>6} {:>10} {:>10} {:>10}  statement",
+            "count", "total_ms", "avg_ms", "max_ms"
+        );
+        while let Some(r) = rows.next()? {
+            let key: String = r.get(0)?;
+            let (count, total, avg, max): (i64, i64, f64, i64) =
+                (r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?);
+            println!(
+                "{count:>6} {total:>10} {avg:>10.1} {max:>10}  {}",
+                first_line(&key)
+            );
+            keys.push(key);
+        }
+        drop(rows);
+        drop(stmt);
+
+        self.remember(&from, &values, &keys)
+    }
+
+    /// Pairs up the statements nearly the same as one another, among those taking the
+    /// most time.
+    fn variants(&mut self, n: usize) -> rusqlite::Result<()> {
+        let (conditions, values) = self.conditions();
+        let from = format!(
+            "FROM statements s
+             JOIN events e ON e.id = s.event_id
+             LEFT JOIN attachments a ON a.id = s.attachment_id
+             WHERE s.duration_ms IS NOT NULL AND {conditions}"
+        );
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT coalesce(s.fingerprint, s.sql), count(*), sum(s.duration_ms)
+                 {from}
+                 GROUP BY 1 ORDER BY 3 DESC LIMIT {}",
+            variants::MAX_CANDIDATES
+        ))?;
+        let statements: Vec<(String, i64, i64)> = stmt
+            .query_map(params_from_iter(&values), |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        drop(stmt);
+
+        let sql: Vec<&str> = statements.iter().map(|(sql, _, _)| sql.as_str()).collect();
+        let found = variants::find(&sql);
+        if found.is_empty() {
+            println!("No statements are nearly the same as another.");
+        }
+        let mut keys = vec![];
+        for v in found.iter().take(n) {
+            let (first, second) = (&statements[v.first], &statements[v.second]);
+            println!(
+                "{:.0}% similar, {} and {} executions, {} and {} ms in total:",
+                v.similarity * 100.0,
+                first.1,
+                second.1,
+                first.2,
+                second.2
+            );
+            println!(
+                "  {}\n",
+                variants::to_text(&variants::word_diff(&first.0, &second.0))
+            );
+            keys.extend([first.0.clone(), second.0.clone()]);
+        }
+        keys.sort();
+        keys.dedup();
+        self.remember(&from, &values, &keys)
+    }
+
+    /// Keeps the events of the statements `keys`, within `from`, for `export last`.
+    fn remember(&mut self, from: &str, values: &[String], keys: &[String]) -> rusqlite::Result<()> {
+        self.last.clear();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT e.id {from} AND coalesce(s.fingerprint, s.sql) = ?{} ORDER BY e.id",
+            values.len() + 1
+        ))?;
+        for key in keys {
+            let ids = stmt.query_map(params_from_iter(values.iter().chain([key])), |r| r.get(0))?;
+            for id in ids {
+                self.last.push(id?);
+            }
+        }
+        self.last.sort_unstable();
+        Ok(())
+    }
+
+    fn show(&self, id: &str) -> rusqlite::Result<()> {
+        let raw: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT raw FROM events WHERE uid = ?1 OR CAST(id AS TEXT) = ?1",
+                [id],
+                |r| r.get(0),
+            )
+            .map(Some)
+            .or_else(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                e => Err(e),
+            })?;
+        match raw {
+            Some(raw) => println!("{raw}"),
+            None => eprintln!("There is no event with ID {id}"),
+        }
+        Ok(())
+    }
+
+    fn export_last(&self, file: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT {EVENT_COLUMNS} FROM events WHERE id = ?1"))?;
+        let mut out = String::new();
+        for id in &self.last {
+            let (mut event, raw) = stmt.query_row([id], event_row)?;
+            event["raw"] = json!(raw);
+            out.push_str(&event.to_string());
+            out.push('\n');
+        }
+
+        match file {
+            Some(path) => {
+                std::fs::write(path, out)?;
+                eprintln!("Wrote {} events to {path}", self.last.len());
+            }
+            None => print!("{out}"),
+        }
+        Ok(())
+    }
+}
+
+/// The SQL condition for a filter key, with the value as a parameter.
+fn filter_condition(key: &str) -> Result<String, String> {
+    Ok(match key {
+        "db" => "a.database LIKE ?".into(),
+        "user" => "a.user = upper(?)".into(),
+        "kind" => "e.kind = ?".into(),
+        "remote" => "a.remote LIKE ?".into(),
+        "process" => "a.process LIKE ?".into(),
+        "sql" => "s.sql LIKE ?".into(),
+        "fingerprint" => "s.fingerprint LIKE ?".into(),
+        "since" => "e.timestamp >= ?".into(),
+        "until" => "e.timestamp < ?".into(),
+        "capture" => "e.capture_id = ?".into(),
+        _ => match key.strip_prefix("tag.") {
+            Some(tag)
+                if !tag.is_empty()
+                    && tag
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || c == '_' || c == '-') =>
+            {
+                format!("json_extract(e.tags, '$.\"{tag}\"') = ?")
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown filter '{key}'. Type help for the filters."
+                ))
+            }
+        },
+    })
+}
+
+fn count(word: Option<&&str>, default: usize) -> Result<usize, String> {
+    match word {
+        Some(w) => w.parse().map_err(|_| format!("'{w}' is not a number")),
+        None => Ok(default),
+    }
+}
+
+pub fn first_line(sql: &str) -> String {
+    let line = sql.lines().next().unwrap_or_default();
+    match line.char_indices().nth(80) {
+        Some((i, _)) => format!("{}\u{2026}", &line[..i]),
+        None if sql.lines().nth(1).is_some() => format!("{line}\u{2026}"),
+        None => line.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{sqlite::SqliteSink, Capture, Sink};
+
+    #[test]
+    fn top_and_export_follow_the_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.db");
+        let path = path.to_str().unwrap();
+
+        let mut sink = SqliteSink::open(path, &Capture::default()).unwrap();
+        let trace = "2024-01-15T10:23:45.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH
+\t/data/erp.fdb (ATT_12, ERP_APP:NONE, UTF8, TCPv4:10.0.0.5/51234)
+\t\t(TRA_45, CONCURRENCY | WAIT | READ_WRITE)
+
+Statement 7:
+-------------------------------------------------------------------------------
+select * from customers
+^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
+
+0 records fetched
+    120 ms, 10 read(s), 2 write(s), 30 fetch(es), 1 mark(s)
+
+2024-01-15T10:23:46.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH
+\t/data/hr.fdb (ATT_13, HR_APP:NONE, UTF8, TCPv4:10.0.0.6/51234)
+\t\t(TRA_46, CONCURRENCY | WAIT | READ_WRITE)
+
+Statement 8:
+-------------------------------------------------------------------------------
+select * from employees
+^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
+
+0 records fetched
+    900 ms, 10 read(s), 2 write(s), 30 fetch(es), 1 mark(s)
+";
+        for event in crate::fixtures::parse_all(trace) {
+            sink.write(&event).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let mut shell = Shell {
+            conn: Connection::open(path).unwrap(),
+            filters: BTreeMap::new(),
+            last: vec![],
+        };
+        assert_eq!(shell.execute("filter db=ERP"), Ok(true));
+        assert_eq!(shell.execute("top 5 by max_ms"), Ok(true));
+        assert_eq!(shell.last, vec![1]);
+
+        assert_eq!(shell.execute("filter clear"), Ok(true));
+        assert_eq!(shell.execute("top"), Ok(true));
+        assert_eq!(shell.last, vec![1, 2]);
+
+        assert_eq!(shell.execute("filter db=ERP"), Ok(true));
+        assert_eq!(shell.execute("variants"), Ok(true));
+        assert!(shell.last.is_empty());
+        assert_eq!(shell.execute("filter clear"), Ok(true));
+        assert_eq!(shell.execute("variants 1"), Ok(true));
+        assert_eq!(shell.last, vec![1, 2]);
+
+        assert!(shell.execute("filter database=ERP").is_err());
+        assert_eq!(shell.execute("list"), Ok(true));
+        assert_eq!(shell.last, vec![1, 2]);
+        assert_eq!(shell.execute("quit"), Ok(false));
+    }
+}
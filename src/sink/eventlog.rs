@@ -0,0 +1,106 @@
+//! Writing events to the Windows Application event log.
+
+use super::Sink;
+use crate::alert;
+use crate::event::{Event, EventKind};
+use crate::format;
+use std::error::Error;
+use std::time::Duration;
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+    EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE, REPORT_EVENT_TYPE,
+};
+
+/// The source events are logged under.
+const SOURCE: &str = "rsfbtrace";
+
+/// Event IDs, so views can tell trace events from alerts without reading the message.
+const EVENT_ID: u32 = 1;
+const ALERT_ID: u32 = 2;
+
+/// The longest string an event log entry holds, in UTF-16 units.
+const MAX_MESSAGE: usize = 31839;
+
+pub struct EventLogSink {
+    handle: HANDLE,
+    compat: u32,
+    alert_threshold: Option<Duration>,
+}
+
+impl EventLogSink {
+    pub fn open(compat: u32, alert_threshold: Option<Duration>) -> Result<Self, Box<dyn Error>> {
+        let source = wide(SOURCE);
+        // SAFETY: `source` is a NUL-terminated UTF-16 string that outlives the call.
+        let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+        if handle == 0 {
+            return Err(format!(
+                "Unable to open the event log: {}",
+                std::io::Error::last_os_error()
+            )
+            .into());
+        }
+        Ok(Self {
+            handle,
+            compat,
+            alert_threshold,
+        })
+    }
+}
+
+impl Sink for EventLogSink {
+    fn write(&mut self, event: &Event) -> Result<(), Box<dyn Error>> {
+        let reason = alert::reason(event, self.alert_threshold);
+        let json = format::to_json(event, self.compat)?;
+        let (kind, id, text) = match reason {
+            Some(r) => (
+                event_type(event, reason),
+                ALERT_ID,
+                format!(r#"{{"reason":"{r}","event":{json}}}"#),
+            ),
+            None => (event_type(event, reason), EVENT_ID, json),
+        };
+
+        let mut message: Vec<u16> = text.encode_utf16().take(MAX_MESSAGE).collect();
+        message.push(0);
+        let strings = [message.as_ptr()];
+        // SAFETY: `handle` is open until drop, and `strings` holds one NUL-terminated
+        // UTF-16 string that outlives the call.
+        let ok = unsafe {
+            ReportEventW(
+                self.handle,
+                kind,
+                0,
+                id,
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                std::ptr::null(),
+            )
+        };
+        if ok == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EventLogSink {
+    fn drop(&mut self) {
+        // SAFETY: `handle` came from RegisterEventSourceW and isn't used after this.
+        unsafe { DeregisterEventSource(self.handle) };
+    }
+}
+
+fn event_type(event: &Event, reason: Option<&str>) -> REPORT_EVENT_TYPE {
+    match (&event.kind, reason) {
+        (_, Some("error" | "replication_error")) => EVENTLOG_ERROR_TYPE,
+        (EventKind::Warning, _) | (_, Some(_)) => EVENTLOG_WARNING_TYPE,
+        _ => EVENTLOG_INFORMATION_TYPE,
+    }
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
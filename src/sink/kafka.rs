@@ -0,0 +1,91 @@
+//! Publishing events to a Kafka topic, to collect the traces of many servers in one place.
+
+use super::Sink;
+use crate::event::Event;
+use crate::format;
+use kafka::producer::{Producer, Record, RequiredAcks};
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+/// Events are sent in batches, as a round trip to the broker per event can't keep up
+/// with a busy server. A batch is sent once it's this big or this old.
+const BATCH_SIZE: usize = 200;
+const BATCH_AGE: Duration = Duration::from_secs(1);
+
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct KafkaSink {
+    producer: Producer,
+    topic: String,
+    compat: u32,
+    /// Events waiting to be sent, as the database they're from, which makes the events
+    /// of a database keep their order within a partition, and their JSON.
+    pending: Vec<(String, String)>,
+    since: Instant,
+}
+
+impl KafkaSink {
+    pub fn open(brokers: &[String], topic: &str, compat: u32) -> Result<Self, Box<dyn Error>> {
+        let producer = Producer::from_hosts(brokers.to_vec())
+            .with_client_id("rsfbtrace".into())
+            .with_required_acks(RequiredAcks::One)
+            .with_ack_timeout(ACK_TIMEOUT)
+            .create()
+            .map_err(|e| format!("Unable to connect to Kafka at {}: {e}", brokers.join(",")))?;
+        Ok(Self {
+            producer,
+            topic: topic.into(),
+            compat,
+            pending: vec![],
+            since: Instant::now(),
+        })
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let records: Vec<_> = self
+            .pending
+            .iter()
+            .map(|(key, json)| Record::from_key_value(&self.topic, key.as_str(), json.as_str()))
+            .collect();
+        let confirms = self.producer.send_all(&records)?;
+        for partition in confirms.iter().flat_map(|c| &c.partition_confirms) {
+            if let Err(code) = partition.offset {
+                return Err(format!(
+                    "Kafka refused events for {} partition {}: {code:?}",
+                    self.topic, partition.partition
+                )
+                .into());
+            }
+        }
+        self.pending.clear();
+        self.since = Instant::now();
+        Ok(())
+    }
+}
+
+impl Sink for KafkaSink {
+    fn write(&mut self, event: &Event) -> Result<(), Box<dyn Error>> {
+        if self.pending.is_empty() {
+            self.since = Instant::now();
+        }
+        let json = format::to_json(event, self.compat)?;
+        let key = event
+            .attachment
+            .as_ref()
+            .map(|a| a.database.clone())
+            .unwrap_or_default();
+        self.pending.push((key, json));
+
+        if self.pending.len() >= BATCH_SIZE || self.since.elapsed() >= BATCH_AGE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.flush()
+    }
+}
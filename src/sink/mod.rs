@@ -0,0 +1,207 @@
+use crate::event::Event;
+use crate::throttle::{Counts, Thinning};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(windows)]
+pub mod eventlog;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "nats")]
+pub mod nats;
+pub mod pipe;
+pub mod serve;
+pub mod sqlite;
+pub mod stdout;
+pub mod syslog;
+
+/// A destination for parsed trace events.
+pub trait Sink {
+    fn write(&mut self, event: &Event) -> Result<(), Box<dyn Error>>;
+
+    /// Called once the trace has ended, to flush anything buffered.
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Called as a session ends with what `--sample` and `--rate-limit` did to its
+    /// statement events, for stores to scale their counts by.
+    fn thinned(&mut self, _counts: &Counts) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// The bytes this sink has added to stdout or disk, counted toward `--max-output`.
+    fn written(&self) -> u64 {
+        0
+    }
+}
+
+/// How a trace was run, recorded by stores next to its events so an export can show
+/// exactly what produced them.
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    pub config: String,
+    pub tags: BTreeMap<String, String>,
+    /// The server's engine version, when it could be queried.
+    pub server_version: Option<String>,
+    /// How statement events were thinned out, if they were.
+    pub thinning: Option<Thinning>,
+}
+
+/// A `--store` target, written as `<kind>:<location>`.
+#[derive(Debug, Clone)]
+pub enum Store {
+    Sqlite(String),
+}
+
+impl FromStr for Store {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("sqlite", path)) if !path.is_empty() => Ok(Self::Sqlite(path.into())),
+            _ => Err(format!(
+                "'{s}' is not a valid store. Expected e.g. 'sqlite:trace.db'."
+            )),
+        }
+    }
+}
+
+impl Store {
+    pub fn open(&self, capture: &Capture) -> Result<Box<dyn Sink>, Box<dyn Error>> {
+        match self {
+            Self::Sqlite(path) => Ok(Box::new(sqlite::SqliteSink::open(path, capture)?)),
+        }
+    }
+}
+
+/// An `--output` target for events, next to stdout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Syslog(syslog::Transport),
+    /// The Windows Application event log.
+    EventLog,
+    #[cfg(feature = "kafka")]
+    Kafka {
+        brokers: Vec<String>,
+        topic: String,
+    },
+    #[cfg(feature = "nats")]
+    Nats(nats::Target),
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "'{s}' is not a valid output. Expected e.g. 'syslog', 'syslog:udp://host:514', \
+                 'syslog:tcp://host:601', 'syslog:unix:/dev/log', 'eventlog', \
+                 'kafka://broker:9092/topic' or 'nats://host:4222/subject'."
+            )
+        };
+        if let Some(url) = s.strip_prefix("kafka://") {
+            return kafka_output(url).ok_or_else(invalid)?;
+        }
+        if let Some(url) = s.strip_prefix("nats://") {
+            return nats_output(url).ok_or_else(invalid)?;
+        }
+        let transport = match s.split_once(':') {
+            None if s == "syslog" => syslog::Transport::Unix(syslog::LOCAL_SOCKET.into()),
+            None if s == "eventlog" => {
+                return if cfg!(windows) {
+                    Ok(Self::EventLog)
+                } else {
+                    Err("The event log is only available on Windows".into())
+                };
+            }
+            Some(("syslog", target)) => match target.split_once(':') {
+                Some(("udp", addr)) => syslog::Transport::Udp(host_port(addr).ok_or_else(invalid)?),
+                Some(("tcp", addr)) => syslog::Transport::Tcp(host_port(addr).ok_or_else(invalid)?),
+                Some(("unix", path)) if !path.is_empty() => {
+                    syslog::Transport::Unix(path.trim_start_matches("//").into())
+                }
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        };
+        Ok(Self::Syslog(transport))
+    }
+}
+
+/// The output for a `kafka://broker:9092[,broker:9092...]/topic` URL, without the scheme.
+#[cfg(feature = "kafka")]
+fn kafka_output(url: &str) -> Option<Result<Output, String>> {
+    let (brokers, topic) = url.split_once('/')?;
+    let brokers: Vec<String> = brokers.split(',').map(String::from).collect();
+    let valid_topic = topic
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if topic.is_empty() || !valid_topic || brokers.iter().any(|b| !b.contains(':')) {
+        return None;
+    }
+    Some(Ok(Output::Kafka {
+        brokers,
+        topic: topic.into(),
+    }))
+}
+
+#[cfg(not(feature = "kafka"))]
+fn kafka_output(_: &str) -> Option<Result<Output, String>> {
+    Some(Err(
+        "This rsfbtrace was built without Kafka support. Build it with --features kafka.".into(),
+    ))
+}
+
+#[cfg(feature = "nats")]
+fn nats_output(url: &str) -> Option<Result<Output, String>> {
+    nats::parse_url(url).map(|t| Ok(Output::Nats(t)))
+}
+
+#[cfg(not(feature = "nats"))]
+fn nats_output(_: &str) -> Option<Result<Output, String>> {
+    Some(Err(
+        "This rsfbtrace was built without NATS support. Build it with --features nats.".into(),
+    ))
+}
+
+/// The `host:port` of a `//host:port` address.
+fn host_port(addr: &str) -> Option<String> {
+    let addr = addr.strip_prefix("//")?.trim_end_matches('/');
+    let (host, port) = addr.rsplit_once(':')?;
+    (!host.is_empty() && port.parse::<u16>().is_ok()).then(|| addr.to_string())
+}
+
+impl Output {
+    /// Opens the output, writing events as JSON in `compat`'s format. Events worth an
+    /// alert at `alert_threshold` are sent with a higher severity and marked as alerts.
+    pub fn open(
+        &self,
+        compat: u32,
+        alert_threshold: Option<Duration>,
+    ) -> Result<Box<dyn Sink>, Box<dyn Error>> {
+        match self {
+            Self::Syslog(transport) => Ok(Box::new(syslog::SyslogSink::open(
+                transport,
+                compat,
+                alert_threshold,
+            )?)),
+            #[cfg(windows)]
+            Self::EventLog => Ok(Box::new(eventlog::EventLogSink::open(
+                compat,
+                alert_threshold,
+            )?)),
+            #[cfg(not(windows))]
+            Self::EventLog => unreachable!("only parsed on Windows"),
+            #[cfg(feature = "kafka")]
+            Self::Kafka { brokers, topic } => {
+                Ok(Box::new(kafka::KafkaSink::open(brokers, topic, compat)?))
+            }
+            #[cfg(feature = "nats")]
+            Self::Nats(target) => Ok(Box::new(nats::NatsSink::open(target, compat)?)),
+        }
+    }
+}
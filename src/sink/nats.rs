@@ -0,0 +1,204 @@
+//! Publishing events to a NATS subject, to collect the traces of many servers in one
+//! place. Core NATS is a line protocol, simple enough to speak without a client library.
+
+use super::Sink;
+use crate::event::Event;
+use crate::format;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A NATS server to publish to, from a `nats://[user:pass@|token@]host:port/subject` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub server: String,
+    pub subject: String,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    pub token: Option<String>,
+}
+
+pub struct NatsSink {
+    stream: Arc<Mutex<TcpStream>>,
+    subject: String,
+    compat: u32,
+}
+
+impl NatsSink {
+    pub fn open(target: &Target, compat: u32) -> Result<Self, Box<dyn Error>> {
+        let connect_failed = |e: &dyn std::fmt::Display| {
+            format!("Unable to connect to NATS at {}: {e}", target.server)
+        };
+        let addr = target
+            .server
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("{} doesn't resolve to an address", target.server))?;
+        let stream =
+            TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).map_err(|e| connect_failed(&e))?;
+
+        // The server starts with an INFO line describing itself.
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let info: Value = line
+            .strip_prefix("INFO ")
+            .and_then(|i| serde_json::from_str(i.trim()).ok())
+            .ok_or_else(|| connect_failed(&"not a NATS server"))?;
+        if info["tls_required"].as_bool() == Some(true) {
+            return Err(connect_failed(&"the server requires TLS, which isn't supported").into());
+        }
+
+        let mut connect = json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "rsfbtrace",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+        });
+        if let Some(user) = &target.user {
+            connect["user"] = json!(user);
+            connect["pass"] = json!(target.pass.as_deref().unwrap_or_default());
+        }
+        if let Some(token) = &target.token {
+            connect["auth_token"] = json!(token);
+        }
+        let mut writer = stream.try_clone()?;
+        write!(writer, "CONNECT {connect}\r\nPING\r\n")?;
+
+        // The PING's PONG confirms the CONNECT was accepted; a refusal comes as -ERR.
+        line.clear();
+        reader.read_line(&mut line)?;
+        if !line.starts_with("PONG") {
+            return Err(connect_failed(&line.trim()).into());
+        }
+
+        let stream = Arc::new(Mutex::new(stream));
+        let pinged = Arc::clone(&stream);
+        // The server disconnects clients that don't answer its PINGs.
+        std::thread::spawn(move || {
+            for line in reader.lines() {
+                let Ok(line) = line else { break };
+                if line.starts_with("PING") {
+                    if let Ok(mut s) = pinged.lock() {
+                        let _ = s.write_all(b"PONG\r\n");
+                    }
+                } else if let Some(err) = line.strip_prefix("-ERR ") {
+                    eprintln!("NATS: {err}");
+                }
+            }
+        });
+
+        Ok(Self {
+            stream,
+            subject: target.subject.clone(),
+            compat,
+        })
+    }
+}
+
+impl Sink for NatsSink {
+    fn write(&mut self, event: &Event) -> Result<(), Box<dyn Error>> {
+        let json = format::to_json(event, self.compat)?;
+        let mut stream = self
+            .stream
+            .lock()
+            .map_err(|_| "the NATS connection failed")?;
+        write!(stream, "PUB {} {}\r\n{json}\r\n", self.subject, json.len())?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut stream = self
+            .stream
+            .lock()
+            .map_err(|_| "the NATS connection failed")?;
+        stream.flush()?;
+        Ok(())
+    }
+}
+
+/// The target of a `nats://` URL, without the scheme.
+pub fn parse_url(url: &str) -> Option<Target> {
+    let (authority, subject) = url.split_once('/')?;
+    let (auth, server) = match authority.rsplit_once('@') {
+        Some((auth, server)) => (Some(auth), server),
+        None => (None, authority),
+    };
+    // Subjects are dot-separated tokens without spaces.
+    if subject.is_empty() || subject.contains(char::is_whitespace) || !server.contains(':') {
+        return None;
+    }
+    let (user, pass, token) = match auth.map(|a| a.split_once(':')) {
+        Some(Some((user, pass))) => (Some(user.into()), Some(pass.into()), None),
+        Some(None) => (None, None, auth.map(String::from)),
+        None => (None, None, None),
+    };
+    Some(Target {
+        server: server.into(),
+        subject: subject.into(),
+        user,
+        pass,
+        token,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn publishes_events_to_a_subject() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let received = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            conn.write_all(b"INFO {\"server_id\":\"test\"}\r\n")
+                .unwrap();
+            let mut reader = BufReader::new(conn.try_clone().unwrap());
+            let mut lines = vec![];
+            for _ in 0..4 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.starts_with("PING") {
+                    conn.write_all(b"PONG\r\n").unwrap();
+                }
+                lines.push(line.trim_end().to_string());
+            }
+            lines
+        });
+
+        let target = parse_url(&format!("ci:secret@{server}/fbtrace.erp")).unwrap();
+        assert_eq!(target.user.as_deref(), Some("ci"));
+        let mut sink = NatsSink::open(&target, crate::format::LATEST).unwrap();
+        let event = crate::fixtures::bare("ERROR AT JStatement::execute");
+        sink.write(&event).unwrap();
+        sink.finish().unwrap();
+
+        let lines = received.join().unwrap();
+        assert!(lines[0].starts_with("CONNECT {"), "{lines:?}");
+        assert!(
+            lines[0].contains(r#""user":"ci","pass":"secret""#),
+            "{lines:?}"
+        );
+        assert_eq!(lines[1], "PING");
+        let json = format::to_json(&event, crate::format::LATEST).unwrap();
+        assert_eq!(lines[2], format!("PUB fbtrace.erp {}", json.len()));
+        assert_eq!(lines[3], json);
+
+        assert_eq!(parse_url("localhost:4222/"), None);
+        assert_eq!(
+            parse_url("tok@localhost:4222/a.b")
+                .unwrap()
+                .token
+                .as_deref(),
+            Some("tok")
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Streaming events as JSON lines to the stdin of a command, e.g. a script doing
+//! processing rsfbtrace has no sink for. The command is restarted if it exits.
+
+use super::Sink;
+use crate::alert;
+use crate::event::Event;
+use crate::format;
+use std::error::Error;
+use std::io::Write;
+use std::process::{Child, Stdio};
+use std::time::{Duration, Instant};
+
+/// A command exiting this often within `RESTART_WINDOW` is broken rather than crashing
+/// now and then, and ends the trace instead of being restarted again.
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+pub struct PipeSink {
+    command: String,
+    compat: u32,
+    child: Child,
+    restarts: Vec<Instant>,
+}
+
+impl PipeSink {
+    pub fn open(command: &str, compat: u32) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            command: command.into(),
+            compat,
+            child: spawn(command)?,
+            restarts: vec![],
+        })
+    }
+
+    fn restart(&mut self) -> Result<(), Box<dyn Error>> {
+        let status = match self.child.try_wait() {
+            Ok(Some(status)) => status.to_string(),
+            _ => {
+                let _ = self.child.kill();
+                self.child.wait()?.to_string()
+            }
+        };
+        let now = Instant::now();
+        self.restarts.retain(|t| now - *t < RESTART_WINDOW);
+        if self.restarts.len() >= MAX_RESTARTS {
+            return Err(format!(
+                "--pipe-to command kept exiting, restarted {MAX_RESTARTS} times within {}s, \
+                 last with {status}",
+                RESTART_WINDOW.as_secs()
+            )
+            .into());
+        }
+        self.restarts.push(now);
+        eprintln!("--pipe-to command exited with {status}, restarting it");
+        self.child = spawn(&self.command)?;
+        Ok(())
+    }
+}
+
+fn spawn(command: &str) -> std::io::Result<Child> {
+    alert::shell(command).stdin(Stdio::piped()).spawn()
+}
+
+fn send(child: &mut Child, line: &str) -> std::io::Result<()> {
+    match &mut child.stdin {
+        Some(stdin) => stdin.write_all(line.as_bytes()),
+        None => Err(std::io::ErrorKind::BrokenPipe.into()),
+    }
+}
+
+impl Sink for PipeSink {
+    fn write(&mut self, event: &Event) -> Result<(), Box<dyn Error>> {
+        let json = format::to_json(event, self.compat)?;
+        let line = json + "\n";
+        // The pipe stays writable while anything the command started holds it open, so
+        // a command that exited isn't always noticed by the write failing.
+        loop {
+            let exited = matches!(self.child.try_wait(), Ok(Some(_)));
+            if !exited && send(&mut self.child, &line).is_ok() {
+                return Ok(());
+            }
+            self.restart()?;
+        }
+    }
+
+    /// Closes the command's stdin and waits for it to process what it was sent.
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        drop(self.child.stdin.take());
+        let status = self.child.wait()?;
+        if !status.success() {
+            eprintln!("--pipe-to command exited with {status}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::fixtures::bare;
+
+    #[test]
+    fn restarts_a_command_that_exited() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("events");
+        let event = bare("ERROR AT JStatement::execute");
+
+        // Reads a single event and exits.
+        let command = format!("read line && echo \"$line\" >> '{}'", out.display());
+        let mut sink = PipeSink::open(&command, format::LATEST).unwrap();
+        sink.write(&event).unwrap();
+        while sink.child.try_wait().unwrap().is_none() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        sink.write(&event).unwrap();
+        sink.finish().unwrap();
+
+        let written = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(written.lines().count(), 2, "{written}");
+        assert!(written.contains("\"ERROR\""), "{written}");
+        assert_eq!(sink.restarts.len(), 1);
+    }
+}
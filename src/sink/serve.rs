@@ -0,0 +1,495 @@
+//! Serving the trace live over HTTP, for teammates to watch from a browser: a page at
+//! `/`, and a WebSocket at `/events` sending each event as a JSON text message. Only
+//! the little of HTTP and WebSocket this needs is spoken, without a server library.
+
+use super::Sink;
+use crate::crypto::sha1;
+use crate::event::Event;
+use crate::format;
+use base64::Engine;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Events queued for a browser that isn't keeping up. Past this it is disconnected, and
+/// the page reconnects, rather than slowing down the trace.
+const CLIENT_QUEUE: usize = 1000;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Appended to a client's key to prove the server speaks WebSocket, per RFC 6455.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+struct Client {
+    peer: SocketAddr,
+    frames: SyncSender<Arc<Vec<u8>>>,
+}
+
+pub struct ServeSink {
+    clients: Arc<Mutex<Vec<Client>>>,
+}
+
+impl ServeSink {
+    /// Serves on `addr`, to those giving `token` if there is one. An address other
+    /// machines can reach is refused without one, as anyone reaching it could watch the
+    /// trace, statements and parameters included.
+    pub fn open(addr: &str, token: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| format!("Unable to serve on {addr}: {e}"))?;
+        let addr = listener.local_addr()?;
+        match token {
+            Some(token) => eprintln!("Serving the trace at http://{addr}/?token={token}"),
+            None if !addr.ip().is_loopback() => {
+                return Err(format!(
+                    "--serve {addr} isn't limited to this machine, so anyone who can reach \
+                     it could watch the trace. Give --serve-token, or serve on 127.0.0.1"
+                )
+                .into())
+            }
+            None => eprintln!("Serving the trace at http://{addr}/"),
+        }
+        Ok(Self::serve(listener, token.map(Into::into)))
+    }
+
+    fn serve(listener: TcpListener, token: Option<String>) -> Self {
+        let clients: Arc<Mutex<Vec<Client>>> = Arc::default();
+        let joined = Arc::clone(&clients);
+        let token: Arc<Option<String>> = Arc::new(token);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let clients = Arc::clone(&joined);
+                let token = Arc::clone(&token);
+                std::thread::spawn(move || {
+                    if let Err(e) = handle(stream, &clients, token.as_deref()) {
+                        eprintln!("--serve: {e}");
+                    }
+                });
+            }
+        });
+        Self { clients }
+    }
+}
+
+impl Sink for ServeSink {
+    fn write(&mut self, event: &Event) -> Result<(), Box<dyn Error>> {
+        let mut clients = self
+            .clients
+            .lock()
+            .map_err(|_| "the --serve server failed")?;
+        if clients.is_empty() {
+            return Ok(());
+        }
+        // The page reads the latest format, whatever --compat is.
+        let json = format::to_json(event, format::LATEST)?;
+        let frame = Arc::new(text_frame(&json));
+        clients.retain(|c| match c.frames.try_send(Arc::clone(&frame)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                eprintln!("--serve: {} fell behind and was disconnected", c.peer);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        Ok(())
+    }
+
+    /// Closes the WebSockets once what was queued for them is sent.
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.clear();
+        }
+        Ok(())
+    }
+}
+
+fn handle(
+    stream: TcpStream,
+    clients: &Mutex<Vec<Client>>,
+    token: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut key = None;
+    let mut upgrade = false;
+    let mut bearer = None;
+    let mut origin = None;
+    let mut host = None;
+    for _ in 0..100 {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("upgrade") {
+                upgrade = value.eq_ignore_ascii_case("websocket");
+            } else if name.eq_ignore_ascii_case("authorization") {
+                bearer = value.strip_prefix("Bearer ").map(str::to_string);
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("host") {
+                host = Some(value.to_string());
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    // Browsers let any page open a WebSocket to 127.0.0.1, and say which page did in
+    // `Origin`, so only the page served here may.
+    let served = writer.local_addr()?.to_string();
+    if origin.is_some_and(|o| !same_origin(&o, host.as_deref(), &served)) {
+        return respond(
+            &mut writer,
+            "403 Forbidden",
+            "text/plain",
+            "Only the page served here can watch the trace\n",
+        );
+    }
+    // Browsers can't give a WebSocket headers, so the page passes the token on in the URL.
+    let given = bearer.or_else(|| {
+        query
+            .split('&')
+            .find_map(|p| p.strip_prefix("token="))
+            .map(str::to_string)
+    });
+    let authorized = match token {
+        Some(token) => given.is_some_and(|g| same(g.as_bytes(), token.as_bytes())),
+        None => true,
+    };
+    if !authorized {
+        return respond(
+            &mut writer,
+            "401 Unauthorized",
+            "text/plain",
+            "Give the token --serve-token was given, as ?token=...\n",
+        );
+    }
+    match (method, path, key) {
+        ("GET", "/events", Some(key)) if upgrade => {
+            write!(
+                writer,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(&key)
+            )?;
+        }
+        ("GET", "/", _) => {
+            return respond(&mut writer, "200 OK", "text/html; charset=utf-8", PAGE);
+        }
+        ("GET", _, _) => return respond(&mut writer, "404 Not Found", "text/plain", "Not found\n"),
+        _ => {
+            return respond(
+                &mut writer,
+                "405 Method Not Allowed",
+                "text/plain",
+                "Only GET is supported\n",
+            )
+        }
+    }
+
+    let (frames, queued) = mpsc::sync_channel::<Arc<Vec<u8>>>(CLIENT_QUEUE);
+    clients
+        .lock()
+        .map_err(|_| "the --serve server failed")?
+        .push(Client { peer, frames });
+
+    // Messages from the browser are only read to notice it closing the WebSocket.
+    reader.get_ref().set_read_timeout(None)?;
+    let closed = writer.try_clone()?;
+    std::thread::spawn(move || {
+        while let Ok(true) = read_frame(&mut reader) {}
+        let _ = closed.shutdown(Shutdown::Both);
+    });
+
+    for frame in queued {
+        if writer.write_all(&frame).is_err() {
+            return Ok(());
+        }
+    }
+    let _ = writer.write_all(&[0x88, 0]);
+    let _ = writer.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+fn respond(
+    writer: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<(), Box<dyn Error>> {
+    write!(
+        writer,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+/// Parses a `--serve-token`, which goes in URLs as it is.
+pub fn parse_token(s: &str) -> Result<String, String> {
+    match s
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"-_.~".contains(&b))
+    {
+        true if s.len() >= 16 => Ok(s.into()),
+        true => Err("A --serve-token needs at least 16 characters".into()),
+        false => Err("A --serve-token can only have letters, digits and - _ . ~".into()),
+    }
+}
+
+/// Whether a request's `Origin` is the page served here, reached as `host` or at the
+/// address `served`.
+fn same_origin(origin: &str, host: Option<&str>, served: &str) -> bool {
+    let Some((_, authority)) = origin.split_once("://") else {
+        return false;
+    };
+    let authority = authority.split('/').next().unwrap_or_default();
+    host.into_iter()
+        .chain([served])
+        .any(|h| h.eq_ignore_ascii_case(authority))
+}
+
+/// Whether `given` is `token`, taking as long wherever they differ so the token can't
+/// be guessed a byte at a time.
+fn same(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len() && given.iter().zip(token).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+}
+
+/// The `Sec-WebSocket-Accept` answering a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    base64::engine::general_purpose::STANDARD
+        .encode(sha1(format!("{key}{WEBSOCKET_GUID}").as_bytes()))
+}
+
+/// An unmasked text frame, as servers send them.
+fn text_frame(text: &str) -> Vec<u8> {
+    let len = text.len();
+    let mut frame = Vec::with_capacity(len + 10);
+    frame.push(0x81);
+    match len {
+        0..=125 => frame.push(len as u8),
+        126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(text.as_bytes());
+    frame
+}
+
+/// Reads and discards a frame from the browser, returning whether the WebSocket is
+/// still open.
+fn read_frame(input: &mut impl Read) -> std::io::Result<bool> {
+    let mut header = [0; 2];
+    input.read_exact(&mut header)?;
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            input.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0; 8];
+            input.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    let mask = if header[1] & 0x80 != 0 { 4 } else { 0 };
+    std::io::copy(&mut input.take(len + mask), &mut std::io::sink())?;
+    Ok(header[0] & 0x0F != 0x8)
+}
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>rsfbtrace</title><style>
+body { font-family: sans-serif; margin: 1em; color: #222; }
+header { display: flex; gap: 1em; align-items: center; margin-bottom: 1em; }
+#status { color: #666; }
+table { border-collapse: collapse; width: 100%; font-size: 0.85em; }
+th, td { text-align: left; padding: 0.2em 0.5em; border-bottom: 1px solid #eee; vertical-align: top; }
+td.n, th.n { text-align: right; font-variant-numeric: tabular-nums; }
+tr.failed td { background: #fdd; }
+code { white-space: pre-wrap; word-break: break-word; }
+</style></head><body>
+<header><strong>rsfbtrace</strong><span id="status">Connecting...</span>
+<input id="filter" placeholder="Filter, e.g. ORDERS" size="30">
+<button id="pause">Pause</button><button id="clear">Clear</button></header>
+<table><thead><tr><th>Time</th><th>Event</th><th>Attachment</th><th>User</th>
+<th class="n">ms</th><th class="n">Rows</th><th>Statement</th></tr></thead>
+<tbody id="rows"></tbody></table>
+<script>
+const MAX_ROWS = 2000;
+const rows = document.getElementById("rows");
+const status = document.getElementById("status");
+const filter = document.getElementById("filter");
+const pause = document.getElementById("pause");
+let paused = false, held = [], seen = 0;
+
+function cell(row, text, cls) {
+  const td = row.insertCell();
+  if (cls) td.className = cls;
+  td.textContent = text ?? "";
+  return td;
+}
+
+function shown(row) {
+  return row.textContent.toLowerCase().includes(filter.value.toLowerCase());
+}
+
+function add(e) {
+  const row = document.createElement("tr");
+  if (e.failed) row.className = "failed";
+  cell(row, e.timestamp.replace("T", " "));
+  cell(row, e.kind);
+  cell(row, e.attachment && e.attachment.id);
+  cell(row, e.attachment && e.attachment.user);
+  cell(row, e.perf && e.perf.duration_ms, "n");
+  cell(row, e.records_fetched, "n");
+  const sql = document.createElement("code");
+  sql.textContent = e.statement ? e.statement.sql : e.lines.join("\n");
+  row.insertCell().appendChild(sql);
+  row.hidden = !shown(row);
+  rows.prepend(row);
+  while (rows.rows.length > MAX_ROWS) rows.lastChild.remove();
+}
+
+function connect() {
+  const ws = new WebSocket((location.protocol === "https:" ? "wss://" : "ws://") + location.host + "/events" + location.search);
+  ws.onopen = () => status.textContent = "Connected";
+  ws.onmessage = m => {
+    seen++;
+    const e = JSON.parse(m.data);
+    if (paused) held.push(e); else add(e);
+    if (!paused) status.textContent = "Connected, " + seen + " events";
+  };
+  ws.onclose = () => {
+    status.textContent = "Disconnected after " + seen + " events, reconnecting...";
+    setTimeout(connect, 2000);
+  };
+}
+
+filter.oninput = () => { for (const row of rows.rows) row.hidden = !shown(row); };
+pause.onclick = () => {
+  paused = !paused;
+  pause.textContent = paused ? "Resume" : "Pause";
+  if (!paused) { held.forEach(add); held = []; }
+  else status.textContent = "Paused";
+};
+document.getElementById("clear").onclick = () => rows.replaceChildren();
+connect();
+</script></body></html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::bare;
+
+    #[test]
+    fn streams_events_to_websockets() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let token = "s3cret-token-0123";
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut sink = ServeSink::serve(listener, Some(token.into()));
+        let mut stranger = TcpStream::connect(addr).unwrap();
+        write!(
+            stranger,
+            "GET /?token=guess HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stranger.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+
+        let mut browser = TcpStream::connect(addr).unwrap();
+        write!(
+            browser,
+            "GET /events?token={token} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n"
+        )
+        .unwrap();
+        let mut reader = BufReader::new(browser.try_clone().unwrap());
+        let mut response = String::new();
+        while !response.ends_with("\r\n\r\n") {
+            reader.read_line(&mut response).unwrap();
+        }
+        assert!(response.starts_with("HTTP/1.1 101"), "{response}");
+        while sink.clients.lock().unwrap().is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        sink.write(&bare("ERROR AT JStatement::execute")).unwrap();
+        sink.finish().unwrap();
+
+        let mut header = [0; 4];
+        reader.read_exact(&mut header).unwrap();
+        assert_eq!((header[0], header[1]), (0x81, 126));
+        let mut text = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+        reader.read_exact(&mut text).unwrap();
+        let event: serde_json::Value = serde_json::from_slice(&text).unwrap();
+        assert_eq!(event["kind"], "ERROR");
+        assert!(
+            !read_frame(&mut reader).unwrap(),
+            "expected the WebSocket to close"
+        );
+
+        let mut page = TcpStream::connect(addr).unwrap();
+        write!(
+            page,
+            "GET /events?token={token} HTTP/1.1\r\nHost: localhost:{port}\r\n\
+             Origin: https://evil.example\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            port = addr.port()
+        )
+        .unwrap();
+        let mut response = String::new();
+        page.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+
+        let host = format!("localhost:{}", addr.port());
+        assert!(same_origin(&format!("http://{host}"), Some(&host), "x"));
+        assert!(same_origin(
+            &format!("http://{addr}"),
+            None,
+            &addr.to_string()
+        ));
+        assert!(!same_origin("null", Some(&host), &addr.to_string()));
+        assert!(!same_origin(
+            "http://127.0.0.1:1",
+            Some(&host),
+            &addr.to_string()
+        ));
+
+        assert!(ServeSink::open("0.0.0.0:0", None).is_err());
+        assert!(parse_token("short").is_err() && parse_token("not/url-safe-0123").is_err());
+    }
+}
@@ -0,0 +1,399 @@
+use super::{Capture, Sink};
+use crate::event::{Attachment, Event, EventKind};
+use crate::fanout;
+use crate::throttle::{Counts, Thinning};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::error::Error;
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS attachments (
+    id INTEGER PRIMARY KEY,
+    server TEXT NOT NULL,
+    number INTEGER NOT NULL,
+    database TEXT NOT NULL,
+    user TEXT NOT NULL,
+    role TEXT NOT NULL,
+    charset TEXT NOT NULL,
+    remote TEXT NOT NULL,
+    process TEXT,
+    pid INTEGER,
+    attached_at TEXT,
+    detached_at TEXT
+);
+
+CREATE TABLE IF NOT EXISTS transactions (
+    id INTEGER PRIMARY KEY,
+    attachment_id INTEGER NOT NULL REFERENCES attachments (id),
+    number INTEGER NOT NULL,
+    options TEXT NOT NULL,
+    started_at TEXT,
+    ended_at TEXT,
+    outcome TEXT,
+    UNIQUE (attachment_id, number)
+);
+
+CREATE TABLE IF NOT EXISTS captures (
+    id INTEGER PRIMARY KEY,
+    started_at TEXT NOT NULL,
+    tool_version TEXT NOT NULL,
+    server_version TEXT,
+    config TEXT NOT NULL,
+    tags TEXT,
+    sample REAL,
+    rate_limit TEXT,
+    kept_statements INTEGER,
+    sampled_statements INTEGER,
+    limited_statements INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY,
+    uid TEXT,
+    capture_id INTEGER REFERENCES captures (id),
+    timestamp TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    failed INTEGER NOT NULL,
+    location TEXT,
+    attachment_id INTEGER REFERENCES attachments (id),
+    transaction_id INTEGER REFERENCES transactions (id),
+    tags TEXT,
+    context TEXT,
+    raw TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS statements (
+    event_id INTEGER PRIMARY KEY REFERENCES events (id),
+    attachment_id INTEGER REFERENCES attachments (id),
+    transaction_id INTEGER REFERENCES transactions (id),
+    number INTEGER NOT NULL,
+    sql TEXT NOT NULL,
+    plan TEXT,
+    truncated INTEGER,
+    fingerprint TEXT,
+    records_fetched INTEGER,
+    duration_ms INTEGER,
+    reads INTEGER,
+    writes INTEGER,
+    fetches INTEGER,
+    marks INTEGER,
+    thinned INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS events_timestamp ON events (timestamp);
+CREATE INDEX IF NOT EXISTS events_uid ON events (uid);
+CREATE INDEX IF NOT EXISTS statements_number ON statements (attachment_id, number);
+CREATE INDEX IF NOT EXISTS statements_fingerprint ON statements (fingerprint);
+CREATE INDEX IF NOT EXISTS attachments_number ON attachments (server, database, number);
+"#;
+
+/// Events are committed in batches; a commit per event is far too slow for a busy server.
+const BATCH_SIZE: usize = 500;
+
+/// Writes parsed events into a SQLite database for later ad-hoc analysis.
+pub struct SqliteSink {
+    conn: Connection,
+    path: String,
+    capture_id: i64,
+    /// How statement events are thinned out, so those that may have been are marked.
+    thinning: Option<Thinning>,
+    pending: usize,
+    /// The size of the database when opened, so only this capture counts as written.
+    initial_size: u64,
+    /// By the database, told apart by server, and attachment number.
+    attachments: HashMap<(String, i64), i64>,
+    transactions: HashMap<(i64, i64), i64>,
+}
+
+impl SqliteSink {
+    pub fn open(path: &str, capture: &Capture) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        conn.execute(
+            "INSERT INTO captures (started_at, tool_version, server_version, config, tags,
+                sample, rate_limit)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                chrono::Local::now().format("%Y-%m-%dT%H:%M:%S").to_string(),
+                env!("CARGO_PKG_VERSION"),
+                capture.server_version,
+                capture.config,
+                (!capture.tags.is_empty()).then(|| serde_json::json!(capture.tags).to_string()),
+                capture.thinning.and_then(|t| t.sample),
+                capture.thinning.and_then(|t| t.rate).map(|r| r.to_string()),
+            ],
+        )?;
+        let capture_id = conn.last_insert_rowid();
+        conn.execute_batch("BEGIN")?;
+
+        Ok(Self {
+            conn,
+            path: path.into(),
+            capture_id,
+            thinning: capture.thinning,
+            pending: 0,
+            initial_size: disk_size(path),
+            attachments: HashMap::new(),
+            transactions: HashMap::new(),
+        })
+    }
+
+    /// The row of the attachment `event` belongs to. An attachment being made gets a row
+    /// of its own, as its number may have been used before the server restarted; others
+    /// are taken to be the last attachment of their number.
+    fn attachment_id(&mut self, event: &Event, att: &Attachment) -> rusqlite::Result<i64> {
+        let key = (fanout::database_key(&event.tags, &att.database), att.id);
+        let attaching = event.kind == EventKind::AttachDatabase;
+        if let Some(id) = self.attachments.get(&key).filter(|_| !attaching) {
+            return Ok(*id);
+        }
+
+        let server = event.tags.get(fanout::HOST_TAG).map_or("", String::as_str);
+        let existing = match attaching {
+            true => None,
+            false => self
+                .conn
+                .query_row(
+                    "SELECT id FROM attachments WHERE server = ?1 AND database = ?2 AND number = ?3
+                     ORDER BY id DESC LIMIT 1",
+                    params![server, att.database, att.id],
+                    |r| r.get(0),
+                )
+                .optional()?,
+        };
+        let id = match existing {
+            Some(id) => {
+                self.conn.execute(
+                    "UPDATE attachments SET process = coalesce(process, ?1), pid = coalesce(pid, ?2)
+                     WHERE id = ?3",
+                    params![att.process, att.pid, id],
+                )?;
+                id
+            }
+            None => self.conn.query_row(
+                "INSERT INTO attachments (server, number, database, user, role, charset, remote,
+                    process, pid)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 RETURNING id",
+                params![
+                    server,
+                    att.id,
+                    att.database,
+                    att.user,
+                    att.role,
+                    att.charset,
+                    att.remote,
+                    att.process,
+                    att.pid
+                ],
+                |r| r.get(0),
+            )?,
+        };
+
+        self.attachments.insert(key, id);
+        Ok(id)
+    }
+
+    fn transaction_id(
+        &mut self,
+        attachment_id: i64,
+        number: i64,
+        options: &str,
+    ) -> rusqlite::Result<i64> {
+        let key = (attachment_id, number);
+        if let Some(id) = self.transactions.get(&key) {
+            return Ok(*id);
+        }
+
+        let id = self.conn.query_row(
+            "INSERT INTO transactions (attachment_id, number, options) VALUES (?1, ?2, ?3)
+             ON CONFLICT (attachment_id, number) DO UPDATE SET options = excluded.options
+             RETURNING id",
+            params![attachment_id, number, options],
+            |r| r.get(0),
+        )?;
+
+        self.transactions.insert(key, id);
+        Ok(id)
+    }
+
+    fn insert(&mut self, event: &Event) -> rusqlite::Result<()> {
+        let attachment_id = match &event.attachment {
+            Some(att) => Some(self.attachment_id(event, att)?),
+            None => None,
+        };
+
+        let transaction_id = match (attachment_id, &event.transaction) {
+            (Some(att), Some(tra)) => Some(self.transaction_id(att, tra.id, &tra.options)?),
+            _ => None,
+        };
+
+        match (&event.kind, attachment_id, transaction_id) {
+            (EventKind::AttachDatabase, Some(id), _) => {
+                self.conn.execute(
+                    "UPDATE attachments SET attached_at = ?1 WHERE id = ?2",
+                    params![event.timestamp, id],
+                )?;
+            }
+            (EventKind::DetachDatabase, Some(id), _) => {
+                self.conn.execute(
+                    "UPDATE attachments SET detached_at = ?1 WHERE id = ?2",
+                    params![event.timestamp, id],
+                )?;
+            }
+            (EventKind::StartTransaction, _, Some(id)) => {
+                self.conn.execute(
+                    "UPDATE transactions SET started_at = ?1 WHERE id = ?2",
+                    params![event.timestamp, id],
+                )?;
+            }
+            (EventKind::CommitTransaction | EventKind::RollbackTransaction, _, Some(id)) => {
+                let outcome = if event.kind == EventKind::CommitTransaction {
+                    "commit"
+                } else {
+                    "rollback"
+                };
+                self.conn.execute(
+                    "UPDATE transactions SET ended_at = ?1, outcome = ?2 WHERE id = ?3",
+                    params![event.timestamp, outcome, id],
+                )?;
+            }
+            _ => {}
+        }
+
+        self.conn.execute(
+            "INSERT INTO events (uid, capture_id, timestamp, kind, failed, location, attachment_id,
+                transaction_id, tags, context, raw)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                event.id,
+                self.capture_id,
+                event.timestamp,
+                event.kind.name(),
+                event.failed,
+                event.location,
+                attachment_id,
+                transaction_id,
+                // As a JSON object, for SQLite's json functions.
+                (!event.tags.is_empty()).then(|| serde_json::json!(event.tags).to_string()),
+                (!event.context.is_empty()).then(|| context_json(event)),
+                event.raw
+            ],
+        )?;
+        let event_id = self.conn.last_insert_rowid();
+
+        if let Some(stmt) = &event.statement {
+            let perf = event.perf.as_ref();
+            self.conn.execute(
+                "INSERT INTO statements (event_id, attachment_id, transaction_id, number, sql, plan,
+                    truncated, fingerprint, records_fetched, duration_ms, reads, writes, fetches,
+                    marks, thinned)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+                params![
+                    event_id,
+                    attachment_id,
+                    transaction_id,
+                    stmt.id,
+                    stmt.sql,
+                    stmt.plan,
+                    stmt.truncated,
+                    stmt.fingerprint,
+                    event.records_fetched,
+                    perf.map(|p| p.duration_ms),
+                    perf.map(|p| p.reads),
+                    perf.map(|p| p.writes),
+                    perf.map(|p| p.fetches),
+                    perf.map(|p| p.marks),
+                    self.thinning.map(|t| t.applies(event)),
+                ],
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The event's context variables as a JSON object by namespace, e.g.
+/// `{"USER_SESSION": {"APP_USER": "jdoe"}}`, for `json_extract(context,
+/// '$.USER_SESSION.APP_USER')`.
+fn context_json(event: &Event) -> String {
+    let mut namespaces = serde_json::Map::new();
+    for var in &event.context {
+        let vars = namespaces
+            .entry(var.namespace.clone())
+            .or_insert_with(|| serde_json::json!({}));
+        vars[&var.name] = serde_json::json!(var.value);
+    }
+    serde_json::Value::Object(namespaces).to_string()
+}
+
+impl Sink for SqliteSink {
+    fn write(&mut self, event: &Event) -> Result<(), Box<dyn Error>> {
+        self.insert(event)?;
+
+        self.pending += 1;
+        if self.pending >= BATCH_SIZE {
+            self.conn.execute_batch("COMMIT; BEGIN")?;
+            self.pending = 0;
+        }
+
+        Ok(())
+    }
+
+    fn thinned(&mut self, counts: &Counts) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "UPDATE captures SET kept_statements = coalesce(kept_statements, 0) + ?1,
+                sampled_statements = coalesce(sampled_statements, 0) + ?2,
+                limited_statements = coalesce(limited_statements, 0) + ?3
+             WHERE id = ?4",
+            params![counts.kept, counts.sampled, counts.limited, self.capture_id],
+        )?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.conn.execute_batch("COMMIT")?;
+        self.pending = 0;
+        Ok(())
+    }
+
+    fn written(&self) -> u64 {
+        disk_size(&self.path).saturating_sub(self.initial_size)
+    }
+}
+
+/// The size of a database and its write-ahead log, which holds everything written since
+/// the last checkpoint.
+pub fn disk_size(path: &str) -> u64 {
+    [path.to_string(), format!("{path}-wal")]
+        .iter()
+        .filter_map(|p| std::fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Says how many statement events `--sample` and `--rate-limit` dropped from the captures
+/// in the store at `conn`, if they dropped any, as what's counted from it is then only
+/// the share of them kept.
+pub fn thinning_note(conn: &Connection) -> Option<String> {
+    // Counts are written as a session ends, so a capture cut short has none.
+    let (captures, counted, dropped): (u64, u64, u64) = conn
+        .query_row(
+            "SELECT count(*), count(kept_statements),
+                coalesce(sum(sampled_statements + limited_statements), 0)
+             FROM captures WHERE sample IS NOT NULL OR rate_limit IS NOT NULL",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .ok()?;
+    let dropped = match dropped {
+        0 if counted == captures => return None,
+        0 => "Some".to_string(),
+        d => d.to_string(),
+    };
+    Some(format!(
+        "{dropped} statement events were dropped by --sample or --rate-limit in {captures} \
+         capture(s); the statements here are only those kept"
+    ))
+}
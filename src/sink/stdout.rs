@@ -0,0 +1,154 @@
+use super::Sink;
+use crate::binary;
+use crate::event::{Event, EventKind};
+use crate::format;
+use crate::gdscode;
+use rsfbtrace_model::schema;
+use std::error::Error;
+use std::io::{Stdout, Write};
+
+/// Writes each event's trace text to stdout.
+///
+/// Used instead of echoing the trace as it's read when events are filtered or changed
+/// before output.
+pub struct Raw {
+    truncate_sql: Option<usize>,
+    /// Replace the parameter lines with a `params: [...]` list, explain status codes and
+    /// list the context variables set for statements.
+    inline_params: bool,
+    written: u64,
+}
+
+impl Raw {
+    pub fn new(truncate_sql: Option<usize>, inline_params: bool) -> Self {
+        Self {
+            truncate_sql,
+            inline_params,
+            written: 0,
+        }
+    }
+
+    fn text(&self, event: &Event) -> String {
+        if self.inline_params {
+            let mut text = event.inline_params();
+            if let Some(explanation) = gdscode::explain(event) {
+                text += &format!("\n=> {explanation}");
+            }
+            if event.kind != EventKind::SetContext && !event.context.is_empty() {
+                let vars: Vec<String> = event
+                    .context
+                    .iter()
+                    .map(|v| {
+                        format!(
+                            "{} = \"{}\"",
+                            v.name,
+                            v.value.as_deref().unwrap_or_default()
+                        )
+                    })
+                    .collect();
+                text += &format!("\n=> context: {}", vars.join(", "));
+            }
+            text
+        } else {
+            event.raw.clone()
+        }
+    }
+}
+
+impl Sink for Raw {
+    fn write(&mut self, event: &Event) -> Result<(), Box<dyn Error>> {
+        let text = match self.truncate_sql {
+            Some(max) if event.statement.is_some() => {
+                let mut event = event.clone();
+                event.truncate_sql(max);
+                self.text(&event)
+            }
+            _ => self.text(event),
+        };
+        let mut out = std::io::stdout().lock();
+        writeln!(out, "{text}\n")?;
+        out.flush()?;
+        self.written += text.len() as u64 + 2;
+        Ok(())
+    }
+
+    fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+/// Writes each event to stdout as a line of JSON.
+pub struct JsonLines {
+    compat: u32,
+    truncate_sql: Option<usize>,
+    written: u64,
+}
+
+impl JsonLines {
+    pub fn new(compat: u32, truncate_sql: Option<usize>) -> Self {
+        Self {
+            compat,
+            truncate_sql,
+            written: 0,
+        }
+    }
+}
+
+impl Sink for JsonLines {
+    fn write(&mut self, event: &Event) -> Result<(), Box<dyn Error>> {
+        let json = match self.truncate_sql {
+            Some(max) if event.statement.is_some() => {
+                let mut event = event.clone();
+                event.truncate_sql(max);
+                format::to_json(&event, self.compat)?
+            }
+            _ => format::to_json(event, self.compat)?,
+        };
+        let mut out = std::io::stdout().lock();
+        writeln!(out, "{json}")?;
+        out.flush()?;
+        self.written += json.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+/// Writes the events to stdout as a binary capture, always in the latest format version.
+pub struct Binary {
+    writer: binary::Writer<Stdout>,
+    truncate_sql: Option<usize>,
+}
+
+impl Binary {
+    pub fn new(truncate_sql: Option<usize>) -> std::io::Result<Self> {
+        Ok(Self {
+            writer: binary::Writer::new(std::io::stdout())?,
+            truncate_sql,
+        })
+    }
+}
+
+impl Sink for Binary {
+    fn write(&mut self, event: &Event) -> Result<(), Box<dyn Error>> {
+        match self.truncate_sql {
+            Some(max) if event.statement.is_some() => {
+                let mut event = event.clone();
+                event.truncate_sql(max);
+                self.writer.write(&schema::Event::from(&event))?;
+            }
+            _ => self.writer.write(&schema::Event::from(event))?,
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(self.writer.finish()?)
+    }
+
+    fn written(&self) -> u64 {
+        self.writer.written()
+    }
+}
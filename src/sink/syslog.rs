@@ -0,0 +1,207 @@
+//! Sending events to a syslog collector as RFC 5424 messages, so they reach an existing
+//! log pipeline without going through a file.
+
+use super::Sink;
+use crate::alert;
+use crate::event::{Event, EventKind};
+use crate::format;
+use std::error::Error;
+use std::io::Write;
+use std::net::{TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// The socket the local syslog daemon listens on.
+pub const LOCAL_SOCKET: &str = "/dev/log";
+
+/// The facility messages are sent with: user-level messages.
+const FACILITY: u8 = 1;
+
+/// The largest message sent over UDP. Longer ones are cut, as RFC 5426 asks, rather than
+/// lost to a datagram the network can't carry.
+const MAX_DATAGRAM: usize = 65000;
+
+/// The private enterprise number RFC 5612 reserves for documentation, used to name the
+/// structured data element alerts are marked with.
+const SD_ID: &str = "alert@32473";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How messages reach the collector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// A collector's `host:port`, one datagram per message (RFC 5426).
+    Udp(String),
+    /// A collector's `host:port`, with octet-counted framing (RFC 6587).
+    Tcp(String),
+    /// A local datagram socket, e.g. `/dev/log`.
+    Unix(String),
+}
+
+enum Socket {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+pub struct SyslogSink {
+    transport: Transport,
+    socket: Socket,
+    hostname: String,
+    compat: u32,
+    alert_threshold: Option<Duration>,
+}
+
+impl SyslogSink {
+    pub fn open(
+        transport: &Transport,
+        compat: u32,
+        alert_threshold: Option<Duration>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            transport: transport.clone(),
+            socket: connect(transport)?,
+            hostname: hostname(),
+            compat,
+            alert_threshold,
+        })
+    }
+
+    fn send(&mut self, message: &[u8]) -> std::io::Result<()> {
+        match &mut self.socket {
+            Socket::Udp(s) => s
+                .send(&message[..message.len().min(MAX_DATAGRAM)])
+                .map(|_| ()),
+            Socket::Tcp(s) => {
+                s.write_all(format!("{} ", message.len()).as_bytes())?;
+                s.write_all(message)
+            }
+            #[cfg(unix)]
+            Socket::Unix(s) => s.send(message).map(|_| ()),
+        }
+    }
+}
+
+impl Sink for SyslogSink {
+    fn write(&mut self, event: &Event) -> Result<(), Box<dyn Error>> {
+        let json = format::to_json(event, self.compat)?;
+        let reason = alert::reason(event, self.alert_threshold);
+        let message = message(&self.hostname, event, reason, &json);
+
+        if let Err(e) = self.send(message.as_bytes()) {
+            // A collector restarting drops the connection; one reconnect covers that
+            // without hiding a collector that's gone.
+            if !matches!(self.transport, Transport::Tcp(_)) {
+                return Err(e.into());
+            }
+            self.socket = connect(&self.transport)?;
+            self.send(message.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn connect(transport: &Transport) -> Result<Socket, Box<dyn Error>> {
+    let socket = match transport {
+        Transport::Udp(addr) => {
+            let socket = UdpSocket::bind("[::]:0").or_else(|_| UdpSocket::bind("0.0.0.0:0"))?;
+            socket.connect(addr)?;
+            Socket::Udp(socket)
+        }
+        Transport::Tcp(addr) => {
+            let addr = std::net::ToSocketAddrs::to_socket_addrs(addr)?
+                .next()
+                .ok_or_else(|| format!("{addr} doesn't resolve to an address"))?;
+            let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+                .map_err(|e| format!("Unable to connect to {addr}: {e}"))?;
+            Socket::Tcp(stream)
+        }
+        #[cfg(unix)]
+        Transport::Unix(path) => {
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(path)
+                .map_err(|e| format!("Unable to connect to {path}: {e}"))?;
+            Socket::Unix(socket)
+        }
+        #[cfg(not(unix))]
+        Transport::Unix(_) => return Err("Unix sockets aren't available on this platform".into()),
+    };
+    Ok(socket)
+}
+
+/// The RFC 5424 message for `event`, with its JSON as the message text. Alerts are marked
+/// with their reason in structured data, so collectors can route them without parsing
+/// the JSON.
+fn message(hostname: &str, event: &Event, reason: Option<&str>, json: &str) -> String {
+    let pri = FACILITY * 8 + severity(event, reason);
+    let timestamp = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, false);
+    let data = match reason {
+        Some(r) => format!("[{SD_ID} reason=\"{r}\"]"),
+        None => "-".into(),
+    };
+    // MSGIDs are up to 32 printable characters.
+    let msgid: String = event.kind.name().chars().take(32).collect();
+    format!(
+        "<{pri}>1 {timestamp} {hostname} rsfbtrace {} {msgid} {data} {json}",
+        std::process::id(),
+    )
+}
+
+/// The syslog severity of `event`: error (3) for errors, warning (4) for warnings and
+/// other alerts, informational (6) otherwise.
+fn severity(event: &Event, reason: Option<&str>) -> u8 {
+    match (&event.kind, reason) {
+        (_, Some("error" | "replication_error")) => 3,
+        (EventKind::Warning, _) | (_, Some(_)) => 4,
+        _ => 6,
+    }
+}
+
+/// The local host's name, or `-` if it can't be found, as RFC 5424 asks.
+pub fn hostname() -> String {
+    let name = std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .unwrap_or_default();
+    // Hostnames are printable ASCII without spaces, up to 255 characters.
+    let name: String = name
+        .trim()
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(255)
+        .collect();
+    if name.is_empty() {
+        "-".into()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::bare;
+
+    #[test]
+    fn formats_events_as_rfc5424() {
+        let event = bare("ERROR AT JStatement::execute");
+
+        let reason = alert::reason(&event, None);
+        let message = message("db1", &event, reason, "{}");
+        let fields: Vec<&str> = message.splitn(8, ' ').collect();
+        assert_eq!(fields[0], "<11>1");
+        assert!(fields[1].contains('T'), "{message}");
+        assert_eq!(
+            fields[2..5],
+            ["db1", "rsfbtrace", &std::process::id().to_string()]
+        );
+        assert_eq!(fields[5], "ERROR");
+        assert_eq!(fields[6], "[alert@32473");
+        assert_eq!(fields[7], "reason=\"error\"] {}");
+    }
+}
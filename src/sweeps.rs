@@ -0,0 +1,360 @@
+//! Sweeps, followed from `SWEEP_START` to `SWEEP_FINISH` or `SWEEP_FAILED`: how long
+//! each took and the transaction counters around it. A wide gap between the oldest
+//! interesting and the oldest snapshot transaction is garbage that can't be collected,
+//! usually kept by a long-running transaction, and slows every query down.
+
+use crate::event::{Event, EventKind, Perf};
+use crate::fanout;
+use std::collections::HashMap;
+use std::io::{Result as IOResult, Write};
+use std::time::Duration;
+
+/// The transaction counters printed with sweep events, e.g. `Oldest interesting 10439`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub oldest_interesting: i64,
+    pub oldest_active: i64,
+    pub oldest_snapshot: i64,
+    pub next: i64,
+}
+
+impl Counters {
+    fn parse(lines: &[String]) -> Option<Self> {
+        let mut counters = Self::default();
+        let mut found = false;
+        for line in lines {
+            let Some((name, n)) = line.trim().rsplit_once(' ') else {
+                continue;
+            };
+            let Ok(n) = n.parse() else {
+                continue;
+            };
+            let counter = match name.trim() {
+                "Oldest interesting" => &mut counters.oldest_interesting,
+                "Oldest active" => &mut counters.oldest_active,
+                "Oldest snapshot" => &mut counters.oldest_snapshot,
+                "Next transaction" => &mut counters.next,
+                _ => continue,
+            };
+            *counter = n;
+            found = true;
+        }
+        found.then_some(counters)
+    }
+
+    /// Transactions between the oldest interesting and the oldest snapshot, whose record
+    /// versions can't be garbage collected yet.
+    pub fn gap(&self) -> i64 {
+        self.oldest_snapshot - self.oldest_interesting
+    }
+}
+
+/// Reads one of the counters, or the gap.
+type Counter = fn(&Counters) -> i64;
+
+/// When a sweep is worth an alert.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub max_duration: Option<Duration>,
+    pub max_gap: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Running,
+    Finished,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Sweep {
+    database: String,
+    started: Option<String>,
+    at_start: Option<Counters>,
+    at_end: Option<Counters>,
+    duration_ms: Option<i64>,
+    outcome: Outcome,
+    /// Set once a report said the sweep runs too long, so it's only said once.
+    overdue: bool,
+}
+
+/// Follows the sweeps of every database traced.
+#[derive(Debug, Default)]
+pub struct SweepTracker {
+    limits: Limits,
+    /// Whether to emit `SWEEP_REPORT` events, or only keep the sweeps for the report.
+    emit: bool,
+    running: HashMap<String, Sweep>,
+    done: Vec<Sweep>,
+}
+
+impl SweepTracker {
+    pub fn new(limits: Limits, emit: bool) -> Self {
+        Self {
+            limits,
+            emit,
+            ..Default::default()
+        }
+    }
+
+    /// The `SWEEP_REPORT` of a sweep `event` ended, or of one that has been running for
+    /// longer than `--alert-sweep-duration`.
+    pub fn observe(&mut self, event: &Event) -> Option<Event> {
+        if !matches!(
+            event.kind,
+            EventKind::SweepStart
+                | EventKind::SweepProgress
+                | EventKind::SweepFinish
+                | EventKind::SweepFailed
+        ) {
+            return None;
+        }
+        let att = event.attachment.as_ref()?;
+        let key = fanout::database_key(&event.tags, &att.database);
+        let new = || Sweep {
+            database: att.database.clone(),
+            started: None,
+            at_start: None,
+            at_end: None,
+            duration_ms: None,
+            outcome: Outcome::Running,
+            overdue: false,
+        };
+
+        match event.kind {
+            EventKind::SweepStart => {
+                let sweep = Sweep {
+                    started: Some(event.timestamp.clone()),
+                    at_start: Counters::parse(&event.lines),
+                    ..new()
+                };
+                self.running.insert(key, sweep);
+                None
+            }
+            EventKind::SweepProgress => {
+                let sweep = self.running.get_mut(&key)?;
+                let elapsed = elapsed_ms(sweep.started.as_deref()?, event)?;
+                let max = self.limits.max_duration?;
+                if sweep.overdue || elapsed < max.as_millis() as i64 {
+                    return None;
+                }
+                sweep.overdue = true;
+                sweep.duration_ms = Some(elapsed);
+                let report = report(sweep, event);
+                self.emit.then_some(report)
+            }
+            _ => {
+                let mut sweep = self.running.remove(&key).unwrap_or_else(new);
+                sweep.outcome = match event.kind {
+                    EventKind::SweepFinish => Outcome::Finished,
+                    _ => Outcome::Failed,
+                };
+                sweep.at_end = Counters::parse(&event.lines);
+                sweep.duration_ms = sweep
+                    .started
+                    .as_deref()
+                    .and_then(|s| elapsed_ms(s, event))
+                    .or(event.perf.as_ref().map(|p| p.duration_ms));
+                let report = report(&sweep, event);
+                self.done.push(sweep);
+                self.emit.then_some(report)
+            }
+        }
+    }
+
+    /// Lists every sweep seen, the running ones last.
+    pub fn write_report(&self, out: &mut impl Write) -> IOResult<()> {
+        if self.done.is_empty() && self.running.is_empty() {
+            return Ok(());
+        }
+        let mut running: Vec<&Sweep> = self.running.values().collect();
+        running.sort_by(|a, b| a.started.cmp(&b.started));
+
+        writeln!(out, "Sweeps:")?;
+        writeln!(
+            out,
+            "  {:<24} {:>10} {:<8} {:>12} {:>12} {:>12} {:>12}  database",
+            "started", "duration", "outcome", "OIT", "OAT", "OST", "OIT-OST gap"
+        )?;
+        for sweep in self.done.iter().chain(running) {
+            let counters = sweep.at_end.or(sweep.at_start);
+            let counter = |f: Counter| counters.as_ref().map_or("-".into(), |c| f(c).to_string());
+            writeln!(
+                out,
+                "  {:<24} {:>10} {:<8} {:>12} {:>12} {:>12} {:>12}  {}",
+                sweep.started.as_deref().unwrap_or("before the trace"),
+                sweep
+                    .duration_ms
+                    .map_or("-".into(), |ms| format!("{ms} ms")),
+                outcome(sweep),
+                counter(|c| c.oldest_interesting),
+                counter(|c| c.oldest_active),
+                counter(|c| c.oldest_snapshot),
+                counter(Counters::gap),
+                sweep.database
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn outcome(sweep: &Sweep) -> &'static str {
+    match sweep.outcome {
+        Outcome::Running => "running",
+        Outcome::Finished => "finished",
+        Outcome::Failed => "failed",
+    }
+}
+
+fn elapsed_ms(started: &str, event: &Event) -> Option<i64> {
+    Some((event.time()? - Event::parse_timestamp(started)?).num_milliseconds())
+}
+
+/// A `SWEEP_REPORT` event, with a `name: value` line for the outcome, start and duration,
+/// and each counter at the start and at the end, e.g. `oldest interesting: 10439 -> 10441`.
+fn report(sweep: &Sweep, event: &Event) -> Event {
+    let mut lines = vec![
+        format!("outcome: {}", outcome(sweep)),
+        format!(
+            "started: {}",
+            sweep.started.as_deref().unwrap_or("before the trace")
+        ),
+        format!("duration: {} ms", sweep.duration_ms.unwrap_or_default()),
+    ];
+    let counters: [(&str, Counter); 5] = [
+        ("oldest interesting", |c| c.oldest_interesting),
+        ("oldest active", |c| c.oldest_active),
+        ("oldest snapshot", |c| c.oldest_snapshot),
+        ("next transaction", |c| c.next),
+        ("gap", Counters::gap),
+    ];
+    for (name, counter) in counters {
+        let value = |c: Option<Counters>| c.as_ref().map_or("?".into(), |c| counter(c).to_string());
+        match (sweep.at_start, sweep.at_end) {
+            (None, None) => {}
+            (at_start, None) => lines.push(format!("{name}: {}", value(at_start))),
+            (at_start, at_end) => {
+                lines.push(format!("{name}: {} -> {}", value(at_start), value(at_end)))
+            }
+        }
+    }
+
+    Event {
+        id: String::new(),
+        timestamp: event.timestamp.clone(),
+        process: event.process.clone(),
+        kind: EventKind::SweepReport,
+        failed: sweep.outcome == Outcome::Failed,
+        location: None,
+        attachment: event.attachment.clone(),
+        transaction: None,
+        statement: None,
+        records_fetched: None,
+        perf: Some(Perf {
+            duration_ms: sweep.duration_ms.unwrap_or_default(),
+            ..event.perf.clone().unwrap_or_default()
+        }),
+        params: vec![],
+        tables: vec![],
+        tags: event.tags.clone(),
+        replication: None,
+        error_code: None,
+        context: vec![],
+        raw: format!("{} SWEEP_REPORT\n{}", event.timestamp, lines.join("\n")),
+        lines,
+    }
+}
+
+/// Why a `SWEEP_REPORT` is worth an alert, if it is: the sweep ran too long, or left
+/// too wide a gap.
+pub fn alert(event: &Event, limits: &Limits) -> Option<&'static str> {
+    if event.kind != EventKind::SweepReport {
+        return None;
+    }
+    let duration = event.perf.as_ref().map_or(0, |p| p.duration_ms);
+    if limits
+        .max_duration
+        .is_some_and(|max| duration >= max.as_millis() as i64)
+    {
+        return Some("long_sweep");
+    }
+    // The gap the sweep left, or the one it started with while it runs.
+    let gap = event
+        .lines
+        .iter()
+        .find_map(|l| l.strip_prefix("gap: "))
+        .and_then(|gap| gap.rsplit(" -> ").next()?.parse::<i64>().ok());
+    if limits.max_gap.zip(gap).is_some_and(|(max, gap)| gap > max) {
+        return Some("transaction_gap");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::event;
+
+    fn sweep(kind: &str, time: &str, oit: i64, ost: i64) -> Event {
+        event(&format!(
+            "2024-01-15T{time} (4321:00007F12AB) {kind}\n\
+             \t/data/erp.fdb (ATT_0, SYSDBA:NONE, NONE, <internal>)\n\
+             \n\
+             Transaction counters:\n\
+             \tOldest interesting        {oit}\n\
+             \tOldest active             {ost}\n\
+             \tOldest snapshot           {ost}\n\
+             \tNext transaction          {}\n",
+            ost + 2
+        ))
+    }
+
+    #[test]
+    fn reports_duration_and_counters() {
+        let limits = Limits {
+            max_duration: Some(Duration::from_secs(60)),
+            max_gap: Some(1000),
+        };
+        let mut tracker = SweepTracker::new(limits, true);
+        assert!(tracker
+            .observe(&sweep("SWEEP_START", "03:00:00.0000", 10439, 20440))
+            .is_none());
+
+        let progress = event(
+            "2024-01-15T03:02:00.0000 (4321:00007F12AB) SWEEP_PROGRESS\n\
+             \t/data/erp.fdb (ATT_0, SYSDBA:NONE, NONE, <internal>)\n\
+             \x20     0 ms, 22 fetch(es)\n",
+        );
+        let overdue = tracker.observe(&progress).unwrap();
+        assert_eq!(overdue.lines[0], "outcome: running");
+        assert_eq!(alert(&overdue, &limits), Some("long_sweep"));
+        assert!(tracker.observe(&progress).is_none());
+
+        let report = tracker
+            .observe(&sweep("SWEEP_FINISH", "03:05:00.0000", 20441, 20442))
+            .unwrap();
+        assert_eq!(report.kind, EventKind::SweepReport);
+        assert_eq!(report.perf.as_ref().unwrap().duration_ms, 300_000);
+        assert!(report
+            .lines
+            .contains(&"oldest interesting: 10439 -> 20441".to_string()));
+        assert!(report.lines.contains(&"gap: 10001 -> 1".to_string()));
+
+        let quick = Limits {
+            max_duration: None,
+            ..limits
+        };
+        assert_eq!(alert(&report, &quick), None);
+        tracker.observe(&sweep("SWEEP_START", "04:00:00.0000", 100, 5000));
+        let stuck = tracker
+            .observe(&sweep("SWEEP_FINISH", "04:00:01.0000", 100, 5000))
+            .unwrap();
+        assert_eq!(alert(&stuck, &quick), Some("transaction_gap"));
+
+        let mut out = vec![];
+        tracker.write_report(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 4, "{out}");
+        assert!(out.contains("300000 ms finished"), "{out}");
+    }
+}
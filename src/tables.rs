@@ -0,0 +1,126 @@
+//! Totals of the per-table counters the server prints with `print_perf`, to find the
+//! tables read sequentially the most, which are usually missing an index.
+
+use crate::event::{Event, EventKind};
+use std::collections::HashMap;
+use std::io::{Result as IOResult, Write};
+
+/// How many tables the report lists.
+const REPORT_SIZE: usize = 10;
+
+#[derive(Debug, Default)]
+struct Totals {
+    statements: u64,
+    natural: i64,
+    index: i64,
+    insert: i64,
+    update: i64,
+    delete: i64,
+}
+
+/// Collects the counters of each table by database and table.
+#[derive(Debug, Default)]
+pub struct TableReport {
+    tables: HashMap<(String, String), Totals>,
+}
+
+impl TableReport {
+    /// Only statements are counted: the counters of a procedure or trigger are already
+    /// part of those of the statement that ran it.
+    pub fn observe(&mut self, event: &Event) {
+        if event.kind != EventKind::ExecuteStatementFinish {
+            return;
+        }
+        let database = event
+            .attachment
+            .as_ref()
+            .map(|a| a.database.clone())
+            .unwrap_or_default();
+
+        for t in &event.tables {
+            let totals = self
+                .tables
+                .entry((database.clone(), t.table.clone()))
+                .or_default();
+            totals.statements += 1;
+            totals.natural += t.natural;
+            totals.index += t.index;
+            totals.insert += t.insert;
+            totals.update += t.update;
+            totals.delete += t.delete;
+        }
+    }
+
+    /// Lists the tables with the most natural reads first.
+    pub fn write_report(&self, out: &mut impl Write) -> IOResult<()> {
+        if self.tables.is_empty() {
+            return Ok(());
+        }
+
+        let mut tables: Vec<_> = self.tables.iter().collect();
+        tables.sort_by(|a, b| {
+            (b.1.natural, b.1.index)
+                .cmp(&(a.1.natural, a.1.index))
+                .then_with(|| a.0.cmp(b.0))
+        });
+
+        writeln!(out, "Tables by natural reads:")?;
+        writeln!(
+            out,
+            "  {:>12} {:>8} {:>12} {:>10} {:>10} {:>10} {:>11}  table",
+            "natural", "natural%", "index", "inserts", "updates", "deletes", "statements"
+        )?;
+        for ((database, table), t) in tables.iter().take(REPORT_SIZE) {
+            let reads = t.natural + t.index;
+            let share = match reads {
+                0 => 0.0,
+                _ => t.natural as f64 * 100.0 / reads as f64,
+            };
+            writeln!(
+                out,
+                "  {:>12} {share:>7.1}% {:>12} {:>10} {:>10} {:>10} {:>11}  {database}: {table}",
+                t.natural, t.index, t.insert, t.update, t.delete, t.statements
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::event;
+
+    #[test]
+    fn lists_tables_by_natural_reads() {
+        let trace = "2024-01-15T10:23:45.3450 (1234:00007F12AB) EXECUTE_STATEMENT_FINISH
+\t/data/erp.fdb (ATT_12, SYSDBA:NONE, UTF8, TCPv4:10.0.0.5/51234)
+\t\t(TRA_45, CONCURRENCY | WAIT | READ_WRITE)
+
+Statement 7:
+-------------------------------------------------------------------------------
+select * from orders o join customers c on c.id = o.customer_id
+0 records fetched
+    120 ms, 10 read(s), 2 write(s), 30 fetch(es), 1 mark(s)
+
+Table                             Natural     Index    Update    Insert    Delete   Backout     Purge   Expunge
+***************************************************************************************************************
+CUSTOMERS                                        40
+ORDERS                               5000
+";
+        let event = event(trace);
+
+        let mut report = TableReport::default();
+        report.observe(&event);
+        report.observe(&event);
+        let mut out = vec![];
+        report.write_report(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines.len(), 4, "{out}");
+        assert!(lines[2].ends_with("/data/erp.fdb: ORDERS"), "{out}");
+        assert!(lines[2].trim_start().starts_with("10000   100.0%"), "{out}");
+        assert!(lines[3].ends_with("/data/erp.fdb: CUSTOMERS"), "{out}");
+    }
+}
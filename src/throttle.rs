@@ -0,0 +1,267 @@
+//! Thinning out the statement events of a busy server, by keeping a share of them or no
+//! more than so many a second, while keeping every error and slow statement.
+
+use crate::alert;
+use crate::event::{Event, EventKind};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// A `--rate-limit`, e.g. `1000/s`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub events: u64,
+    pub per: Duration,
+}
+
+impl std::fmt::Display for Rate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let per = match self.per.as_secs() {
+            1 => "s",
+            60 => "m",
+            _ => "h",
+        };
+        write!(f, "{}/{per}", self.events)
+    }
+}
+
+/// Parses a share such as `10%`, as a fraction.
+pub fn parse_share(s: &str) -> Result<f64, String> {
+    let invalid = || format!("'{s}' is not a valid share. Expected e.g. 10%.");
+    let share: f64 = s
+        .trim()
+        .strip_suffix('%')
+        .ok_or_else(invalid)?
+        .trim()
+        .parse()
+        .map_err(|_| invalid())?;
+    if !(share > 0.0 && share <= 100.0) {
+        return Err(format!("'{s}' is not a share between 0% and 100%"));
+    }
+    Ok(share / 100.0)
+}
+
+/// Parses a rate such as `1000/s`, `500/m` or `10000/h`.
+pub fn parse_rate(s: &str) -> Result<Rate, String> {
+    let invalid = || format!("'{s}' is not a valid rate. Expected e.g. 1000/s or 500/m.");
+    let (events, per) = s.trim().split_once('/').ok_or_else(invalid)?;
+    let events: u64 = events.trim().parse().map_err(|_| invalid())?;
+    let per = match per.trim() {
+        "s" => Duration::from_secs(1),
+        "m" => Duration::from_secs(60),
+        "h" => Duration::from_secs(60 * 60),
+        _ => return Err(invalid()),
+    };
+    if events == 0 {
+        return Err(format!("'{s}' would drop every statement event"));
+    }
+    Ok(Rate { events, per })
+}
+
+/// How statement events are thinned out, recorded by stores with the capture so its
+/// counts can be scaled back up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thinning {
+    pub sample: Option<f64>,
+    pub rate: Option<Rate>,
+    /// Statements this slow are always kept.
+    pub alert_threshold: Option<Duration>,
+}
+
+impl Thinning {
+    /// Whether `event` may be dropped: a statement event that neither failed nor is
+    /// slow enough to alert on.
+    pub fn applies(&self, event: &Event) -> bool {
+        is_statement_event(event)
+            && !event.failed
+            && alert::reason(event, self.alert_threshold).is_none()
+    }
+
+    /// The sample and rate limit, e.g. `--sample 10% --rate-limit 1000/s`.
+    pub fn describe(&self) -> String {
+        let mut options = vec![];
+        if let Some(share) = self.sample {
+            options.push(format!("--sample {}%", share * 100.0));
+        }
+        if let Some(rate) = self.rate {
+            options.push(format!("--rate-limit {rate}"));
+        }
+        options.join(" ")
+    }
+}
+
+/// What became of the statement events the thinning applies to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub kept: u64,
+    /// Dropped by the sample and by the rate limit.
+    pub sampled: u64,
+    pub limited: u64,
+}
+
+impl Counts {
+    pub fn dropped(&self) -> u64 {
+        self.sampled + self.limited
+    }
+}
+
+pub struct Throttle {
+    thinning: Thinning,
+    /// Events the rate limit still allows, refilled as time passes, up to a period's worth.
+    allowance: f64,
+    last: Option<Instant>,
+    pub counts: Counts,
+}
+
+impl Throttle {
+    pub fn new(thinning: Thinning) -> Self {
+        Self {
+            thinning,
+            allowance: thinning.rate.map_or(0.0, |r| r.events as f64),
+            last: None,
+            counts: Counts::default(),
+        }
+    }
+
+    pub fn keep(&mut self, event: &Event) -> bool {
+        self.keep_at(event, Instant::now())
+    }
+
+    fn keep_at(&mut self, event: &Event, now: Instant) -> bool {
+        if !self.thinning.applies(event) {
+            return true;
+        }
+
+        if let Some(share) = self.thinning.sample {
+            if !sampled(event, share) {
+                self.counts.sampled += 1;
+                return false;
+            }
+        }
+
+        if let Some(rate) = self.thinning.rate {
+            let elapsed = self.last.map_or(Duration::ZERO, |l| now - l);
+            self.last = Some(now);
+            let refill = elapsed.as_secs_f64() / rate.per.as_secs_f64() * rate.events as f64;
+            self.allowance = (self.allowance + refill).min(rate.events as f64);
+            if self.allowance < 1.0 {
+                self.counts.limited += 1;
+                return false;
+            }
+            self.allowance -= 1.0;
+        }
+        self.counts.kept += 1;
+        true
+    }
+
+    /// How many events were dropped, if any were.
+    pub fn summary(&self) -> Option<String> {
+        let mut dropped = vec![];
+        if self.counts.sampled > 0 {
+            dropped.push(format!("{} by --sample", self.counts.sampled));
+        }
+        if self.counts.limited > 0 {
+            dropped.push(format!("{} by --rate-limit", self.counts.limited));
+        }
+        (!dropped.is_empty()).then(|| {
+            format!(
+                "Statement events dropped: {}, which the summaries above don't count",
+                dropped.join(", ")
+            )
+        })
+    }
+}
+
+/// How many executions each kept one of the events `counts` are of stands for.
+pub fn scale(counts: &Counts) -> f64 {
+    match counts.kept {
+        0 => 1.0,
+        kept => (kept + counts.dropped()) as f64 / kept as f64,
+    }
+}
+
+fn is_statement_event(event: &Event) -> bool {
+    matches!(
+        event.kind,
+        EventKind::PrepareStatement
+            | EventKind::FreeStatement
+            | EventKind::CloseCursor
+            | EventKind::ExecuteStatementStart
+            | EventKind::ExecuteStatementFinish
+            | EventKind::ExecuteProcedureStart
+            | EventKind::ExecuteProcedureFinish
+            | EventKind::ExecuteFunctionStart
+            | EventKind::ExecuteFunctionFinish
+            | EventKind::ExecuteTriggerStart
+            | EventKind::ExecuteTriggerFinish
+    )
+}
+
+/// Whether `event` is in the sample. The choice is made per statement, or routine, in a
+/// transaction, so the start and finish of an execution are kept or dropped together.
+fn sampled(event: &Event, share: f64) -> bool {
+    let mut hasher = DefaultHasher::new();
+    if let Some(att) = &event.attachment {
+        (&att.database, att.id).hash(&mut hasher);
+    }
+    event.transaction.as_ref().map(|t| t.id).hash(&mut hasher);
+    match &event.statement {
+        Some(stmt) => stmt.id.hash(&mut hasher),
+        None => event.lines.first().hash(&mut hasher),
+    }
+    (hasher.finish() as f64 / u64::MAX as f64) < share
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{Perf, Statement};
+
+    #[test]
+    fn keeps_a_share_and_a_rate_but_every_slow_statement() {
+        let template = crate::fixtures::bare("EXECUTE_STATEMENT_FINISH");
+        let statement = |id: i64, ms: i64| Event {
+            statement: Some(Statement {
+                id,
+                sql: "select 1 from rdb$database".into(),
+                ..Default::default()
+            }),
+            perf: Some(Perf {
+                duration_ms: ms,
+                ..Default::default()
+            }),
+            ..template.clone()
+        };
+
+        assert_eq!(parse_share("10%"), Ok(0.1));
+        let mut sample = Throttle::new(Thinning {
+            sample: Some(0.1),
+            rate: None,
+            alert_threshold: Some(Duration::from_millis(500)),
+        });
+        let kept = (0..10_000)
+            .filter(|&id| sample.keep(&statement(id, 5)))
+            .count();
+        assert!((800..1200).contains(&kept), "{kept}");
+        assert!((0..100).all(|id| sample.keep(&statement(id, 900))));
+
+        let rate = parse_rate("100/s").unwrap();
+        let mut limit = Throttle::new(Thinning {
+            sample: None,
+            rate: Some(rate),
+            alert_threshold: None,
+        });
+        let start = Instant::now();
+        let kept = (0..150)
+            .filter(|&id| limit.keep_at(&statement(id, 5), start))
+            .count();
+        assert_eq!(kept, 100);
+        assert!(limit.keep_at(&statement(0, 5), start + Duration::from_millis(20)));
+        assert_eq!(
+            limit.summary().as_deref(),
+            Some("Statement events dropped: 50 by --rate-limit, which the summaries above don't count")
+        );
+        assert_eq!(limit.counts.kept, 101);
+        assert!((scale(&limit.counts) - 151.0 / 101.0).abs() < 1e-9);
+    }
+}
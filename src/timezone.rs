@@ -0,0 +1,461 @@
+//! Converting trace timestamps, which are the server's wall-clock time without an offset,
+//! to RFC 3339 in a chosen zone, correcting for a server clock that is off.
+//!
+//! Named zones are read from the system's tz database, as compiled TZif files, along
+//! with the POSIX TZ rule at their end that covers the years after the last transition.
+
+use crate::event::Event;
+use crate::units;
+use chrono::{
+    DateTime, Datelike, Duration as TimeDelta, FixedOffset, Local, NaiveDate, NaiveDateTime,
+    Offset, SecondsFormat, TimeZone, Utc,
+};
+use std::path::PathBuf;
+
+/// Where tz databases are usually installed; `TZDIR` takes precedence.
+const ZONEINFO_DIRS: &[&str] = &[
+    "/usr/share/zoneinfo",
+    "/usr/lib/zoneinfo",
+    "/usr/share/lib/zoneinfo",
+];
+
+/// A time zone for `--timezone` and `--server-timezone`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Zone {
+    Utc,
+    /// The zone of the machine running rsfbtrace.
+    Local,
+    Fixed(FixedOffset),
+    Named(String, Rules),
+}
+
+/// Parses `UTC`, `local`, an offset such as `+05:30` or a zone name such as
+/// `Europe/Berlin`.
+pub fn parse_zone(s: &str) -> Result<Zone, String> {
+    let s = s.trim();
+    if s.eq_ignore_ascii_case("utc") || s == "Z" {
+        return Ok(Zone::Utc);
+    }
+    if s.eq_ignore_ascii_case("local") {
+        return Ok(Zone::Local);
+    }
+    if s.starts_with(['+', '-']) {
+        return parse_offset(s)
+            .and_then(FixedOffset::east_opt)
+            .map(Zone::Fixed)
+            .ok_or_else(|| format!("'{s}' is not a valid offset. Expected e.g. +02:00."));
+    }
+
+    if s.is_empty() || s.split('/').any(|part| part.is_empty() || part == "..") {
+        return Err(format!("'{s}' is not a valid time zone"));
+    }
+    let dirs = std::env::var_os("TZDIR")
+        .map(PathBuf::from)
+        .into_iter()
+        .chain(ZONEINFO_DIRS.iter().map(PathBuf::from));
+    for dir in dirs {
+        if let Ok(data) = std::fs::read(dir.join(s)) {
+            return Rules::parse(&data)
+                .map(|rules| Zone::Named(s.into(), rules))
+                .ok_or_else(|| {
+                    format!("{} isn't a valid tz database file", dir.join(s).display())
+                });
+        }
+    }
+    Err(format!(
+        "Unknown time zone '{s}'. Expected UTC, local, an offset such as +02:00 or a zone of \
+         the tz database such as Europe/Berlin"
+    ))
+}
+
+/// Parses `+hh[:mm[:ss]]`, `-hhmm` or `+h`, as seconds east of UTC.
+fn parse_offset(s: &str) -> Option<i32> {
+    if s.is_empty() {
+        return None;
+    }
+    let (sign, rest) = match s.split_at(1) {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => (1, s),
+    };
+    let parts: Vec<&str> = match rest.contains(':') {
+        true => rest.split(':').collect(),
+        false if rest.len() == 4 => vec![&rest[..2], &rest[2..]],
+        false => vec![rest],
+    };
+    if parts.len() > 3 || parts.iter().any(|p| p.is_empty() || p.len() > 2) {
+        return None;
+    }
+    let mut seconds = 0;
+    for (part, unit) in parts.iter().zip([3600, 60, 1]) {
+        seconds += part.parse::<i32>().ok()? * unit;
+    }
+    Some(sign * seconds)
+}
+
+impl Zone {
+    /// The offset from UTC of this zone at `utc`.
+    pub fn offset_at(&self, utc: NaiveDateTime) -> FixedOffset {
+        let seconds = match self {
+            Self::Utc => 0,
+            Self::Local => Local.offset_from_utc_datetime(&utc).fix().local_minus_utc(),
+            Self::Fixed(offset) => offset.local_minus_utc(),
+            Self::Named(_, rules) => rules.offset_at(utc.and_utc().timestamp()),
+        };
+        FixedOffset::east_opt(seconds).unwrap_or(Utc.fix())
+    }
+
+    /// The UTC time of the wall-clock time `local` in this zone. Times skipped or
+    /// repeated by a DST change are taken with the offset before it.
+    pub fn to_utc(&self, local: NaiveDateTime) -> NaiveDateTime {
+        let first = local - TimeDelta::seconds(self.offset_at(local).local_minus_utc().into());
+        local - TimeDelta::seconds(self.offset_at(first).local_minus_utc().into())
+    }
+
+    /// `utc` as RFC 3339 in this zone.
+    pub fn format(&self, utc: NaiveDateTime) -> String {
+        let offset = self.offset_at(utc);
+        let time: DateTime<FixedOffset> = DateTime::from_naive_utc_and_offset(utc, offset);
+        match self {
+            Self::Utc => time.to_rfc3339_opts(SecondsFormat::Micros, true),
+            _ => time.to_rfc3339_opts(SecondsFormat::Micros, false),
+        }
+    }
+}
+
+/// The UTC offsets of a named zone, from a TZif file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rules {
+    /// Unix times at which the offset changes, and the offset from then on.
+    transitions: Vec<(i64, i32)>,
+    /// The offset before the first transition.
+    initial: i32,
+    /// The rule for times after the last transition.
+    footer: Option<Posix>,
+}
+
+impl Rules {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let header = |data: &[u8]| -> Option<[usize; 6]> {
+            if data.get(..4)? != b"TZif" {
+                return None;
+            }
+            let mut counts = [0; 6];
+            for (i, count) in counts.iter_mut().enumerate() {
+                let at = 20 + 4 * i;
+                *count = u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?) as usize;
+            }
+            Some(counts)
+        };
+        let block_len = |[isut, isstd, leap, time, ty, chars]: [usize; 6], time_size: usize| {
+            time * time_size + time + ty * 6 + chars + leap * (time_size + 4) + isstd + isut
+        };
+
+        let v1 = header(data)?;
+        let version = *data.get(4)?;
+        // Version 2 and later repeat the data with 64-bit times, followed by the footer.
+        let (counts, body, time_size) = match version {
+            0 => (v1, data.get(44..)?, 4),
+            _ => {
+                let v2 = data.get(44 + block_len(v1, 4)..)?;
+                (header(v2)?, v2.get(44..)?, 8)
+            }
+        };
+        let [_, _, _, time_count, type_count, _] = counts;
+
+        let times = body.get(..time_count * time_size)?;
+        let indices = body.get(time_count * time_size..time_count * (time_size + 1))?;
+        let types_at = time_count * (time_size + 1);
+        let offsets: Vec<i32> = (0..type_count)
+            .map(|i| {
+                let at = types_at + 6 * i;
+                Some(i32::from_be_bytes(body.get(at..at + 4)?.try_into().ok()?))
+            })
+            .collect::<Option<_>>()?;
+
+        let mut transitions = vec![];
+        for (time, &index) in times.chunks(time_size).zip(indices) {
+            let time = match time_size {
+                4 => i32::from_be_bytes(time.try_into().ok()?).into(),
+                _ => i64::from_be_bytes(time.try_into().ok()?),
+            };
+            transitions.push((time, *offsets.get(index as usize)?));
+        }
+
+        let footer = match version {
+            0 => None,
+            _ => {
+                let rest = body.get(block_len(counts, 8)..)?;
+                let text = std::str::from_utf8(rest).ok()?;
+                text.trim_matches('\n')
+                    .lines()
+                    .next()
+                    .and_then(Posix::parse)
+            }
+        };
+        Some(Self {
+            transitions,
+            initial: *offsets.first()?,
+            footer,
+        })
+    }
+
+    fn offset_at(&self, unix: i64) -> i32 {
+        let after_last = self
+            .transitions
+            .last()
+            .is_none_or(|&(last, _)| unix >= last);
+        match &self.footer {
+            Some(footer) if after_last => footer.offset_at(unix),
+            _ => match self.transitions.partition_point(|&(t, _)| t <= unix) {
+                0 => self.initial,
+                n => self.transitions[n - 1].1,
+            },
+        }
+    }
+}
+
+/// A POSIX TZ rule such as `CET-1CEST,M3.5.0,M10.5.0/3`, with offsets east of UTC.
+#[derive(Debug, Clone, PartialEq)]
+struct Posix {
+    standard: i32,
+    dst: Option<(i32, Transition, Transition)>,
+}
+
+/// When DST starts or ends: day `weekday` (0 is Sunday) of week `week` (5 is the last)
+/// of `month`, at `seconds` after midnight local time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Transition {
+    month: u32,
+    week: u32,
+    weekday: u32,
+    seconds: i64,
+}
+
+impl Posix {
+    fn parse(rule: &str) -> Option<Self> {
+        let mut rest = rule;
+        skip_name(&mut rest)?;
+        // POSIX offsets are west of UTC.
+        let standard = -take_offset(&mut rest)?;
+        if rest.is_empty() {
+            return Some(Self {
+                standard,
+                dst: None,
+            });
+        }
+
+        skip_name(&mut rest)?;
+        let dst_offset = match rest.starts_with(',') {
+            true => standard + 3600,
+            false => -take_offset(&mut rest)?,
+        };
+        let mut transitions = rest.strip_prefix(',')?.split(',').map(Transition::parse);
+        let (start, end) = (transitions.next()??, transitions.next()??);
+        Some(Self {
+            standard,
+            dst: Some((dst_offset, start, end)),
+        })
+    }
+
+    fn offset_at(&self, unix: i64) -> i32 {
+        let Some((dst, start, end)) = self.dst else {
+            return self.standard;
+        };
+        let Some(year) =
+            DateTime::from_timestamp(unix + i64::from(self.standard), 0).map(|t| t.year())
+        else {
+            return self.standard;
+        };
+        // DST starts in standard time and ends in DST.
+        let starts = start.unix(year) - i64::from(self.standard);
+        let ends = end.unix(year) - i64::from(dst);
+        let in_dst = match starts < ends {
+            true => (starts..ends).contains(&unix),
+            // The southern hemisphere, where DST spans the new year.
+            false => !(ends..starts).contains(&unix),
+        };
+        match in_dst {
+            true => dst,
+            false => self.standard,
+        }
+    }
+}
+
+impl Transition {
+    /// Parses `Mm.w.d[/time]`. The other forms of a date aren't used by the tz database.
+    fn parse(s: &str) -> Option<Self> {
+        let (date, time) = match s.split_once('/') {
+            Some((date, time)) => (date, parse_offset(time)?),
+            None => (s, 2 * 3600),
+        };
+        let mut parts = date.strip_prefix('M')?.split('.');
+        let mut next = || parts.next()?.parse::<u32>().ok();
+        Some(Self {
+            month: next()?,
+            week: next()?,
+            weekday: next()?,
+            seconds: time.into(),
+        })
+    }
+
+    /// The local time of this transition in `year`, as if it were a Unix time.
+    fn unix(self, year: i32) -> i64 {
+        let Some(first) = NaiveDate::from_ymd_opt(year, self.month, 1) else {
+            return 0;
+        };
+        let to_weekday = (7 + self.weekday - first.weekday().num_days_from_sunday()) % 7;
+        let mut day = 1 + to_weekday + 7 * (self.week.clamp(1, 5) - 1);
+        while NaiveDate::from_ymd_opt(year, self.month, day).is_none() {
+            day -= 7;
+        }
+        let date = NaiveDate::from_ymd_opt(year, self.month, day).unwrap_or(first);
+        date.and_hms_opt(0, 0, 0)
+            .map_or(0, |t| t.and_utc().timestamp())
+            + self.seconds
+    }
+}
+
+/// Skips a zone abbreviation, `CET` or `<+0330>`.
+fn skip_name(rest: &mut &str) -> Option<()> {
+    let end = match rest.strip_prefix('<') {
+        Some(quoted) => quoted.find('>')? + 2,
+        None => rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len()),
+    };
+    (end >= 3).then(|| *rest = &rest[end..])
+}
+
+/// Takes a POSIX offset, `-1`, `5` or `-3:30`, as seconds.
+fn take_offset(rest: &mut &str) -> Option<i32> {
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, ':' | '+' | '-')))
+        .unwrap_or(rest.len());
+    let (offset, after) = rest.split_at(end);
+    *rest = after;
+    parse_offset(offset)
+}
+
+/// How far the server's clock is ahead of this machine's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Skew {
+    Fixed(TimeDelta),
+    /// Estimated from when events arrive.
+    Auto,
+}
+
+/// Parses `auto` or a duration such as `1500ms` or `-2s`.
+pub fn parse_skew(s: &str) -> Result<Skew, String> {
+    let s = s.trim();
+    if s.eq_ignore_ascii_case("auto") {
+        return Ok(Skew::Auto);
+    }
+    let (sign, duration) = match s.strip_prefix('-') {
+        Some(d) => (-1, d),
+        None => (1, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let duration = TimeDelta::from_std(units::parse_duration(duration)?)
+        .map_err(|_| format!("'{s}' is too large a clock skew"))?;
+    Ok(Skew::Fixed(duration * sign))
+}
+
+/// Rewrites the timestamps of events for `--timezone`.
+pub struct Clock {
+    server: Zone,
+    target: Zone,
+    skew: Skew,
+    /// With `Skew::Auto`, the largest lead of an event's timestamp over when it arrived,
+    /// which is the skew less the fastest delivery.
+    lead: Option<TimeDelta>,
+}
+
+impl Clock {
+    pub fn new(server: Zone, target: Zone, skew: Skew) -> Self {
+        Self {
+            server,
+            target,
+            skew,
+            lead: None,
+        }
+    }
+
+    /// Replaces the timestamp of an event that just arrived with the corrected time in
+    /// the target zone, as RFC 3339.
+    pub fn convert(&mut self, event: &mut Event) {
+        let Some(time) = event.time() else {
+            return;
+        };
+        let utc = self.server.to_utc(time);
+        let skew = match self.skew {
+            Skew::Fixed(skew) => skew,
+            Skew::Auto => {
+                let lead = utc - Utc::now().naive_utc();
+                *self.lead.insert(self.lead.map_or(lead, |l| l.max(lead)))
+            }
+        };
+        event.timestamp = self.target.format(utc - skew);
+    }
+
+    /// Replaces the timestamp of an event rsfbtrace made itself with this machine's time
+    /// in the target zone.
+    pub fn now(&self, event: &mut Event) {
+        event.timestamp = self.target.format(Utc::now().naive_utc());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_across_dst_and_reads_posix_rules() {
+        let berlin = Posix::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        let rules = Rules {
+            transitions: vec![],
+            initial: 3600,
+            footer: Some(berlin),
+        };
+        let zone = Zone::Named("Europe/Berlin".into(), rules);
+        let at = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f").unwrap();
+
+        assert_eq!(
+            zone.format(zone.to_utc(at("2024-01-15T10:23:45.3450"))),
+            "2024-01-15T10:23:45.345000+01:00"
+        );
+        assert_eq!(
+            Zone::Utc.format(zone.to_utc(at("2024-07-15T10:23:45"))),
+            "2024-07-15T08:23:45.000000Z"
+        );
+        // DST ends at 03:00 CEST on the last Sunday of October.
+        assert_eq!(
+            zone.offset_at(at("2024-10-27T00:59:59")).local_minus_utc(),
+            7200
+        );
+        assert_eq!(
+            zone.offset_at(at("2024-10-27T01:00:00")).local_minus_utc(),
+            3600
+        );
+
+        let sydney = Posix::parse("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+        assert_eq!(
+            sydney.offset_at(at("2024-01-15T00:00:00").and_utc().timestamp()),
+            39600
+        );
+        assert_eq!(
+            sydney.offset_at(at("2024-07-15T00:00:00").and_utc().timestamp()),
+            36000
+        );
+        assert_eq!(Posix::parse("<+0530>-5:30").unwrap().standard, 19800);
+
+        assert_eq!(
+            parse_zone("+05:30"),
+            Ok(Zone::Fixed(FixedOffset::east_opt(19800).unwrap()))
+        );
+        assert!(parse_zone("../etc/passwd").is_err());
+        assert_eq!(
+            parse_skew("-1500ms"),
+            Ok(Skew::Fixed(TimeDelta::milliseconds(-1500)))
+        );
+    }
+}
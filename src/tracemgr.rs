@@ -0,0 +1,448 @@
+//! Driving `fbtracemgr` and interpreting what it prints.
+
+use crate::connstr;
+use crate::error::AppError;
+use crate::event::Event;
+use crate::locate;
+use crate::parser::{self, Parser};
+use crate::session;
+use crate::tunnel::Tunnel;
+use crate::units;
+use crate::Args;
+use std::io::{BufRead, BufReader, Read, Result as IOResult};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Messages fbtracemgr prints in place of trace output when the server refuses the
+/// session or its configuration.
+const ERROR_BANNERS: &[&str] = &[
+    "error creating trace session",
+    "error while parsing trace configuration",
+    "unable to perform operation",
+    "your user name and password are not defined",
+    "no permission for",
+    "can not access",
+    "cannot access",
+];
+
+// How to reach the server's service manager. Not a doc comment, as clap would use it as
+// the about text of every command flattening it.
+#[derive(clap::Args, Debug, Clone)]
+pub struct Connection {
+    /// Optional remote hostname, e.g. dbhost, dbhost/3051, inet://dbhost:3051 or xnet://
+    #[arg(long, env = "FIREBIRD_HOST", hide_env = true, value_parser = connstr::parse_host)]
+    pub host: Option<String>,
+
+    /// Firebird username
+    #[arg(short, long, env = "ISC_USER", hide_env = true)]
+    pub user: String,
+
+    /// Firebird password
+    #[arg(
+        short,
+        long,
+        env = "ISC_PASSWORD",
+        hide_env = true,
+        required_unless_present = "pass_file"
+    )]
+    pub pass: Option<String>,
+
+    /// Firebird role to attach with, e.g. one with the TRACE_ANY_ATTACHMENT privilege
+    /// (Firebird 4 and later) so a user other than SYSDBA can trace others' attachments
+    #[arg(long, visible_alias = "trace-role")]
+    pub role: Option<String>,
+
+    /// Read the Firebird password from this file, instead of --pass
+    // Not a conflict with --pass, so it can override ISC_PASSWORD.
+    #[arg(long)]
+    pub pass_file: Option<PathBuf>,
+
+    /// Connect through an SSH tunnel to this host, e.g. user@dbhost
+    #[arg(long)]
+    pub ssh: Option<String>,
+
+    /// Run this fbtracemgr, or the one in this directory, instead of searching $FIREBIRD,
+    /// PATH and the usual install locations. fbsvcmgr is looked for alongside it
+    #[arg(long, value_name = "PATH")]
+    pub fbtracemgr_path: Option<PathBuf>,
+
+    /// The local end of the tunnel, once opened.
+    #[arg(skip)]
+    pub tunnel_port: Option<u16>,
+}
+
+impl Connection {
+    pub fn pass(&self) -> &str {
+        self.pass.as_deref().unwrap_or_default()
+    }
+
+    /// Replaces `--pass-file` with the password it contains.
+    pub fn read_pass_file(&mut self) -> Result<(), AppError> {
+        if let Some(path) = &self.pass_file {
+            match std::fs::read_to_string(path) {
+                Ok(p) => self.pass = Some(p.trim().into()),
+                Err(e) => {
+                    return Err(AppError::InvalidArgs(format!(
+                        "Unable to read the password file {}: {e}",
+                        path.display()
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens the `--ssh` tunnel, if any, and connects through it from then on.
+    pub fn open_tunnel(&mut self) -> Result<Option<Tunnel>, AppError> {
+        let Some(destination) = &self.ssh else {
+            return Ok(None);
+        };
+        let tunnel = Tunnel::open(destination, self.host.as_deref().unwrap_or("localhost"))?;
+        self.tunnel_port = Some(tunnel.port);
+        Ok(Some(tunnel))
+    }
+
+    /// Where to run the Firebird utility `name` from, e.g. `fbsvcmgr`.
+    pub fn tool(&self, name: &str) -> PathBuf {
+        match &self.fbtracemgr_path {
+            Some(path) if name == "fbtracemgr" && path.is_file() => path.clone(),
+            path => locate::tool(name, path.as_deref()),
+        }
+    }
+
+    /// The server as the user named it, for keying what's recorded about it.
+    pub fn server_name(&self) -> &str {
+        match (&self.host, &self.ssh) {
+            (Some(host), _) => host,
+            (None, Some(ssh)) => ssh.rsplit('@').next().unwrap_or(ssh),
+            (None, None) => "localhost",
+        }
+    }
+}
+
+/// The service manager of the server, e.g. `dbhost:service_mgr` or
+/// `inet://dbhost:3051/service_mgr`.
+fn service_mgr(conn: &Connection) -> String {
+    let host = match conn.tunnel_port {
+        Some(port) => Some(format!("127.0.0.1/{port}")),
+        None => conn.host.clone(),
+    };
+    host.map_or("service_mgr".into(), |x| connstr::service_mgr(&x))
+}
+
+/// A `fbtracemgr` command connected to the service manager.
+pub fn fbtracemgr(conn: &Connection) -> Command {
+    let mut cmd = Command::new(conn.tool("fbtracemgr"));
+    cmd.args([
+        "-SE",
+        &service_mgr(conn),
+        "-USER",
+        &conn.user,
+        "-PASS",
+        conn.pass(),
+    ]);
+    if let Some(role) = &conn.role {
+        cmd.args(["-ROLE", role]);
+    }
+    cmd
+}
+
+/// The credentials to give `fbsvcmgr`, after the service manager.
+fn service_auth(conn: &Connection) -> Vec<&str> {
+    let mut auth = vec!["user", &conn.user, "password", conn.pass()];
+    if let Some(role) = &conn.role {
+        auth.extend(["role", role]);
+    }
+    auth
+}
+
+/// The databases the server has open, as reported by `fbsvcmgr`, or `None` if it can't
+/// be asked.
+pub fn attached_databases(conn: &Connection) -> Option<Vec<String>> {
+    let output = Command::new(conn.tool("fbsvcmgr"))
+        .arg(service_mgr(conn))
+        .args(service_auth(conn))
+        .arg("info_svr_db_info")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+    let databases = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("Database in use:"))
+        .map(|d| d.trim().to_string())
+        .collect();
+    Some(databases)
+}
+
+/// The server's version as reported by `fbsvcmgr`, e.g. `5.0.1` for `LI-V5.0.1.1469
+/// Firebird 5.0`, or `None` if it can't be asked.
+pub fn server_version(conn: &Connection) -> Option<String> {
+    let output = Command::new(conn.tool("fbsvcmgr"))
+        .arg(service_mgr(conn))
+        .args(service_auth(conn))
+        .arg("info_server_version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let (_, version) = output.split_once("-V")?;
+    let version: Vec<&str> = version
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()?
+        .split('.')
+        .take(3)
+        .collect();
+    (!version[0].is_empty()).then(|| version.join("."))
+}
+
+/// Whether the service manager answers a trivial request within `timeout`.
+pub fn service_answers(conn: &Connection, timeout: Duration) -> bool {
+    let child = Command::new(conn.tool("fbsvcmgr"))
+        .arg(service_mgr(conn))
+        .args(service_auth(conn))
+        .arg("info_server_version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    let Ok(mut child) = child else {
+        return false;
+    };
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return status.success(),
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(100)),
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return false;
+            }
+        }
+    }
+}
+
+/// The command starting a trace session with the given config.
+pub fn start_command(conn: &Connection, args: &Args, config: &Path) -> Command {
+    let mut cmd = fbtracemgr(conn);
+    cmd.args(["-START", "-NAME"])
+        .arg(session::session_name(args.name.as_deref(), &args.tags))
+        .arg("-CONFIG")
+        .arg(config);
+    cmd
+}
+
+/// Renders `cmd` as a shell command line, with the password redacted.
+pub fn display_command(cmd: &Command) -> String {
+    let mut line = vec![cmd.get_program().to_string_lossy().into_owned()];
+    let mut redact = false;
+    for arg in cmd.get_args() {
+        let arg = arg.to_string_lossy();
+        line.push(if redact {
+            "********".into()
+        } else if arg.is_empty()
+            || arg.contains(|c: char| c.is_whitespace() || "'\"$`\\".contains(c))
+        {
+            format!("'{}'", arg.replace('\'', r"'\''"))
+        } else {
+            arg.to_string()
+        });
+        redact = arg == "-PASS";
+    }
+    line.join(" ")
+}
+
+/// Stops the session server-side, so it doesn't linger if the connection isn't closed
+/// cleanly. If the session ID was never announced, fall back to killing fbtracemgr.
+pub fn stop_trace(conn: &Connection, session_id: i64, child: &mut Child) {
+    if session_id > 0 {
+        let stopped = fbtracemgr(conn)
+            .args(["-STOP", "-ID", &session_id.to_string()])
+            .stdout(Stdio::null())
+            .status();
+        if stopped.is_ok_and(|s| s.success()) {
+            return;
+        }
+    }
+    let _ = child.kill();
+}
+
+/// Events of the trace the parser couldn't make sense of, over every session.
+pub static UNPARSED: AtomicU64 = AtomicU64::new(0);
+
+/// What `read_trace` passes through to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Echo {
+    Off,
+    /// Every line, as soon as it's read.
+    Lines,
+    /// Only lines outside of events, e.g. the session banner, as events are written by a
+    /// sink.
+    OutsideEvents,
+}
+
+/// Feeds the trace output through the parser, for a session started with `max_sql_length`
+/// `max_sql` if known.
+///
+/// Error banners are kept out of the output and collected into `failure` instead, so
+/// they are reported once as the reason the trace failed.
+pub fn read_trace(
+    stdout: impl Read,
+    tx: &Sender<Event>,
+    session_id: &AtomicI64,
+    failure: &Mutex<Option<String>>,
+    echo: Echo,
+    max_sql: Option<usize>,
+) -> IOResult<()> {
+    let mut reader = BufReader::new(stdout);
+    let mut parser = Parser::default();
+    parser.max_sql = max_sql;
+    let mut buf = vec![];
+    let mut in_banner = false;
+
+    while read_line(&mut reader, &mut buf, parser::MAX_EVENT_BYTES)? {
+        // The trace is emitted in the server's charset, which is not necessarily UTF-8.
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        if let Some(id) = line
+            .strip_prefix("Trace session ID ")
+            .and_then(|l| l.strip_suffix(" started"))
+            .and_then(|id| id.parse().ok())
+        {
+            session_id.store(id, Ordering::SeqCst);
+        }
+
+        // Banners continue with detail lines, e.g. the offending line of the config.
+        in_banner = is_error_banner(line)
+            || (in_banner && !line.trim().is_empty() && !parser::is_header(line));
+        if in_banner {
+            if let Ok(mut f) = failure.lock() {
+                let f = f.get_or_insert_with(String::new);
+                if !f.is_empty() {
+                    f.push('\n');
+                }
+                f.push_str(line.trim());
+            }
+            continue;
+        }
+
+        let event = parser.push(line);
+        if parser.unparsed > 0 {
+            UNPARSED.fetch_add(std::mem::take(&mut parser.unparsed), Ordering::Relaxed);
+        }
+        if std::mem::take(&mut parser.cut) > 0 {
+            eprintln!(
+                "Warning: an event was longer than {} and was cut there",
+                units::format_size(parser::MAX_EVENT_BYTES as u64)
+            );
+        }
+        if echo == Echo::Lines || (echo == Echo::OutsideEvents && !parser.in_event()) {
+            println!("{line}");
+        }
+        if let Some(event) = event {
+            let _ = tx.send(event);
+        }
+    }
+
+    if let Some(event) = parser.finish() {
+        let _ = tx.send(event);
+    }
+    UNPARSED.fetch_add(parser.unparsed, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Reads a line into `buf`, returning false at the end of the stream. A line longer
+/// than `max` is cut there and the rest skipped, so one line can't take all the memory.
+fn read_line(reader: &mut impl BufRead, buf: &mut Vec<u8>, max: usize) -> IOResult<bool> {
+    buf.clear();
+    buf.shrink_to(parser::RETAINED_BYTES);
+    let n = reader.take(max as u64).read_until(b'\n', buf)?;
+    if n == 0 {
+        return Ok(false);
+    }
+    if n == max && buf.last() != Some(&b'\n') {
+        loop {
+            let available = reader.fill_buf()?;
+            let Some(end) = available.iter().position(|&b| b == b'\n') else {
+                let len = available.len();
+                reader.consume(len);
+                if len == 0 {
+                    break;
+                }
+                continue;
+            };
+            reader.consume(end + 1);
+            break;
+        }
+    }
+    Ok(true)
+}
+
+fn is_error_banner(line: &str) -> bool {
+    let lower = line.trim().to_lowercase();
+    ERROR_BANNERS.iter().any(|b| lower.starts_with(b))
+}
+
+/// Collects fbtracemgr's stderr, passing it through as it arrives.
+pub fn read_stderr(stderr: impl Read) -> String {
+    let mut text = String::new();
+    for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+        eprintln!("{line}");
+        text.push_str(&line);
+        text.push('\n');
+    }
+    text
+}
+
+/// How to let `conn`'s user trace other users' attachments. Firebird 4 and later grant
+/// it through a role, which fbtracemgr only uses if it's given or the user's default.
+fn privilege_hint(conn: &Connection) -> String {
+    let role = conn.role.as_deref().unwrap_or("TRACER");
+    let then = match &conn.role {
+        Some(_) => "then trace again".to_string(),
+        None => format!("then trace again, as the default role or with --role {role}"),
+    };
+    format!(
+        "Tracing other users' attachments requires SYSDBA, the database owner or the \
+         TRACE_ANY_ATTACHMENT privilege. On Firebird 4 and later, SYSDBA can grant it \
+         through a role in the security database:\n  \
+         CREATE ROLE {role} SET SYSTEM PRIVILEGES TO TRACE_ANY_ATTACHMENT;\n  \
+         GRANT DEFAULT {role} TO USER {};\n{then}.",
+        conn.user
+    )
+}
+
+/// Works out why the trace failed from what fbtracemgr printed.
+pub fn trace_failure(conn: &Connection, output: &str, status: ExitStatus) -> AppError {
+    let output = output.trim();
+    let message = if output.is_empty() {
+        format!("fbtracemgr exited with {status}")
+    } else {
+        output.into()
+    };
+
+    let lower = message.to_lowercase();
+    if lower.contains("user name and password are not defined") || lower.contains("login") {
+        AppError::AuthFailed(message)
+    } else if lower.contains("no permission") || lower.contains("unable to perform operation") {
+        AppError::ConfigRejected(format!("{message}\n{}", privilege_hint(conn)))
+    } else if lower.contains("error while parsing trace configuration") {
+        AppError::ConfigRejected(format!(
+            "{message}\nCheck the values given for --database-matcher and --include-filter."
+        ))
+    } else {
+        AppError::ConfigRejected(message)
+    }
+}
@@ -0,0 +1,67 @@
+//! Watching for statements the server cuts at `max_sql_length`, so a session started with
+//! a low limit, to keep the server's tracing overhead down, can be given a higher one
+//! once the limit turns out to lose statements that matter.
+
+use crate::event::Event;
+use std::collections::HashMap;
+
+/// How many times the same statement has to be cut before it's acted on: one long
+/// statement run once doesn't justify the overhead of a higher limit.
+pub const CUTS_BEFORE_RAISING: u64 = 10;
+
+/// How much the limit is raised by at a time.
+const FACTOR: usize = 4;
+
+#[derive(Debug, Default)]
+pub struct TruncationWatch {
+    /// Cut statements by fingerprint.
+    cuts: HashMap<String, u64>,
+}
+
+impl TruncationWatch {
+    /// The fingerprint of `event`'s statement if it was cut, and that made it
+    /// `CUTS_BEFORE_RAISING` times.
+    pub fn observe<'a>(&mut self, event: &'a Event) -> Option<&'a str> {
+        let stmt = event.statement.as_ref().filter(|s| s.truncated)?;
+        let key = stmt.fingerprint.as_ref().unwrap_or(&stmt.sql);
+        let cuts = self.cuts.entry(key.clone()).or_default();
+        *cuts += 1;
+        (*cuts == CUTS_BEFORE_RAISING).then_some(key.as_str())
+    }
+}
+
+/// The limit to restart with instead of `current`, if `max` allows a higher one.
+pub fn raised(current: usize, max: usize) -> Option<usize> {
+    (current < max).then(|| current.saturating_mul(FACTOR).min(max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Statement;
+
+    #[test]
+    fn reports_statements_cut_repeatedly() {
+        let mut event = crate::fixtures::bare("EXECUTE_STATEMENT_FINISH");
+        event.statement = Some(Statement {
+            sql: "select * from orders where id in (1, 2, 3...".into(),
+            truncated: true,
+            fingerprint: Some("select * from orders where id in (?...".into()),
+            ..Default::default()
+        });
+
+        let mut watch = TruncationWatch::default();
+        for _ in 1..CUTS_BEFORE_RAISING {
+            assert_eq!(watch.observe(&event), None);
+        }
+        assert_eq!(
+            watch.observe(&event),
+            Some("select * from orders where id in (?...")
+        );
+        assert_eq!(watch.observe(&event), None);
+
+        assert_eq!(raised(4096, 65536), Some(16384));
+        assert_eq!(raised(32768, 65536), Some(65536));
+        assert_eq!(raised(65536, 65536), None);
+    }
+}
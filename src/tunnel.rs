@@ -0,0 +1,71 @@
+//! Reaching servers behind a firewall through an SSH port forward.
+
+use crate::connstr;
+use crate::error::AppError;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// How long to wait for ssh to log in and start forwarding, including any passphrase or
+/// password prompt.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An `ssh -L` process forwarding a local port to the server. The forward is closed when
+/// dropped.
+pub struct Tunnel {
+    child: Child,
+    pub port: u16,
+}
+
+impl Tunnel {
+    /// Forwards a free local port through `destination`, e.g. `user@dbhost`, to `target`,
+    /// the Firebird server as seen from there, e.g. `localhost`, `db2/3051` or
+    /// `inet://db2:3051`.
+    pub fn open(destination: &str, target: &str) -> Result<Self, AppError> {
+        let failed = |reason: String| AppError::SshTunnel(destination.into(), reason);
+
+        let (host, port) = connstr::tcp_address(target).map_err(AppError::InvalidArgs)?;
+
+        // ssh can't report the port it picked, so find a free one first.
+        let local = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|l| l.local_addr())
+            .map_err(|e| failed(format!("no free local port: {e}")))?
+            .port();
+
+        let mut child = Command::new("ssh")
+            .args(["-N", "-o", "ExitOnForwardFailure=yes", "-L"])
+            .arg(format!("{}:{local}:{host}:{port}", Ipv4Addr::LOCALHOST))
+            .arg(destination)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| match e.kind() {
+                ErrorKind::NotFound => failed("ssh was not found on PATH".into()),
+                _ => failed(e.to_string()),
+            })?;
+
+        // The forward only listens once ssh has logged in.
+        let started = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Err(failed(format!("ssh exited with {status}")));
+            }
+            if TcpStream::connect((Ipv4Addr::LOCALHOST, local)).is_ok() {
+                return Ok(Self { child, port: local });
+            }
+            if started.elapsed() > CONNECT_TIMEOUT {
+                let _ = child.kill();
+                return Err(failed("timed out waiting for the port forward".into()));
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+/// Parses durations such as `500ms`, `30s`, `10m`, `2h` or `30d`. A bare number is taken as
+/// milliseconds, matching how the trace itself reports timings.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+
+    let n: u64 = n
+        .parse()
+        .map_err(|_| format!("'{s}' is not a valid duration. Expected e.g. 500ms, 30s or 10m."))?;
+
+    match unit.trim() {
+        "" | "ms" => Ok(Duration::from_millis(n)),
+        "s" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        "h" => Ok(Duration::from_secs(n * 60 * 60)),
+        "d" => Ok(Duration::from_secs(n * 60 * 60 * 24)),
+        u => Err(format!(
+            "'{u}' is not a valid duration unit. Valid units are ms, s, m, h and d."
+        )),
+    }
+}
+
+/// Parses sizes such as `512K`, `100M` or `2G`, in powers of 1024. A bare number is taken
+/// as bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+
+    let n: u64 = n
+        .parse()
+        .map_err(|_| format!("'{s}' is not a valid size. Expected e.g. 512K, 100M or 2G."))?;
+
+    let shift = match unit.trim().trim_end_matches(['B', 'b']) {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" | "m" => 20,
+        "G" | "g" => 30,
+        "T" | "t" => 40,
+        u => {
+            return Err(format!(
+                "'{u}' is not a valid size unit. Valid units are K, M, G and T."
+            ))
+        }
+    };
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| format!("'{s}' is too large"))
+}
+
+/// Formats a size in the largest unit it has at least one of, e.g. `1.5M`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let mut size = bytes as f64;
+    let mut unit = "";
+    for u in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = u;
+    }
+    let size = format!("{size:.1}");
+    format!("{}{unit}", size.trim_end_matches(".0"))
+}
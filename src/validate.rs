@@ -0,0 +1,255 @@
+//! Checks for trace options the server accepts but ignores, so a session that would
+//! capture less than asked for doesn't go unnoticed.
+
+use crate::{
+    Args, OPT_PROCEDURE_FINISH, OPT_STATEMENT_FINISH, OPT_STATEMENT_FREE, OPT_STATEMENT_PREPARE,
+    OPT_STATEMENT_START, OPT_TRIGGER_FINISH,
+};
+use regex::RegexBuilder;
+
+const STATEMENT_EVENTS: &[&str] = &[
+    OPT_STATEMENT_PREPARE,
+    OPT_STATEMENT_FREE,
+    OPT_STATEMENT_START,
+    OPT_STATEMENT_FINISH,
+];
+
+/// The events the server reports plans for.
+const PLAN_EVENTS: &[&str] = &[
+    OPT_STATEMENT_PREPARE,
+    OPT_STATEMENT_START,
+    OPT_STATEMENT_FINISH,
+];
+
+/// The events the server reports performance counters for, and applies `time_threshold` to.
+const FINISH_EVENTS: &[&str] = &[
+    OPT_STATEMENT_FINISH,
+    OPT_PROCEDURE_FINISH,
+    OPT_TRIGGER_FINISH,
+];
+
+/// Warnings about the options of `args`. `databases` are those the server has open, if
+/// known, to check `--database-matcher` against.
+pub fn warnings(args: &Args, databases: Option<&[String]>) -> Vec<String> {
+    let traced = |events: &[&str]| args.events.iter().any(|e| events.contains(&e.as_str()));
+    let mut warnings = vec![];
+
+    if args.include_filter.is_some() && !traced(STATEMENT_EVENTS) {
+        warnings.push(format!(
+            "--include-filter only applies to statements; trace the {} events",
+            any_of(STATEMENT_EVENTS)
+        ));
+    }
+    if args.print_plan && !traced(PLAN_EVENTS) {
+        warnings.push(format!(
+            "--print-plan has no effect without the {} events",
+            any_of(PLAN_EVENTS)
+        ));
+    }
+    if args.print_perf && !traced(FINISH_EVENTS) {
+        warnings.push(format!(
+            "--print-perf has no effect without the {} events",
+            any_of(FINISH_EVENTS)
+        ));
+    }
+    if args.print_blr && !args.log_blr_requests {
+        warnings.push("--print-blr has no effect without --log-blr-requests".into());
+    }
+    if args.print_dyn && !args.log_dyn_requests {
+        warnings.push("--print-dyn has no effect without --log-dyn-requests".into());
+    }
+    if let Some(threshold) = args.alert_threshold {
+        if !traced(&[OPT_STATEMENT_FINISH]) {
+            warnings.push(format!(
+                "--alert-threshold has no effect without the {OPT_STATEMENT_FINISH} events"
+            ));
+        } else if threshold.as_millis() < args.time_threshold.into() {
+            warnings.push(format!(
+                "The server doesn't report statements finishing in less than {} ms, so \
+                 --alert-threshold {} ms alerts on those from {} ms",
+                args.time_threshold,
+                threshold.as_millis(),
+                args.time_threshold
+            ));
+        }
+    }
+
+    if let (Some(pattern), Some(databases)) = (&args.database_matcher, databases) {
+        if !databases.is_empty() && !databases.iter().any(|d| matches_database(pattern, d)) {
+            warnings.push(format!(
+                "--database-matcher '{pattern}' matches none of the databases in use: {}. \
+                 Patterns are matched against the full path, e.g. '%[\\\\/]erp.fdb'",
+                databases.join(", ")
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// Explains why a session captured nothing in its first `secs` seconds, after the server
+/// sent `traced` events.
+pub fn empty_capture(args: &Args, traced: u64, secs: u64) -> String {
+    if traced > 0 {
+        let mut filters = vec![];
+        for (name, active) in [
+            ("--filter-user", !args.filter_user.is_empty()),
+            ("--filter-role", !args.filter_role.is_empty()),
+            ("--filter-process", !args.filter_process.is_empty()),
+            ("--stmt-types", !args.stmt_types.is_empty()),
+            ("--where", args.where_expr.is_some()),
+            ("--sample", args.sample.is_some()),
+            ("--rate-limit", args.rate_limit.is_some()),
+        ] {
+            if active {
+                filters.push(name);
+            }
+        }
+        return format!(
+            "{} dropped all {traced} events the server sent in the first {secs}s",
+            filters.join(" and ")
+        );
+    }
+
+    let mut scope = String::new();
+    if let Some(pattern) = &args.database_matcher {
+        scope += &format!(" in databases matching '{pattern}'");
+    }
+    if let Some(filter) = &args.include_filter {
+        scope += &format!(" for statements matching '{filter}'");
+    }
+    let threshold = match args
+        .events
+        .iter()
+        .any(|e| FINISH_EVENTS.contains(&e.as_str()))
+    {
+        true => format!(
+            " Finishes faster than {} ms aren't reported.",
+            args.time_threshold
+        ),
+        false => String::new(),
+    };
+    // Users without the privilege only see their own attachments, and no error says so.
+    let privilege = match args.conn.user.eq_ignore_ascii_case("SYSDBA") {
+        true => String::new(),
+        false => format!(
+            ", and that {} may trace them: without SYSDBA or the TRACE_ANY_ATTACHMENT \
+             privilege, only its own attachments are traced (see --role)",
+            args.conn.user
+        ),
+    };
+    format!(
+        "The server sent no {} events{scope} in the first {secs}s.{threshold} \
+         Check that these match the activity you expect{privilege}",
+        any_of(&args.events.iter().map(String::as_str).collect::<Vec<_>>())
+    )
+}
+
+/// Lists events as alternatives, e.g. `a, b or c`.
+fn any_of(events: &[&str]) -> String {
+    match events.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} or {last}", rest.join(", ")),
+        None => String::new(),
+    }
+}
+
+/// Whether the server would trace `database` for a `<database pattern>` section. Patterns
+/// are `SIMILAR TO` expressions matched against the whole path, with `\` escaping. Case is
+/// ignored, as it is on Windows servers, so this never warns about a pattern that could
+/// match.
+fn matches_database(pattern: &str, database: &str) -> bool {
+    if pattern.eq_ignore_ascii_case(database) {
+        return true;
+    }
+
+    let mut regex = String::from("^(?:");
+    let mut chars = pattern.chars();
+    let mut in_class = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) => regex.push_str(&regex::escape(&escaped.to_string())),
+                None => regex.push_str(r"\\"),
+            },
+            '[' if !in_class => {
+                in_class = true;
+                regex.push('[');
+            }
+            ']' if in_class => {
+                in_class = false;
+                regex.push(']');
+            }
+            // Within a class, only the ends and `^` have a meaning.
+            c if in_class => match c {
+                '^' | '-' => regex.push(c),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            },
+            '%' => regex.push_str(".*"),
+            '_' => regex.push('.'),
+            '|' | '*' | '+' | '?' | '{' | '}' | '(' | ')' | ',' => regex.push(c),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push_str(")$");
+
+    // An invalid pattern is for the server to reject.
+    RegexBuilder::new(&regex)
+        .case_insensitive(true)
+        .build()
+        .map_or(true, |r| r.is_match(database))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cli;
+    use clap::Parser;
+
+    fn args(argv: &[&str]) -> Args {
+        let argv = ["rsfbtrace", "-u", "SYSDBA", "-p", "x"].iter().chain(argv);
+        Cli::try_parse_from(argv).unwrap().trace.unwrap()
+    }
+
+    #[test]
+    fn warns_about_options_the_server_ignores() {
+        let databases = ["/data/erp.fdb".to_string(), r"C:\DATA\CRM.FDB".to_string()];
+
+        let quiet = args(&[
+            "-e",
+            "statement_finish",
+            "--print-plan",
+            "-d",
+            r"%[\\/]crm.fdb",
+        ]);
+        assert!(warnings(&quiet, Some(&databases)).is_empty());
+
+        let noisy = args(&[
+            "-e",
+            "connections",
+            "--print-perf",
+            "-i",
+            "%ORDERS%",
+            "-d",
+            "/data/hr.fdb",
+        ]);
+        let warnings = warnings(&noisy, Some(&databases));
+        assert_eq!(warnings.len(), 3, "{warnings:?}");
+        assert!(warnings[0].starts_with("--include-filter"));
+        assert!(warnings[1].starts_with("--print-perf"));
+        assert!(warnings[2].contains("/data/erp.fdb, C:\\DATA\\CRM.FDB"));
+
+        let filtered = args(&["-e", "statement_finish", "--where", "duration > 5s"]);
+        assert_eq!(
+            empty_capture(&filtered, 5000, 30),
+            "--where dropped all 5000 events the server sent in the first 30s"
+        );
+        assert!(empty_capture(&filtered, 0, 30).contains("faster than 100 ms"));
+        assert!(!empty_capture(&filtered, 0, 30).contains("--role"));
+
+        let mut unprivileged = filtered;
+        unprivileged.conn.user = "ana".into();
+        assert!(empty_capture(&unprivileged, 0, 30)
+            .ends_with("only its own attachments are traced (see --role)"));
+    }
+}
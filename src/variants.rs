@@ -0,0 +1,114 @@
+//! Finding statements that are nearly the same, e.g. differing only in their column list,
+//! which usually come from different code paths building the same query.
+
+pub use similar::ChangeTag;
+use similar::TextDiff;
+use std::time::Duration;
+
+/// How alike two statements have to be to count as variants, as the share of their
+/// words they have in common.
+pub const MIN_SIMILARITY: f32 = 0.8;
+
+/// How many statements are compared with each other: every pair is diffed.
+pub const MAX_CANDIDATES: usize = 200;
+
+/// Diffing two long statements can take a while; past this the diff is approximate.
+const DIFF_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// A pair of near-identical statements, by their indices in the compared list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub first: usize,
+    pub second: usize,
+    pub similarity: f32,
+}
+
+/// The pairs of `statements` that are variants of each other, most alike first.
+pub fn find(statements: &[&str]) -> Vec<Variant> {
+    let words: Vec<usize> = statements
+        .iter()
+        .map(|s| s.split_whitespace().count())
+        .collect();
+
+    let mut variants = vec![];
+    for i in 0..statements.len() {
+        for j in i + 1..statements.len() {
+            // Statements of very different lengths can't have enough words in common.
+            let (short, long) = (words[i].min(words[j]), words[i].max(words[j]));
+            if long == 0 || (2 * short) as f32 / ((short + long) as f32) < MIN_SIMILARITY {
+                continue;
+            }
+            let similarity = diff(statements[i], statements[j]).ratio();
+            if similarity >= MIN_SIMILARITY && statements[i] != statements[j] {
+                variants.push(Variant {
+                    first: i,
+                    second: j,
+                    similarity,
+                });
+            }
+        }
+    }
+    variants.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    variants
+}
+
+/// The words of `first` and `second`, with consecutive words in the same one of them
+/// merged, each marked as only in `first` (`Delete`), only in `second` (`Insert`) or in
+/// both (`Equal`).
+pub fn word_diff(first: &str, second: &str) -> Vec<(ChangeTag, String)> {
+    let mut parts: Vec<(ChangeTag, String)> = vec![];
+    for change in diff(first, second).iter_all_changes() {
+        match parts.last_mut() {
+            Some((tag, text)) if *tag == change.tag() => text.push_str(change.value()),
+            _ => parts.push((change.tag(), change.value().to_string())),
+        }
+    }
+    parts
+}
+
+/// `word_diff` for a terminal, marking words only in the first statement as `[-...-]`
+/// and only in the second as `{+...+}`, like `git diff --word-diff`.
+pub fn to_text(parts: &[(ChangeTag, String)]) -> String {
+    parts
+        .iter()
+        .map(|(tag, text)| match tag {
+            ChangeTag::Equal => text.clone(),
+            ChangeTag::Delete => format!("[-{text}-]"),
+            ChangeTag::Insert => format!("{{+{text}+}}"),
+        })
+        .collect()
+}
+
+fn diff<'a>(first: &'a str, second: &'a str) -> TextDiff<'a, 'a, 'a, str> {
+    TextDiff::configure()
+        .timeout(DIFF_TIMEOUT)
+        .diff_words(first, second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs_statements_differing_in_a_few_words() {
+        let statements = [
+            "select id, name, email from customers where id = ?",
+            "select * from orders where customer_id = ? order by created_at desc",
+            "select id, name from customers where id = ?",
+            "select id, name, email from customers where id = ?",
+        ];
+        let variants = find(&statements);
+        assert_eq!(
+            variants
+                .iter()
+                .map(|v| (v.first, v.second))
+                .collect::<Vec<_>>(),
+            [(0, 2), (2, 3)]
+        );
+
+        assert_eq!(
+            to_text(&word_diff(statements[0], statements[2])),
+            "select id, [-name, email-]{+name+} from customers where id = ?"
+        );
+    }
+}
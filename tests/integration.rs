@@ -0,0 +1,233 @@
+//! End-to-end tests against a Firebird server in a container: each starts one with
+//! docker, traces it with rsfbtrace while the container's isql runs a workload, and
+//! checks the events parsed.
+//!
+//! Run with `cargo test --features integration`. They need docker, and Firebird's
+//! fbtracemgr on this machine, found as rsfbtrace finds it; `RSFBTRACE_TEST_IMAGE` picks
+//! another image than the latest Firebird, e.g. firebirdsql/firebird:4.
+
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+const PASSWORD: &str = "masterkey";
+
+const DATABASE: &str = "/var/lib/firebird/data/test.fdb";
+
+/// How long a server has to start, and a trace to see what it waits for.
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+fn docker(args: &[&str]) -> String {
+    let output = Command::new("docker")
+        .args(args)
+        .output()
+        .expect("docker is needed for the integration tests");
+    assert!(
+        output.status.success(),
+        "docker {}: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).into()
+}
+
+/// A Firebird server in a container, removed when dropped.
+struct Firebird {
+    id: String,
+    port: u16,
+}
+
+impl Firebird {
+    fn start() -> Self {
+        let image = std::env::var("RSFBTRACE_TEST_IMAGE").unwrap_or("firebirdsql/firebird".into());
+        let id = docker(&[
+            "run",
+            "-d",
+            "--rm",
+            "-p",
+            "127.0.0.1::3050",
+            "-e",
+            &format!("FIREBIRD_ROOT_PASSWORD={PASSWORD}"),
+            "-e",
+            "FIREBIRD_DATABASE=test.fdb",
+            &image,
+        ])
+        .trim()
+        .to_string();
+        let port = docker(&["port", &id, "3050/tcp"]);
+        let port = port
+            .lines()
+            .next()
+            .and_then(|l| l.rsplit(':').next())
+            .and_then(|p| p.parse().ok())
+            .expect("the server's port");
+        let server = Self { id, port };
+
+        // The database is created once the server is up.
+        let deadline = Instant::now() + TIMEOUT;
+        while server.try_isql("SELECT 1 FROM RDB$DATABASE;").is_err() {
+            assert!(Instant::now() < deadline, "the server didn't start");
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        server
+    }
+
+    fn host(&self) -> String {
+        format!("127.0.0.1/{}", self.port)
+    }
+
+    /// Runs `sql` with the container's isql, attached over TCP so it's traced like a
+    /// client would be.
+    fn try_isql(&self, sql: &str) -> Result<String, String> {
+        let mut isql = Command::new("docker")
+            .args(["exec", "-i", &self.id, "/opt/firebird/bin/isql", "-q"])
+            .args(["-user", "SYSDBA", "-password", PASSWORD])
+            .arg(format!("localhost:{DATABASE}"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| e.to_string())?;
+        isql.stdin
+            .take()
+            .unwrap()
+            .write_all(sql.as_bytes())
+            .map_err(|e| e.to_string())?;
+        let output = isql.wait_with_output().map_err(|e| e.to_string())?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match output.status.success() && stderr.trim().is_empty() {
+            true => Ok(String::from_utf8_lossy(&output.stdout).into()),
+            false => Err(stderr.into()),
+        }
+    }
+}
+
+impl Drop for Firebird {
+    fn drop(&mut self) {
+        let _ = Command::new("docker").args(["rm", "-f", &self.id]).output();
+    }
+}
+
+/// rsfbtrace running `subcommand`, or a trace with none, connecting to `server`.
+fn rsfbtrace(server: &Firebird, subcommand: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rsfbtrace"));
+    command
+        .args(subcommand)
+        .args(["--host", &server.host(), "-u", "SYSDBA", "-p", PASSWORD])
+        .env_remove("FIREBIRD_HOST");
+    command
+}
+
+/// rsfbtrace tracing a server, its JSON events read as they arrive. Stopped when dropped.
+struct Trace {
+    child: Child,
+    events: Receiver<Value>,
+}
+
+impl Trace {
+    fn start(server: &Firebird, events: &[&str]) -> Self {
+        let mut command = rsfbtrace(server, &[]);
+        for event in events {
+            command.args(["-e", event]);
+        }
+        let mut child = command
+            .args(["--output-format", "json"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (tx, events) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                let event = serde_json::from_str(&line)
+                    .unwrap_or_else(|e| panic!("not a JSON event: {e}: {line}"));
+                if tx.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { child, events }
+    }
+
+    /// Runs `sql` until an event it makes is found, as the session takes a moment to
+    /// start after rsfbtrace does.
+    fn wait_for(&self, server: &Firebird, sql: &str, found: impl Fn(&Value) -> bool) -> Value {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            let _ = server.try_isql(sql);
+            let until = Instant::now() + Duration::from_secs(2);
+            while let Ok(event) = self
+                .events
+                .recv_timeout(until.saturating_duration_since(Instant::now()))
+            {
+                if found(&event) {
+                    return event;
+                }
+            }
+        }
+        panic!("no event of {sql} was traced within {}s", TIMEOUT.as_secs());
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn selftest_passes() {
+    let server = Firebird::start();
+    let output = rsfbtrace(&server, &["selftest"])
+        .args(["--timeout", "60s"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{stdout}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("ok    trace"), "{stdout}");
+}
+
+#[test]
+fn traces_the_statements_of_a_workload() {
+    let server = Firebird::start();
+    let trace = Trace::start(&server, &["connections", "statement_finish"]);
+    let sql = "SELECT 42 AS rsfbtrace_probe FROM RDB$DATABASE;";
+
+    let finish = trace.wait_for(&server, sql, |e| {
+        e["kind"] == "EXECUTE_STATEMENT_FINISH"
+            && e["statement"]["sql"]
+                .as_str()
+                .is_some_and(|s| s.contains("rsfbtrace_probe"))
+    });
+    assert_eq!(finish["attachment"]["user"], "SYSDBA");
+    assert!(finish["attachment"]["database"]
+        .as_str()
+        .is_some_and(|d| d.ends_with("test.fdb")));
+    assert_eq!(finish["records_fetched"], 1);
+    assert!(finish["perf"]["duration_ms"].is_i64(), "{finish}");
+    assert_eq!(finish["failed"], false);
+
+    let attach = trace.wait_for(&server, sql, |e| e["kind"] == "ATTACH_DATABASE");
+    assert_eq!(attach["attachment"]["user"], "SYSDBA");
+}
+
+#[test]
+fn decodes_the_errors_of_a_workload() {
+    let server = Firebird::start();
+    let trace = Trace::start(&server, &["errors"]);
+    let error = trace.wait_for(&server, "SELECT * FROM rsfbtrace_missing;", |e| {
+        e["kind"] == "ERROR"
+            && e["raw"]
+                .as_str()
+                .is_some_and(|r| r.contains("RSFBTRACE_MISSING"))
+    });
+    assert!(error["error_code"]["gdscode"].is_i64(), "{error}");
+    assert!(error["error_code"]["name"].is_string(), "{error}");
+}